@@ -0,0 +1,340 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use std::iter::Peekable;
+
+type Tokens = Peekable<proc_macro::token_stream::IntoIter>;
+
+/**
+ * Derive VariableResolver and ToValue for a struct with named fields, the
+ * fields are the variables and each field has a variable of its name.
+ * `#[rule(rename = "name")]` gives the field another variable name and
+ * `#[rule(skip)]` leaves it out. Field types implement ToValue, a struct
+ * field which derives RuleContext is a MAP so ${user.name} reads into it.
+ */
+#[proc_macro_derive(RuleContext, attributes(rule))]
+pub fn derive_rule_context(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(output) => return output,
+        Err((span, msg)) => return compile_error(span, &msg),
+    }
+}
+
+type Error = (Span, String);
+
+// A field of the struct and the name of its variable
+struct Field {
+    ident: String,
+    var: String,
+}
+
+fn compile_error(span: Span, msg: &str) -> TokenStream {
+    let mut tokens: Vec<TokenTree> = vec![
+        Ident::new("compile_error", span).into(),
+        Punct::new('!', Spacing::Alone).into(),
+        Group::new(
+            Delimiter::Parenthesis,
+            TokenTree::from(Literal::string(msg)).into(),
+        )
+        .into(),
+        Punct::new(';', Spacing::Alone).into(),
+    ];
+    for token in tokens.iter_mut() {
+        token.set_span(span);
+    }
+    return tokens.into_iter().collect();
+}
+
+fn is_punct(token: Option<&TokenTree>, c: char) -> bool {
+    return matches!(token, Some(TokenTree::Punct(p)) if p.as_char() == c);
+}
+
+fn is_ident(token: Option<&TokenTree>, name: &str) -> bool {
+    return matches!(token, Some(TokenTree::Ident(i)) if i.to_string() == name);
+}
+
+// Attributes before an item or a field, the args of #[rule(...)] are
+// returned and the others skipped
+fn attributes(tokens: &mut Tokens) -> Result<Vec<TokenStream>, Error> {
+    let mut rule = Vec::new();
+    while is_punct(tokens.peek(), '#') {
+        tokens.next();
+        let group = match tokens.next() {
+            Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Bracket => group,
+            other => return Err((span_of(other.as_ref()), "Expected an attribute".to_string())),
+        };
+        let mut inner = group.stream().into_iter().peekable();
+        if is_ident(inner.peek(), "rule") {
+            inner.next();
+            match inner.next() {
+                Some(TokenTree::Group(args)) if args.delimiter() == Delimiter::Parenthesis => {
+                    rule.push(args.stream());
+                }
+                _ => {
+                    return Err((
+                        group.span(),
+                        "Expected #[rule(rename = \"name\")] or #[rule(skip)]".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+    return Ok(rule);
+}
+
+fn span_of(token: Option<&TokenTree>) -> Span {
+    return token.map_or_else(Span::call_site, |token| token.span());
+}
+
+// pub, pub(crate) and the like
+fn visibility(tokens: &mut Tokens) {
+    if is_ident(tokens.peek(), "pub") {
+        tokens.next();
+        if let Some(TokenTree::Group(group)) = tokens.peek() {
+            if group.delimiter() == Delimiter::Parenthesis {
+                tokens.next();
+            }
+        }
+    }
+}
+
+// Tokens up to the comma which ends a field or a generic param, or up to
+// the > which ends the generic params. Commas in the angle brackets of a
+// type like HashMap<String, i64> are part of it.
+fn until_comma(tokens: &mut Tokens) -> Vec<TokenTree> {
+    let mut taken = Vec::new();
+    let mut depth = 0;
+    while let Some(token) = tokens.peek() {
+        match token {
+            TokenTree::Punct(p) if p.as_char() == ',' && depth == 0 => break,
+            TokenTree::Punct(p) if p.as_char() == '<' => depth += 1,
+            // The > of -> does not close a bracket
+            TokenTree::Punct(p) if p.as_char() == '>' && !is_punct(taken.last(), '-') => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1
+            }
+            _ => {}
+        }
+        taken.extend(tokens.next());
+    }
+    return taken;
+}
+
+fn to_string(tokens: &[TokenTree]) -> String {
+    return tokens.iter().cloned().collect::<TokenStream>().to_string();
+}
+
+// Params of the impl as declared, and the args of the type, which are the
+// params without their bounds and defaults
+fn generics(tokens: &mut Tokens) -> (String, String) {
+    if !is_punct(tokens.peek(), '<') {
+        return (String::new(), String::new());
+    }
+    tokens.next();
+    let (mut params, mut args) = (Vec::new(), Vec::new());
+    while tokens.peek().is_some() && !is_punct(tokens.peek(), '>') {
+        let param = until_comma(tokens);
+        if is_punct(tokens.peek(), ',') {
+            tokens.next();
+        }
+        // The default after =, which is not the = of Iterator<Item = u8>
+        let mut depth = 0;
+        let declared = param
+            .iter()
+            .take_while(|token| {
+                depth += match token {
+                    TokenTree::Punct(p) if p.as_char() == '<' => 1,
+                    TokenTree::Punct(p) if p.as_char() == '>' => -1,
+                    _ => 0,
+                };
+                return depth != 0 || !is_punct(Some(token), '=');
+            })
+            .cloned()
+            .collect::<Vec<TokenTree>>();
+        let name = match declared.as_slice() {
+            // 'a: 'b is 'a
+            [TokenTree::Punct(quote), TokenTree::Ident(name), ..] if quote.as_char() == '\'' => {
+                format!("'{}", name)
+            }
+            // const N: usize is N
+            [TokenTree::Ident(konst), TokenTree::Ident(name), ..]
+                if konst.to_string() == "const" =>
+            {
+                name.to_string()
+            }
+            [TokenTree::Ident(name), ..] => name.to_string(),
+            _ => continue,
+        };
+        params.push(to_string(&declared));
+        args.push(name);
+    }
+    tokens.next();
+    return (
+        format!("<{}>", params.join(", ")),
+        format!("<{}>", args.join(", ")),
+    );
+}
+
+// The name of a variable is a single key of its path, which rule content
+// writes with a-z, A-Z, 0-9 and _
+fn var_name(name: &str, span: Span) -> Result<String, Error> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err((span, format!("{:?} is not a valid variable name", name)));
+    }
+    return Ok(name.to_string());
+}
+
+// The variable of the field, None when it is skipped
+fn field_var(ident: &str, rule: Vec<TokenStream>) -> Result<Option<String>, Error> {
+    let mut var = ident.trim_start_matches("r#").to_string();
+    for args in rule {
+        let args: Vec<TokenTree> = args.into_iter().collect();
+        match args.as_slice() {
+            [TokenTree::Ident(skip)] if skip.to_string() == "skip" => return Ok(None),
+            [TokenTree::Ident(rename), TokenTree::Punct(eq), TokenTree::Literal(name)]
+                if rename.to_string() == "rename" && eq.as_char() == '=' =>
+            {
+                let quoted = name.to_string();
+                let unquoted = quoted
+                    .strip_prefix('"')
+                    .and_then(|s| s.strip_suffix('"'))
+                    .filter(|s| !s.contains('\\'));
+                match unquoted {
+                    Some(s) => var = var_name(s, name.span())?,
+                    None => {
+                        return Err((name.span(), "Expected a plain string literal".to_string()));
+                    }
+                }
+            }
+            _ => {
+                let span = span_of(args.first());
+                return Err((
+                    span,
+                    "Expected #[rule(rename = \"name\")] or #[rule(skip)]".to_string(),
+                ));
+            }
+        }
+    }
+    return Ok(Some(var));
+}
+
+fn fields(body: Group) -> Result<Vec<Field>, Error> {
+    let mut tokens = body.stream().into_iter().peekable();
+    let mut fields: Vec<Field> = Vec::new();
+    while tokens.peek().is_some() {
+        let rule = attributes(&mut tokens)?;
+        visibility(&mut tokens);
+        let ident = match tokens.next() {
+            Some(TokenTree::Ident(ident)) => ident,
+            other => return Err((span_of(other.as_ref()), "Expected a field".to_string())),
+        };
+        if !is_punct(tokens.next().as_ref(), ':') {
+            return Err((ident.span(), "Expected : after the field".to_string()));
+        }
+        until_comma(&mut tokens);
+        tokens.next();
+        let name = ident.to_string();
+        if let Some(var) = field_var(&name, rule)? {
+            if fields.iter().any(|field| field.var == var) {
+                return Err((ident.span(), format!("Variable {} is declared twice", var)));
+            }
+            fields.push(Field {
+                ident: name,
+                var: var,
+            });
+        }
+    }
+    return Ok(fields);
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, Error> {
+    let mut tokens = input.into_iter().peekable();
+    attributes(&mut tokens)?;
+    visibility(&mut tokens);
+    if !is_ident(tokens.peek(), "struct") {
+        return Err((
+            span_of(tokens.peek()),
+            "RuleContext can only be derived for a struct with named fields".to_string(),
+        ));
+    }
+    tokens.next();
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(name)) => name.to_string(),
+        other => {
+            return Err((
+                span_of(other.as_ref()),
+                "Expected the struct name".to_string(),
+            ))
+        }
+    };
+    let (params, args) = generics(&mut tokens);
+    let mut bounds = Vec::new();
+    let body = loop {
+        match tokens.next() {
+            Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace => break group,
+            Some(token @ TokenTree::Group(_)) | Some(token @ TokenTree::Punct(_))
+                if bounds.is_empty() =>
+            {
+                return Err((
+                    token.span(),
+                    "RuleContext can only be derived for a struct with named fields".to_string(),
+                ));
+            }
+            Some(token) => bounds.push(token),
+            None => {
+                return Err((
+                    Span::call_site(),
+                    "RuleContext can only be derived for a struct with named fields".to_string(),
+                ));
+            }
+        }
+    };
+    let fields = fields(body)?;
+    let where_clause = to_string(&bounds);
+
+    let mut arms = String::new();
+    let mut entries = String::new();
+    for field in fields.iter() {
+        arms.push_str(&format!(
+            "{:?} => ::core::option::Option::Some(::newlisp::ToValue::to_value(&self.{})),\n",
+            field.var, field.ident
+        ));
+        entries.push_str(&format!(
+            "map.insert(::std::string::String::from({:?}), ::newlisp::ToValue::to_value(&self.{}));\n",
+            field.var, field.ident
+        ));
+    }
+    let output = format!(
+        "impl{params} ::newlisp::VariableResolver for {name}{args} {bounds} {{
+            fn resolve(&self, name: &str) -> ::core::option::Option<::newlisp::Value> {{
+                match name {{
+                    {arms}
+                    _ => ::core::option::Option::None,
+                }}
+            }}
+        }}
+
+        impl{params} ::newlisp::ToValue for {name}{args} {bounds} {{
+            fn to_value(&self) -> ::newlisp::Value {{
+                let mut map = ::std::collections::HashMap::new();
+                {entries}
+                ::newlisp::Value::MAP(map)
+            }}
+        }}",
+        params = params,
+        name = name,
+        args = args,
+        bounds = where_clause,
+        arms = arms,
+        entries = entries,
+    );
+    return output.parse().map_err(|e| {
+        (
+            Span::call_site(),
+            format!("RuleContext expansion failed: {:?}", e),
+        )
+    });
+}
@@ -0,0 +1,348 @@
+use crate::ast::{CompareOp, Expr, ExprKind, Value};
+use crate::check::{static_type, Type};
+use crate::vm::literal;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/**
+ * What the analysis of a rule set found about a pair of its rules, see
+ * RuleSet::analyze
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    // The rules are never true together, their tests of the variable
+    // exclude each other
+    Conflict {
+        first: String,
+        second: String,
+        variable: String,
+    },
+    // Whenever `narrower` is true so is `wider`. It is shadowed when
+    // `wider` ranks before it, so that HighestPriority never selects it.
+    Subsumes {
+        wider: String,
+        narrower: String,
+        shadowed: bool,
+    },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Finding::Conflict {
+                first,
+                second,
+                variable,
+            } => write!(
+                f,
+                "rules {} and {} are never true together, by their tests of ${{{}}}",
+                first, second, variable
+            ),
+            Finding::Subsumes {
+                wider,
+                narrower,
+                shadowed: true,
+            } => write!(
+                f,
+                "rule {} is shadowed by {}, which ranks before it and is true whenever it is",
+                narrower, wider
+            ),
+            Finding::Subsumes {
+                wider, narrower, ..
+            } => write!(f, "rule {} is true whenever {} is", wider, narrower),
+        }
+    }
+}
+
+// Test of a variable against literals
+#[derive(Debug, Clone)]
+enum Test {
+    In(Vec<Value>),
+    NotIn(Vec<Value>),
+    // Bounds and whether they are included
+    Low(Value, bool),
+    High(Value, bool),
+}
+
+impl Test {
+    // Values which can not be compared with a bound fail it, the same as
+    // the comparison fails at eval
+    fn accepts(&self, val: &Value) -> bool {
+        match self {
+            Test::In(values) => values.iter().any(|v| v == val),
+            Test::NotIn(values) => !values.iter().any(|v| v == val),
+            Test::Low(low, included) => match val.partial_cmp(low) {
+                Some(Ordering::Greater) => true,
+                Some(Ordering::Equal) => *included,
+                _ => false,
+            },
+            Test::High(high, included) => match val.partial_cmp(high) {
+                Some(Ordering::Less) => true,
+                Some(Ordering::Equal) => *included,
+                _ => false,
+            },
+        }
+    }
+
+    // Whether every value this test accepts is accepted by the other
+    fn implies(&self, other: &Test) -> bool {
+        match (self, other) {
+            (Test::In(values), other) => values.iter().all(|v| other.accepts(v)),
+            (Test::NotIn(values), Test::NotIn(others)) => others.iter().all(|v| values.contains(v)),
+            (Test::Low(val, included), Test::Low(bound, bound_included))
+            | (Test::High(val, included), Test::High(bound, bound_included)) => {
+                if val == bound {
+                    return *bound_included || !*included;
+                }
+                return other.accepts(val);
+            }
+            (Test::Low(..), Test::NotIn(values)) | (Test::High(..), Test::NotIn(values)) => {
+                values.iter().all(|v| !self.accepts(v))
+            }
+            _ => false,
+        }
+    }
+}
+
+// Whether no value passes all the tests, false when it is not known
+fn unsatisfiable(tests: &[&Test]) -> bool {
+    let allowed = tests.iter().find_map(|test| match test {
+        Test::In(values) => Some(values),
+        _ => None,
+    });
+    if let Some(values) = allowed {
+        return !values.iter().any(|v| tests.iter().all(|t| t.accepts(v)));
+    }
+    for low in tests.iter() {
+        for high in tests.iter() {
+            let (low, high, included) = match (low, high) {
+                (Test::Low(low, low_in), Test::High(high, high_in)) => {
+                    (low, high, *low_in && *high_in)
+                }
+                _ => continue,
+            };
+            match low.partial_cmp(high) {
+                Some(Ordering::Greater) => return true,
+                // The bounds leave a single value at most
+                Some(Ordering::Equal) if !included || !tests.iter().all(|t| t.accepts(low)) => {
+                    return true;
+                }
+                _ => {}
+            }
+        }
+    }
+    return false;
+}
+
+/**
+ * What holds when a rule is true: the tests of variables against literals
+ * in the args of its top AND, and the other args by their rule content
+ */
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Conjunction {
+    tests: BTreeMap<String, Vec<Test>>,
+    others: Vec<String>,
+}
+
+fn var_name(expr: &Expr) -> Option<String> {
+    match &expr.kind {
+        ExprKind::Var(var) => Some(var.name().to_string()),
+        _ => None,
+    }
+}
+
+// Tests which hold when the expression is true, None when it is not a test
+// of a variable against literals
+fn tests_of(expr: &Expr) -> Option<Vec<(String, Test)>> {
+    match &expr.kind {
+        ExprKind::Equals(args) if args.len() == 2 => {
+            if let (Some(name), Some(val)) = (var_name(&args[0]), literal(&args[1])) {
+                return Some(vec![(name, Test::In(vec![val]))]);
+            }
+            let (name, val) = (var_name(&args[1])?, literal(&args[0])?);
+            return Some(vec![(name, Test::In(vec![val]))]);
+        }
+        ExprKind::In(args) if args.len() >= 2 => {
+            let name = var_name(&args[0])?;
+            let values: Option<Vec<Value>> = args[1..].iter().map(literal).collect();
+            return Some(vec![(name, Test::In(values?))]);
+        }
+        ExprKind::Not(arg) => match tests_of(arg)?.as_slice() {
+            [(name, Test::In(values))] => {
+                return Some(vec![(name.clone(), Test::NotIn(values.clone()))]);
+            }
+            _ => return None,
+        },
+        ExprKind::Compare(op, arg0, arg1) => {
+            // (GT 5 ${x}) is (LT ${x} 5)
+            let (name, val, flipped) = match (var_name(arg0), var_name(arg1)) {
+                (Some(name), _) => (name, literal(arg1)?, false),
+                (None, Some(name)) => (name, literal(arg0)?, true),
+                (None, None) => return None,
+            };
+            let test = match (op, flipped) {
+                (CompareOp::GT, false) | (CompareOp::LT, true) => Test::Low(val, false),
+                (CompareOp::GTE, false) | (CompareOp::LTE, true) => Test::Low(val, true),
+                (CompareOp::LT, false) | (CompareOp::GT, true) => Test::High(val, false),
+                (CompareOp::LTE, false) | (CompareOp::GTE, true) => Test::High(val, true),
+            };
+            return Some(vec![(name, test)]);
+        }
+        ExprKind::Between(val, low, high) => {
+            let name = var_name(val)?;
+            return Some(vec![
+                (name.clone(), Test::Low(literal(low)?, true)),
+                (name, Test::High(literal(high)?, true)),
+            ]);
+        }
+        _ => return None,
+    }
+}
+
+// Args of nested ANDs and referenced rules, the rule itself otherwise
+fn conjuncts<'a>(expr: &'a Expr, out: &mut Vec<&'a Expr>) {
+    match &expr.kind {
+        ExprKind::And(args) => {
+            for arg in args.iter() {
+                conjuncts(arg, out);
+            }
+        }
+        ExprKind::Rule(_, Some(body)) => conjuncts(body, out),
+        _ => out.push(expr),
+    }
+}
+
+impl Conjunction {
+    /**
+     * None when the rule is not known to eval to a BOOL, a rule like
+     * (COALESCE ${flag} 0) which is never true can not be compared with
+     * others
+     */
+    pub(crate) fn of(expr: &Expr) -> Option<Conjunction> {
+        let mut top = expr;
+        while let ExprKind::Rule(_, Some(body)) = &top.kind {
+            top = body;
+        }
+        let and = matches!(top.kind, ExprKind::And(_));
+        if !and && tests_of(top).is_none() && static_type(top) != Some(Type::BOOL) {
+            return None;
+        }
+        let mut args = Vec::new();
+        conjuncts(expr, &mut args);
+        let mut conjunction = Conjunction::default();
+        for arg in args {
+            match tests_of(arg) {
+                Some(tests) => {
+                    for (name, test) in tests {
+                        conjunction.tests.entry(name).or_default().push(test);
+                    }
+                }
+                None => conjunction.others.push(arg.to_sexpr()),
+            }
+        }
+        return Some(conjunction);
+    }
+
+    // A variable both test so that no value passes the tests of both
+    pub(crate) fn conflict(&self, other: &Conjunction) -> Option<&str> {
+        for (name, tests) in self.tests.iter() {
+            let others = match other.tests.get(name) {
+                Some(others) => others,
+                None => continue,
+            };
+            let all: Vec<&Test> = tests.iter().chain(others.iter()).collect();
+            if unsatisfiable(&all) {
+                return Some(name);
+            }
+        }
+        return None;
+    }
+
+    // Whether this holds whenever the other does
+    pub(crate) fn subsumes(&self, other: &Conjunction) -> bool {
+        if !self.others.iter().all(|arg| other.others.contains(arg)) {
+            return false;
+        }
+        for (name, tests) in self.tests.iter() {
+            let others = match other.tests.get(name) {
+                Some(others) => others,
+                None => return false,
+            };
+            let allowed = others.iter().find_map(|test| match test {
+                Test::In(values) => Some(values),
+                _ => None,
+            });
+            for test in tests.iter() {
+                let implied = match allowed {
+                    // Only the allowed values which pass the other tests
+                    Some(values) => values
+                        .iter()
+                        .filter(|v| others.iter().all(|t| t.accepts(v)))
+                        .all(|v| test.accepts(v)),
+                    None => others.iter().any(|t| t.implies(test)),
+                };
+                if !implied {
+                    return false;
+                }
+            }
+        }
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Conjunction;
+    use crate::rule::Rule;
+
+    fn conjunction(source: &str) -> Option<Conjunction> {
+        return Conjunction::of(Rule::compile(source).unwrap().expr());
+    }
+
+    #[test]
+    fn test_conjunctions() {
+        let conflicts = vec![
+            ("(EQUALS ${c} \"DE\")", "(IN ${c} \"FR\" \"IT\")", true),
+            ("(EQUALS ${c} \"DE\")", "(IN ${c} \"FR\" \"DE\")", false),
+            ("(GT ${age} 65)", "(AND ${vip} (LT ${age} 18))", true),
+            ("(GTE ${age} 18)", "(LTE ${age} 18)", false),
+            ("(GT ${age} 18)", "(GTE 18 ${age})", true),
+            ("(BETWEEN ${age} 18 18)", "(NOT (EQUALS ${age} 18))", true),
+            (
+                "(EQUALS ${c} \"DE\")",
+                "(NOT (IN ${c} \"DE\" \"FR\"))",
+                true,
+            ),
+            ("(EQUALS ${c} \"DE\")", "(GT ${c} 5)", true),
+            ("(GT ${a} 5)", "(LT ${b} 5)", false),
+            ("(GT ${a} 5)", "(LT ${a} \"x\")", false),
+        ];
+        for (first, second, expected) in conflicts {
+            let (a, b) = (conjunction(first).unwrap(), conjunction(second).unwrap());
+            assert_eq!(a.conflict(&b).is_some(), expected, "{} {}", first, second);
+        }
+        let subsumes = vec![
+            ("(GT ${age} 18)", "(AND (GT ${age} 65) ${vip})", true),
+            ("(AND (GT ${age} 18) ${vip})", "(GT ${age} 65)", false),
+            ("(GTE ${age} 18)", "(GT ${age} 18)", true),
+            ("(GT ${age} 18)", "(GTE ${age} 18)", false),
+            ("(IN ${c} \"DE\" \"FR\")", "(EQUALS \"FR\" ${c})", true),
+            ("(EQUALS ${c} \"FR\")", "(IN ${c} \"DE\" \"FR\")", false),
+            (
+                "(EQUALS ${c} \"FR\")",
+                "(AND (IN ${c} \"DE\" \"FR\") (GT ${c} \"E\"))",
+                true,
+            ),
+            ("(NOT (EQUALS ${c} \"DE\"))", "(EQUALS ${c} \"FR\")", true),
+            ("(NOT (EQUALS ${age} 10))", "(BETWEEN ${age} 18 65)", true),
+            ("(OR ${a} ${b})", "(AND (OR ${a} ${b}) (GT ${x} 1))", true),
+        ];
+        for (wider, narrower, expected) in subsumes {
+            let (a, b) = (conjunction(wider).unwrap(), conjunction(narrower).unwrap());
+            assert_eq!(a.subsumes(&b), expected, "{} {}", wider, narrower);
+        }
+        assert!(conjunction("(ADD ${x} 1)").is_none());
+        assert!(conjunction("(AND ${flag})").is_some());
+    }
+}
@@ -1,582 +1,5909 @@
-use crate::token::{
-    ErrCode, Lexer, Num as TokenNum, OpType, Str as TokenStr, Token, TokenTag, Var as TokenVar,
-};
-use std::collections::HashMap;
+use crate::check::Schema;
+use crate::function::{Calls, Function, FunctionRegistry};
+use crate::json::{self, Json};
+use crate::regex::{LikePattern, Regex};
+use crate::time;
+use crate::token::{ErrCode, Lexer, Span, Symbols, Token, TokenTag};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
 use std::sync::Arc;
 
+/**
+ * Value of a variable or of an expression. In JSON, and with serde when
+ * the serde feature is on, INT is an integer and FLOAT a number with a
+ * fraction or an exponent, BOOL, STR and NULL are true/false, strings and
+ * null, LIST is an array and MAP an object. TIMESTAMP is written as an
+ * ISO 8601 string, BYTES and LAMBDA as in rule content, and all three are
+ * read back as STR. Bytes of a binary format are read as BYTES.
+ */
 #[allow(dead_code)]
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub enum Value {
     INT(i64),
+    FLOAT(f64),
     BOOL(bool),
     STR(String),
+    LIST(Vec<Value>),
+    MAP(HashMap<String, Value>),
+    NULL,
+    BYTES(Vec<u8>),
+    // Milliseconds since the Unix epoch in UTC
+    TIMESTAMP(i64),
+    LAMBDA(Arc<Lambda>),
 }
 
-pub trait Expr {
-    fn eval(&self, ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError>;
+/**
+ * Function value of a LAMBDA, applied by APPLY. It keeps the values of the
+ * variables its body reads as they were where the LAMBDA was evaluated,
+ * so it sees the names bound by a LET around it wherever it is applied.
+ */
+#[derive(Debug)]
+pub struct Lambda {
+    params: Vec<String>,
+    body: Expr,
+    captured: Vec<(String, Value)>,
+    // Applications of LAMBDA values the body can be nested in
+    max_calls: usize,
 }
 
-#[allow(dead_code)]
-pub struct And {
-    token: Box<dyn Token>,
-    args: Vec<Box<dyn Expr>>,
+impl Lambda {
+    pub fn params(&self) -> &[String] {
+        return &self.params;
+    }
+
+    // Value of the body with the params bound to the args
+    fn apply(&self, args: Vec<Value>, ctx: Env) -> Result<Value, AstError> {
+        if args.len() != self.params.len() {
+            return Err(AstError::ARITY_NOT_MATCH(format!(
+                "LAMBDA ({}) should be applied to {} argument{}, but got {}",
+                self.params.join(" "),
+                self.params.len(),
+                if self.params.len() == 1 { "" } else { "s" },
+                args.len()
+            )));
+        }
+        if ctx.depth >= self.max_calls {
+            return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                "LAMBDA ({}) is applied inside more than {} applications",
+                self.params.join(" "),
+                self.max_calls
+            )));
+        }
+        let mut scope = Scope {
+            bindings: Vec::with_capacity(self.captured.len() + args.len()),
+            parent: ctx.root,
+        };
+        for (name, val) in self.captured.iter() {
+            scope.bindings.push((name, val.clone()));
+        }
+        for (name, val) in self.params.iter().zip(args) {
+            scope.bindings.push((name, val));
+        }
+        let mut ctx = ctx.with_vars(&scope);
+        ctx.depth += 1;
+        return self.body.eval_env(ctx);
+    }
 }
 
-impl And {
-    #[allow(dead_code)]
-    fn create(op_tag: Box<dyn Token>, args: Vec<Box<dyn Expr>>) -> Result<And, AstError> {
-        Ok(And {
-            token: op_tag,
-            args: args,
-        })
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::INT(_) => "INT",
+            Value::FLOAT(_) => "FLOAT",
+            Value::BOOL(_) => "BOOL",
+            Value::STR(_) => "STR",
+            Value::LIST(_) => "LIST",
+            Value::MAP(_) => "MAP",
+            Value::NULL => "NULL",
+            Value::BYTES(_) => "BYTES",
+            Value::TIMESTAMP(_) => "TIMESTAMP",
+            Value::LAMBDA(_) => "LAMBDA",
+        }
     }
 }
 
-impl Expr for And {
-    fn eval(&self, ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        let val = true;
-        for arg in self.args.iter() {
-            let eval_val = arg.eval(ctx.clone())?;
-            match eval_val {
-                Value::INT(i) => {
-                    if i == 0 {
-                        return Ok(Value::BOOL(false));
+// Values are written the way they are written in rule content, LIST and
+// MAP have no literal syntax and are written as [a, b] and {"k": v}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::INT(i) => write!(f, "{}", i),
+            Value::FLOAT(v) => {
+                if v.is_finite() && v.fract() == 0.0 {
+                    write!(f, "{:.1}", v)
+                } else {
+                    write!(f, "{}", v)
+                }
+            }
+            Value::BOOL(b) => write!(f, "{}", b),
+            Value::STR(s) => write!(f, "\"{}\"", s),
+            Value::LIST(list) => {
+                write!(f, "[")?;
+                for (i, val) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
                     }
+                    write!(f, "{}", val)?;
                 }
-                Value::BOOL(b) => {
-                    if !b {
-                        return Ok(Value::BOOL(false));
+                write!(f, "]")
+            }
+            Value::MAP(map) => {
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+                write!(f, "{{")?;
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
                     }
+                    write!(f, "\"{}\": {}", key, map[*key])?;
                 }
-                _ => {
-                    return Err(AstError::FORMAT_NOT_MATCH(
-                        "Not correct value format in and operator".to_string(),
-                    ));
+                write!(f, "}}")
+            }
+            Value::NULL => write!(f, "NULL"),
+            Value::BYTES(bytes) => {
+                write!(f, "#x\"")?;
+                for b in bytes.iter() {
+                    write!(f, "{:02x}", b)?;
                 }
+                write!(f, "\"")
             }
+            Value::TIMESTAMP(millis) => write!(f, "{}", time::format_iso8601(*millis)),
+            Value::LAMBDA(lambda) => write!(
+                f,
+                "(LAMBDA ({}) {})",
+                lambda.params.join(" "),
+                lambda.body.to_sexpr()
+            ),
         }
-        return Ok(Value::BOOL(val));
     }
 }
 
-#[allow(dead_code)]
-pub struct Mod {
-    token: Box<dyn Token>,
-    args: Vec<Box<dyn Expr>>,
+/**
+ * Ordering of values, shared by the comparison operators:
+ *
+ * - INT and FLOAT compare as numbers, INT is promoted to FLOAT when mixed
+ * - STR and BYTES compare lexicographically, BOOL has false < true
+ * - TIMESTAMP compares with TIMESTAMP only
+ * - LIST compares element by element, then by length
+ * - NULL only equals NULL, MAP values are only equal when they have the
+ *   same entries and LAMBDA values when they are the same value, they are
+ *   never less or greater
+ *
+ * Any other pair of values is not comparable and `partial_cmp` returns
+ * None, which operators report as a type error.
+ */
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::INT(x), Value::INT(y)) => Some(x.cmp(y)),
+            (Value::INT(x), Value::FLOAT(y)) => (*x as f64).partial_cmp(y),
+            (Value::FLOAT(x), Value::INT(y)) => x.partial_cmp(&(*y as f64)),
+            (Value::FLOAT(x), Value::FLOAT(y)) => x.partial_cmp(y),
+            (Value::STR(x), Value::STR(y)) => Some(x.cmp(y)),
+            (Value::BYTES(x), Value::BYTES(y)) => Some(x.cmp(y)),
+            (Value::BOOL(x), Value::BOOL(y)) => Some(x.cmp(y)),
+            (Value::TIMESTAMP(x), Value::TIMESTAMP(y)) => Some(x.cmp(y)),
+            (Value::NULL, Value::NULL) => Some(Ordering::Equal),
+            (Value::MAP(x), Value::MAP(y)) if x == y => Some(Ordering::Equal),
+            (Value::LAMBDA(x), Value::LAMBDA(y)) if Arc::ptr_eq(x, y) => Some(Ordering::Equal),
+            (Value::LIST(x), Value::LIST(y)) => {
+                for (a, b) in x.iter().zip(y.iter()) {
+                    match a.partial_cmp(b)? {
+                        Ordering::Equal => continue,
+                        ord => return Some(ord),
+                    }
+                }
+                Some(x.len().cmp(&y.len()))
+            }
+            _ => None,
+        }
+    }
 }
 
-#[allow(dead_code)]
-impl Mod {
-    fn create(op_tag: Box<dyn Token>, args: Vec<Box<dyn Expr>>) -> Result<Mod, AstError> {
-        Ok(Mod {
-            token: op_tag,
-            args: args,
-        })
+// Equality agrees with the ordering above, so INT(1) equals FLOAT(1.0),
+// MAP values are equal when they have the same entries and LAMBDA values
+// when they are the same value
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::LAMBDA(x), Value::LAMBDA(y)) => Arc::ptr_eq(x, y),
+            (Value::MAP(x), Value::MAP(y)) => x == y,
+            (Value::LIST(x), Value::LIST(y)) => x == y,
+            _ => self.partial_cmp(other) == Some(Ordering::Equal),
+        }
     }
 }
 
-impl Expr for Mod {
-    fn eval(&self, ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        if self.args.len() < 2 {
-            return Err(AstError::NOT_ENOUGH_ARGS(
-                "Mod does not have enough args!".to_string(),
-            ));
-        }
-        let arg0 = self.args.get(0);
-        let arg1 = self.args.get(1);
-        if arg0.is_none() || arg1.is_none() {
-            return Err(AstError::NOT_ENOUGH_ARGS(
-                "Args in mod has noe value".to_string(),
-            ));
-        }
-        let arg0 = arg0.unwrap().eval(ctx.clone())?;
-        let arg1 = arg1.unwrap().eval(ctx.clone())?;
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::INT(i)
+    }
+}
 
-        if let Value::INT(i1) = arg0 {
-            if let Value::INT(i2) = arg1 {
-                let result = i1 % i2;
-                return Ok(Value::INT(result));
-            }
-        }
-        return Err(AstError::ARG_NOT_CORRECT(
-            "Arg's format is not correct for mod ".to_string(),
-        ));
+impl From<f64> for Value {
+    fn from(f: f64) -> Value {
+        Value::FLOAT(f)
     }
 }
 
-#[allow(dead_code)]
-pub struct Or {
-    token: Box<dyn Token>,
-    args: Vec<Box<dyn Expr>>,
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::BOOL(b)
+    }
 }
 
-#[allow(dead_code)]
-impl Or {
-    fn create(op_tag: Box<dyn Token>, args: Vec<Box<dyn Expr>>) -> Result<Or, AstError> {
-        Ok(Or {
-            token: op_tag,
-            args: args,
-        })
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::STR(s.to_string())
     }
 }
 
-impl Expr for Or {
-    fn eval(&self, ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        let val = false;
-        for arg in self.args.iter() {
-            let eval_val = arg.eval(ctx.clone())?;
-            match eval_val {
-                Value::INT(i) => {
-                    if i == 1 {
-                        return Ok(Value::BOOL(true));
-                    }
-                }
-                Value::BOOL(b) => {
-                    if !b {
-                        return Ok(Value::BOOL(true));
-                    }
-                }
-                _ => {
-                    return Err(AstError::FORMAT_NOT_MATCH(
-                        "Not correct value format in and operator".to_string(),
-                    ));
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::STR(s)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(list: Vec<T>) -> Value {
+        Value::LIST(list.into_iter().map(|v| v.into()).collect())
+    }
+}
+
+// MAP keys are sorted so that the same value is always written the same
+#[cfg(feature = "serde")]
+pub(crate) fn serialize_map<S: serde::Serializer>(
+    map: &HashMap<String, Value>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use serde::ser::SerializeMap;
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    let mut entries = serializer.serialize_map(Some(keys.len()))?;
+    for key in keys {
+        entries.serialize_entry(key, &map[key])?;
+    }
+    entries.end()
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Value {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        match self {
+            Value::INT(i) => serializer.serialize_i64(*i),
+            Value::FLOAT(f) => serializer.serialize_f64(*f),
+            Value::BOOL(b) => serializer.serialize_bool(*b),
+            Value::STR(s) => serializer.serialize_str(s),
+            Value::NULL => serializer.serialize_unit(),
+            Value::LIST(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for val in list.iter() {
+                    seq.serialize_element(val)?;
                 }
+                seq.end()
             }
+            Value::MAP(map) => serialize_map(map, serializer),
+            Value::TIMESTAMP(millis) => serializer.serialize_str(&time::format_iso8601(*millis)),
+            Value::BYTES(_) | Value::LAMBDA(_) => serializer.collect_str(self),
         }
-        return Ok(Value::BOOL(val));
     }
 }
 
-#[allow(dead_code)]
-pub struct In {
-    token: Box<dyn Token>,
-    args: Vec<Box<dyn Expr>>,
+#[cfg(feature = "serde")]
+struct ValueVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> serde::de::Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a number, bool, string, null, array or object")
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<Value, E> {
+        Ok(Value::BOOL(b))
+    }
+
+    fn visit_i64<E>(self, i: i64) -> Result<Value, E> {
+        Ok(Value::INT(i))
+    }
+
+    // Beyond the INT range it is a FLOAT, as in a JSON document
+    fn visit_u64<E>(self, u: u64) -> Result<Value, E> {
+        match i64::try_from(u) {
+            Ok(i) => Ok(Value::INT(i)),
+            Err(_) => Ok(Value::FLOAT(u as f64)),
+        }
+    }
+
+    fn visit_f64<E>(self, f: f64) -> Result<Value, E> {
+        Ok(Value::FLOAT(f))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Value, E> {
+        Ok(Value::STR(s.to_string()))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<Value, E> {
+        Ok(Value::STR(s))
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Value, E> {
+        Ok(Value::BYTES(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::BYTES(bytes))
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::NULL)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::NULL)
+    }
+
+    fn visit_some<D: serde::Deserializer<'de>>(self, d: D) -> Result<Value, D::Error> {
+        serde::Deserialize::deserialize(d)
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut list = Vec::new();
+        while let Some(val) = seq.next_element()? {
+            list.push(val);
+        }
+        Ok(Value::LIST(list))
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut entries: A) -> Result<Value, A::Error> {
+        let mut map = HashMap::new();
+        while let Some((key, val)) = entries.next_entry()? {
+            map.insert(key, val);
+        }
+        Ok(Value::MAP(map))
+    }
 }
 
-#[allow(dead_code)]
-impl In {
-    fn create(op_tag: Box<dyn Token>, args: Vec<Box<dyn Expr>>) -> Result<In, AstError> {
-        Ok(In {
-            token: op_tag,
-            args: args,
-        })
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Value {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Value, D::Error> {
+        d.deserialize_any(ValueVisitor)
     }
 }
 
-impl Expr for In {
-    fn eval(&self, ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        if self.args.len() <= 1 {
-            return Err(AstError::NOT_ENOUGH_ARGS(
-                "In operator should have at least two arguments".to_string(),
-            ));
-        }
-        let arg0 = self.args.get(0);
-        if arg0.is_none() {
-            return Ok(Value::BOOL(false));
+// Values of a serde_json document, the same as of a JSON document
+#[cfg(feature = "serde_json")]
+fn serde_json_value(doc: &serde_json::Value) -> Value {
+    match doc {
+        serde_json::Value::Null => Value::NULL,
+        serde_json::Value::Bool(b) => Value::BOOL(*b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::INT(i),
+            None => Value::FLOAT(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(s) => Value::STR(s.clone()),
+        serde_json::Value::Array(items) => {
+            Value::LIST(items.iter().map(serde_json_value).collect())
         }
-        let arg0 = arg0.unwrap().eval(ctx.clone())?;
-        // 逐个判断值之间是否相等
-        for i in 1..self.args.len() {
-            let arg = self.args.get(i);
-            if arg.is_some() {
-                let arg = arg.unwrap().eval(ctx.clone())?;
-                if arg0 == arg {
-                    return Ok(Value::BOOL(true));
+        serde_json::Value::Object(entries) => Value::MAP(
+            entries
+                .iter()
+                .map(|(key, val)| (key.clone(), serde_json_value(val)))
+                .collect(),
+        ),
+    }
+}
+
+/**
+ * The keys of a JSON object are the variables. A path is read in the
+ * document and only the value at its end is converted, a missing key or
+ * a document which is not an object is a missing variable.
+ */
+#[cfg(feature = "serde_json")]
+impl VariableResolver for serde_json::Value {
+    fn resolve(&self, name: &str) -> Option<Value> {
+        return self.get(name).map(serde_json_value);
+    }
+
+    fn resolve_path(&self, path: &[PathSegment]) -> Option<(Value, usize)> {
+        let mut doc = self.as_object().map(|_| self)?;
+        for (read, segment) in path.iter().enumerate() {
+            let next = match (doc, segment) {
+                (serde_json::Value::Object(entries), PathSegment::Key(key)) => {
+                    Some(entries.get(&**key)?)
                 }
+                (serde_json::Value::Array(items), PathSegment::Index(i)) => items.get(*i),
+                _ => None,
+            };
+            match next {
+                Some(next) => doc = next,
+                // The rest of the path is walked to fail as it does on a
+                // Value
+                None if read > 0 => return Some((serde_json_value(doc), read)),
+                None => return None,
             }
         }
-        return Ok(Value::BOOL(false));
+        return Some((serde_json_value(doc), path.len()));
     }
 }
 
-#[allow(dead_code)]
-pub struct Equals {
-    token: Box<dyn Token>,
-    args: Vec<Box<dyn Expr>>,
+// Value of a JSON document, see Value for the mapping
+pub(crate) fn json_value(doc: Json) -> Value {
+    match doc {
+        Json::Null => Value::NULL,
+        Json::Bool(b) => Value::BOOL(b),
+        Json::Int(i) => Value::INT(i),
+        Json::Float(f) => Value::FLOAT(f),
+        Json::Str(s) => Value::STR(s),
+        Json::Array(items) => Value::LIST(items.into_iter().map(json_value).collect()),
+        Json::Object(entries) => Value::MAP(
+            entries
+                .into_iter()
+                .map(|(key, val)| (key, json_value(val)))
+                .collect(),
+        ),
+    }
 }
 
-#[allow(dead_code)]
-impl Equals {
-    fn create(op_tag: Box<dyn Token>, args: Vec<Box<dyn Expr>>) -> Result<Equals, AstError> {
-        Ok(Equals {
-            token: op_tag,
-            args: args,
-        })
+fn type_not_match(expected: &str, val: &Value) -> AstError {
+    AstError::TYPE_NOT_MATCH(format!(
+        "Expected {} value, but got {} {}",
+        expected,
+        val.type_name(),
+        val
+    ))
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = AstError;
+
+    fn try_from(val: Value) -> Result<i64, AstError> {
+        match val {
+            Value::INT(i) => Ok(i),
+            val => Err(type_not_match("INT", &val)),
+        }
     }
 }
 
-impl Expr for Equals {
-    fn eval(&self, ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        // if self.args.len() <= 1 {
-        //     return Err(AstError::NOT_ENOUGH_ARGS(
-        //         "In operator should have at least two arguments".to_string(),
-        //     ));
-        // }
-        // let arg0 = self.args.get(0);
-        // if arg0.is_none() {
-        //     return Ok(Value::BOOL(false));
-        // }
-        // let arg0 = arg0.unwrap().eval(ctx.clone())?;
-        // // 逐个判断值之间是否相等
-        // for i in 1..(self.args.len() - 1) {
-        //     let arg = self.args.get(i);
-        //     if arg.is_some() {
-        //         let arg = arg.unwrap().eval(ctx.clone())?;
-        //         if arg0 == arg {
-        //             return Ok(Value::BOOL(true));
-        //         }
-        //     }
-        // }
-        // return Ok(Value::BOOL(false));
-        if self.args.len() < 2 {
-            return Err(AstError::NOT_ENOUGH_ARGS(
-                "Mod does not have enough args!".to_string(),
-            ));
+// INT is promoted to FLOAT like in the numeric operators
+impl TryFrom<Value> for f64 {
+    type Error = AstError;
+
+    fn try_from(val: Value) -> Result<f64, AstError> {
+        match val {
+            Value::FLOAT(f) => Ok(f),
+            Value::INT(i) => Ok(i as f64),
+            val => Err(type_not_match("FLOAT", &val)),
         }
-        let arg0 = self.args.get(0);
-        let arg1 = self.args.get(1);
-        if arg0.is_none() || arg1.is_none() {
-            return Err(AstError::NOT_ENOUGH_ARGS(
-                "Args in mod has noe value".to_string(),
-            ));
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = AstError;
+
+    fn try_from(val: Value) -> Result<bool, AstError> {
+        match val {
+            Value::BOOL(b) => Ok(b),
+            val => Err(type_not_match("BOOL", &val)),
         }
-        let arg0 = arg0.unwrap().eval(ctx.clone())?;
-        let arg1 = arg1.unwrap().eval(ctx.clone())?;
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = AstError;
 
-        return Ok(Value::BOOL(arg0 == arg1));
+    fn try_from(val: Value) -> Result<String, AstError> {
+        match val {
+            Value::STR(s) => Ok(s),
+            val => Err(type_not_match("STR", &val)),
+        }
     }
 }
 
-pub struct Num {
-    token: Box<dyn Token>,
+/**
+ * Source of the variables an expression is evaluated against, so values can
+ * be read lazily from a database row or a request instead of being copied
+ * into a map first. None is a missing variable.
+ */
+pub trait VariableResolver {
+    fn resolve(&self, name: &str) -> Option<Value>;
+
+    /**
+     * Value at the longest start of the path the resolver reads directly,
+     * with the number of segments it read, the rest of the path is walked
+     * on that value. A resolver over nested documents reads ${a.b} without
+     * building the value of a. The default resolves the context key.
+     */
+    fn resolve_path(&self, path: &[PathSegment]) -> Option<(Value, usize)> {
+        match path.first()? {
+            PathSegment::Key(name) => return self.resolve(name).map(|val| (val, 1)),
+            PathSegment::Index(_) => return None,
+        }
+    }
 }
 
-#[allow(dead_code)]
-impl Num {
-    fn create(op_tag: Box<dyn Token>) -> Result<Num, AstError> {
-        Ok(Num { token: op_tag })
+impl VariableResolver for HashMap<String, Value> {
+    fn resolve(&self, name: &str) -> Option<Value> {
+        return self.get(name).cloned();
     }
 }
 
-impl Expr for Num {
-    fn eval(&self, _ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        match self.token.lexeme().parse::<i64>() {
-            Ok(i) => {
-                return Ok(Value::INT(i));
-            }
-            Err(_) => {
-                return Err(AstError::EVAL_NUM_FAILED(
-                    "eval number failed!maybe it's not a number".to_string(),
-                ));
+impl<T: VariableResolver + ?Sized> VariableResolver for &T {
+    fn resolve(&self, name: &str) -> Option<Value> {
+        return (**self).resolve(name);
+    }
+
+    fn resolve_path(&self, path: &[PathSegment]) -> Option<(Value, usize)> {
+        return (**self).resolve_path(path);
+    }
+}
+
+/**
+ * Value of a field of a struct deriving RuleContext. Integers beyond the
+ * INT range are FLOAT as in JSON, None is NULL and maps with STR keys are
+ * MAP.
+ */
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+impl ToValue for Value {
+    fn to_value(&self) -> Value {
+        return self.clone();
+    }
+}
+
+impl ToValue for bool {
+    fn to_value(&self) -> Value {
+        return Value::BOOL(*self);
+    }
+}
+
+macro_rules! int_to_value {
+    ($($t:ty),*) => {
+        $(impl ToValue for $t {
+            fn to_value(&self) -> Value {
+                match i64::try_from(*self) {
+                    Ok(i) => return Value::INT(i),
+                    Err(_) => return Value::FLOAT(*self as f64),
+                }
             }
-        }
+        })*
+    };
+}
+
+int_to_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToValue for f32 {
+    fn to_value(&self) -> Value {
+        return Value::FLOAT(*self as f64);
     }
 }
 
-pub struct Str {
-    token: Box<dyn Token>,
+impl ToValue for f64 {
+    fn to_value(&self) -> Value {
+        return Value::FLOAT(*self);
+    }
 }
 
-#[allow(dead_code)]
-impl Str {
-    fn create(op_tag: Box<dyn Token>) -> Result<Str, AstError> {
-        Ok(Str { token: op_tag })
+impl ToValue for str {
+    fn to_value(&self) -> Value {
+        return Value::STR(self.to_string());
     }
 }
 
-impl Expr for Str {
-    fn eval(&self, _ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        return Ok(Value::STR(self.token.lexeme()));
+impl ToValue for String {
+    fn to_value(&self) -> Value {
+        return Value::STR(self.clone());
     }
 }
 
-pub struct Var {
-    token: Box<dyn Token>,
+impl<T: ToValue + ?Sized> ToValue for &T {
+    fn to_value(&self) -> Value {
+        return (**self).to_value();
+    }
 }
 
-#[allow(dead_code)]
-impl Var {
-    fn create(op_tag: Box<dyn Token>) -> Result<Var, AstError> {
-        Ok(Var { token: op_tag })
+impl<T: ToValue + ?Sized> ToValue for Box<T> {
+    fn to_value(&self) -> Value {
+        return (**self).to_value();
     }
 }
 
-impl Expr for Var {
-    fn eval(&self, ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        let key = self.token.lexeme();
-        let val = ctx.get(&key);
-        if val.is_none() {
-            return Ok(Value::BOOL(false));
-        } else {
-            return Ok(val.unwrap().clone());
+impl<T: ToValue> ToValue for Option<T> {
+    fn to_value(&self) -> Value {
+        match self {
+            Some(val) => return val.to_value(),
+            None => return Value::NULL,
         }
     }
 }
 
-pub struct Bool {
-    token: Box<dyn Token>,
+impl<T: ToValue> ToValue for Vec<T> {
+    fn to_value(&self) -> Value {
+        return Value::LIST(self.iter().map(|val| val.to_value()).collect());
+    }
 }
 
-#[allow(dead_code)]
-impl Bool {
-    fn create(op_tag: Box<dyn Token>) -> Result<Bool, AstError> {
-        Ok(Bool { token: op_tag })
+impl<T: ToValue, S> ToValue for HashMap<String, T, S> {
+    fn to_value(&self) -> Value {
+        let map = self.iter().map(|(key, val)| (key.clone(), val.to_value()));
+        return Value::MAP(map.collect());
     }
 }
 
-impl Expr for Bool {
-    fn eval(&self, _ctx: Arc<HashMap<String, Value>>) -> Result<Value, AstError> {
-        if self.token.lexeme().to_lowercase() == "true" || self.token.lexeme().to_lowercase() == "1"
-        {
-            return Ok(Value::BOOL(true));
-        } else {
-            return Ok(Value::BOOL(false));
-        }
+impl<T: ToValue> ToValue for std::collections::BTreeMap<String, T> {
+    fn to_value(&self) -> Value {
+        let map = self.iter().map(|(key, val)| (key.clone(), val.to_value()));
+        return Value::MAP(map.collect());
     }
 }
 
-#[allow(dead_code)]
-pub struct Parser {
-    lexer: Lexer,
-    look_token: Option<Box<dyn Token>>,
+/**
+ * Node of the expression tree built by Parser, span is where the node was
+ * written in the rule content: the brackets of an operator or the token of
+ * a leaf. Nodes which were not parsed from rule content have the default
+ * span.
+ */
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
 }
 
-#[allow(dead_code, non_camel_case_types)]
-#[derive(Debug)]
-pub enum AstError {
-    OTHER(String),
-    FORMAT_NOT_MATCH(String),
-    LEXER_FAILED(String),
-    NOT_MATCH(String),
-    NO_TOKEN_MATCH(String),
-    NOT_SUPP_OPER(String),
-    EVAL_NUM_FAILED(String),
-    NOT_ENOUGH_ARGS(String),
-    ARG_NOT_CORRECT(String),
+/**
+ * Two expressions are equal when they have the same structure wherever
+ * they were written, patterns of MATCH and LIKE are compared by their text
+ * and FLOAT literals as numbers.
+ */
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.kind == other.kind
+    }
 }
 
-#[allow(dead_code)]
-impl Parser {
-    fn create(content: String) -> Result<Parser, AstError> {
-        let lexer = Lexer::create(content);
-        if lexer.is_err() {
-            return Err(AstError::LEXER_FAILED("Lexer init failed!".to_string()));
+/**
+ * Every operator is a variant holding its operands, leaves hold the value
+ * parsed from their literal
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Xor(Vec<Expr>),
+    Nand(Vec<Expr>),
+    Mod(Box<Expr>, Box<Expr>),
+    // INT results which overflow follow the policy of the rule
+    Arith(ArithOp, Vec<Expr>, OverflowPolicy),
+    In(Vec<Expr>),
+    Equals(Vec<Expr>),
+    EqualsCi(Vec<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+    Between(Box<Expr>, Box<Expr>, Box<Expr>),
+    StrPredicate(StrPredicateOp, Box<Expr>, Box<Expr>),
+    StrTransform(StrTransformOp, Box<Expr>),
+    Concat(Vec<Expr>),
+    Len(Box<Expr>),
+    Hash(Box<Expr>),
+    // Id, salt and percentage of the users in the rollout
+    Rollout(Box<Expr>, Box<Expr>, Box<Expr>),
+    Substr(Box<Expr>, Box<Expr>, Box<Expr>),
+    IndexOf(Box<Expr>, Box<Expr>),
+    Split(Box<Expr>, Box<Expr>),
+    Get(Box<Expr>, Box<Expr>),
+    Match(Box<Expr>, Regex),
+    Like(Box<Expr>, LikePattern),
+    Coalesce(Vec<Expr>),
+    Let(Vec<(String, Expr)>, Box<Expr>),
+    Cond(Vec<(Expr, Expr)>, Option<Box<Expr>>),
+    // Rule of the same rule set by name, with the expression of that rule
+    // once the set has resolved it
+    Rule(String, Option<Box<Expr>>),
+    // Function of the host found in the options of the parser
+    Call(Function, Vec<Expr>),
+    // Params, body and the max_calls of the options of the parser
+    Lambda(Vec<String>, Box<Expr>, usize),
+    Apply(Box<Expr>, Vec<Expr>),
+    // A LIST and the predicate of its elements, see EachOp
+    Each(EachOp, Box<Expr>, Box<Expr>),
+    Aggregate(AggregateOp, Box<Expr>, OverflowPolicy),
+    Now,
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Null,
+    Bytes(Vec<u8>),
+    // Milliseconds since the Unix epoch in UTC
+    Timestamp(i64),
+    Var(Var),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    GT,
+    GTE,
+    LT,
+    LTE,
+}
+
+impl CompareOp {
+    pub(crate) fn apply(&self, arg0: &Value, arg1: &Value) -> Result<Value, AstError> {
+        match arg0.partial_cmp(arg1) {
+            Some(ord) => {
+                let result = match self {
+                    CompareOp::GT => ord == Ordering::Greater,
+                    CompareOp::GTE => ord != Ordering::Less,
+                    CompareOp::LT => ord == Ordering::Less,
+                    CompareOp::LTE => ord != Ordering::Greater,
+                };
+                return Ok(Value::BOOL(result));
+            }
+            None => {
+                return Err(AstError::TYPE_NOT_MATCH(format!(
+                    "{} operator needs two comparable args, but got {:?} and {:?}",
+                    self.name(),
+                    arg0,
+                    arg1
+                )));
+            }
         }
-        Ok(Parser {
-            lexer: lexer.unwrap(),
-            look_token: None,
-        })
     }
 
-    fn parse(&mut self) -> Result<Box<dyn Expr>, AstError> {
-        if !self.move_token()? {
-            return Err(AstError::OTHER(
-                "Has already analyzed this rule content to expr".to_string(),
-            ));
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompareOp::GT => "GT",
+            CompareOp::GTE => "GTE",
+            CompareOp::LT => "LT",
+            CompareOp::LTE => "LTE",
         }
-        let expr = self.expr()?;
-        self.match_term(TokenTag::RIGHT_BRACKET)?;
-        return Ok(expr);
     }
+}
 
-    fn expr(&mut self) -> Result<Box<dyn Expr>, AstError> {
-        match self.look_token.as_ref() {
-            Some(token) => match *token.token_tag() {
-                TokenTag::LEFT_BRACKET => {
-                    self.move_token()?;
-                    match self.look_token.as_ref().unwrap().token_tag() {
-                        TokenTag::AND => {
-                            let a = self.args_add(TokenTag::AND, "AND".to_string())?;
-                            println!(
-                                "current token is:{}",
-                                self.look_token.as_ref().unwrap().lexeme()
-                            );
-                            return Ok(a);
-                        }
-                        TokenTag::OR => {
-                            return Ok(self.args_add(TokenTag::OR, "OR".to_string())?);
-                        }
-                        TokenTag::MOD => {
-                            return Ok(self.args_add(TokenTag::MOD, "MOD".to_string())?);
-                        }
-                        TokenTag::EQUALS => {
-                            return Ok(self.args_add(TokenTag::EQUALS, "EQUALS".to_string())?);
-                        }
-                        TokenTag::IN => {
-                            let a = self.args_add(TokenTag::IN, "IN".to_string())?;
-                            println!(
-                                "in current token is:{}",
-                                self.look_token.as_ref().unwrap().lexeme()
-                            );
-                            return Ok(a);
-                        }
-                        _ => {
-                            return Err(AstError::NOT_SUPP_OPER(
-                                "Not supported operator!".to_string(),
-                            ));
-                        }
-                    }
-                }
-                TokenTag::NUM => {
-                    let token = TokenNum::create_with_token_and_val(TokenTag::NUM, token.lexeme());
-                    if token.is_err() {
-                        return Err(AstError::OTHER("Create num token failed!".to_string()));
-                    }
-                    return Ok(Box::new(Num::create(token.unwrap())?));
-                }
-                TokenTag::STR => {
-                    let token = TokenStr::create_with_token_and_val(TokenTag::STR, token.lexeme());
-                    if token.is_err() {
-                        return Err(AstError::OTHER("Create str token failed!".to_string()));
-                    }
-                    return Ok(Box::new(Str::create(token.unwrap())?));
-                }
-                TokenTag::VAR => {
-                    let token = TokenVar::create_with_token_and_val(TokenTag::VAR, token.lexeme());
-                    if token.is_err() {
-                        println!("Var token create failed");
-                        return Err(AstError::OTHER("Create var token failed!".to_string()));
-                    }
-                    return Ok(Box::new(Var::create(token.unwrap())?));
-                }
-                _ => {
-                    println!("Not correct token is {:?}", token.lexeme());
-                    return Err(AstError::OTHER(
-                        "Not find available token tag to process".to_string(),
-                    ));
-                }
-            },
-            None => {
-                return Err(AstError::OTHER("Current token is none!".to_string()));
-            }
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StrPredicateOp {
+    CONTAINS,
+    STARTS_WITH,
+    ENDS_WITH,
+}
+
+impl StrPredicateOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StrPredicateOp::CONTAINS => "CONTAINS",
+            StrPredicateOp::STARTS_WITH => "STARTS-WITH",
+            StrPredicateOp::ENDS_WITH => "ENDS-WITH",
         }
     }
+}
 
-    fn args_add(&mut self, tag: TokenTag, s: String) -> Result<Box<dyn Expr>, AstError> {
-        let mut args: Vec<Box<dyn Expr>> = Vec::new();
-        for _ in 0..10000 {
-            if !self.move_token()? {
-                return Err(AstError::FORMAT_NOT_MATCH(
-                    "no right branch packet for and operator but has already went to the end"
-                        .to_string(),
-                ));
-            }
-            if self.look_token.is_some()
-                && *self.look_token.as_ref().unwrap().token_tag() == TokenTag::RIGHT_BRACKET
-            {
-                let and_token = Box::new(OpType {
-                    tag: tag.clone(),
-                    lexeme: s,
-                });
-                match tag {
-                    TokenTag::AND => {
-                        return Ok(Box::new(And::create(and_token, args)?));
-                    }
-                    TokenTag::OR => {
-                        return Ok(Box::new(Or::create(and_token, args)?));
-                    }
-                    TokenTag::MOD => {
-                        return Ok(Box::new(Mod::create(and_token, args)?));
-                    }
-                    TokenTag::IN => {
-                        println!("match tag in");
-                        return Ok(Box::new(In::create(and_token, args)?));
-                    }
-                    TokenTag::EQUALS => {
-                        return Ok(Box::new(Equals::create(and_token, args)?));
-                    }
-                    _ => {
-                        return Err(AstError::NOT_SUPP_OPER("not supported opt".to_string()));
-                    }
-                }
-            }
-            args.push(self.expr()?);
+/**
+ * Operators over the elements of a LIST value. The predicate sees every
+ * element as ${item}, or it is a LAMBDA applied to every element, like
+ * (ANY ${orders} (GT ${item.amount} 100)) or
+ * (ANY ${orders} (LAMBDA (order) (GT ${order.amount} 100))).
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EachOp {
+    // Whether the predicate is true for an element, false for an empty LIST
+    ANY,
+    // Whether the predicate is true for every element, true for an empty LIST
+    ALL,
+    // LIST of the elements the predicate is true for
+    FILTER,
+}
+
+impl EachOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EachOp::ANY => "ANY",
+            EachOp::ALL => "ALL",
+            EachOp::FILTER => "FILTER",
         }
-        return Err(AstError::OTHER(
-            "Serious problem!!!!!!!!Should not be here".to_string(),
-        ));
     }
+}
 
-    fn move_token(&mut self) -> Result<bool, AstError> {
-        let scan_result = self.lexer.scan();
-        match scan_result {
-            Ok(r) => {
-                self.look_token.replace(r);
-                return Ok(true);
+// Name the predicate of ANY, ALL and FILTER sees the element as
+pub(crate) const ITEM: &str = "item";
+
+/**
+ * Operators which reduce a LIST value to a single value, like
+ * (GT (SUM ${cart_prices}) 500). NULL elements are skipped, the same as SQL
+ * aggregates skip the NULL values of a column.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateOp {
+    // INT when every element is an INT, FLOAT otherwise, 0 for an empty LIST
+    SUM,
+    // FLOAT, NULL for an empty LIST
+    AVG,
+    // Number of the elements which are not NULL
+    COUNT,
+    // Least element, NULL for an empty LIST
+    MIN,
+    // Greatest element, NULL for an empty LIST
+    MAX,
+}
+
+impl AggregateOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggregateOp::SUM => "SUM",
+            AggregateOp::AVG => "AVG",
+            AggregateOp::COUNT => "COUNT",
+            AggregateOp::MIN => "MIN",
+            AggregateOp::MAX => "MAX",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StrTransformOp {
+    UPPER,
+    LOWER,
+    TRIM,
+}
+
+impl StrTransformOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StrTransformOp::UPPER => "UPPER",
+            StrTransformOp::LOWER => "LOWER",
+            StrTransformOp::TRIM => "TRIM",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithOp {
+    ADD,
+    SUB,
+    MUL,
+}
+
+impl ArithOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArithOp::ADD => "ADD",
+            ArithOp::SUB => "SUB",
+            ArithOp::MUL => "MUL",
+        }
+    }
+}
+
+/**
+ * What an INT result out of the INT range becomes, for ADD, SUB, MUL and
+ * integer literals
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    // ARITH_OVERFLOW error
+    #[default]
+    Error,
+    // Clamped to the INT range
+    Saturate,
+    // Wrapped around in two's complement
+    Wrap,
+}
+
+impl OverflowPolicy {
+    // None when the result overflows with the Error policy
+    pub fn apply(self, op: ArithOp, a: i64, b: i64) -> Option<i64> {
+        match (self, op) {
+            (OverflowPolicy::Error, ArithOp::ADD) => a.checked_add(b),
+            (OverflowPolicy::Error, ArithOp::SUB) => a.checked_sub(b),
+            (OverflowPolicy::Error, ArithOp::MUL) => a.checked_mul(b),
+            (OverflowPolicy::Saturate, ArithOp::ADD) => Some(a.saturating_add(b)),
+            (OverflowPolicy::Saturate, ArithOp::SUB) => Some(a.saturating_sub(b)),
+            (OverflowPolicy::Saturate, ArithOp::MUL) => Some(a.saturating_mul(b)),
+            (OverflowPolicy::Wrap, ArithOp::ADD) => Some(a.wrapping_add(b)),
+            (OverflowPolicy::Wrap, ArithOp::SUB) => Some(a.wrapping_sub(b)),
+            (OverflowPolicy::Wrap, ArithOp::MUL) => Some(a.wrapping_mul(b)),
+        }
+    }
+}
+
+// Value of an integer literal like 42, 0xff or 0b1010, the digits are
+// checked by the lexer
+pub(crate) fn int_literal(lexeme: &str, overflow: OverflowPolicy) -> Option<i64> {
+    let (digits, radix) = if let Some(digits) = lexeme.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = lexeme.strip_prefix("0b") {
+        (digits, 2)
+    } else {
+        (lexeme, 10)
+    };
+    let mut val: i64 = 0;
+    for c in digits.chars() {
+        let digit = c.to_digit(radix)? as i64;
+        val = overflow.apply(ArithOp::MUL, val, radix as i64)?;
+        val = overflow.apply(ArithOp::ADD, val, digit)?;
+    }
+    return Some(val);
+}
+
+// Count of args an operator takes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    Variadic,
+}
+
+impl Arity {
+    fn of(tag: &TokenTag) -> Arity {
+        match tag {
+            TokenTag::NOW => Arity::Exact(0),
+            TokenTag::NOT
+            | TokenTag::LEN
+            | TokenTag::HASH
+            | TokenTag::UPPER
+            | TokenTag::LOWER
+            | TokenTag::TRIM
+            | TokenTag::SUM
+            | TokenTag::AVG
+            | TokenTag::COUNT
+            | TokenTag::MIN
+            | TokenTag::MAX => Arity::Exact(1),
+            TokenTag::MOD
+            | TokenTag::GT
+            | TokenTag::GTE
+            | TokenTag::LT
+            | TokenTag::LTE
+            | TokenTag::CONTAINS
+            | TokenTag::STARTS_WITH
+            | TokenTag::ENDS_WITH
+            | TokenTag::INDEX_OF
+            | TokenTag::SPLIT
+            | TokenTag::GET
+            | TokenTag::ANY
+            | TokenTag::ALL
+            | TokenTag::FILTER => Arity::Exact(2),
+            TokenTag::SUBSTR | TokenTag::BETWEEN | TokenTag::ROLLOUT => Arity::Exact(3),
+            TokenTag::ADD | TokenTag::SUB | TokenTag::MUL | TokenTag::APPLY => Arity::AtLeast(1),
+            TokenTag::IN | TokenTag::EQUALS | TokenTag::EQUALS_CI => Arity::AtLeast(2),
+            _ => Arity::Variadic,
+        }
+    }
+
+    fn check(self, s: &str, len: usize) -> Result<(), AstError> {
+        let (bound, n) = match self {
+            Arity::Exact(n) if len != n => ("exactly", n),
+            Arity::AtLeast(n) if len < n => ("at least", n),
+            _ => return Ok(()),
+        };
+        return Err(AstError::ARITY_NOT_MATCH(format!(
+            "{} operator should have {} {} argument{}, but got {}",
+            s,
+            bound,
+            n,
+            if n == 1 { "" } else { "s" },
+            len
+        )));
+    }
+}
+
+// The args of an operator which takes exactly N of them
+fn operands<const N: usize>(s: &str, args: Vec<Expr>) -> Result<[Box<Expr>; N], AstError> {
+    let len = args.len();
+    let args: Vec<Box<Expr>> = args.into_iter().map(Box::new).collect();
+    <[Box<Expr>; N]>::try_from(args).map_err(|_| {
+        AstError::ARITY_NOT_MATCH(format!(
+            "{} operator should have exactly {} argument{}, but got {}",
+            s,
+            N,
+            if N == 1 { "" } else { "s" },
+            len
+        ))
+    })
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        Expr {
+            kind: kind,
+            span: span,
+        }
+    }
+
+    // Node of the operator tag with the parsed args, s is the operator name
+    fn create(
+        tag: TokenTag,
+        s: &str,
+        args: Vec<Expr>,
+        overflow: OverflowPolicy,
+    ) -> Result<ExprKind, AstError> {
+        Arity::of(&tag).check(s, args.len())?;
+        match tag {
+            TokenTag::ADD => return Ok(ExprKind::Arith(ArithOp::ADD, args, overflow)),
+            TokenTag::SUB => return Ok(ExprKind::Arith(ArithOp::SUB, args, overflow)),
+            TokenTag::MUL => return Ok(ExprKind::Arith(ArithOp::MUL, args, overflow)),
+            TokenTag::AND => return Ok(ExprKind::And(args)),
+            TokenTag::OR => return Ok(ExprKind::Or(args)),
+            TokenTag::XOR => return Ok(ExprKind::Xor(args)),
+            TokenTag::NAND => return Ok(ExprKind::Nand(args)),
+            TokenTag::MOD => {
+                let [arg0, arg1] = operands(s, args)?;
+                return Ok(ExprKind::Mod(arg0, arg1));
             }
-            Err(e) => {
-                match e {
-                    ErrCode::READ_TO_END(_) => {
-                        // Have reached to the end, move will do nothing
-                        return Ok(false);
+            TokenTag::IN => return Ok(ExprKind::In(args)),
+            TokenTag::EQUALS => return Ok(ExprKind::Equals(args)),
+            TokenTag::EQUALS_CI => return Ok(ExprKind::EqualsCi(args)),
+            TokenTag::CONCAT => return Ok(ExprKind::Concat(args)),
+            TokenTag::COALESCE => return Ok(ExprKind::Coalesce(args)),
+            TokenTag::NOT => {
+                let [arg] = operands(s, args)?;
+                return Ok(ExprKind::Not(arg));
+            }
+            TokenTag::LEN => {
+                let [arg] = operands(s, args)?;
+                return Ok(ExprKind::Len(arg));
+            }
+            TokenTag::HASH => {
+                let [arg] = operands(s, args)?;
+                return Ok(ExprKind::Hash(arg));
+            }
+            TokenTag::UPPER | TokenTag::LOWER | TokenTag::TRIM => {
+                let op = match tag {
+                    TokenTag::UPPER => StrTransformOp::UPPER,
+                    TokenTag::LOWER => StrTransformOp::LOWER,
+                    _ => StrTransformOp::TRIM,
+                };
+                let [arg] = operands(s, args)?;
+                return Ok(ExprKind::StrTransform(op, arg));
+            }
+            TokenTag::GT | TokenTag::GTE | TokenTag::LT | TokenTag::LTE => {
+                let op = match tag {
+                    TokenTag::GT => CompareOp::GT,
+                    TokenTag::GTE => CompareOp::GTE,
+                    TokenTag::LT => CompareOp::LT,
+                    _ => CompareOp::LTE,
+                };
+                let [arg0, arg1] = operands(s, args)?;
+                return Ok(ExprKind::Compare(op, arg0, arg1));
+            }
+            TokenTag::CONTAINS | TokenTag::STARTS_WITH | TokenTag::ENDS_WITH => {
+                let op = match tag {
+                    TokenTag::CONTAINS => StrPredicateOp::CONTAINS,
+                    TokenTag::STARTS_WITH => StrPredicateOp::STARTS_WITH,
+                    _ => StrPredicateOp::ENDS_WITH,
+                };
+                let [arg0, arg1] = operands(s, args)?;
+                return Ok(ExprKind::StrPredicate(op, arg0, arg1));
+            }
+            TokenTag::INDEX_OF => {
+                let [s, needle] = operands(s, args)?;
+                return Ok(ExprKind::IndexOf(s, needle));
+            }
+            TokenTag::SPLIT => {
+                let [s, sep] = operands(s, args)?;
+                return Ok(ExprKind::Split(s, sep));
+            }
+            TokenTag::GET => {
+                let [map, key] = operands(s, args)?;
+                return Ok(ExprKind::Get(map, key));
+            }
+            TokenTag::SUBSTR => {
+                let [s, start, len] = operands(s, args)?;
+                return Ok(ExprKind::Substr(s, start, len));
+            }
+            TokenTag::BETWEEN => {
+                let [val, low, high] = operands(s, args)?;
+                return Ok(ExprKind::Between(val, low, high));
+            }
+            TokenTag::ROLLOUT => {
+                let [id, salt, percent] = operands(s, args)?;
+                return Ok(ExprKind::Rollout(id, salt, percent));
+            }
+            TokenTag::NOW => {
+                let [] = operands(s, args)?;
+                return Ok(ExprKind::Now);
+            }
+            TokenTag::ANY | TokenTag::ALL | TokenTag::FILTER => {
+                let op = match tag {
+                    TokenTag::ANY => EachOp::ANY,
+                    TokenTag::ALL => EachOp::ALL,
+                    _ => EachOp::FILTER,
+                };
+                let [list, predicate] = operands(s, args)?;
+                return Ok(ExprKind::Each(op, list, predicate));
+            }
+            TokenTag::SUM | TokenTag::AVG | TokenTag::COUNT | TokenTag::MIN | TokenTag::MAX => {
+                let op = match tag {
+                    TokenTag::SUM => AggregateOp::SUM,
+                    TokenTag::AVG => AggregateOp::AVG,
+                    TokenTag::COUNT => AggregateOp::COUNT,
+                    TokenTag::MIN => AggregateOp::MIN,
+                    _ => AggregateOp::MAX,
+                };
+                let [list] = operands(s, args)?;
+                return Ok(ExprKind::Aggregate(op, list, overflow));
+            }
+            TokenTag::APPLY => {
+                let mut args = args;
+                let f = args.remove(0);
+                return Ok(ExprKind::Apply(Box::new(f), args));
+            }
+            _ => {
+                return Err(AstError::NOT_SUPP_OPER("not supported opt".to_string()));
+            }
+        }
+    }
+
+    // The pattern is compiled once here, eval only runs the match
+    pub(crate) fn create_match(
+        tag: TokenTag,
+        arg: Expr,
+        pattern: &str,
+    ) -> Result<ExprKind, AstError> {
+        let invalid = |e: String| {
+            AstError::PATTERN_NOT_VALID(format!("Pattern \"{}\" is not valid: {}", pattern, e))
+        };
+        if tag == TokenTag::LIKE {
+            let like = LikePattern::new(pattern).map_err(invalid)?;
+            return Ok(ExprKind::Like(Box::new(arg), like));
+        }
+        let regex = Regex::new(pattern).map_err(invalid)?;
+        return Ok(ExprKind::Match(Box::new(arg), regex));
+    }
+
+    // Token lexeme is the hex digits of the literal without #x""
+    fn create_bytes(hex: &str) -> Result<ExprKind, AstError> {
+        let mut bytes = Vec::new();
+        for i in (0..hex.len()).step_by(2) {
+            match hex.get(i..i + 2).map(|b| u8::from_str_radix(b, 16)) {
+                Some(Ok(b)) => bytes.push(b),
+                _ => {
+                    return Err(AstError::FORMAT_NOT_MATCH(format!(
+                        "Not a valid bytes literal: #x\"{}\"",
+                        hex
+                    )));
+                }
+            }
+        }
+        return Ok(ExprKind::Bytes(bytes));
+    }
+
+    /**
+     * Operands of the node in the order they are written, LET bindings
+     * come before the body and COND tests before their results
+     */
+    pub fn children(&self) -> Vec<&Expr> {
+        match &self.kind {
+            ExprKind::And(args)
+            | ExprKind::Or(args)
+            | ExprKind::Xor(args)
+            | ExprKind::Nand(args)
+            | ExprKind::In(args)
+            | ExprKind::Equals(args)
+            | ExprKind::EqualsCi(args)
+            | ExprKind::Arith(_, args, _)
+            | ExprKind::Concat(args)
+            | ExprKind::Coalesce(args) => args.iter().collect(),
+            ExprKind::Not(arg)
+            | ExprKind::Len(arg)
+            | ExprKind::Hash(arg)
+            | ExprKind::Aggregate(_, arg, _)
+            | ExprKind::StrTransform(_, arg)
+            | ExprKind::Match(arg, _)
+            | ExprKind::Like(arg, _) => vec![arg.as_ref()],
+            ExprKind::Mod(arg0, arg1)
+            | ExprKind::Compare(_, arg0, arg1)
+            | ExprKind::StrPredicate(_, arg0, arg1)
+            | ExprKind::IndexOf(arg0, arg1)
+            | ExprKind::Split(arg0, arg1)
+            | ExprKind::Get(arg0, arg1)
+            | ExprKind::Each(_, arg0, arg1) => vec![arg0.as_ref(), arg1.as_ref()],
+            ExprKind::Between(arg0, arg1, arg2)
+            | ExprKind::Substr(arg0, arg1, arg2)
+            | ExprKind::Rollout(arg0, arg1, arg2) => {
+                vec![arg0.as_ref(), arg1.as_ref(), arg2.as_ref()]
+            }
+            ExprKind::Let(bindings, body) => {
+                let mut children: Vec<&Expr> = bindings.iter().map(|(_, expr)| expr).collect();
+                children.push(body.as_ref());
+                children
+            }
+            ExprKind::Cond(branches, default) => {
+                let mut children = Vec::new();
+                for (test, result) in branches.iter() {
+                    children.push(test);
+                    children.push(result);
+                }
+                children.extend(default.as_deref());
+                children
+            }
+            ExprKind::Rule(_, body) => body.as_deref().into_iter().collect(),
+            ExprKind::Call(_, args) => args.iter().collect(),
+            ExprKind::Lambda(_, body, _) => vec![body.as_ref()],
+            ExprKind::Apply(f, args) => {
+                let mut children = vec![f.as_ref()];
+                children.extend(args.iter());
+                children
+            }
+            ExprKind::Now
+            | ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Str(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Null
+            | ExprKind::Bytes(_)
+            | ExprKind::Timestamp(_)
+            | ExprKind::Var(_) => Vec::new(),
+        }
+    }
+
+    // Same order as children
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match &mut self.kind {
+            ExprKind::And(args)
+            | ExprKind::Or(args)
+            | ExprKind::Xor(args)
+            | ExprKind::Nand(args)
+            | ExprKind::In(args)
+            | ExprKind::Equals(args)
+            | ExprKind::EqualsCi(args)
+            | ExprKind::Arith(_, args, _)
+            | ExprKind::Concat(args)
+            | ExprKind::Coalesce(args) => args.iter_mut().collect(),
+            ExprKind::Not(arg)
+            | ExprKind::Len(arg)
+            | ExprKind::Hash(arg)
+            | ExprKind::Aggregate(_, arg, _)
+            | ExprKind::StrTransform(_, arg)
+            | ExprKind::Match(arg, _)
+            | ExprKind::Like(arg, _) => vec![arg.as_mut()],
+            ExprKind::Mod(arg0, arg1)
+            | ExprKind::Compare(_, arg0, arg1)
+            | ExprKind::StrPredicate(_, arg0, arg1)
+            | ExprKind::IndexOf(arg0, arg1)
+            | ExprKind::Split(arg0, arg1)
+            | ExprKind::Get(arg0, arg1)
+            | ExprKind::Each(_, arg0, arg1) => vec![arg0.as_mut(), arg1.as_mut()],
+            ExprKind::Between(arg0, arg1, arg2)
+            | ExprKind::Substr(arg0, arg1, arg2)
+            | ExprKind::Rollout(arg0, arg1, arg2) => {
+                vec![arg0.as_mut(), arg1.as_mut(), arg2.as_mut()]
+            }
+            ExprKind::Let(bindings, body) => {
+                let mut children: Vec<&mut Expr> =
+                    bindings.iter_mut().map(|(_, expr)| expr).collect();
+                children.push(body.as_mut());
+                children
+            }
+            ExprKind::Cond(branches, default) => {
+                let mut children = Vec::new();
+                for (test, result) in branches.iter_mut() {
+                    children.push(test);
+                    children.push(result);
+                }
+                children.extend(default.as_deref_mut());
+                children
+            }
+            ExprKind::Rule(_, body) => body.as_deref_mut().into_iter().collect(),
+            ExprKind::Call(_, args) => args.iter_mut().collect(),
+            ExprKind::Lambda(_, body, _) => vec![body.as_mut()],
+            ExprKind::Apply(f, args) => {
+                let mut children = vec![f.as_mut()];
+                children.extend(args.iter_mut());
+                children
+            }
+            ExprKind::Now
+            | ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Str(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Null
+            | ExprKind::Bytes(_)
+            | ExprKind::Timestamp(_)
+            | ExprKind::Var(_) => Vec::new(),
+        }
+    }
+
+    // Name of the operator as written in rule content, leaves are named
+    // by the type of their value
+    pub fn name(&self) -> &'static str {
+        match &self.kind {
+            ExprKind::And(_) => "AND",
+            ExprKind::Or(_) => "OR",
+            ExprKind::Not(_) => "NOT",
+            ExprKind::Xor(_) => "XOR",
+            ExprKind::Nand(_) => "NAND",
+            ExprKind::Mod(..) => "MOD",
+            ExprKind::In(_) => "IN",
+            ExprKind::Equals(_) => "EQUALS",
+            ExprKind::EqualsCi(_) => "EQUALS-CI",
+            ExprKind::Compare(op, _, _) => op.name(),
+            ExprKind::Between(..) => "BETWEEN",
+            ExprKind::StrPredicate(op, _, _) => op.name(),
+            ExprKind::Each(op, _, _) => op.name(),
+            ExprKind::Aggregate(op, ..) => op.name(),
+            ExprKind::StrTransform(op, _) => op.name(),
+            ExprKind::Arith(op, ..) => op.name(),
+            ExprKind::Concat(_) => "CONCAT",
+            ExprKind::Len(_) => "LEN",
+            ExprKind::Hash(_) => "HASH",
+            ExprKind::Rollout(..) => "ROLLOUT",
+            ExprKind::Substr(..) => "SUBSTR",
+            ExprKind::IndexOf(..) => "INDEX-OF",
+            ExprKind::Split(..) => "SPLIT",
+            ExprKind::Get(..) => "GET",
+            ExprKind::Match(..) => "MATCH",
+            ExprKind::Like(..) => "LIKE",
+            ExprKind::Coalesce(_) => "COALESCE",
+            ExprKind::Let(..) => "LET",
+            ExprKind::Cond(..) => "COND",
+            ExprKind::Rule(..) => "RULE",
+            ExprKind::Call(..) => "CALL",
+            ExprKind::Lambda(..) => "LAMBDA",
+            ExprKind::Apply(..) => "APPLY",
+            ExprKind::Now => "NOW",
+            ExprKind::Int(_) => "INT",
+            ExprKind::Float(_) => "FLOAT",
+            ExprKind::Str(_) => "STR",
+            ExprKind::Bool(_) => "BOOL",
+            ExprKind::Null => "NULL",
+            ExprKind::Bytes(_) => "BYTES",
+            ExprKind::Timestamp(_) => "TIMESTAMP",
+            ExprKind::Var(_) => "VAR",
+        }
+    }
+
+    /**
+     * Rule content of the expression in canonical form: operator names in
+     * upper case, one space between args and no comments, parsing it gives
+     * the same expression again. Bare atoms are written as strings.
+     */
+    pub fn to_sexpr(&self) -> String {
+        let mut out = String::new();
+        self.write_sexpr(&mut out);
+        return out;
+    }
+
+    fn write_sexpr(&self, out: &mut String) {
+        match &self.kind {
+            ExprKind::Int(i) => out.push_str(&i.to_string()),
+            ExprKind::Float(f) => out.push_str(&Value::FLOAT(*f).to_string()),
+            ExprKind::Str(s) => out.push_str(&format!("\"{}\"", s)),
+            ExprKind::Bool(b) => out.push_str(&b.to_string()),
+            ExprKind::Null => out.push_str("NULL"),
+            ExprKind::Bytes(bytes) => out.push_str(&Value::BYTES(bytes.clone()).to_string()),
+            ExprKind::Timestamp(millis) => out.push_str(&time::format_iso8601(*millis)),
+            ExprKind::Var(var) => out.push_str(&format!("${{{}}}", var.name())),
+            ExprKind::Rule(name, _) => out.push_str(&format!("(RULE \"{}\")", name)),
+            ExprKind::Call(function, args) => {
+                out.push_str(&format!("(CALL \"{}\"", function.name()));
+                for arg in args.iter() {
+                    out.push(' ');
+                    arg.write_sexpr(out);
+                }
+                out.push(')');
+            }
+            ExprKind::Match(arg, regex) => {
+                out.push_str("(MATCH ");
+                arg.write_sexpr(out);
+                out.push_str(&format!(" \"{}\")", regex.as_str()));
+            }
+            ExprKind::Like(arg, pattern) => {
+                out.push_str("(LIKE ");
+                arg.write_sexpr(out);
+                out.push_str(&format!(" \"{}\")", pattern.as_str()));
+            }
+            ExprKind::Lambda(params, body, _) => {
+                out.push_str(&format!("(LAMBDA ({}) ", params.join(" ")));
+                body.write_sexpr(out);
+                out.push(')');
+            }
+            ExprKind::Let(bindings, body) => {
+                out.push_str("(LET (");
+                for (i, (name, expr)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    out.push_str(&format!("({} ", name));
+                    expr.write_sexpr(out);
+                    out.push(')');
+                }
+                out.push_str(") ");
+                body.write_sexpr(out);
+                out.push(')');
+            }
+            ExprKind::Cond(branches, default) => {
+                out.push_str("(COND");
+                for (test, result) in branches.iter() {
+                    out.push_str(" (");
+                    test.write_sexpr(out);
+                    out.push(' ');
+                    result.write_sexpr(out);
+                    out.push(')');
+                }
+                if let Some(default) = default {
+                    out.push_str(" (ELSE ");
+                    default.write_sexpr(out);
+                    out.push(')');
+                }
+                out.push(')');
+            }
+            _ => {
+                out.push('(');
+                out.push_str(self.name());
+                for child in self.children() {
+                    out.push(' ');
+                    child.write_sexpr(out);
+                }
+                out.push(')');
+            }
+        }
+    }
+
+    /**
+     * JSON document of the expression. Operators are objects like
+     * `{"op":"AND","args":[...]}`, MATCH and LIKE have a "pattern", LET has
+     * "bindings" of `{"name":..,"expr":..}` and a "body", COND has
+     * "branches" of `{"test":..,"result":..}` and an optional "else", RULE
+     * has the "rule" name, CALL the "function" name and LAMBDA has the
+     * "params" names and a "body".
+     * Literals are JSON values, FLOAT always has a fraction, and variables,
+     * bytes and timestamps are `{"var":..}`, `{"bytes":"00ff"}` and
+     * `{"timestamp":"2024-01-31T00:00:00Z"}`.
+     */
+    pub fn to_json(&self) -> String {
+        return self.to_json_value().to_string();
+    }
+
+    /**
+     * Build the expression from a JSON document in the layout written by
+     * to_json, without going through the lexer. Operator names are matched
+     * like keywords in rule content, and the options limit the nesting and
+     * the args the same way.
+     */
+    pub fn from_json(json: &str, options: &ParserOptions) -> Result<Expr, AstError> {
+        let doc = json::parse(json)
+            .map_err(|e| AstError::FORMAT_NOT_MATCH(format!("Not a valid JSON rule: {}", e)))?;
+        let lexer = Lexer::create(String::new())
+            .map_err(|_| AstError::LEXER_FAILED("Lexer init failed!".to_string()))?;
+        return Self::from_json_value(&doc, &lexer, options, 1);
+    }
+
+    fn from_json_value(
+        doc: &Json,
+        lexer: &Lexer,
+        options: &ParserOptions,
+        depth: usize,
+    ) -> Result<Expr, AstError> {
+        let kind = Self::from_json_kind(doc, lexer, options, depth)?;
+        return Ok(Expr::new(kind, Span::default()));
+    }
+
+    fn from_json_kind(
+        doc: &Json,
+        lexer: &Lexer,
+        options: &ParserOptions,
+        depth: usize,
+    ) -> Result<ExprKind, AstError> {
+        let field = |key: &str| {
+            doc.get(key).ok_or_else(|| {
+                AstError::FORMAT_NOT_MATCH(format!("Expected \"{}\" in JSON rule {}", key, doc))
+            })
+        };
+        let str_field = |key: &str| match field(key)? {
+            Json::Str(s) => Ok(s.as_str()),
+            other => Err(AstError::FORMAT_NOT_MATCH(format!(
+                "Expected \"{}\" to be a string, but got {}",
+                key, other
+            ))),
+        };
+        let array_field = |key: &str| match field(key)? {
+            Json::Array(items) => Ok(items),
+            other => Err(AstError::FORMAT_NOT_MATCH(format!(
+                "Expected \"{}\" to be an array, but got {}",
+                key, other
+            ))),
+        };
+        let expr_of = |doc: &Json| Self::from_json_value(doc, lexer, options, depth + 1);
+        match doc {
+            Json::Null => return Ok(ExprKind::Null),
+            Json::Bool(b) => return Ok(ExprKind::Bool(*b)),
+            Json::Int(i) => return Ok(ExprKind::Int(*i)),
+            Json::Float(f) => return Ok(ExprKind::Float(*f)),
+            Json::Str(s) => return Ok(ExprKind::Str(s.clone())),
+            Json::Array(_) => {
+                return Err(AstError::FORMAT_NOT_MATCH(format!(
+                    "Expected an expression, but got array {}",
+                    doc
+                )));
+            }
+            Json::Object(_) => {}
+        }
+        if doc.get("var").is_some() {
+            let name = str_field("var")?;
+            return Ok(ExprKind::Var(Var::create(
+                name,
+                Span::default(),
+                options,
+                lexer.symbols(),
+            )?));
+        }
+        if doc.get("bytes").is_some() {
+            let hex = str_field("bytes")?;
+            if hex.is_empty() || hex.len() % 2 != 0 {
+                return Err(AstError::FORMAT_NOT_MATCH(format!(
+                    "Not a valid bytes literal: #x\"{}\"",
+                    hex
+                )));
+            }
+            return Self::create_bytes(hex);
+        }
+        if doc.get("timestamp").is_some() {
+            let s = str_field("timestamp")?;
+            let millis = time::parse_iso8601(s).map_err(|e| {
+                AstError::FORMAT_NOT_MATCH(format!("Not a valid timestamp literal {}: {}", s, e))
+            })?;
+            return Ok(ExprKind::Timestamp(millis));
+        }
+        let op = str_field("op")?;
+        if depth > options.max_depth {
+            return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                "Operators are nested deeper than {} at {}",
+                options.max_depth, op
+            )));
+        }
+        let tag = match lexer.keyword(op) {
+            Some(TokenTag::NULL) | Some(TokenTag::BOOL) | Some(TokenTag::ELSE) | None => {
+                return Err(AstError::NOT_SUPP_OPER(format!(
+                    "Not supported operator {} in JSON rule",
+                    op
+                )));
+            }
+            Some(tag) => tag,
+        };
+        match tag {
+            TokenTag::LET => {
+                let mut bindings = Vec::new();
+                for binding in array_field("bindings")?.iter() {
+                    let name = match binding.get("name") {
+                        Some(Json::Str(name)) if !name.is_empty() => name.clone(),
+                        _ => {
+                            return Err(AstError::FORMAT_NOT_MATCH(format!(
+                                "Expected a binding with a name, but got {}",
+                                binding
+                            )));
+                        }
+                    };
+                    let expr = match binding.get("expr") {
+                        Some(expr) => expr_of(expr)?,
+                        None => {
+                            return Err(AstError::FORMAT_NOT_MATCH(format!(
+                                "Expected a binding with an expr, but got {}",
+                                binding
+                            )));
+                        }
+                    };
+                    bindings.push((name, expr));
+                }
+                let body = expr_of(field("body")?)?;
+                return Ok(ExprKind::Let(bindings, Box::new(body)));
+            }
+            TokenTag::COND => {
+                let mut branches = Vec::new();
+                for branch in array_field("branches")?.iter() {
+                    match (branch.get("test"), branch.get("result")) {
+                        (Some(test), Some(result)) => {
+                            branches.push((expr_of(test)?, expr_of(result)?));
+                        }
+                        _ => {
+                            return Err(AstError::FORMAT_NOT_MATCH(format!(
+                                "Expected a branch with test and result, but got {}",
+                                branch
+                            )));
+                        }
                     }
-                    _ => {
-                        return Err(AstError::LEXER_FAILED(
-                            "Lexer move token failed!".to_string(),
-                        ));
+                }
+                let default = match doc.get("else") {
+                    Some(default) => Some(Box::new(expr_of(default)?)),
+                    None => None,
+                };
+                return Ok(ExprKind::Cond(branches, default));
+            }
+            TokenTag::RULE => return Ok(ExprKind::Rule(str_field("rule")?.to_string(), None)),
+            TokenTag::LAMBDA => {
+                let mut params = Vec::new();
+                for param in array_field("params")?.iter() {
+                    match param {
+                        Json::Str(name) if !name.is_empty() => params.push(name.clone()),
+                        _ => {
+                            return Err(AstError::FORMAT_NOT_MATCH(format!(
+                                "Expected a param name, but got {}",
+                                param
+                            )));
+                        }
                     }
                 }
+                let body = expr_of(field("body")?)?;
+                return Ok(ExprKind::Lambda(params, Box::new(body), options.max_calls));
             }
+            _ => {}
+        }
+        let items = array_field("args")?;
+        if items.len() > options.max_args {
+            return Err(AstError::TOO_MANY_ARGS(format!(
+                "{} operator has more than {} arguments",
+                op, options.max_args
+            )));
         }
+        let mut args = Vec::new();
+        for item in items.iter() {
+            args.push(expr_of(item)?);
+        }
+        let name = op.to_ascii_uppercase();
+        if tag == TokenTag::MATCH || tag == TokenTag::LIKE {
+            let [arg] = operands(&name, args)?;
+            return Self::create_match(tag, *arg, str_field("pattern")?);
+        }
+        if tag == TokenTag::CALL {
+            let function = options.function(str_field("function")?)?;
+            function.check_call(&args)?;
+            return Ok(ExprKind::Call(function, args));
+        }
+        return Self::create(tag, &name, args, options.overflow);
     }
 
-    fn match_term(&mut self, tag: TokenTag) -> Result<(), AstError> {
-        match self.look_token.as_ref() {
-            Some(s) => {
-                if *s.token_tag() == tag {
-                    self.move_token()?;
-                    return Ok(());
-                } else {
-                    return Err(AstError::NOT_MATCH(
-                        "Expected is not match with current".to_string(),
-                    ));
+    pub(crate) fn to_json_value(&self) -> Json {
+        let op = Json::Str(self.name().to_string());
+        match &self.kind {
+            ExprKind::Int(i) => Json::Int(*i),
+            ExprKind::Float(f) => Json::Float(*f),
+            ExprKind::Str(s) => Json::Str(s.clone()),
+            ExprKind::Bool(b) => Json::Bool(*b),
+            ExprKind::Null => Json::Null,
+            ExprKind::Bytes(bytes) => {
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                Json::object(vec![("bytes", Json::Str(hex))])
+            }
+            ExprKind::Timestamp(millis) => Json::object(vec![(
+                "timestamp",
+                Json::Str(time::format_iso8601(*millis)),
+            )]),
+            ExprKind::Var(var) => Json::object(vec![("var", Json::Str(var.name().to_string()))]),
+            ExprKind::Rule(name, _) => {
+                Json::object(vec![("op", op), ("rule", Json::Str(name.clone()))])
+            }
+            ExprKind::Call(function, args) => Json::object(vec![
+                ("op", op),
+                ("function", Json::Str(function.name().to_string())),
+                (
+                    "args",
+                    Json::Array(args.iter().map(|arg| arg.to_json_value()).collect()),
+                ),
+            ]),
+            ExprKind::Match(arg, regex) => Json::object(vec![
+                ("op", op),
+                ("args", Json::Array(vec![arg.to_json_value()])),
+                ("pattern", Json::Str(regex.as_str().to_string())),
+            ]),
+            ExprKind::Like(arg, pattern) => Json::object(vec![
+                ("op", op),
+                ("args", Json::Array(vec![arg.to_json_value()])),
+                ("pattern", Json::Str(pattern.as_str().to_string())),
+            ]),
+            ExprKind::Lambda(params, body, _) => {
+                let params = params.iter().map(|name| Json::Str(name.clone())).collect();
+                Json::object(vec![
+                    ("op", op),
+                    ("params", Json::Array(params)),
+                    ("body", body.to_json_value()),
+                ])
+            }
+            ExprKind::Let(bindings, body) => {
+                let bindings = bindings
+                    .iter()
+                    .map(|(name, expr)| {
+                        Json::object(vec![
+                            ("name", Json::Str(name.clone())),
+                            ("expr", expr.to_json_value()),
+                        ])
+                    })
+                    .collect();
+                Json::object(vec![
+                    ("op", op),
+                    ("bindings", Json::Array(bindings)),
+                    ("body", body.to_json_value()),
+                ])
+            }
+            ExprKind::Cond(branches, default) => {
+                let branches = branches
+                    .iter()
+                    .map(|(test, result)| {
+                        Json::object(vec![
+                            ("test", test.to_json_value()),
+                            ("result", result.to_json_value()),
+                        ])
+                    })
+                    .collect();
+                let mut entries = vec![("op", op), ("branches", Json::Array(branches))];
+                if let Some(default) = default {
+                    entries.push(("else", default.to_json_value()));
                 }
+                Json::object(entries)
             }
-            None => {
-                return Err(AstError::NO_TOKEN_MATCH(
-                    "There is not token is current parser status".to_string(),
-                ));
+            _ => {
+                let args = self.children().iter().map(|e| e.to_json_value()).collect();
+                Json::object(vec![("op", op), ("args", Json::Array(args))])
             }
         }
     }
-}
 
-mod tests {
-    use super::{Parser, Value};
-    use std::collections::HashMap;
-    use std::sync::Arc;
+    /**
+     * Context keys the expression reads, names bound by an enclosing LET
+     * are not context keys and left out
+     */
+    pub fn variables(&self) -> HashSet<String> {
+        let mut vars = HashSet::new();
+        self.collect_variables(&mut Vec::new(), &mut vars);
+        return vars;
+    }
 
-    // #[test]
-    fn test_simple_in() {
-        let mut kv: HashMap<String, Value> = HashMap::new();
-        kv.insert("id".to_string(), Value::INT(1));
-        let parser = Parser::create("(IN ${id} 2 3)".to_string());
-        if let Ok(mut p) = parser {
-            match p.parse() {
-                Ok(o) => {
-                    println!("execute result is: {:?}", o.eval(Arc::new(kv)));
+    fn collect_variables<'a>(&'a self, bound: &mut Vec<&'a str>, vars: &mut HashSet<String>) {
+        match &self.kind {
+            ExprKind::Var(var) => {
+                if !bound.contains(&var.key()) {
+                    vars.insert(var.key().to_string());
                 }
-                Err(e) => {
-                    println!("execute error: {:?}", e);
+            }
+            // Every binding is seen by the later bindings and the body
+            ExprKind::Let(bindings, body) => {
+                let depth = bound.len();
+                for (name, expr) in bindings.iter() {
+                    expr.collect_variables(bound, vars);
+                    bound.push(name);
+                }
+                body.collect_variables(bound, vars);
+                bound.truncate(depth);
+            }
+            ExprKind::Lambda(params, body, _) => {
+                let depth = bound.len();
+                bound.extend(params.iter().map(|name| name.as_str()));
+                body.collect_variables(bound, vars);
+                bound.truncate(depth);
+            }
+            ExprKind::Each(_, list, predicate) => {
+                list.collect_variables(bound, vars);
+                bound.push(ITEM);
+                predicate.collect_variables(bound, vars);
+                bound.pop();
+            }
+            // A referenced rule does not see the names bound around it
+            ExprKind::Rule(_, Some(body)) => body.collect_variables(&mut Vec::new(), vars),
+            _ => {
+                for child in self.children() {
+                    child.collect_variables(bound, vars);
                 }
             }
         }
     }
 
-    #[test]
-    fn test_simple_and() {
-        let mut kv: HashMap<String, Value> = HashMap::new();
-        kv.insert("id".to_string(), Value::INT(1));
-        let parser = Parser::create("(AND (IN ${id} 1) (AND (IN 1 1) 1) (IN 2 2))".to_string());
-        if let Ok(mut p) = parser {
-            match p.parse() {
-                Ok(o) => {
-                    println!("execute result is: {:?}", o.eval(Arc::new(kv)));
+    // Levels of operators in the expression, 0 for a literal or a variable
+    pub(crate) fn height(&self) -> usize {
+        return self
+            .children()
+            .iter()
+            .map(|child| child.height() + 1)
+            .max()
+            .unwrap_or(0);
+    }
+
+    // Count of the nodes of the expression, the node itself included
+    pub(crate) fn size(&self) -> usize {
+        return 1 + self
+            .children()
+            .iter()
+            .map(|child| child.size())
+            .sum::<usize>();
+    }
+
+    // Call f on the node and then on every node below it, parents first
+    pub fn walk(&self, f: &mut impl FnMut(&Expr)) {
+        f(self);
+        for child in self.children() {
+            child.walk(f);
+        }
+    }
+
+    // Call f on every node below and then on the node itself, so a
+    // rewritten parent already sees its rewritten children
+    pub fn walk_mut(&mut self, f: &mut impl FnMut(&mut Expr)) {
+        for child in self.children_mut() {
+            child.walk_mut(f);
+        }
+        f(self);
+    }
+
+    /**
+     * Bind every variable to the slot of the schema declaring it, or
+     * declaring the context key it starts from, so that eval with slots
+     * reads the value by index instead of by name. Names bound by LET are
+     * not bound to slots, a variable the schema does not declare is an
+     * UNDEFINED_VAR error.
+     */
+    pub(crate) fn bind_slots(&mut self, schema: &Schema) -> Result<(), AstError> {
+        return self.bind_slots_in(schema, &mut Vec::new());
+    }
+
+    fn bind_slots_in(&mut self, schema: &Schema, scope: &mut Vec<String>) -> Result<(), AstError> {
+        let span = self.span;
+        match &mut self.kind {
+            ExprKind::Let(bindings, body) => {
+                let depth = scope.len();
+                for (name, arg) in bindings.iter_mut() {
+                    arg.bind_slots_in(schema, scope)?;
+                    scope.push(name.clone());
                 }
-                Err(e) => {
-                    println!("execute error: {:?}", e);
+                body.bind_slots_in(schema, scope)?;
+                scope.truncate(depth);
+            }
+            ExprKind::Lambda(params, body, _) => {
+                let depth = scope.len();
+                scope.extend(params.iter().cloned());
+                body.bind_slots_in(schema, scope)?;
+                scope.truncate(depth);
+            }
+            ExprKind::Each(_, list, predicate) => {
+                list.bind_slots_in(schema, scope)?;
+                scope.push(ITEM.to_string());
+                predicate.bind_slots_in(schema, scope)?;
+                scope.pop();
+            }
+            ExprKind::Rule(_, Some(body)) => body.bind_slots_in(schema, &mut Vec::new())?,
+            ExprKind::Var(var) => {
+                if scope.iter().any(|name| name == var.key()) {
+                    return Ok(());
+                }
+                var.slot = match (schema.slot(&var.name), schema.slot(var.key())) {
+                    (Some(index), _) => Some((index, var.path.len())),
+                    (None, Some(index)) => Some((index, 1)),
+                    (None, None) => {
+                        return Err(AstError::UNDEFINED_VAR(format!(
+                            "Variable ${{{}}} is not declared in the schema",
+                            var.name
+                        ))
+                        .at(span));
+                    }
+                };
+            }
+            _ => {
+                for child in self.children_mut() {
+                    child.bind_slots_in(schema, scope)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /**
+     * Give every RULE node which is not resolved yet the expression of the
+     * rule it references, resolve is called with the name and the span of
+     * the node
+     */
+    pub(crate) fn resolve_rules(
+        &mut self,
+        resolve: &mut impl FnMut(&str, Span) -> Result<Expr, AstError>,
+    ) -> Result<(), AstError> {
+        let span = self.span;
+        if let ExprKind::Rule(name, body) = &mut self.kind {
+            if body.is_none() {
+                *body = Some(Box::new(resolve(name, span)?));
+            }
+            return Ok(());
+        }
+        for child in self.children_mut() {
+            child.resolve_rules(resolve)?;
+        }
+        return Ok(());
+    }
+
+    /**
+     * Errors are at the span of the innermost node which failed, like
+     * "TYPE_NOT_MATCH: ... at line 2, column 5"
+     */
+    pub fn eval(&self, ctx: &dyn VariableResolver) -> Result<Value, AstError> {
+        return self.eval_with_options(ctx, &EvalOptions::default());
+    }
+
+    pub fn eval_with_options(
+        &self,
+        ctx: &dyn VariableResolver,
+        options: &EvalOptions,
+    ) -> Result<Value, AstError> {
+        return self.eval_env(Env {
+            vars: ctx,
+            root: ctx,
+            slots: None,
+            options: options,
+            calls: None,
+            depth: 0,
+        });
+    }
+
+    pub(crate) fn eval_env(&self, ctx: Env) -> Result<Value, AstError> {
+        return self.eval_kind(ctx).map_err(|e| e.at(self.span));
+    }
+
+    fn eval_kind(&self, ctx: Env) -> Result<Value, AstError> {
+        match &self.kind {
+            ExprKind::And(args) => {
+                for arg in args.iter() {
+                    if !truthy(arg.eval_env(ctx)?, "and", ctx.options)? {
+                        return Ok(Value::BOOL(false));
+                    }
+                }
+                return Ok(Value::BOOL(true));
+            }
+            ExprKind::Or(args) => return eval_or(args, ctx),
+            ExprKind::Not(arg) => {
+                return Ok(Value::BOOL(!truthy(
+                    arg.eval_env(ctx)?,
+                    "not",
+                    ctx.options,
+                )?))
+            }
+            // True when an odd number of args are true, which is "exactly
+            // one of" for two args
+            ExprKind::Xor(args) => {
+                let mut val = false;
+                for arg in args.iter() {
+                    if truthy(arg.eval_env(ctx)?, "xor", ctx.options)? {
+                        val = !val;
+                    }
+                }
+                return Ok(Value::BOOL(val));
+            }
+            ExprKind::Nand(args) => {
+                for arg in args.iter() {
+                    if !truthy(arg.eval_env(ctx)?, "nand", ctx.options)? {
+                        return Ok(Value::BOOL(true));
+                    }
+                }
+                return Ok(Value::BOOL(false));
+            }
+            ExprKind::Mod(arg0, arg1) => return eval_mod(arg0, arg1, ctx),
+            ExprKind::In(args) => return eval_in(args, ctx),
+            ExprKind::Equals(args) => return eval_equals(args, false, ctx),
+            ExprKind::EqualsCi(args) => return eval_equals(args, true, ctx),
+            ExprKind::Compare(op, arg0, arg1) => {
+                return op.apply(&arg0.eval_env(ctx)?, &arg1.eval_env(ctx)?);
+            }
+            ExprKind::Between(val, low, high) => {
+                return between(
+                    &val.eval_env(ctx)?,
+                    &low.eval_env(ctx)?,
+                    &high.eval_env(ctx)?,
+                );
+            }
+            ExprKind::StrPredicate(op, arg0, arg1) => {
+                match (arg0.eval_env(ctx)?, arg1.eval_env(ctx)?) {
+                    (Value::STR(s), Value::STR(pattern)) => {
+                        let result = match op {
+                            StrPredicateOp::CONTAINS => s.contains(pattern.as_str()),
+                            StrPredicateOp::STARTS_WITH => s.starts_with(pattern.as_str()),
+                            StrPredicateOp::ENDS_WITH => s.ends_with(pattern.as_str()),
+                        };
+                        return Ok(Value::BOOL(result));
+                    }
+                    (arg0, arg1) => {
+                        return Err(AstError::TYPE_NOT_MATCH(format!(
+                            "{} operator needs two STR args, but got {:?} and {:?}",
+                            op.name(),
+                            arg0,
+                            arg1
+                        )));
+                    }
+                }
+            }
+            ExprKind::StrTransform(op, arg) => match arg.eval_env(ctx)? {
+                Value::STR(s) => match op {
+                    StrTransformOp::UPPER => return Ok(Value::STR(s.to_uppercase())),
+                    StrTransformOp::LOWER => return Ok(Value::STR(s.to_lowercase())),
+                    StrTransformOp::TRIM => return Ok(Value::STR(s.trim().to_string())),
+                },
+                val => {
+                    return Err(AstError::TYPE_NOT_MATCH(format!(
+                        "{} operator needs a STR arg, but got {:?}",
+                        op.name(),
+                        val
+                    )));
+                }
+            },
+            ExprKind::Arith(op, args, overflow) => return eval_arith(*op, args, *overflow, ctx),
+            ExprKind::Concat(args) => return eval_concat(args, ctx),
+            // Length of a STR is counted in chars, not bytes
+            ExprKind::Len(arg) => match arg.eval_env(ctx)? {
+                Value::STR(s) => return Ok(Value::INT(s.chars().count() as i64)),
+                Value::LIST(list) => return Ok(Value::INT(list.len() as i64)),
+                Value::BYTES(bytes) => return Ok(Value::INT(bytes.len() as i64)),
+                val => {
+                    return Err(AstError::TYPE_NOT_MATCH(format!(
+                        "Len operator needs a STR, LIST or BYTES arg, but got {:?}",
+                        val
+                    )));
+                }
+            },
+            ExprKind::Hash(arg) => return Ok(Value::INT(stable_hash(&arg.eval_env(ctx)?)?)),
+            ExprKind::Rollout(id, salt, percent) => return eval_rollout(id, salt, percent, ctx),
+            ExprKind::Substr(s, start, len) => return eval_substr(s, start, len, ctx),
+            // Char index of the first occurrence of needle, -1 when not found
+            ExprKind::IndexOf(s, needle) => match (s.eval_env(ctx)?, needle.eval_env(ctx)?) {
+                (Value::STR(s), Value::STR(needle)) => match s.find(needle.as_str()) {
+                    Some(byte_index) => {
+                        return Ok(Value::INT(s[..byte_index].chars().count() as i64))
+                    }
+                    None => return Ok(Value::INT(-1)),
+                },
+                (s, needle) => {
+                    return Err(AstError::TYPE_NOT_MATCH(format!(
+                        "Index-of operator needs two STR args, but got {:?} and {:?}",
+                        s, needle
+                    )));
+                }
+            },
+            ExprKind::Split(s, sep) => return eval_split(s, sep, ctx),
+            // A missing key is NULL, so that COALESCE falls back from it
+            ExprKind::Get(map, key) => match (map.eval_env(ctx)?, key.eval_env(ctx)?) {
+                (Value::MAP(mut map), Value::STR(key)) => match map.remove(&key) {
+                    Some(val) => return Ok(val),
+                    None => return Ok(Value::NULL),
+                },
+                (map, key) => {
+                    return Err(AstError::TYPE_NOT_MATCH(format!(
+                        "Get operator needs MAP and STR args, but got {:?} and {:?}",
+                        map, key
+                    )));
                 }
+            },
+            ExprKind::Match(arg, regex) => match arg.eval_env(ctx)? {
+                Value::STR(s) => return Ok(Value::BOOL(regex.is_match(&s))),
+                val => {
+                    return Err(AstError::TYPE_NOT_MATCH(format!(
+                        "Match operator needs a STR arg, but got {:?}",
+                        val
+                    )));
+                }
+            },
+            ExprKind::Like(arg, pattern) => match arg.eval_env(ctx)? {
+                Value::STR(s) => return Ok(Value::BOOL(pattern.is_match(&s))),
+                val => {
+                    return Err(AstError::TYPE_NOT_MATCH(format!(
+                        "Like operator needs a STR arg, but got {:?}",
+                        val
+                    )));
+                }
+            },
+            // First arg which is not NULL, NULL when all of them are
+            ExprKind::Coalesce(args) => {
+                for arg in args.iter() {
+                    let val = arg.eval_env(ctx)?;
+                    if val != Value::NULL {
+                        return Ok(val);
+                    }
+                }
+                return Ok(Value::NULL);
             }
+            ExprKind::Let(bindings, body) => return eval_let(bindings, body, ctx),
+            ExprKind::Lambda(params, body, max_calls) => {
+                let mut captured = Vec::new();
+                for name in self.variables() {
+                    if let Some(val) = ctx.vars.resolve(&name) {
+                        captured.push((name, val));
+                    }
+                }
+                return Ok(Value::LAMBDA(Arc::new(Lambda {
+                    params: params.clone(),
+                    body: body.as_ref().clone(),
+                    captured: captured,
+                    max_calls: *max_calls,
+                })));
+            }
+            ExprKind::Each(op, list, predicate) => return eval_each(*op, list, predicate, ctx),
+            ExprKind::Aggregate(op, list, overflow) => {
+                return eval_aggregate(*op, list, *overflow, ctx);
+            }
+            ExprKind::Apply(f, args) => {
+                let lambda = match f.eval_env(ctx)? {
+                    Value::LAMBDA(lambda) => lambda,
+                    val => return Err(type_not_match("LAMBDA", &val)),
+                };
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    values.push(arg.eval_env(ctx)?);
+                }
+                return lambda.apply(values, ctx);
+            }
+            // Result of the first branch whose test is true, then the ELSE
+            // branch, BOOL(false) when nothing matched and there is no ELSE
+            ExprKind::Cond(branches, default) => {
+                for (test, result) in branches.iter() {
+                    if truthy(test.eval_env(ctx)?, "cond", ctx.options)? {
+                        return result.eval_env(ctx);
+                    }
+                }
+                match default {
+                    Some(default) => return default.eval_env(ctx),
+                    None => return Ok(Value::BOOL(false)),
+                }
+            }
+            // A referenced rule sees the variables of the context, not the
+            // names bound by a LET around it
+            ExprKind::Rule(_, Some(body)) => return body.eval_env(ctx.with_vars(ctx.root)),
+            ExprKind::Rule(name, None) => {
+                return Err(AstError::UNDEFINED_RULE(format!(
+                    "Rule {} is not resolved, rules are only referenced in a rule set",
+                    name
+                )));
+            }
+            ExprKind::Call(function, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    values.push(arg.eval_env(ctx)?);
+                }
+                match ctx.calls {
+                    Some(calls) => return calls.call(self, function, values),
+                    None => return function.apply(&values),
+                }
+            }
+            ExprKind::Now => return Ok(Value::TIMESTAMP(time::now_millis())),
+            ExprKind::Int(i) => return Ok(Value::INT(*i)),
+            ExprKind::Float(f) => return Ok(Value::FLOAT(*f)),
+            ExprKind::Str(s) => return Ok(Value::STR(s.clone())),
+            ExprKind::Bool(b) => return Ok(Value::BOOL(*b)),
+            ExprKind::Null => return Ok(Value::NULL),
+            ExprKind::Bytes(bytes) => return Ok(Value::BYTES(bytes.clone())),
+            ExprKind::Timestamp(millis) => return Ok(Value::TIMESTAMP(*millis)),
+            ExprKind::Var(var) => return var.eval(ctx),
+        }
+    }
+}
+
+/**
+ * How AND, OR, NOT, XOR, NAND and the tests of COND turn a value into a
+ * boolean
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Truthiness {
+    // INT 0, BOOL false and NULL are false, any other INT or BOOL is true
+    #[default]
+    Lenient,
+    // Only BOOL values are accepted
+    StrictBool,
+}
+
+/**
+ * Options of one evaluation, unlike ParserOptions they can differ between
+ * evaluations of the same expression
+ */
+#[derive(Debug, Clone, Default)]
+pub struct EvalOptions {
+    pub truthiness: Truthiness,
+}
+
+// Variables and options of the evaluation in progress
+#[derive(Clone, Copy)]
+pub(crate) struct Env<'a> {
+    pub(crate) vars: &'a dyn VariableResolver,
+    // Variables of the context, without the names bound by LET
+    pub(crate) root: &'a dyn VariableResolver,
+    // Values of the variables bound to slots, see Expr::bind_slots
+    pub(crate) slots: Option<&'a [Value]>,
+    pub(crate) options: &'a EvalOptions,
+    // Results of the function calls of an async eval
+    pub(crate) calls: Option<&'a Calls>,
+    // Applications of LAMBDA values the node is evaluated in
+    pub(crate) depth: usize,
+}
+
+impl<'a> Env<'a> {
+    fn with_vars(self, vars: &'a dyn VariableResolver) -> Env<'a> {
+        Env {
+            vars: vars,
+            root: self.root,
+            slots: self.slots,
+            options: self.options,
+            calls: self.calls,
+            depth: self.depth,
+        }
+    }
+}
+
+pub(crate) fn truthy(val: Value, op: &str, options: &EvalOptions) -> Result<bool, AstError> {
+    match (val, options.truthiness) {
+        (Value::BOOL(b), _) => Ok(b),
+        (Value::INT(i), Truthiness::Lenient) => Ok(i != 0),
+        (Value::NULL, Truthiness::Lenient) => Ok(false),
+        (val, Truthiness::StrictBool) => Err(AstError::TYPE_NOT_MATCH(format!(
+            "{} operator expects BOOL values, but got {} {}",
+            op,
+            val.type_name(),
+            val
+        ))),
+        (_, Truthiness::Lenient) => Err(AstError::FORMAT_NOT_MATCH(format!(
+            "Not correct value format in {} operator",
+            op
+        ))),
+    }
+}
+
+fn eval_or(args: &[Expr], ctx: Env) -> Result<Value, AstError> {
+    for arg in args.iter() {
+        if truthy(arg.eval_env(ctx)?, "or", ctx.options)? {
+            return Ok(Value::BOOL(true));
+        }
+    }
+    return Ok(Value::BOOL(false));
+}
+
+fn eval_mod(arg0: &Expr, arg1: &Expr, ctx: Env) -> Result<Value, AstError> {
+    return modulo(arg0.eval_env(ctx)?, arg1.eval_env(ctx)?);
+}
+
+// (BETWEEN x low high) is low <= x <= high
+pub(crate) fn between(val: &Value, low: &Value, high: &Value) -> Result<Value, AstError> {
+    match (low.partial_cmp(val), val.partial_cmp(high)) {
+        (Some(ord_low), Some(ord_high)) => {
+            return Ok(Value::BOOL(
+                ord_low != Ordering::Greater && ord_high != Ordering::Greater,
+            ));
+        }
+        _ => {
+            return Err(AstError::TYPE_NOT_MATCH(format!(
+                "Between operator needs comparable args, but got {:?}, {:?} and {:?}",
+                val, low, high
+            )));
+        }
+    }
+}
+
+pub(crate) fn modulo(arg0: Value, arg1: Value) -> Result<Value, AstError> {
+    if arg1 == Value::INT(0) {
+        return Err(AstError::DIV_BY_ZERO(format!(
+            "Mod operator can not divide {} by zero",
+            arg0
+        )));
+    }
+    match (arg0, arg1) {
+        (Value::INT(i1), Value::INT(i2)) => match i1.checked_rem(i2) {
+            Some(result) => return Ok(Value::INT(result)),
+            None => {
+                return Err(AstError::ARITH_OVERFLOW(format!(
+                    "Mod operator overflows for {} and {}",
+                    i1, i2
+                )));
+            }
+        },
+        (Value::INT(i1), Value::FLOAT(f2)) => return Ok(Value::FLOAT(i1 as f64 % f2)),
+        (Value::FLOAT(f1), Value::INT(i2)) => return Ok(Value::FLOAT(f1 % i2 as f64)),
+        (Value::FLOAT(f1), Value::FLOAT(f2)) => return Ok(Value::FLOAT(f1 % f2)),
+        _ => {}
+    }
+    return Err(AstError::ARG_NOT_CORRECT(
+        "Arg's format is not correct for mod ".to_string(),
+    ));
+}
+
+// Args are folded from the left, (SUB x) is the negation of x. INT args give
+// an INT, any FLOAT arg makes the result FLOAT
+fn eval_arith(
+    op: ArithOp,
+    args: &[Expr],
+    overflow: OverflowPolicy,
+    ctx: Env,
+) -> Result<Value, AstError> {
+    let mut values = Vec::with_capacity(args.len() + 1);
+    if op == ArithOp::SUB && args.len() == 1 {
+        values.push(Value::INT(0));
+    }
+    for arg in args.iter() {
+        values.push(arg.eval_env(ctx)?);
+    }
+    return arith(op, overflow, values);
+}
+
+pub(crate) fn arith(
+    op: ArithOp,
+    overflow: OverflowPolicy,
+    values: Vec<Value>,
+) -> Result<Value, AstError> {
+    let mut result = None;
+    for val in values {
+        result = match (result, val) {
+            (None, val @ Value::INT(_)) | (None, val @ Value::FLOAT(_)) => Some(val),
+            (Some(Value::INT(a)), Value::INT(b)) => match overflow.apply(op, a, b) {
+                Some(i) => Some(Value::INT(i)),
+                None => {
+                    return Err(AstError::ARITH_OVERFLOW(format!(
+                        "{} operator overflows for {} and {}",
+                        op.name(),
+                        a,
+                        b
+                    )));
+                }
+            },
+            (Some(Value::INT(a)), Value::FLOAT(b)) => Some(float_arith(op, a as f64, b)),
+            (Some(Value::FLOAT(a)), Value::INT(b)) => Some(float_arith(op, a, b as f64)),
+            (Some(Value::FLOAT(a)), Value::FLOAT(b)) => Some(float_arith(op, a, b)),
+            (_, val) => {
+                return Err(AstError::TYPE_NOT_MATCH(format!(
+                    "{} operator needs INT or FLOAT args, but got {} {}",
+                    op.name(),
+                    val.type_name(),
+                    val
+                )));
+            }
+        };
+    }
+    return Ok(result.unwrap_or(Value::INT(0)));
+}
+
+fn float_arith(op: ArithOp, a: f64, b: f64) -> Value {
+    match op {
+        ArithOp::ADD => Value::FLOAT(a + b),
+        ArithOp::SUB => Value::FLOAT(a - b),
+        ArithOp::MUL => Value::FLOAT(a * b),
+    }
+}
+
+// (IN x a b ...) is true when x equals one of the args or one of the
+// elements of a LIST arg, so the set can be a context variable like
+// (IN ${user_id} ${vip_list}) and change without changing the rule
+fn eval_in(args: &[Expr], ctx: Env) -> Result<Value, AstError> {
+    if args.len() <= 1 {
+        return Err(AstError::NOT_ENOUGH_ARGS(
+            "In operator should have at least two arguments".to_string(),
+        ));
+    }
+    let arg0 = args[0].eval_env(ctx)?;
+    // 逐个判断值之间是否相等
+    for arg in args[1..].iter() {
+        if is_member(&arg0, &arg.eval_env(ctx)?) {
+            return Ok(Value::BOOL(true));
+        }
+    }
+    return Ok(Value::BOOL(false));
+}
+
+// LIST args are expanded to their elements
+pub(crate) fn is_member(val: &Value, arg: &Value) -> bool {
+    match arg {
+        _ if val == arg => true,
+        Value::LIST(list) => list.contains(val),
+        _ => false,
+    }
+}
+
+// True when all args are equal, the args after the first one which differs
+// are not evaluated. EQUALS-CI ignores case when both sides are STR
+fn eval_equals(args: &[Expr], ignore_case: bool, ctx: Env) -> Result<Value, AstError> {
+    if args.len() < 2 {
+        return Err(AstError::NOT_ENOUGH_ARGS(
+            "Equals does not have enough args!".to_string(),
+        ));
+    }
+    let arg0 = args[0].eval_env(ctx)?;
+    for arg in args[1..].iter() {
+        if !equal_values(&arg0, &arg.eval_env(ctx)?, ignore_case) {
+            return Ok(Value::BOOL(false));
+        }
+    }
+    return Ok(Value::BOOL(true));
+}
+
+pub(crate) fn equal_values(val0: &Value, val1: &Value, ignore_case: bool) -> bool {
+    match (val0, val1) {
+        (Value::STR(s0), Value::STR(s1)) if ignore_case => s0.to_lowercase() == s1.to_lowercase(),
+        _ => val0 == val1,
+    }
+}
+
+fn eval_concat(args: &[Expr], ctx: Env) -> Result<Value, AstError> {
+    let mut result = String::new();
+    for arg in args.iter() {
+        match arg.eval_env(ctx)? {
+            Value::INT(i) => result.push_str(&i.to_string()),
+            Value::FLOAT(f) => result.push_str(&f.to_string()),
+            Value::BOOL(b) => result.push_str(&b.to_string()),
+            Value::STR(s) => result.push_str(&s),
+            val => {
+                return Err(AstError::TYPE_NOT_MATCH(format!(
+                    "Concat operator can not concat {:?}",
+                    val
+                )));
+            }
+        }
+    }
+    return Ok(Value::STR(result));
+}
+
+// (SUBSTR s start len) works on chars, start and len out of range are
+// clamped to the string instead of failing
+fn eval_substr(s: &Expr, start: &Expr, len: &Expr, ctx: Env) -> Result<Value, AstError> {
+    let s = s.eval_env(ctx)?;
+    let start = start.eval_env(ctx)?;
+    let len = len.eval_env(ctx)?;
+    match (s, start, len) {
+        (Value::STR(s), Value::INT(start), Value::INT(len)) => {
+            let start = start.max(0) as usize;
+            let len = len.max(0) as usize;
+            return Ok(Value::STR(s.chars().skip(start).take(len).collect()));
+        }
+        (s, start, len) => {
+            return Err(AstError::TYPE_NOT_MATCH(format!(
+                "Substr operator needs STR, INT and INT args, but got {:?}, {:?} and {:?}",
+                s, start, len
+            )));
+        }
+    }
+}
+
+fn eval_split(s: &Expr, sep: &Expr, ctx: Env) -> Result<Value, AstError> {
+    match (s.eval_env(ctx)?, sep.eval_env(ctx)?) {
+        (Value::STR(s), Value::STR(sep)) => {
+            if sep.is_empty() {
+                return Err(AstError::ARG_NOT_CORRECT(
+                    "Split operator needs a non empty separator".to_string(),
+                ));
+            }
+            let list = s
+                .split(sep.as_str())
+                .map(|part| Value::STR(part.to_string()));
+            return Ok(Value::LIST(list.collect()));
+        }
+        (s, sep) => {
+            return Err(AstError::TYPE_NOT_MATCH(format!(
+                "Split operator needs two STR args, but got {:?} and {:?}",
+                s, sep
+            )));
+        }
+    }
+}
+
+// A predicate which is a LAMBDA is evaluated once, any other predicate is
+// evaluated for every element and applied to it when it evals to a LAMBDA
+fn eval_each(op: EachOp, list: &Expr, predicate: &Expr, ctx: Env) -> Result<Value, AstError> {
+    let items = match list.eval_env(ctx)? {
+        Value::LIST(items) => items,
+        val => return Err(type_not_match("LIST", &val)),
+    };
+    let lambda = match &predicate.kind {
+        ExprKind::Lambda(..) => Some(predicate.eval_env(ctx)?),
+        _ => None,
+    };
+    let mut kept = Vec::new();
+    for item in items {
+        let val = match &lambda {
+            Some(Value::LAMBDA(lambda)) => lambda.apply(vec![item.clone()], ctx)?,
+            _ => {
+                let scope = Scope {
+                    bindings: vec![(ITEM, item.clone())],
+                    parent: ctx.vars,
+                };
+                match predicate.eval_env(ctx.with_vars(&scope))? {
+                    Value::LAMBDA(lambda) => lambda.apply(vec![item.clone()], ctx)?,
+                    val => val,
+                }
+            }
+        };
+        let holds = truthy(val, &op.name().to_lowercase(), ctx.options)?;
+        match op {
+            EachOp::ANY if holds => return Ok(Value::BOOL(true)),
+            EachOp::ALL if !holds => return Ok(Value::BOOL(false)),
+            EachOp::FILTER if holds => kept.push(item),
+            _ => {}
+        }
+    }
+    match op {
+        EachOp::ANY => return Ok(Value::BOOL(false)),
+        EachOp::ALL => return Ok(Value::BOOL(true)),
+        EachOp::FILTER => return Ok(Value::LIST(kept)),
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// 64-bit FNV-1a of the bytes with the top bit cleared, so the hash is never
+// negative and (MOD (HASH ${id}) 100) is a bucket from 0 to 99. Rules
+// sample users by it, the hash of a value must never change.
+fn fnv1a(bytes: &[u8]) -> i64 {
+    let mut hash = FNV_OFFSET;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    return (hash & i64::MAX as u64) as i64;
+}
+
+// The UTF-8 bytes of a STR, the bytes of BYTES or the decimal digits of an
+// INT, so that 42 and "42" hash the same
+fn hash_bytes(op: &str, val: &Value) -> Result<Vec<u8>, AstError> {
+    match val {
+        Value::STR(s) => return Ok(s.as_bytes().to_vec()),
+        Value::BYTES(bytes) => return Ok(bytes.clone()),
+        Value::INT(i) => return Ok(i.to_string().into_bytes()),
+        val => {
+            return Err(AstError::TYPE_NOT_MATCH(format!(
+                "{} operator needs a STR, INT or BYTES arg, but got {} {}",
+                op,
+                val.type_name(),
+                val
+            )));
+        }
+    }
+}
+
+pub(crate) fn stable_hash(val: &Value) -> Result<i64, AstError> {
+    return Ok(fnv1a(&hash_bytes("HASH", val)?));
+}
+
+// (ROLLOUT id salt percent) puts the id in one of 10000 buckets by the hash
+// of "salt:id" and is true for the first percent * 100 of them, so a
+// percentage can have two decimals. Raising the percentage keeps every id
+// which was in the rollout, and ids are in the rollouts of different salts
+// independently.
+fn eval_rollout(id: &Expr, salt: &Expr, percent: &Expr, ctx: Env) -> Result<Value, AstError> {
+    let id = id.eval_env(ctx)?;
+    let salt = match salt.eval_env(ctx)? {
+        Value::STR(salt) => salt,
+        val => return Err(type_not_match("STR", &val)),
+    };
+    let percent = match percent.eval_env(ctx)? {
+        Value::INT(i) => i as f64,
+        Value::FLOAT(f) => f,
+        val => return Err(type_not_match("INT or FLOAT", &val)),
+    };
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(AstError::ARG_NOT_CORRECT(format!(
+            "ROLLOUT operator needs a percentage from 0 to 100, but got {}",
+            percent
+        )));
+    }
+    let mut bytes = salt.into_bytes();
+    bytes.push(b':');
+    bytes.extend(hash_bytes("ROLLOUT", &id)?);
+    let bucket = fnv1a(&bytes) % 10000;
+    return Ok(Value::BOOL((bucket as f64) < percent * 100.0));
+}
+
+fn eval_aggregate(
+    op: AggregateOp,
+    list: &Expr,
+    overflow: OverflowPolicy,
+    ctx: Env,
+) -> Result<Value, AstError> {
+    let values: Vec<Value> = match list.eval_env(ctx)? {
+        Value::LIST(items) => items
+            .into_iter()
+            .filter(|item| !matches!(item, Value::NULL))
+            .collect(),
+        val => return Err(type_not_match("LIST", &val)),
+    };
+    if matches!(op, AggregateOp::SUM | AggregateOp::AVG) {
+        let other = values
+            .iter()
+            .find(|val| !matches!(val, Value::INT(_) | Value::FLOAT(_)));
+        if let Some(val) = other {
+            return Err(AstError::TYPE_NOT_MATCH(format!(
+                "{} operator needs INT or FLOAT elements, but got {} {}",
+                op.name(),
+                val.type_name(),
+                val
+            )));
+        }
+    }
+    match op {
+        AggregateOp::SUM => return arith(ArithOp::ADD, overflow, values),
+        AggregateOp::COUNT => return Ok(Value::INT(values.len() as i64)),
+        AggregateOp::AVG => {
+            if values.is_empty() {
+                return Ok(Value::NULL);
+            }
+            let sum: f64 = values
+                .iter()
+                .map(|val| match val {
+                    Value::INT(i) => *i as f64,
+                    Value::FLOAT(f) => *f,
+                    _ => 0.0,
+                })
+                .sum();
+            return Ok(Value::FLOAT(sum / values.len() as f64));
+        }
+        AggregateOp::MIN | AggregateOp::MAX => {
+            let wanted = match op {
+                AggregateOp::MIN => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            let mut values = values.into_iter();
+            let mut result = match values.next() {
+                Some(val) => val,
+                None => return Ok(Value::NULL),
+            };
+            for val in values {
+                match val.partial_cmp(&result) {
+                    Some(ord) if ord == wanted => result = val,
+                    Some(_) => {}
+                    None => {
+                        return Err(AstError::TYPE_NOT_MATCH(format!(
+                            "{} operator needs comparable elements, but got {} {} and {} {}",
+                            op.name(),
+                            result.type_name(),
+                            result,
+                            val.type_name(),
+                            val
+                        )));
+                    }
+                }
+            }
+            return Ok(result);
+        }
+    }
+}
+
+// Bindings are evaluated in order, every bound name shadows the context
+// variable with the same name for later bindings and body
+fn eval_let(bindings: &[(String, Expr)], body: &Expr, ctx: Env) -> Result<Value, AstError> {
+    let mut scope = Scope {
+        bindings: Vec::with_capacity(bindings.len()),
+        parent: ctx.vars,
+    };
+    for (name, expr) in bindings.iter() {
+        let val = expr.eval_env(ctx.with_vars(&scope))?;
+        scope.bindings.push((name, val));
+    }
+    return body.eval_env(ctx.with_vars(&scope));
+}
+
+// Names bound by LET on top of the variables of the enclosing scope, a name
+// bound later shadows an earlier one
+struct Scope<'a> {
+    bindings: Vec<(&'a str, Value)>,
+    parent: &'a dyn VariableResolver,
+}
+
+impl<'a> VariableResolver for Scope<'a> {
+    fn resolve(&self, name: &str) -> Option<Value> {
+        match self.bindings.iter().rev().find(|(n, _)| *n == name) {
+            Some((_, val)) => Some(val.clone()),
+            None => self.parent.resolve(name),
+        }
+    }
+
+    fn resolve_path(&self, path: &[PathSegment]) -> Option<(Value, usize)> {
+        if let Some(PathSegment::Key(name)) = path.first() {
+            if let Some((_, val)) = self.bindings.iter().rev().find(|(n, _)| *n == &**name) {
+                return Some((val.clone(), 1));
+            }
+        }
+        return self.parent.resolve_path(path);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Var {
+    // Path as written in ${...}, like user.items[0].name, names are
+    // interned by the parser
+    name: Arc<str>,
+    // The first segment is the context key and the others reach into
+    // nested MAP and LIST values
+    path: Vec<PathSegment>,
+    missing_as_null: bool,
+    strict: bool,
+    // Value when the context does not have the variable, from the
+    // defaults of the options
+    default: Option<Value>,
+    // Index of the slot holding the value and the count of path segments
+    // the value is at, when the variable is bound to a schema
+    slot: Option<(usize, usize)>,
+}
+
+/**
+ * Part of the path of a variable, ${user.items[0]} is Key("user"),
+ * Key("items") and Index(0)
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    Key(Arc<str>),
+    Index(usize),
+}
+
+impl Var {
+    pub(crate) fn create(
+        name: &str,
+        span: Span,
+        options: &ParserOptions,
+        symbols: &Symbols,
+    ) -> Result<Var, AstError> {
+        let path = match Self::parse_path(name, symbols) {
+            Some(path) => path,
+            None => {
+                return Err(AstError::FORMAT_NOT_MATCH(format!(
+                    "Variable path {} is not valid at {}",
+                    name, span
+                )));
+            }
+        };
+        Ok(Var {
+            name: symbols.intern(name),
+            path: path,
+            missing_as_null: options.missing_as_null,
+            strict: options.strict_vars,
+            default: options.defaults.get(name).cloned(),
+            slot: None,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        return &self.name;
+    }
+
+    // Value the variable evals to when the context does not have it
+    pub fn default_value(&self) -> Option<&Value> {
+        return self.default.as_ref();
+    }
+
+    // Context key the path starts from, user for ${user.items[0]}
+    pub fn key(&self) -> &str {
+        match &self.path[0] {
+            PathSegment::Key(key) => key,
+            PathSegment::Index(_) => "",
+        }
+    }
+
+    // Names of the path when it has no [index], ["user", "country"] for
+    // ${user.country}
+    pub(crate) fn keys(&self) -> Option<Vec<&str>> {
+        let keys = self.path.iter().map(|segment| match segment {
+            PathSegment::Key(key) => Some(key.as_ref()),
+            PathSegment::Index(_) => None,
+        });
+        return keys.collect();
+    }
+
+    // Every dotted part is a name followed by any number of [index]
+    fn parse_path(s: &str, symbols: &Symbols) -> Option<Vec<PathSegment>> {
+        let mut path = Vec::new();
+        for part in s.split('.') {
+            let mut pieces = part.split('[');
+            let name = pieces.next().unwrap_or("");
+            if name.is_empty() || name.contains(']') {
+                return None;
+            }
+            path.push(PathSegment::Key(symbols.intern(name)));
+            for piece in pieces {
+                let index = piece.strip_suffix(']')?;
+                if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+                    return None;
+                }
+                path.push(PathSegment::Index(index.parse::<usize>().ok()?));
+            }
+        }
+        return Some(path);
+    }
+
+    fn lookup(&self, ctx: &dyn VariableResolver) -> Result<Option<Value>, AstError> {
+        match ctx.resolve_path(&self.path) {
+            Some((val, read)) if read < self.path.len() => return self.walk(Some(&val), read),
+            found => return Ok(found.map(|(val, _)| val)),
+        }
+    }
+
+    // Value at the path when val is the value at its first `from` segments
+    fn walk(&self, val: Option<&Value>, from: usize) -> Result<Option<Value>, AstError> {
+        let mut val = val;
+        for segment in &self.path[from..] {
+            val = match (val, segment) {
+                (None, _) => None,
+                (Some(Value::MAP(m)), PathSegment::Key(name)) => m.get(&**name),
+                (Some(Value::LIST(l)), PathSegment::Index(i)) => match l.get(*i) {
+                    Some(v) => Some(v),
+                    None => {
+                        return Err(AstError::INDEX_OUT_OF_BOUNDS(format!(
+                            "Index {} is out of bounds of LIST with {} values in ${{{}}}",
+                            i,
+                            l.len(),
+                            self.name
+                        )));
+                    }
+                },
+                (Some(other), segment) => {
+                    return Err(AstError::TYPE_NOT_MATCH(format!(
+                        "Can not get {:?} of {} in ${{{}}}",
+                        segment,
+                        other.type_name(),
+                        self.name
+                    )));
+                }
+            };
+        }
+        return Ok(val.cloned());
+    }
+
+    pub(crate) fn eval(&self, ctx: Env) -> Result<Value, AstError> {
+        let val = match (self.slot, ctx.slots) {
+            (Some((index, from)), Some(slots)) => self.walk(slots.get(index), from)?,
+            _ => self.lookup(ctx.vars)?,
+        };
+        if let Some(val) = val {
+            return Ok(val);
+        }
+        if let Some(default) = &self.default {
+            return Ok(default.clone());
+        }
+        if self.strict {
+            return Err(AstError::UNDEFINED_VAR(format!(
+                "Variable ${{{}}} is not defined",
+                self.name
+            )));
+        }
+        if self.missing_as_null {
+            return Ok(Value::NULL);
+        }
+        return Ok(Value::BOOL(false));
+    }
+}
+
+#[allow(dead_code)]
+pub struct Parser {
+    lexer: Lexer,
+    look_token: Option<Box<dyn Token>>,
+    options: ParserOptions,
+    // Count of brackets opened before the look token, the look token
+    // itself included
+    depth: i32,
+    // Kinds of the brackets not closed yet, `(`, `[` or `{`
+    brackets: Vec<char>,
+    // When recovering, errors are kept in diagnostics and parsing goes on
+    // after the bracket enclosing the error
+    recover: bool,
+    diagnostics: Vec<Diagnostic>,
+    // Expressions of the names defined by DEFINE so far, with their
+    // height and size
+    definitions: HashMap<String, (Expr, usize, usize)>,
+    // Nodes the uses of the definitions added so far
+    inlined: usize,
+    // Whether the last top-level expression was a DEFINE
+    defined: bool,
+    // Names bound by the enclosing LET and LAMBDA expressions, a bare word
+    // which is one of them reads the binding
+    bound: Vec<String>,
+}
+
+/**
+ * Options of Parser, max_depth limits the nesting of brackets so that
+ * untrusted rule content can not overflow the stack of the recursive parser,
+ * max_args limits the arguments of one operator. The uses of DEFINE names
+ * and RULE references are replaced by copies of their expressions, those
+ * copies count against max_depth where they are put and max_inlined
+ * limits the nodes they add to the rule content.
+ */
+#[derive(Debug, Clone)]
+pub struct ParserOptions {
+    pub max_depth: usize,
+    pub max_args: usize,
+    pub max_inlined: usize,
+    // Applications of LAMBDA values nested in each other when a rule is
+    // evaluated, so a LAMBDA applying itself fails instead of overflowing
+    // the stack
+    pub max_calls: usize,
+    // Missing context variables eval to NULL instead of BOOL(false)
+    pub missing_as_null: bool,
+    // Missing context variables are UNDEFINED_VAR errors, this wins over
+    // missing_as_null
+    pub strict_vars: bool,
+    // Bare words which are not bound by LET or LAMBDA are STR atoms, like
+    // prod or eu-west-1, instead of errors. A misspelled binding is then
+    // an atom as well.
+    pub atoms: bool,
+    // Values of missing context variables by their path as written in
+    // ${...}, like country or user.country, they win over strict_vars and
+    // missing_as_null
+    pub defaults: HashMap<String, Value>,
+    // INT results of ADD, SUB and MUL and integer literals which are out
+    // of the INT range
+    pub overflow: OverflowPolicy,
+    // Functions of the host rules can call
+    pub functions: FunctionRegistry,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions {
+            max_depth: 64,
+            max_args: 10000,
+            max_inlined: 100000,
+            max_calls: 64,
+            missing_as_null: false,
+            strict_vars: false,
+            atoms: false,
+            defaults: HashMap::new(),
+            overflow: OverflowPolicy::Error,
+            functions: FunctionRegistry::new(),
+        }
+    }
+}
+
+impl ParserOptions {
+    fn function(&self, name: &str) -> Result<Function, AstError> {
+        match self.functions.get(name) {
+            Some(function) => return Ok(function.clone()),
+            None => {
+                return Err(AstError::NOT_SUPP_OPER(format!(
+                    "Not registered function {}",
+                    name
+                )));
+            }
+        }
+    }
+}
+
+/**
+ * A syntax error found by Parser::parse_recover or validate, or a type
+ * error found by Rule::check, span is the position of the token or node
+ * where the error was found
+ */
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub error: AstError,
+}
+
+#[allow(dead_code, non_camel_case_types)]
+#[derive(Debug)]
+pub enum AstError {
+    OTHER(String),
+    FORMAT_NOT_MATCH(String),
+    LEXER_FAILED(String),
+    NOT_MATCH(String),
+    NO_TOKEN_MATCH(String),
+    NOT_SUPP_OPER(String),
+    EVAL_NUM_FAILED(String),
+    NOT_ENOUGH_ARGS(String),
+    ARG_NOT_CORRECT(String),
+    ARITY_NOT_MATCH(String),
+    TYPE_NOT_MATCH(String),
+    PATTERN_NOT_VALID(String),
+    INDEX_OUT_OF_BOUNDS(String),
+    MAX_DEPTH_EXCEEDED(String),
+    TOO_MANY_ARGS(String),
+    TOO_MANY_NODES(String),
+    UNDEFINED_VAR(String),
+    UNDEFINED_RULE(String),
+    RULE_CYCLE(String),
+    FUNCTION_FAILED(String),
+    DIV_BY_ZERO(String),
+    ARITH_OVERFLOW(String),
+}
+
+impl fmt::Display for AstError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for AstError {}
+
+impl AstError {
+    /**
+     * Name of the kind of the error, like "TYPE_NOT_MATCH", for tools
+     * which match on it rather than on the message
+     */
+    pub fn code(&self) -> &'static str {
+        return self.parts().0;
+    }
+
+    // Message of the error without the code
+    pub fn message(&self) -> &str {
+        return self.parts().1;
+    }
+
+    fn parts(&self) -> (&'static str, &str) {
+        match self {
+            AstError::OTHER(msg) => ("OTHER", msg),
+            AstError::FORMAT_NOT_MATCH(msg) => ("FORMAT_NOT_MATCH", msg),
+            AstError::LEXER_FAILED(msg) => ("LEXER_FAILED", msg),
+            AstError::NOT_MATCH(msg) => ("NOT_MATCH", msg),
+            AstError::NO_TOKEN_MATCH(msg) => ("NO_TOKEN_MATCH", msg),
+            AstError::NOT_SUPP_OPER(msg) => ("NOT_SUPP_OPER", msg),
+            AstError::EVAL_NUM_FAILED(msg) => ("EVAL_NUM_FAILED", msg),
+            AstError::NOT_ENOUGH_ARGS(msg) => ("NOT_ENOUGH_ARGS", msg),
+            AstError::ARG_NOT_CORRECT(msg) => ("ARG_NOT_CORRECT", msg),
+            AstError::ARITY_NOT_MATCH(msg) => ("ARITY_NOT_MATCH", msg),
+            AstError::TYPE_NOT_MATCH(msg) => ("TYPE_NOT_MATCH", msg),
+            AstError::PATTERN_NOT_VALID(msg) => ("PATTERN_NOT_VALID", msg),
+            AstError::INDEX_OUT_OF_BOUNDS(msg) => ("INDEX_OUT_OF_BOUNDS", msg),
+            AstError::MAX_DEPTH_EXCEEDED(msg) => ("MAX_DEPTH_EXCEEDED", msg),
+            AstError::TOO_MANY_ARGS(msg) => ("TOO_MANY_ARGS", msg),
+            AstError::TOO_MANY_NODES(msg) => ("TOO_MANY_NODES", msg),
+            AstError::UNDEFINED_VAR(msg) => ("UNDEFINED_VAR", msg),
+            AstError::UNDEFINED_RULE(msg) => ("UNDEFINED_RULE", msg),
+            AstError::RULE_CYCLE(msg) => ("RULE_CYCLE", msg),
+            AstError::FUNCTION_FAILED(msg) => ("FUNCTION_FAILED", msg),
+            AstError::DIV_BY_ZERO(msg) => ("DIV_BY_ZERO", msg),
+            AstError::ARITH_OVERFLOW(msg) => ("ARITH_OVERFLOW", msg),
+        }
+    }
+
+    fn message_mut(&mut self) -> &mut String {
+        match self {
+            AstError::OTHER(msg)
+            | AstError::FORMAT_NOT_MATCH(msg)
+            | AstError::LEXER_FAILED(msg)
+            | AstError::NOT_MATCH(msg)
+            | AstError::NO_TOKEN_MATCH(msg)
+            | AstError::NOT_SUPP_OPER(msg)
+            | AstError::EVAL_NUM_FAILED(msg)
+            | AstError::NOT_ENOUGH_ARGS(msg)
+            | AstError::ARG_NOT_CORRECT(msg)
+            | AstError::ARITY_NOT_MATCH(msg)
+            | AstError::TYPE_NOT_MATCH(msg)
+            | AstError::PATTERN_NOT_VALID(msg)
+            | AstError::INDEX_OUT_OF_BOUNDS(msg)
+            | AstError::MAX_DEPTH_EXCEEDED(msg)
+            | AstError::TOO_MANY_ARGS(msg)
+            | AstError::TOO_MANY_NODES(msg)
+            | AstError::UNDEFINED_VAR(msg)
+            | AstError::UNDEFINED_RULE(msg)
+            | AstError::RULE_CYCLE(msg)
+            | AstError::FUNCTION_FAILED(msg)
+            | AstError::DIV_BY_ZERO(msg)
+            | AstError::ARITH_OVERFLOW(msg) => msg,
+        }
+    }
+
+    // Add the position to the message, unless it already has one from a
+    // node below or the span is not from rule content
+    pub(crate) fn at(mut self, span: Span) -> AstError {
+        let msg = self.message_mut();
+        if span.line > 0 && !msg.contains(" at line ") {
+            msg.push_str(&format!(" at {}", span));
+        }
+        return self;
+    }
+}
+
+#[allow(dead_code)]
+impl Parser {
+    pub(crate) fn create(content: String) -> Result<Parser, AstError> {
+        return Self::create_with_options(content, ParserOptions::default());
+    }
+
+    pub(crate) fn create_with_options(
+        content: String,
+        options: ParserOptions,
+    ) -> Result<Parser, AstError> {
+        let lexer = Lexer::create(content);
+        if lexer.is_err() {
+            return Err(AstError::LEXER_FAILED("Lexer init failed!".to_string()));
+        }
+        Ok(Parser {
+            lexer: lexer.unwrap(),
+            look_token: None,
+            options: options,
+            depth: 0,
+            brackets: Vec::new(),
+            recover: false,
+            diagnostics: Vec::new(),
+            definitions: HashMap::new(),
+            inlined: 0,
+            defined: false,
+            bound: Vec::new(),
+        })
+    }
+
+    pub(crate) fn set_missing_as_null(&mut self, missing_as_null: bool) {
+        self.options.missing_as_null = missing_as_null;
+    }
+
+    pub(crate) fn parse(&mut self) -> Result<Expr, AstError> {
+        if !self.move_token()? {
+            return Err(AstError::OTHER(
+                "Has already analyzed this rule content to expr".to_string(),
+            ));
+        }
+        let expr = self.expr()?;
+        self.match_term(TokenTag::RIGHT_BRACKET)?;
+        return Ok(expr);
+    }
+
+    // Parse every top-level expression of the rule content, they can be
+    // separated by blanks, newlines and comments
+    pub(crate) fn parse_all(&mut self) -> Result<Vec<Expr>, AstError> {
+        let mut exprs: Vec<Expr> = Vec::new();
+        while self.move_token()? {
+            let expr = self.expr()?;
+            self.check_term(TokenTag::RIGHT_BRACKET)?;
+            if !std::mem::take(&mut self.defined) {
+                exprs.push(expr);
+            }
+        }
+        return Ok(exprs);
+    }
+
+    // Parse every top-level expression like parse_all, but go on after a
+    // syntax error, every error is returned as a diagnostic
+    pub(crate) fn parse_recover(&mut self) -> (Vec<Expr>, Vec<Diagnostic>) {
+        let mut exprs: Vec<Expr> = Vec::new();
+        self.recover = true;
+        loop {
+            self.depth = 0;
+            self.brackets.clear();
+            match self.move_token() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    self.report(e);
+                    continue;
+                }
+            }
+            let before = self.diagnostics.len();
+            let result = match self.expr() {
+                Ok(expr) => self.check_term(TokenTag::RIGHT_BRACKET).map(|_| expr),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) if std::mem::take(&mut self.defined) => {}
+                Ok(expr) => exprs.push(expr),
+                Err(e) => {
+                    if self.diagnostics.len() == before {
+                        self.report(e);
+                    }
+                    if !self.synchronize(0) {
+                        break;
+                    }
+                }
+            }
+        }
+        self.recover = false;
+        return (exprs, std::mem::take(&mut self.diagnostics));
+    }
+
+    // Lexer errors are at the lexer position, the others are at the look
+    // token
+    fn report(&mut self, error: AstError) {
+        let span = match error {
+            AstError::LEXER_FAILED(_) => self.lexer.position(),
+            _ => self.current_span(),
+        };
+        self.diagnostics.push(Diagnostic {
+            span: span,
+            error: error,
+        });
+    }
+
+    // Skip tokens until the brackets opened after depth are all closed,
+    // return false when the end is reached first
+    fn synchronize(&mut self, depth: i32) -> bool {
+        while self.depth > depth {
+            match self.move_token() {
+                Ok(true) => {}
+                Ok(false) => return false,
+                Err(e) => self.report(e),
+            }
+        }
+        return true;
+    }
+
+    // Operators span from the opening to the closing bracket, leaves are
+    // a single token
+    fn expr(&mut self) -> Result<Expr, AstError> {
+        let start = self.current_span();
+        let kind = self.expr_kind()?;
+        return Ok(Expr::new(kind, start.to(self.current_span())));
+    }
+
+    fn expr_kind(&mut self) -> Result<ExprKind, AstError> {
+        match self.look_token.as_ref() {
+            Some(token) => match *token.token_tag() {
+                TokenTag::LEFT_BRACKET => {
+                    self.move_token()?;
+                    match self.look_token.as_ref().unwrap().token_tag() {
+                        TokenTag::AND => {
+                            return self.args_add(TokenTag::AND, "AND");
+                        }
+                        TokenTag::OR => {
+                            return self.args_add(TokenTag::OR, "OR");
+                        }
+                        TokenTag::MOD => {
+                            return self.args_add(TokenTag::MOD, "MOD");
+                        }
+                        TokenTag::ADD => {
+                            return self.args_add(TokenTag::ADD, "ADD");
+                        }
+                        TokenTag::SUB => {
+                            return self.args_add(TokenTag::SUB, "SUB");
+                        }
+                        TokenTag::MUL => {
+                            return self.args_add(TokenTag::MUL, "MUL");
+                        }
+                        TokenTag::EQUALS => {
+                            return self.args_add(TokenTag::EQUALS, "EQUALS");
+                        }
+                        TokenTag::EQUALS_CI => {
+                            return self.args_add(TokenTag::EQUALS_CI, "EQUALS-CI");
+                        }
+                        TokenTag::NOT => {
+                            return self.args_add(TokenTag::NOT, "NOT");
+                        }
+                        TokenTag::LET => {
+                            return self.let_expr();
+                        }
+                        TokenTag::LAMBDA => {
+                            return self.lambda_expr();
+                        }
+                        TokenTag::APPLY => {
+                            return self.args_add(TokenTag::APPLY, "APPLY");
+                        }
+                        TokenTag::ANY => {
+                            return self.each_expr(TokenTag::ANY, "ANY");
+                        }
+                        TokenTag::ALL => {
+                            return self.each_expr(TokenTag::ALL, "ALL");
+                        }
+                        TokenTag::FILTER => {
+                            return self.each_expr(TokenTag::FILTER, "FILTER");
+                        }
+                        TokenTag::SUM => {
+                            return self.args_add(TokenTag::SUM, "SUM");
+                        }
+                        TokenTag::AVG => {
+                            return self.args_add(TokenTag::AVG, "AVG");
+                        }
+                        TokenTag::COUNT => {
+                            return self.args_add(TokenTag::COUNT, "COUNT");
+                        }
+                        TokenTag::MIN => {
+                            return self.args_add(TokenTag::MIN, "MIN");
+                        }
+                        TokenTag::MAX => {
+                            return self.args_add(TokenTag::MAX, "MAX");
+                        }
+                        TokenTag::COND => {
+                            return self.cond_expr();
+                        }
+                        TokenTag::MATCH => {
+                            return self.match_expr(TokenTag::MATCH);
+                        }
+                        TokenTag::LIKE => {
+                            return self.match_expr(TokenTag::LIKE);
+                        }
+                        TokenTag::RULE => {
+                            return self.rule_expr();
+                        }
+                        TokenTag::CALL => {
+                            self.move_token()?;
+                            return self.call_expr();
+                        }
+                        TokenTag::DEFINE => {
+                            return self.define_expr();
+                        }
+                        TokenTag::IDENT
+                            if self.definitions.contains_key(
+                                self.look_token.as_ref().unwrap().lexeme().as_str(),
+                            ) =>
+                        {
+                            return self.defined_expr();
+                        }
+                        TokenTag::IDENT
+                            if self
+                                .options
+                                .functions
+                                .get(self.look_token.as_ref().unwrap().lexeme())
+                                .is_some() =>
+                        {
+                            return self.call_expr();
+                        }
+                        TokenTag::UPPER => {
+                            return self.args_add(TokenTag::UPPER, "UPPER");
+                        }
+                        TokenTag::CONCAT => {
+                            return self.args_add(TokenTag::CONCAT, "CONCAT");
+                        }
+                        TokenTag::LEN => {
+                            return self.args_add(TokenTag::LEN, "LEN");
+                        }
+                        TokenTag::HASH => {
+                            return self.args_add(TokenTag::HASH, "HASH");
+                        }
+                        TokenTag::ROLLOUT => {
+                            return self.args_add(TokenTag::ROLLOUT, "ROLLOUT");
+                        }
+                        TokenTag::SPLIT => {
+                            return self.args_add(TokenTag::SPLIT, "SPLIT");
+                        }
+                        TokenTag::GET => {
+                            return self.args_add(TokenTag::GET, "GET");
+                        }
+                        TokenTag::COALESCE => {
+                            return self.args_add(TokenTag::COALESCE, "COALESCE");
+                        }
+                        TokenTag::NOW => {
+                            return self.args_add(TokenTag::NOW, "NOW");
+                        }
+                        TokenTag::SUBSTR => {
+                            return self.args_add(TokenTag::SUBSTR, "SUBSTR");
+                        }
+                        TokenTag::INDEX_OF => {
+                            return self.args_add(TokenTag::INDEX_OF, "INDEX-OF");
+                        }
+                        TokenTag::LOWER => {
+                            return self.args_add(TokenTag::LOWER, "LOWER");
+                        }
+                        TokenTag::TRIM => {
+                            return self.args_add(TokenTag::TRIM, "TRIM");
+                        }
+                        TokenTag::BETWEEN => {
+                            return self.args_add(TokenTag::BETWEEN, "BETWEEN");
+                        }
+                        TokenTag::CONTAINS => {
+                            return self.args_add(TokenTag::CONTAINS, "CONTAINS");
+                        }
+                        TokenTag::STARTS_WITH => {
+                            return self.args_add(TokenTag::STARTS_WITH, "STARTS-WITH");
+                        }
+                        TokenTag::ENDS_WITH => {
+                            return self.args_add(TokenTag::ENDS_WITH, "ENDS-WITH");
+                        }
+                        TokenTag::XOR => {
+                            return self.args_add(TokenTag::XOR, "XOR");
+                        }
+                        TokenTag::NAND => {
+                            return self.args_add(TokenTag::NAND, "NAND");
+                        }
+                        TokenTag::GT => {
+                            return self.args_add(TokenTag::GT, "GT");
+                        }
+                        TokenTag::GTE => {
+                            return self.args_add(TokenTag::GTE, "GTE");
+                        }
+                        TokenTag::LT => {
+                            return self.args_add(TokenTag::LT, "LT");
+                        }
+                        TokenTag::LTE => {
+                            return self.args_add(TokenTag::LTE, "LTE");
+                        }
+                        TokenTag::IN => {
+                            return self.args_add(TokenTag::IN, "IN");
+                        }
+                        _ => {
+                            let token = self.look_token.as_ref().unwrap();
+                            return Err(AstError::NOT_SUPP_OPER(format!(
+                                "Not supported operator {} at {}",
+                                token.lexeme(),
+                                token.span()
+                            )));
+                        }
+                    }
+                }
+                TokenTag::NUM => match int_literal(token.lexeme(), self.options.overflow) {
+                    Some(i) => return Ok(ExprKind::Int(i)),
+                    None => {
+                        return Err(AstError::ARITH_OVERFLOW(format!(
+                            "Integer literal {} is out of the INT range at {}",
+                            token.lexeme(),
+                            token.span()
+                        )));
+                    }
+                },
+                TokenTag::FLOAT => match token.lexeme().parse::<f64>() {
+                    Ok(f) => return Ok(ExprKind::Float(f)),
+                    Err(_) => {
+                        return Err(AstError::EVAL_NUM_FAILED(format!(
+                            "{} is not a float number at {}",
+                            token.lexeme(),
+                            token.span()
+                        )));
+                    }
+                },
+                TokenTag::STR => {
+                    return Ok(ExprKind::Str(token.lexeme().to_string()));
+                }
+                // A bare word reads the LET or LAMBDA binding of its name,
+                // or a path into it like item.amount, other words are STR
+                // atoms when the options allow them
+                TokenTag::IDENT => {
+                    let name = token.lexeme().as_str();
+                    let head = name.split(['.', '[']).next().unwrap_or(name);
+                    if self.bound.iter().any(|bound| bound == head) {
+                        return Ok(ExprKind::Var(Var::create(
+                            name,
+                            token.span(),
+                            &self.options,
+                            self.lexer.symbols(),
+                        )?));
+                    }
+                    if self.options.atoms {
+                        return Ok(ExprKind::Str(name.to_string()));
+                    }
+                    return Err(AstError::UNDEFINED_VAR(format!(
+                        "{} is not bound by LET or LAMBDA at {}, quote it as \"{}\" for a STR",
+                        name,
+                        token.span(),
+                        name
+                    )));
+                }
+                TokenTag::VAR => {
+                    return Ok(ExprKind::Var(Var::create(
+                        token.lexeme(),
+                        token.span(),
+                        &self.options,
+                        self.lexer.symbols(),
+                    )?));
+                }
+                TokenTag::BYTES => {
+                    return Expr::create_bytes(token.lexeme());
+                }
+                TokenTag::TIMESTAMP => {
+                    let millis = time::parse_iso8601(token.lexeme()).map_err(|e| {
+                        AstError::FORMAT_NOT_MATCH(format!(
+                            "Not a valid timestamp literal {}: {}",
+                            token.lexeme(),
+                            e
+                        ))
+                    })?;
+                    return Ok(ExprKind::Timestamp(millis));
+                }
+                TokenTag::NULL => {
+                    return Ok(ExprKind::Null);
+                }
+                TokenTag::BOOL => {
+                    let lexeme = token.lexeme().to_lowercase();
+                    return Ok(ExprKind::Bool(lexeme == "true" || lexeme == "1"));
+                }
+                _ => {
+                    return Err(AstError::OTHER(format!(
+                        "Not find available token tag to process {} at {}",
+                        token.lexeme(),
+                        token.span()
+                    )));
+                }
+            },
+            None => {
+                return Err(AstError::OTHER("Current token is none!".to_string()));
+            }
+        }
+    }
+
+    fn args_add(&mut self, tag: TokenTag, s: &'static str) -> Result<ExprKind, AstError> {
+        let span = self.current_span();
+        let args = self.args(s)?;
+        return Expr::create(tag, s, args, self.options.overflow).map_err(|e| e.at(span));
+    }
+
+    // (ANY list predicate), look token is the operator when called. The
+    // predicate sees the element it is evaluated for as item.
+    fn each_expr(&mut self, tag: TokenTag, s: &'static str) -> Result<ExprKind, AstError> {
+        let span = self.current_span();
+        let depth = self.bound.len();
+        let args = self.args_binding(s, Some(ITEM));
+        self.bound.truncate(depth);
+        return Expr::create(tag, s, args?, self.options.overflow).map_err(|e| e.at(span));
+    }
+
+    // Args up to the right bracket of the operator, look token is the
+    // operator when called
+    fn args(&mut self, s: &str) -> Result<Vec<Expr>, AstError> {
+        return self.args_binding(s, None);
+    }
+
+    // Same as args, the args after the first one see the binding as a
+    // bound name
+    fn args_binding(&mut self, s: &str, mut binding: Option<&str>) -> Result<Vec<Expr>, AstError> {
+        let mut args: Vec<Expr> = Vec::new();
+        let span = self.current_span();
+        let depth = self.depth;
+        let mut failed = false;
+        loop {
+            if !self.move_token()? {
+                return Err(AstError::FORMAT_NOT_MATCH(format!(
+                    "no right bracket for {} operator at {} but has already went to the end",
+                    s, span
+                )));
+            }
+            if self.look_token.is_some()
+                && *self.look_token.as_ref().unwrap().token_tag() == TokenTag::RIGHT_BRACKET
+            {
+                if failed {
+                    return Err(AstError::FORMAT_NOT_MATCH(format!(
+                        "{} operator at {} has errors in arguments",
+                        s, span
+                    )));
+                }
+                return Ok(args);
+            }
+            if args.len() >= self.options.max_args {
+                return Err(AstError::TOO_MANY_ARGS(format!(
+                    "{} operator at {} has more than {} arguments",
+                    s, span, self.options.max_args
+                )));
+            }
+            let before = self.diagnostics.len();
+            match self.expr() {
+                Ok(arg) => args.push(arg),
+                Err(e) if self.recover => {
+                    if self.diagnostics.len() == before {
+                        self.report(e);
+                    }
+                    failed = true;
+                    if !self.synchronize(depth) || self.depth < depth {
+                        return Err(AstError::FORMAT_NOT_MATCH(format!(
+                            "{} operator at {} has errors in arguments",
+                            s, span
+                        )));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            if let Some(name) = binding.take() {
+                self.bound.push(name.to_string());
+            }
+        }
+    }
+
+    // (LET ((name expr) ...) body), look token is LET when called. Every
+    // binding is seen by the later bindings and the body.
+    fn let_expr(&mut self) -> Result<ExprKind, AstError> {
+        let depth = self.bound.len();
+        let result = self.let_bindings();
+        self.bound.truncate(depth);
+        return result;
+    }
+
+    fn let_bindings(&mut self) -> Result<ExprKind, AstError> {
+        let mut bindings: Vec<(String, Expr)> = Vec::new();
+        self.move_token()?;
+        self.check_term(TokenTag::LEFT_BRACKET)?;
+        loop {
+            self.move_token()?;
+            if self.check_term(TokenTag::RIGHT_BRACKET).is_ok() {
+                break;
+            }
+            self.check_term(TokenTag::LEFT_BRACKET)?;
+            self.move_token()?;
+            self.check_term(TokenTag::IDENT)?;
+            let name = self.look_token.as_ref().unwrap().lexeme().to_string();
+            self.move_token()?;
+            let expr = self.expr()?;
+            self.move_token()?;
+            self.check_term(TokenTag::RIGHT_BRACKET)?;
+            self.bound.push(name.clone());
+            bindings.push((name, expr));
+        }
+        self.move_token()?;
+        let body = self.expr()?;
+        self.move_token()?;
+        self.check_term(TokenTag::RIGHT_BRACKET)?;
+        return Ok(ExprKind::Let(bindings, Box::new(body)));
+    }
+
+    // (LAMBDA (name ...) body), look token is LAMBDA when called
+    fn lambda_expr(&mut self) -> Result<ExprKind, AstError> {
+        let depth = self.bound.len();
+        let result = self.lambda_params();
+        self.bound.truncate(depth);
+        return result;
+    }
+
+    fn lambda_params(&mut self) -> Result<ExprKind, AstError> {
+        let mut params: Vec<String> = Vec::new();
+        self.move_token()?;
+        self.check_term(TokenTag::LEFT_BRACKET)?;
+        loop {
+            self.move_token()?;
+            if self.check_term(TokenTag::RIGHT_BRACKET).is_ok() {
+                break;
+            }
+            self.check_term(TokenTag::IDENT)?;
+            let token = self.look_token.as_ref().unwrap();
+            if params.iter().any(|name| *token.lexeme() == name.as_str()) {
+                return Err(AstError::FORMAT_NOT_MATCH(format!(
+                    "Param {} of LAMBDA is given twice at {}",
+                    token.lexeme(),
+                    token.span()
+                )));
+            }
+            params.push(token.lexeme().to_string());
+        }
+        self.bound.extend(params.iter().cloned());
+        self.move_token()?;
+        let body = self.expr()?;
+        self.move_token()?;
+        self.check_term(TokenTag::RIGHT_BRACKET)?;
+        return Ok(ExprKind::Lambda(
+            params,
+            Box::new(body),
+            self.options.max_calls,
+        ));
+    }
+
+    // (COND (test result) ... (ELSE default)), look token is COND when called
+    fn cond_expr(&mut self) -> Result<ExprKind, AstError> {
+        let mut branches: Vec<(Expr, Expr)> = Vec::new();
+        let mut default: Option<Expr> = None;
+        loop {
+            self.move_token()?;
+            if self.check_term(TokenTag::RIGHT_BRACKET).is_ok() {
+                break;
+            }
+            if default.is_some() {
+                return Err(AstError::NOT_MATCH(format!(
+                    "ELSE should be the last branch of cond at {}",
+                    self.current_span()
+                )));
+            }
+            self.check_term(TokenTag::LEFT_BRACKET)?;
+            self.move_token()?;
+            if self.check_term(TokenTag::ELSE).is_ok() {
+                self.move_token()?;
+                default = Some(self.expr()?);
+            } else {
+                let test = self.expr()?;
+                self.move_token()?;
+                branches.push((test, self.expr()?));
+            }
+            self.move_token()?;
+            self.check_term(TokenTag::RIGHT_BRACKET)?;
+        }
+        return Ok(ExprKind::Cond(branches, default.map(Box::new)));
+    }
+
+    // (MATCH expr "pattern") or (LIKE expr "pattern"), pattern must be a
+    // string literal so that it can be compiled at parse time, look token
+    // is the operator when called
+    fn match_expr(&mut self, tag: TokenTag) -> Result<ExprKind, AstError> {
+        self.move_token()?;
+        let arg = self.expr()?;
+        self.move_token()?;
+        self.check_term(TokenTag::STR)?;
+        let pattern = self.look_token.as_ref().unwrap().lexeme().clone();
+        self.move_token()?;
+        self.check_term(TokenTag::RIGHT_BRACKET)?;
+        return Expr::create_match(tag, arg, &pattern);
+    }
+
+    // (RULE "name") is resolved by the rule set the rule is compiled in
+    fn rule_expr(&mut self) -> Result<ExprKind, AstError> {
+        self.move_token()?;
+        self.check_term(TokenTag::STR)?;
+        let name = self.look_token.as_ref().unwrap().lexeme().to_string();
+        self.move_token()?;
+        self.check_term(TokenTag::RIGHT_BRACKET)?;
+        return Ok(ExprKind::Rule(name, None));
+    }
+
+    // (CALL name args...) or (name args...), look token is the name, an
+    // identifier or a string, when called
+    fn call_expr(&mut self) -> Result<ExprKind, AstError> {
+        let token = match self.look_token.as_ref() {
+            Some(token) if matches!(token.token_tag(), TokenTag::IDENT | TokenTag::STR) => token,
+            _ => {
+                return Err(AstError::NOT_MATCH(format!(
+                    "Expected a function name after CALL at {}",
+                    self.current_span()
+                )));
+            }
+        };
+        let name = token.lexeme().to_string();
+        let span = token.span();
+        let function = self.options.function(&name).map_err(|e| e.at(span))?;
+        let args = self.args(&name)?;
+        function.check_call(&args).map_err(|e| e.at(span))?;
+        return Ok(ExprKind::Call(function, args));
+    }
+
+    // (DEFINE name expr), look token is DEFINE when called. Only the top
+    // level of the rule content defines names, the DEFINE itself is parsed
+    // as NULL and left out of the top-level expressions.
+    fn define_expr(&mut self) -> Result<ExprKind, AstError> {
+        let span = self.current_span();
+        if self.depth != 1 {
+            return Err(AstError::FORMAT_NOT_MATCH(format!(
+                "DEFINE is only allowed at the top level of the rule content at {}",
+                span
+            )));
+        }
+        self.move_token()?;
+        self.check_term(TokenTag::IDENT)?;
+        let token = self.look_token.as_ref().unwrap();
+        let name = token.lexeme().to_string();
+        if self.definitions.contains_key(&name) || self.options.functions.get(&name).is_some() {
+            return Err(AstError::FORMAT_NOT_MATCH(format!(
+                "{} is already defined at {}",
+                name,
+                token.span()
+            )));
+        }
+        self.move_token()?;
+        let expr = self.expr()?;
+        self.move_token()?;
+        self.check_term(TokenTag::RIGHT_BRACKET)?;
+        let (height, size) = (expr.height(), expr.size());
+        self.definitions.insert(name, (expr, height, size));
+        self.defined = true;
+        return Ok(ExprKind::Null);
+    }
+
+    // (name) of a name defined before, look token is the name when called.
+    // The use is replaced by the expression of the definition, which sees
+    // the names bound by a LET around the use like any expression there.
+    // The copy is nested as deep as the use and its nodes count against
+    // max_inlined, so definitions using each other can not blow up.
+    fn defined_expr(&mut self) -> Result<ExprKind, AstError> {
+        let token = self.look_token.as_ref().unwrap();
+        let (name, span) = (token.lexeme().to_string(), token.span());
+        self.move_token()?;
+        if self.check_term(TokenTag::RIGHT_BRACKET).is_err() {
+            return Err(AstError::ARITY_NOT_MATCH(format!(
+                "{} is defined without arguments at {}",
+                name, span
+            )));
+        }
+        let (expr, height, size) = &self.definitions[&name];
+        if self.depth as usize - 1 + height > self.options.max_depth {
+            return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                "{} nests brackets deeper than {} at {}",
+                name, self.options.max_depth, span
+            )));
+        }
+        self.inlined += size;
+        if self.inlined > self.options.max_inlined {
+            return Err(AstError::TOO_MANY_NODES(format!(
+                "Uses of definitions add more than {} nodes at {}",
+                self.options.max_inlined, span
+            )));
+        }
+        return Ok(expr.kind.clone());
+    }
+
+    // Span of current token
+    fn current_span(&self) -> Span {
+        match self.look_token.as_ref() {
+            Some(token) => token.span(),
+            None => Span::default(),
+        }
+    }
+
+    // Same as match_term, but stay at current token
+    fn check_term(&self, tag: TokenTag) -> Result<(), AstError> {
+        match self.look_token.as_ref() {
+            Some(s) => {
+                if *s.token_tag() == tag {
+                    return Ok(());
+                } else {
+                    return Err(AstError::NOT_MATCH(format!(
+                        "Expected {:?} but current is {} at {}",
+                        tag,
+                        s.lexeme(),
+                        s.span()
+                    )));
+                }
+            }
+            None => {
+                return Err(AstError::NO_TOKEN_MATCH(
+                    "There is not token is current parser status".to_string(),
+                ));
+            }
+        }
+    }
+
+    fn move_token(&mut self) -> Result<bool, AstError> {
+        // Iterator::scan takes the lexer by value, call the inherent one
+        let scan_result = Lexer::scan(&mut self.lexer);
+        match scan_result {
+            Ok(r) => {
+                let opened = *r.token_tag() == TokenTag::LEFT_BRACKET;
+                let mut mismatched = None;
+                match r.token_tag() {
+                    TokenTag::LEFT_BRACKET => {
+                        self.depth += 1;
+                        self.brackets.push(r.lexeme().chars().next().unwrap_or('('));
+                    }
+                    TokenTag::RIGHT_BRACKET => {
+                        self.depth -= 1;
+                        let close = r.lexeme().chars().next().unwrap_or(')');
+                        match self.brackets.pop() {
+                            Some(open) if Self::closing_of(open) != close => {
+                                mismatched = Some(Self::closing_of(open))
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                }
+                self.look_token.replace(r);
+                if let Some(expected) = mismatched {
+                    let s = self.look_token.as_ref().unwrap();
+                    return Err(AstError::NOT_MATCH(format!(
+                        "Expected {} but current is {} at {}",
+                        expected,
+                        s.lexeme(),
+                        s.span()
+                    )));
+                }
+                // Only the bracket crossing the limit fails, recovering
+                // skips the deeper ones without recursion
+                if opened && self.depth == self.options.max_depth as i32 + 1 {
+                    return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                        "Brackets are nested deeper than {} at {}",
+                        self.options.max_depth,
+                        self.current_span()
+                    )));
+                }
+                return Ok(true);
+            }
+            Err(e) => {
+                match e {
+                    ErrCode::READ_TO_END(_) => {
+                        // Have reached to the end, move will do nothing
+                        return Ok(false);
+                    }
+                    ErrCode::OTHER(msg) => {
+                        return Err(AstError::LEXER_FAILED(format!(
+                            "Lexer move token failed at {}: {}",
+                            self.lexer.position(),
+                            msg
+                        )));
+                    }
+                }
+            }
+        }
+    }
+
+    fn closing_of(open: char) -> char {
+        match open {
+            '[' => ']',
+            '{' => '}',
+            _ => ')',
+        }
+    }
+
+    fn match_term(&mut self, tag: TokenTag) -> Result<(), AstError> {
+        match self.look_token.as_ref() {
+            Some(s) => {
+                if *s.token_tag() == tag {
+                    self.move_token()?;
+                    return Ok(());
+                } else {
+                    return Err(AstError::NOT_MATCH(format!(
+                        "Expected {:?} but current is {} at {}",
+                        tag,
+                        s.lexeme(),
+                        s.span()
+                    )));
+                }
+            }
+            None => {
+                return Err(AstError::NO_TOKEN_MATCH(
+                    "There is not token is current parser status".to_string(),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AstError, ExprKind, Parser, PathSegment, Value, VariableResolver};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    // #[test]
+    #[allow(dead_code)]
+    fn test_simple_in() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("id".to_string(), Value::INT(1));
+        let parser = Parser::create("(IN ${id} 2 3)".to_string());
+        if let Ok(mut p) = parser {
+            match p.parse() {
+                Ok(o) => {
+                    println!("execute result is: {:?}", o.eval(&kv));
+                }
+                Err(e) => {
+                    println!("execute error: {:?}", e);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_simple_and() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("id".to_string(), Value::INT(1));
+        let parser = Parser::create("(AND (IN ${id} 1) (AND (IN 1 1) 1) (IN 2 2))".to_string());
+        if let Ok(mut p) = parser {
+            match p.parse() {
+                Ok(o) => {
+                    println!("execute result is: {:?}", o.eval(&kv));
+                }
+                Err(e) => {
+                    println!("execute error: {:?}", e);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_not() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("id".to_string(), Value::INT(1));
+        let ctx = kv;
+        let mut p = Parser::create("(NOT (IN ${id} 2 3))".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        let mut p = Parser::create("(NOT ${id})".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(false));
+        let mut p = Parser::create("(NOT 1 2)".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::ARITY_NOT_MATCH(_))));
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), Value::INT(20));
+        kv.insert("score".to_string(), Value::INT(100));
+        kv.insert("name".to_string(), Value::STR("a".to_string()));
+        let ctx = kv;
+        let mut p = Parser::create("(AND (GT ${age} 18) (LT ${score} 100))".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(false));
+        let mut p = Parser::create("(AND (GTE ${age} 20) (LTE ${score} 100))".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        let mut p = Parser::create("(GT ${name} 1)".to_string()).unwrap();
+        assert!(matches!(
+            p.parse().unwrap().eval(&ctx),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+    }
+
+    #[test]
+    fn test_let() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("id".to_string(), Value::INT(7));
+        kv.insert("x".to_string(), Value::INT(100));
+        let ctx = kv;
+        let mut p = Parser::create(
+            "(LET ((x (MOD ${id} 5)) (y (GT ${x} 1))) (AND ${y} (IN ${x} 2 3)))".to_string(),
+        )
+        .unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        let mut p = Parser::create("(LET ((1 2)) 3)".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::NOT_MATCH(_))));
+    }
+
+    #[test]
+    fn test_xor_nand() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("a".to_string(), Value::INT(1));
+        kv.insert("b".to_string(), Value::BOOL(false));
+        let ctx = kv;
+        let cases = vec![
+            ("(XOR ${a} ${b})", true),
+            ("(XOR ${a} ${a})", false),
+            ("(XOR ${a} ${a} ${a})", true),
+            ("(NAND ${a} ${b})", true),
+            ("(NAND ${a} 1)", false),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(
+                p.parse().unwrap().eval(&ctx).unwrap(),
+                Value::BOOL(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_between() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("price".to_string(), Value::INT(100));
+        let ctx = kv;
+        let cases = vec![
+            ("(BETWEEN ${price} 100 200)", true),
+            ("(BETWEEN ${price} 0 100)", true),
+            ("(BETWEEN ${price} 101 200)", false),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(
+                p.parse().unwrap().eval(&ctx).unwrap(),
+                Value::BOOL(expected)
+            );
+        }
+        let mut p = Parser::create("(BETWEEN ${price} 1)".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::ARITY_NOT_MATCH(_))));
+    }
+
+    #[test]
+    fn test_cond() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("score".to_string(), Value::INT(75));
+        let ctx = kv;
+        let mut p = Parser::create(
+            "(COND ((GT ${score} 90) \"A\") ((GT ${score} 70) \"B\") (ELSE \"C\"))".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&ctx).unwrap(),
+            Value::STR("B".to_string())
+        );
+        let mut p = Parser::create("(COND ((GT ${score} 90) 1))".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(false));
+        let mut p = Parser::create("(COND (ELSE 1) ((GT ${score} 90) 2))".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::NOT_MATCH(_))));
+    }
+
+    #[test]
+    fn test_str_predicate() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert(
+            "url".to_string(),
+            Value::STR("/admin/users.json".to_string()),
+        );
+        kv.insert("id".to_string(), Value::INT(1));
+        let ctx = kv;
+        let cases = vec![
+            ("(CONTAINS ${url} \"/users\")", true),
+            ("(CONTAINS ${url} \"/login\")", false),
+            ("(STARTS-WITH ${url} \"/admin\")", true),
+            ("(ENDS-WITH ${url} \".json\")", true),
+            ("(ENDS-WITH ${url} \".xml\")", false),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(
+                p.parse().unwrap().eval(&ctx).unwrap(),
+                Value::BOOL(expected)
+            );
+        }
+        let mut p = Parser::create("(CONTAINS ${id} \"1\")".to_string()).unwrap();
+        assert!(matches!(
+            p.parse().unwrap().eval(&ctx),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+    }
+
+    #[test]
+    fn test_match() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("code".to_string(), Value::STR("abc123".to_string()));
+        let ctx = kv;
+        let mut p = Parser::create("(MATCH ${code} \"^[a-z]+\\d{3}$\")".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        let mut p = Parser::create("(MATCH ${code} \"^[a-z]+$\")".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(false));
+        let mut p = Parser::create("(MATCH ${code} \"(abc\")".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::PATTERN_NOT_VALID(_))));
+    }
+
+    #[test]
+    fn test_str_transform() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert(
+            "email_domain".to_string(),
+            Value::STR(" GMail.com ".to_string()),
+        );
+        kv.insert("id".to_string(), Value::INT(1));
+        let ctx = kv;
+        let mut p =
+            Parser::create("(EQUALS (LOWER (TRIM ${email_domain})) \"gmail.com\")".to_string())
+                .unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        let mut p = Parser::create("(UPPER (TRIM ${email_domain}))".to_string()).unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&ctx).unwrap(),
+            Value::STR("GMAIL.COM".to_string())
+        );
+        let mut p = Parser::create("(UPPER ${id})".to_string()).unwrap();
+        assert!(matches!(
+            p.parse().unwrap().eval(&ctx),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+    }
+
+    #[test]
+    fn test_concat() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("region".to_string(), Value::STR("eu".to_string()));
+        kv.insert("tier".to_string(), Value::STR("gold".to_string()));
+        let ctx = kv;
+        let mut p = Parser::create(
+            "(IN (CONCAT ${region} \"-\" ${tier}) \"eu-gold\" \"us-gold\")".to_string(),
+        )
+        .unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        let mut p = Parser::create("(CONCAT ${region} 1 (GT 2 1))".to_string()).unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&ctx).unwrap(),
+            Value::STR("eu1true".to_string())
+        );
+    }
+
+    #[test]
+    fn test_len() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("name".to_string(), Value::STR("héllo".to_string()));
+        kv.insert("id".to_string(), Value::INT(1));
+        let ctx = kv;
+        let mut p = Parser::create("(LEN ${name})".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::INT(5));
+        let mut p = Parser::create("(LEN ${id})".to_string()).unwrap();
+        assert!(matches!(
+            p.parse().unwrap().eval(&ctx),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+    }
+
+    #[test]
+    fn test_substr_index_of() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("s".to_string(), Value::STR("héllo wörld".to_string()));
+        let ctx = kv;
+        let cases = vec![
+            ("(SUBSTR ${s} 0 5)", Value::STR("héllo".to_string())),
+            ("(SUBSTR ${s} 6 100)", Value::STR("wörld".to_string())),
+            ("(SUBSTR ${s} 50 2)", Value::STR("".to_string())),
+            ("(INDEX-OF ${s} \"wörld\")", Value::INT(6)),
+            ("(INDEX-OF ${s} \"xyz\")", Value::INT(-1)),
+            ("(IN (INDEX-OF ${s} \"l\") 2)", Value::BOOL(true)),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("tags".to_string(), Value::STR("vip,beta,eu".to_string()));
+        let ctx = kv;
+        let mut p = Parser::create("(SPLIT ${tags} \",\")".to_string()).unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&ctx).unwrap(),
+            Value::LIST(vec![
+                Value::STR("vip".to_string()),
+                Value::STR("beta".to_string()),
+                Value::STR("eu".to_string()),
+            ])
+        );
+        let cases = vec![
+            ("(IN \"beta\" (SPLIT ${tags} \",\"))", Value::BOOL(true)),
+            ("(IN \"us\" (SPLIT ${tags} \",\"))", Value::BOOL(false)),
+            ("(LEN (SPLIT ${tags} \",\"))", Value::INT(3)),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_like() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("path".to_string(), Value::STR("/api/v2/health".to_string()));
+        let ctx = kv;
+        let mut p = Parser::create("(LIKE ${path} \"/api/%/health\")".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        let mut p = Parser::create("(LIKE ${path} \"/api/v_/status\")".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(false));
+    }
+
+    #[test]
+    fn test_equals_ci() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("host".to_string(), Value::STR("Example.COM".to_string()));
+        let ctx = kv;
+        let cases = vec![
+            ("(EQUALS-CI ${host} \"example.com\")", true),
+            ("(EQUALS ${host} \"example.com\")", false),
+            ("(EQUALS-CI ${host} \"example.org\")", false),
+            ("(EQUALS-CI 1 1)", true),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(
+                p.parse().unwrap().eval(&ctx).unwrap(),
+                Value::BOOL(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_float() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("price".to_string(), Value::FLOAT(19.99));
+        kv.insert("qty".to_string(), Value::INT(3));
+        let ctx = kv;
+        let cases = vec![
+            ("(GT ${price} 19)", Value::BOOL(true)),
+            ("(LT ${price} 19.5)", Value::BOOL(false)),
+            ("(GTE ${qty} 2.5)", Value::BOOL(true)),
+            ("(BETWEEN ${price} 10 20.0)", Value::BOOL(true)),
+            ("(EQUALS ${qty} 3.0)", Value::BOOL(true)),
+            ("(MOD 7.5 2)", Value::FLOAT(1.5)),
+            ("(MOD 7 2)", Value::INT(1)),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_map_get() {
+        let mut profile: HashMap<String, Value> = HashMap::new();
+        profile.insert("age".to_string(), Value::INT(30));
+        let mut user: HashMap<String, Value> = HashMap::new();
+        user.insert("plan".to_string(), Value::STR("pro".to_string()));
+        user.insert("profile".to_string(), Value::MAP(profile));
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("user".to_string(), Value::MAP(user));
+        let ctx = kv;
+        let cases = vec![
+            ("(GET ${user} \"plan\")", Value::STR("pro".to_string())),
+            (
+                "(GT (GET (GET ${user} \"profile\") \"age\") 18)",
+                Value::BOOL(true),
+            ),
+            ("(GET ${user} \"missing\")", Value::NULL),
+            (
+                "(COALESCE (GET ${user} \"missing\") \"fallback\")",
+                Value::from("fallback"),
+            ),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), expected);
+        }
+        let mut p = Parser::create("(GET \"user\" \"plan\")".to_string()).unwrap();
+        assert!(matches!(
+            p.parse().unwrap().eval(&ctx),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+    }
+
+    #[test]
+    fn test_null_coalesce() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("name".to_string(), Value::STR("bob".to_string()));
+        let ctx = kv;
+        let cases = vec![
+            ("${maybe}", Value::NULL),
+            (
+                "(COALESCE ${maybe} \"default\")",
+                Value::STR("default".to_string()),
+            ),
+            (
+                "(COALESCE ${name} \"default\")",
+                Value::STR("bob".to_string()),
+            ),
+            ("(COALESCE ${maybe} NULL)", Value::NULL),
+            ("(EQUALS ${maybe} NULL)", Value::BOOL(true)),
+            ("(NOT ${maybe})", Value::BOOL(true)),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(format!("(COALESCE {})", rule)).unwrap();
+            p.set_missing_as_null(true);
+            assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), expected);
+        }
+        // Without the option missing variables are still BOOL(false)
+        let mut p = Parser::create("(COALESCE ${maybe} 1)".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(false));
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert(
+            "mac".to_string(),
+            Value::BYTES(vec![0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e]),
+        );
+        let ctx = kv;
+        let cases = vec![
+            ("(EQUALS ${mac} #x\"001a2b3c4d5e\")", Value::BOOL(true)),
+            ("(EQUALS ${mac} #x\"001A2B3C4D5F\")", Value::BOOL(false)),
+            ("(IN ${mac} #x\"ff\" #x\"001a2b3c4d5e\")", Value::BOOL(true)),
+            ("(LEN ${mac})", Value::INT(6)),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), expected);
+        }
+        let mut p = Parser::create("(EQUALS ${mac} #x\"001\")".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::LEXER_FAILED(_))));
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert(
+            "expires_at".to_string(),
+            Value::TIMESTAMP(4_102_444_800_000),
+        );
+        kv.insert(
+            "created_at".to_string(),
+            Value::TIMESTAMP(1_709_209_815_000),
+        );
+        let ctx = kv;
+        let cases = vec![
+            ("(GT ${expires_at} (NOW))", Value::BOOL(true)),
+            ("(LT ${created_at} (NOW))", Value::BOOL(true)),
+            (
+                "(EQUALS ${created_at} 2024-02-29T12:30:15Z)",
+                Value::BOOL(true),
+            ),
+            ("(GTE ${created_at} 2024-03-01)", Value::BOOL(false)),
+            (
+                "(BETWEEN ${created_at} 2024-02-29 2024-02-29T23:59:59+00:00)",
+                Value::BOOL(true),
+            ),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), expected);
+        }
+        let mut p = Parser::create("(GT ${created_at} 2024-02-30)".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::FORMAT_NOT_MATCH(_))));
+        let mut p = Parser::create("(GT ${created_at} 100)".to_string()).unwrap();
+        assert!(matches!(
+            p.parse().unwrap().eval(&ctx),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+    }
+
+    #[test]
+    fn test_value_display() {
+        let mut map: HashMap<String, Value> = HashMap::new();
+        map.insert("b".to_string(), Value::BOOL(true));
+        map.insert("a".to_string(), Value::NULL);
+        let cases = vec![
+            (Value::INT(-3), "-3", "INT"),
+            (Value::FLOAT(2.0), "2.0", "FLOAT"),
+            (Value::FLOAT(0.25), "0.25", "FLOAT"),
+            (Value::STR("eu".to_string()), "\"eu\"", "STR"),
+            (
+                Value::LIST(vec![Value::INT(1), Value::STR("a".to_string())]),
+                "[1, \"a\"]",
+                "LIST",
+            ),
+            (Value::MAP(map), "{\"a\": NULL, \"b\": true}", "MAP"),
+            (Value::BYTES(vec![0, 255]), "#x\"00ff\"", "BYTES"),
+            (Value::TIMESTAMP(0), "1970-01-01T00:00:00Z", "TIMESTAMP"),
+        ];
+        for (val, display, type_name) in cases {
+            assert_eq!(val.to_string(), display);
+            assert_eq!(val.type_name(), type_name);
+        }
+    }
+
+    #[test]
+    fn test_value_ordering() {
+        use std::cmp::Ordering;
+        let str_val = |s: &str| Value::STR(s.to_string());
+        assert_eq!(Value::INT(1), Value::FLOAT(1.0));
+        assert!(Value::INT(1) < Value::FLOAT(1.5));
+        assert!(str_val("apple") < str_val("banana"));
+        assert!(Value::BOOL(false) < Value::BOOL(true));
+        assert!(
+            Value::LIST(vec![Value::INT(1), Value::INT(2)])
+                < Value::LIST(vec![Value::INT(1), Value::INT(3)])
+        );
+        assert!(Value::LIST(vec![Value::INT(1)]) < Value::LIST(vec![Value::INT(1), Value::INT(0)]));
+        assert_eq!(Value::NULL.partial_cmp(&Value::NULL), Some(Ordering::Equal));
+        assert_eq!(Value::INT(1).partial_cmp(&str_val("1")), None);
+        assert_eq!(Value::TIMESTAMP(1).partial_cmp(&Value::INT(1)), None);
+        assert_ne!(Value::INT(1), str_val("1"));
+
+        // Equal MAP values, and LIST values of them, are ordered as equal
+        let map = |n: i64| Value::MAP(HashMap::from([("n".to_string(), Value::INT(n))]));
+        assert_eq!(map(1), map(1));
+        assert_eq!(map(1).partial_cmp(&map(1)), Some(Ordering::Equal));
+        assert_eq!(map(1).partial_cmp(&map(2)), None);
+        let list = |n: i64| Value::LIST(vec![Value::INT(0), map(n)]);
+        assert_eq!(list(1).partial_cmp(&list(1)), Some(Ordering::Equal));
+        assert_eq!(list(1).partial_cmp(&list(2)), None);
+
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("name".to_string(), str_val("bob"));
+        let ctx = kv;
+        let mut p = Parser::create("(BETWEEN ${name} \"alice\" \"carol\")".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+    }
+
+    #[test]
+    fn test_value_conversion() {
+        use std::convert::TryFrom;
+        assert_eq!(Value::from(42), Value::INT(42));
+        assert_eq!(Value::from("prod"), Value::STR("prod".to_string()));
+        assert_eq!(Value::from(true), Value::BOOL(true));
+        assert_eq!(Value::from(0.5), Value::FLOAT(0.5));
+        assert_eq!(
+            Value::from(vec!["a", "b"]),
+            Value::LIST(vec![Value::from("a"), Value::from("b")])
+        );
+        assert_eq!(i64::try_from(Value::INT(7)).unwrap(), 7);
+        assert_eq!(f64::try_from(Value::INT(7)).unwrap(), 7.0);
+        assert_eq!(String::try_from(Value::from("x")).unwrap(), "x");
+        assert!(bool::try_from(Value::BOOL(true)).unwrap());
+        assert!(matches!(
+            i64::try_from(Value::from("7")),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), 42.into());
+        let mut p = Parser::create("(GT ${age} 18)".to_string()).unwrap();
+        let result = p.parse().unwrap().eval(&kv).unwrap();
+        assert!(bool::try_from(result).unwrap());
+    }
+
+    #[test]
+    fn test_float_literal_tokens() {
+        use crate::token::{Lexer, TokenTag};
+        let lexer = Lexer::create("(IN 3.14 0.5 007.25 12 3.)".to_string()).unwrap();
+        let tokens: Vec<(TokenTag, String)> = lexer
+            .map(|token| token.unwrap())
+            .map(|token| (token.token_tag().clone(), token.lexeme().to_string()))
+            .collect();
+        assert_eq!(
+            tokens[2..6].to_vec(),
+            vec![
+                (TokenTag::FLOAT, "3.14".to_string()),
+                (TokenTag::FLOAT, "0.5".to_string()),
+                (TokenTag::FLOAT, "007.25".to_string()),
+                (TokenTag::NUM, "12".to_string()),
+            ]
+        );
+        let mut p = Parser::create("(IN 0.5 0.50)".to_string()).unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&HashMap::new()).unwrap(),
+            Value::BOOL(true)
+        );
+    }
+
+    #[test]
+    fn test_hex_binary_literals() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("flags".to_string(), Value::INT(0b1010));
+        let ctx = kv;
+        let cases = vec![
+            ("(EQUALS ${flags} 0b1010)", Value::BOOL(true)),
+            ("(EQUALS ${flags} 0xA)", Value::BOOL(true)),
+            ("(MOD 0xFF 0x10)", Value::INT(15)),
+            ("(GT 0x7fffffffffffffff 0)", Value::BOOL(true)),
+            ("(IN 0 0 1)", Value::BOOL(true)),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), expected);
+        }
+        let mut p = Parser::create("(EQUALS ${flags} 0x)".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::LEXER_FAILED(_))));
+        let mut p = Parser::create("(EQUALS ${flags} 0x1ffffffffffffffff)".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::ARITH_OVERFLOW(_))));
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), Value::INT(20));
+        let ctx = kv;
+        let rule = "; adults only\n\
+                    (AND ; every condition must hold\n\
+                    \t(GTE ${age} 18) #| legal age,\n\
+                    checked by ops |#\r\n\
+                    (LT ${age} 65)) ; trailing comment";
+        let mut p = Parser::create(rule.to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        let mut p =
+            Parser::create("(IN \";not a comment\" \";not a comment\")".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+    }
+
+    #[test]
+    fn test_token_spans() {
+        use crate::token::{Lexer, Span};
+        let lexer = Lexer::create("(EQUALS \"é\"\n  ${name})".to_string()).unwrap();
+        let spans: Vec<(String, Span)> = lexer
+            .map(|token| token.unwrap())
+            .map(|token| (token.lexeme().to_string(), token.span()))
+            .collect();
+        let span = |start, end, line, column| Span {
+            start: start,
+            end: end,
+            line: line,
+            column: column,
+        };
+        assert_eq!(
+            spans,
+            vec![
+                ("(".to_string(), span(0, 1, 1, 1)),
+                ("EQUALS".to_string(), span(1, 7, 1, 2)),
+                ("é".to_string(), span(8, 12, 1, 9)),
+                ("name".to_string(), span(15, 22, 2, 3)),
+                (")".to_string(), span(22, 23, 2, 10)),
+            ]
+        );
+        let mut p = Parser::create("(LET ((x 1)\n      (2 3))\n  ${x})".to_string()).unwrap();
+        match p.parse() {
+            Err(AstError::NOT_MATCH(msg)) => {
+                assert!(msg.ends_with("at line 2, column 8"), "{}", msg)
+            }
+            other => panic!("unexpected {:?}", other.is_ok()),
+        }
+    }
+
+    #[test]
+    fn test_lexer_iterator() {
+        use crate::token::{ErrCode, Lexer};
+        let lexer = Lexer::create("(AND ${a} ; comment\n 1)  ".to_string()).unwrap();
+        let lexemes: Result<Vec<String>, ErrCode> = lexer
+            .map(|token| token.map(|t| t.lexeme().to_string()))
+            .collect();
+        assert_eq!(lexemes.unwrap(), vec!["(", "AND", "a", "1", ")"]);
+        let mut lexer = Lexer::create("(IN ${a-b} 1)".to_string()).unwrap();
+        assert!(lexer.nth(2).unwrap().is_err());
+        assert!(lexer.next().is_none());
+        assert_eq!(Lexer::create(String::new()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_lexemes_share_rule_content() {
+        use crate::token::{Lexeme, Lexer};
+        let lexer = Lexer::create("(IN ${id} \"a b\" 007 1.50 #x\"ff\" AND)".to_string()).unwrap();
+        let tokens: Vec<_> = lexer.map(|token| token.unwrap()).collect();
+        let lexemes: Vec<&str> = tokens.iter().map(|t| t.lexeme().as_str()).collect();
+        assert_eq!(
+            lexemes,
+            vec!["(", "IN", "id", "a b", "007", "1.50", "ff", "AND", ")"]
+        );
+        for token in &tokens[2..7] {
+            assert!(
+                matches!(token.lexeme(), Lexeme::Slice(..)),
+                "{}",
+                token.lexeme()
+            );
+        }
+        let mut p = Parser::create("(IN ${id} 007 8)".to_string()).unwrap();
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("id".to_string(), Value::INT(7));
+        assert_eq!(p.parse().unwrap().eval(&kv).unwrap(), Value::BOOL(true));
+        let mut p = Parser::create("(IN 1 99999999999999999999)".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::ARITH_OVERFLOW(_))));
+    }
+
+    #[test]
+    fn test_reserved_words() {
+        use crate::token::{Lexer, TokenTag};
+        let lexer = Lexer::create("(STARTS-WITH ANDY IN_1 INDEX-OF LTE)".to_string()).unwrap();
+        let tags: Vec<TokenTag> = lexer
+            .map(|token| token.unwrap().token_tag().clone())
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                TokenTag::LEFT_BRACKET,
+                TokenTag::STARTS_WITH,
+                TokenTag::IDENT,
+                TokenTag::IDENT,
+                TokenTag::INDEX_OF,
+                TokenTag::LTE,
+                TokenTag::RIGHT_BRACKET,
+            ]
+        );
+        let mut p =
+            Parser::create("(LET ((ORDER 3) (INDEX 4)) (LT ${ORDER} ${INDEX}))".to_string())
+                .unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&HashMap::new()).unwrap(),
+            Value::BOOL(true)
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_keywords() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("name".to_string(), "Alice".into());
+        kv.insert("age".to_string(), Value::INT(30));
+        let ctx = kv;
+        for rule in [
+            "(and (gte ${age} 18) (Starts-With ${name} \"Al\"))",
+            "(And (IN ${age} 30 31) (equals-ci ${name} \"ALICE\"))",
+            "(let ((x ${age})) (not (lt ${x} 18)))",
+        ] {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(
+                p.parse().unwrap().eval(&ctx).unwrap(),
+                Value::BOOL(true),
+                "{}",
+                rule
+            );
+        }
+        let tokens: Vec<String> = crate::token::Lexer::create("(oR nOw)".to_string())
+            .unwrap()
+            .map(|token| token.unwrap().lexeme().to_string())
+            .collect();
+        assert_eq!(tokens, vec!["(", "OR", "NOW", ")"]);
+    }
+
+    #[test]
+    fn test_nested_var_path() {
+        let mut profile: HashMap<String, Value> = HashMap::new();
+        profile.insert("age".to_string(), Value::INT(30));
+        let mut user: HashMap<String, Value> = HashMap::new();
+        user.insert("profile".to_string(), Value::MAP(profile));
+        user.insert("name".to_string(), "Bob".into());
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("user".to_string(), Value::MAP(user));
+        let ctx = kv;
+        let eval = |rule: &str| {
+            Parser::create(rule.to_string())
+                .unwrap()
+                .parse()
+                .unwrap()
+                .eval(&ctx)
+        };
+        assert_eq!(
+            eval("(GT ${user.profile.age} 18)").unwrap(),
+            Value::BOOL(true)
+        );
+        assert_eq!(
+            eval("(EQUALS ${user.name} \"Bob\")").unwrap(),
+            Value::BOOL(true)
+        );
+        let mut p = Parser::create("(COALESCE ${user.profile.city} \"none\")".to_string()).unwrap();
+        p.set_missing_as_null(true);
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), "none".into());
+        assert!(matches!(
+            eval("(LEN ${user.name.first})"),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+        let mut p = Parser::create("(COALESCE ${user..name} 1)".to_string()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::FORMAT_NOT_MATCH(_))));
+    }
+
+    #[test]
+    fn test_indexed_var() {
+        let mut order: HashMap<String, Value> = HashMap::new();
+        order.insert("id".to_string(), Value::INT(7));
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("items".to_string(), vec!["a", "b"].into());
+        kv.insert("orders".to_string(), Value::LIST(vec![Value::MAP(order)]));
+        kv.insert("matrix".to_string(), vec![vec![1, 2], vec![3, 4]].into());
+        let ctx = kv;
+        let eval = |rule: &str| {
+            Parser::create(rule.to_string())
+                .unwrap()
+                .parse()
+                .unwrap()
+                .eval(&ctx)
+        };
+        assert_eq!(
+            eval("(EQUALS ${items[1]} \"b\")").unwrap(),
+            Value::BOOL(true)
+        );
+        assert_eq!(
+            eval("(EQUALS ${orders[0].id} 7)").unwrap(),
+            Value::BOOL(true)
+        );
+        assert_eq!(
+            eval("(EQUALS ${matrix[1][0]} 3)").unwrap(),
+            Value::BOOL(true)
+        );
+        assert!(matches!(
+            eval("(LEN ${items[2]})"),
+            Err(AstError::INDEX_OUT_OF_BOUNDS(_))
+        ));
+        assert!(matches!(
+            eval("(LEN ${items.first})"),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+        for rule in [
+            "(LEN ${items[})",
+            "(LEN ${items[a]})",
+            "(LEN ${[0]})",
+            "(LEN ${a]b})",
+        ] {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert!(
+                matches!(p.parse(), Err(AstError::FORMAT_NOT_MATCH(_))),
+                "{}",
+                rule
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), Value::INT(20));
+        let ctx = kv;
+        let rules = "; adults\n(GTE ${age} 18)\n\n#| teenager |#\n(AND (GTE ${age} 13) (LT ${age} 20))  (IN ${age} 20 30)\n";
+        let mut p = Parser::create(rules.to_string()).unwrap();
+        let results: Vec<Value> = p
+            .parse_all()
+            .unwrap()
+            .iter()
+            .map(|e| e.eval(&ctx).unwrap())
+            .collect();
+        assert_eq!(
+            results,
+            vec![Value::BOOL(true), Value::BOOL(false), Value::BOOL(true)]
+        );
+        assert!(Parser::create(" ; nothing\n".to_string())
+            .unwrap()
+            .parse_all()
+            .unwrap()
+            .is_empty());
+        let mut p = Parser::create("(GT 2 1) (GT 1".to_string()).unwrap();
+        assert!(matches!(p.parse_all(), Err(AstError::FORMAT_NOT_MATCH(_))));
+    }
+
+    #[test]
+    fn test_parse_recover() {
+        let rules = "(AND (GT ${a} 1) (FOO 1) (LT 2))\n(IN ${a} 1 2)\n(OR (LET ((1 2)) 3) (NOT))\n(GT ${b-c} 1)";
+        let mut p = Parser::create(rules.to_string()).unwrap();
+        let (exprs, diagnostics) = p.parse_recover();
+        assert_eq!(exprs.len(), 1);
+        let found: Vec<(usize, usize)> = diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.span.column))
+            .collect();
+        assert_eq!(found, vec![(1, 19), (1, 31), (3, 12), (3, 25), (4, 8)]);
+        assert!(matches!(diagnostics[0].error, AstError::NOT_SUPP_OPER(_)));
+        assert!(matches!(diagnostics[1].error, AstError::ARITY_NOT_MATCH(_)));
+        let mut p = Parser::create("(GT 2 1) (LT 1 2)".to_string()).unwrap();
+        let (exprs, diagnostics) = p.parse_recover();
+        assert_eq!((exprs.len(), diagnostics.len()), (2, 0));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        use super::ParserOptions;
+        let nested = |n: usize| format!("{}1{}", "(NOT ".repeat(n), ")".repeat(n));
+        let options = ParserOptions {
+            max_depth: 4,
+            ..ParserOptions::default()
+        };
+        let mut p = Parser::create_with_options(nested(4), options.clone()).unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&HashMap::new()).unwrap(),
+            Value::BOOL(true)
+        );
+        let mut p = Parser::create_with_options(nested(5), options.clone()).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::MAX_DEPTH_EXCEEDED(_))));
+        let mut p = Parser::create(nested(100_000)).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::MAX_DEPTH_EXCEEDED(_))));
+        let content = format!("{} (NOT 0)", nested(10));
+        let mut p = Parser::create_with_options(content, options).unwrap();
+        let (exprs, diagnostics) = p.parse_recover();
+        assert_eq!((exprs.len(), diagnostics.len()), (1, 1));
+    }
+
+    #[test]
+    fn test_max_args() {
+        use super::ParserOptions;
+        let options = ParserOptions {
+            max_args: 3,
+            ..ParserOptions::default()
+        };
+        let mut p = Parser::create_with_options("(IN 1 2 3)".to_string(), options.clone()).unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&HashMap::new()).unwrap(),
+            Value::BOOL(false)
+        );
+        let mut p = Parser::create_with_options("(IN 1 2 3 1)".to_string(), options).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::TOO_MANY_ARGS(_))));
+        let rule = format!("(IN 1 {})", "2 ".repeat(20000));
+        let mut p = Parser::create(rule).unwrap();
+        assert!(matches!(p.parse(), Err(AstError::TOO_MANY_ARGS(_))));
+    }
+
+    #[test]
+    fn test_bool_literals() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("flag".to_string(), Value::BOOL(true));
+        let ctx = kv;
+        for (rule, expected) in [
+            ("(EQUALS ${flag} true)", true),
+            ("(EQUALS ${flag} FALSE)", false),
+            ("(AND true (NOT false))", true),
+            ("(IN ${flag} false True)", true),
+        ] {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(
+                p.parse().unwrap().eval(&ctx).unwrap(),
+                Value::BOOL(expected),
+                "{}",
+                rule
+            );
+        }
+    }
+
+    #[test]
+    fn test_atoms() {
+        use super::ParserOptions;
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("env".to_string(), "prod".into());
+        kv.insert("region".to_string(), "eu-west-1".into());
+        let ctx = kv;
+        let parse = |rule: &str, atoms: bool| {
+            let options = ParserOptions {
+                atoms: atoms,
+                ..ParserOptions::default()
+            };
+            return Parser::create_with_options(rule.to_string(), options)?.parse();
+        };
+        for rule in [
+            "(AND (EQUALS ${env} prod) (IN ${region} us-east-1 eu-west-1))",
+            "(LET ((env staging)) (EQUALS ${env} staging))",
+        ] {
+            let expr = parse(rule, true).unwrap();
+            assert_eq!(expr.eval(&ctx).unwrap(), Value::BOOL(true), "{}", rule);
+            let err = parse(rule, false).unwrap_err();
+            assert!(matches!(err, AstError::UNDEFINED_VAR(_)), "{}", err);
+        }
+
+        // Bound names read the binding, a misspelled one does not compile
+        let cases = vec![
+            ("(LET ((limit 5)) (GT 6 limit))", Value::BOOL(true)),
+            (
+                "(LET ((a 1) (b (ADD a 1))) (EQUALS b 2))",
+                Value::BOOL(true),
+            ),
+            ("(APPLY (LAMBDA (x y) (ADD x y)) 1 2)", Value::INT(3)),
+        ];
+        for (rule, expected) in cases {
+            assert_eq!(parse(rule, false).unwrap().eval(&ctx).unwrap(), expected);
+        }
+        let err = parse("(LET ((limit 5)) (GT ${x} limt))", false).unwrap_err();
+        assert!(err.to_string().contains("limt is not bound"), "{}", err);
+        // The binding ends with its LET
+        let err = parse("(EQUALS (LET ((limit 5)) limit) limit)", false).unwrap_err();
+        assert!(err.to_string().contains("limit is not bound"), "{}", err);
+    }
+
+    #[test]
+    fn test_square_and_curly_brackets() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("items".to_string(), vec![1, 2].into());
+        let ctx = kv;
+        let mut p =
+            Parser::create("[AND {IN ${items[1]} 2 3} (NOT [EQUALS ${items[0]} 2])]".to_string())
+                .unwrap();
+        assert_eq!(p.parse().unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+        for rule in ["[AND 1 1)", "(AND {NOT 0] 1)"] {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            match p.parse() {
+                Err(AstError::NOT_MATCH(msg)) => assert!(msg.starts_with("Expected"), "{}", msg),
+                _ => panic!("{} should not parse", rule),
+            }
+        }
+    }
+
+    #[test]
+    fn test_expr_tree() {
+        use super::{CompareOp, ExprKind};
+        let mut p = Parser::create(
+            "(AND (GT ${user.age} 18) (NOT true) (MATCH \"x\" \"^x$\"))".to_string(),
+        )
+        .unwrap();
+        let expr = p.parse().unwrap();
+        match &expr.kind {
+            ExprKind::And(args) => {
+                assert_eq!(args.len(), 3);
+                match &args[0].kind {
+                    ExprKind::Compare(CompareOp::GT, var, limit) => {
+                        assert!(matches!(&var.kind, ExprKind::Var(v) if v.name() == "user.age"));
+                        assert!(matches!(limit.kind, ExprKind::Int(18)));
+                    }
+                    other => panic!("unexpected {:?}", other),
+                }
+                assert!(
+                    matches!(&args[1].kind, ExprKind::Not(arg) if matches!(arg.kind, ExprKind::Bool(true)))
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        let mut user: HashMap<String, Value> = HashMap::new();
+        user.insert("age".to_string(), Value::INT(30));
+        kv.insert("user".to_string(), Value::MAP(user));
+        let copy = expr.clone();
+        assert_eq!(copy.eval(&kv).unwrap(), Value::BOOL(false));
+        let mut p = Parser::create("(SUBSTR \"abc\" 1)".to_string()).unwrap();
+        match p.parse() {
+            Err(AstError::ARITY_NOT_MATCH(msg)) => {
+                assert_eq!(
+                    msg,
+                    "SUBSTR operator should have exactly 3 arguments, but got 2 at line 1, column 2"
+                )
+            }
+            other => panic!("unexpected {:?}", other.is_ok()),
+        }
+    }
+
+    #[test]
+    fn test_walk() {
+        use super::ExprKind;
+        let mut p = Parser::create(
+            "(AND (GT ${age} 18) (COND ((IN ${country} \"DE\" \"FR\") 1) (ELSE (LEN ${name}))))"
+                .to_string(),
+        )
+        .unwrap();
+        let mut expr = p.parse().unwrap();
+        let mut vars = Vec::new();
+        let mut count = 0;
+        expr.walk(&mut |e| {
+            count += 1;
+            if let ExprKind::Var(var) = &e.kind {
+                vars.push(var.name().to_string());
+            }
+        });
+        assert_eq!(vars, vec!["age", "country", "name"]);
+        assert_eq!(count, 12);
+        assert_eq!(expr.children().len(), 2);
+        // Raise the age limit and turn every IN into a constant false
+        expr.walk_mut(&mut |e| match e.kind {
+            ExprKind::Int(18) => e.kind = ExprKind::Int(21),
+            ExprKind::In(_) => e.kind = ExprKind::Bool(false),
+            _ => {}
+        });
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), Value::INT(20));
+        kv.insert("name".to_string(), "bob".into());
+        kv.insert("country".to_string(), "DE".into());
+        let ctx = kv;
+        assert_eq!(expr.eval(&ctx).unwrap(), Value::BOOL(false));
+        let mut kv = ctx.clone();
+        kv.insert("age".to_string(), Value::INT(22));
+        assert_eq!(expr.eval(&kv).unwrap(), Value::BOOL(true));
+    }
+
+    #[test]
+    fn test_to_sexpr() {
+        let cases = vec![
+            (
+                "(and  (gte ${age} 18)\n  ; adults\n  (in ${country} \"DE\" \"FR\"))",
+                "(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))",
+            ),
+            (
+                "[let ((x 0xff) (y 2.0)) {equals-ci ${x} ${y} true}]",
+                "(LET ((x 255) (y 2.0)) (EQUALS-CI ${x} ${y} true))",
+            ),
+            (
+                "(COND ((MATCH ${code} \"^a\\d+$\") NULL) (ELSE #x\"00FF\"))",
+                "(COND ((MATCH ${code} \"^a\\d+$\") NULL) (ELSE #x\"00ff\"))",
+            ),
+            (
+                "(OR (LIKE ${path} \"/api/%\") (GT (NOW) 2024-02-29))",
+                "(OR (LIKE ${path} \"/api/%\") (GT (NOW) 2024-02-29T00:00:00Z))",
+            ),
+            ("(NOT ${user.items[0]})", "(NOT ${user.items[0]})"),
+        ];
+        for (rule, expected) in cases {
+            let sexpr = Parser::create(rule.to_string())
+                .unwrap()
+                .parse()
+                .unwrap()
+                .to_sexpr();
+            assert_eq!(sexpr, expected);
+            let again = Parser::create(sexpr).unwrap().parse().unwrap().to_sexpr();
+            assert_eq!(again, expected);
+        }
+    }
+
+    #[test]
+    fn test_to_json() {
+        let cases = vec![
+            (
+                "(AND (GTE ${age} 18) (IN ${country} \"DE\" \"F\\\t\" 1.0 NULL))",
+                r#"{"op":"AND","args":[{"op":"GTE","args":[{"var":"age"},18]},{"op":"IN","args":[{"var":"country"},"DE","F\\\t",1.0,null]}]}"#,
+            ),
+            (
+                "(LET ((x #x\"0aff\")) (COND ((LIKE ${x} \"a%\") (NOW)) (ELSE 2024-02-29)))",
+                r#"{"op":"LET","bindings":[{"name":"x","expr":{"bytes":"0aff"}}],"body":{"op":"COND","branches":[{"test":{"op":"LIKE","args":[{"var":"x"}],"pattern":"a%"},"result":{"op":"NOW","args":[]}}],"else":{"timestamp":"2024-02-29T00:00:00Z"}}}"#,
+            ),
+        ];
+        for (rule, expected) in cases {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.to_json(), expected);
+        }
+    }
+
+    #[test]
+    fn test_from_json() {
+        use super::{Expr, ParserOptions};
+        let options = ParserOptions::default();
+        for rule in [
+            "(AND (GTE ${user.age} 18) (IN ${country} \"DE\" \"FR\" 1.5 NULL true))",
+            "(LET ((x #x\"0aff\") (y (UPPER \"a\"))) (COND ((LIKE ${y} \"A%\") (NOW)) ((MATCH ${y} \"^\\w$\") 1) (ELSE 2024-02-29T00:00:00Z)))",
+            "(OR (COND) (STARTS-WITH \"ab\" \"a\"))",
+        ] {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            let copy = Expr::from_json(&expr.to_json(), &options).unwrap();
+            assert_eq!(copy.to_sexpr(), expr.to_sexpr());
+        }
+        let json = r#"{"op": "and", "args": [{"op": "gt", "args": [{"var": "age"}, 18]}, true]}"#;
+        let expr = Expr::from_json(json, &options).unwrap();
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), Value::INT(20));
+        assert_eq!(expr.eval(&kv).unwrap(), Value::BOOL(true));
+        for (json, expected) in [
+            (r#"{"op": "FOO", "args": []}"#, "NOT_SUPP_OPER"),
+            (r#"{"op": "NOT", "args": [1, 2]}"#, "ARITY_NOT_MATCH"),
+            (r#"{"op": "NOT"}"#, "FORMAT_NOT_MATCH"),
+            (
+                r#"{"op": "MATCH", "args": ["a"], "pattern": "("}"#,
+                "PATTERN_NOT_VALID",
+            ),
+            (r#"{"var": "a..b"}"#, "FORMAT_NOT_MATCH"),
+            (r#"[1, 2]"#, "FORMAT_NOT_MATCH"),
+            (r#"{"op": "AND", "args": [1,]}"#, "FORMAT_NOT_MATCH"),
+        ] {
+            let err = Expr::from_json(json, &options).unwrap_err();
+            assert!(err.to_string().starts_with(expected), "{} {}", json, err);
+        }
+        let nested = format!(
+            "{}1{}",
+            r#"{"op":"NOT","args":["#.repeat(100),
+            "]}".repeat(100)
+        );
+        assert!(matches!(
+            Expr::from_json(&nested, &options),
+            Err(AstError::MAX_DEPTH_EXCEEDED(_))
+        ));
+    }
+
+    #[test]
+    fn test_variables() {
+        let vars = |rule: &str| {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            let mut vars: Vec<String> = expr.variables().into_iter().collect();
+            vars.sort();
+            vars
+        };
+        assert_eq!(
+            vars(
+                "(AND (GT ${user.age} 18) (IN ${country} \"DE\") (EQUALS ${items[0]} ${country}))"
+            ),
+            vec!["country", "items", "user"]
+        );
+        assert_eq!(
+            vars("(LET ((x ${x}) (y (LEN ${x}))) (AND ${y} ${z.a}))"),
+            vec!["x", "z"]
+        );
+        assert!(vars("(NOT (LET ((a 1)) ${a}))").is_empty());
+    }
+
+    #[test]
+    fn test_expr_equality() {
+        let parse = |rule: &str| Parser::create(rule.to_string()).unwrap().parse().unwrap();
+        let expr = parse("(AND (GT ${age} 18) (MATCH ${code} \"^a+$\") (IN ${tier} \"gold\"))");
+        assert_eq!(
+            expr,
+            parse("(and ; same rule\n  [gt ${age} 0x12] (MATCH ${code} \"^a+$\") {IN ${tier} \"gold\"})")
+        );
+        assert_eq!(expr.clone(), expr);
+        for other in [
+            "(AND (GT ${age} 19) (MATCH ${code} \"^a+$\") (IN ${tier} \"gold\"))",
+            "(AND (GTE ${age} 18) (MATCH ${code} \"^a+$\") (IN ${tier} \"gold\"))",
+            "(AND (GT ${age} 18) (MATCH ${code} \"^a*$\") (IN ${tier} \"gold\"))",
+            "(AND (GT ${age} 18) (IN ${tier} \"gold\") (MATCH ${code} \"^a+$\"))",
+        ] {
+            assert_ne!(expr, parse(other), "{}", other);
+        }
+        assert_eq!(parse("(EQUALS 1.0 ${a})"), parse("(EQUALS 1.00 ${a})"));
+        assert_ne!(parse("(EQUALS 1.0 ${a})"), parse("(EQUALS 1 ${a})"));
+    }
+
+    #[test]
+    fn test_node_spans() {
+        let rule = "(AND (EQUALS ${a} 1)\n     (GT ${name} \"é\"))";
+        let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+        assert_eq!((expr.span.start, expr.span.end), (0, rule.len()));
+        let gt = expr.children()[1];
+        assert_eq!(&rule[gt.span.start..gt.span.end], "(GT ${name} \"é\")");
+        assert_eq!((gt.span.line, gt.span.column), (2, 6));
+        let leaves: Vec<&str> = gt
+            .children()
+            .iter()
+            .map(|e| &rule[e.span.start..e.span.end])
+            .collect();
+        assert_eq!(leaves, vec!["${name}", "\"é\""]);
+
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("a".to_string(), Value::INT(1));
+        kv.insert("name".to_string(), Value::INT(3));
+        match expr.eval(&kv) {
+            Err(AstError::TYPE_NOT_MATCH(msg)) => {
+                assert!(msg.ends_with(" at line 2, column 6"), "{}", msg)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let expr = Parser::create("(LEN ${items[3]})".to_string())
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("items".to_string(), vec![1].into());
+        let err = expr.eval(&kv).unwrap_err();
+        assert!(err.to_string().ends_with("at line 1, column 6"), "{}", err);
+    }
+
+    // Columns of a row are only read when the rule asks for them
+    struct Row {
+        columns: Vec<(&'static str, Value)>,
+        reads: RefCell<Vec<String>>,
+    }
+
+    impl VariableResolver for Row {
+        fn resolve(&self, name: &str) -> Option<Value> {
+            self.reads.borrow_mut().push(name.to_string());
+            self.columns
+                .iter()
+                .find(|(column, _)| *column == name)
+                .map(|(_, val)| val.clone())
+        }
+    }
+
+    #[test]
+    fn test_variable_resolver() {
+        let row = Row {
+            columns: vec![
+                ("age", Value::INT(30)),
+                ("user", Value::from(vec!["a", "b"])),
+            ],
+            reads: RefCell::new(Vec::new()),
+        };
+        let mut p = Parser::create(
+            "(AND (LET ((age 10)) (LT ${age} 20)) (EQUALS ${user[1]} \"b\") (NOT ${country}))"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(p.parse().unwrap().eval(&row).unwrap(), Value::BOOL(true));
+        assert_eq!(*row.reads.borrow(), vec!["user", "country"]);
+    }
+
+    #[test]
+    fn test_strict_vars() {
+        use super::ParserOptions;
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("user".to_string(), Value::from(vec![1, 2]));
+        let options = ParserOptions {
+            strict_vars: true,
+            missing_as_null: true,
+            ..ParserOptions::default()
+        };
+        let mut p = Parser::create_with_options(
+            "(LET ((x 1)) (IN ${x} ${user[1]} 1))".to_string(),
+            options.clone(),
+        )
+        .unwrap();
+        assert_eq!(p.parse().unwrap().eval(&kv).unwrap(), Value::BOOL(true));
+        let mut p =
+            Parser::create_with_options("(AND true\n  (NOT ${usr}))".to_string(), options).unwrap();
+        match p.parse().unwrap().eval(&kv) {
+            Err(AstError::UNDEFINED_VAR(msg)) => {
+                assert_eq!(msg, "Variable ${usr} is not defined at line 2, column 8")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // Without the option the renamed field silently passes
+        let mut p = Parser::create("(NOT ${usr})".to_string()).unwrap();
+        assert_eq!(p.parse().unwrap().eval(&kv).unwrap(), Value::BOOL(true));
+    }
+
+    #[test]
+    fn test_truthiness() {
+        use super::{EvalOptions, Truthiness};
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("n".to_string(), Value::INT(2));
+        kv.insert("f".to_string(), Value::BOOL(false));
+        let strict = EvalOptions {
+            truthiness: Truthiness::StrictBool,
+        };
+        let cases = vec![
+            ("(OR ${f} ${n})", Some(true), None),
+            ("(OR ${f} false)", Some(false), Some(false)),
+            ("(OR ${f} 0)", Some(false), None),
+            ("(OR true \"x\")", Some(true), Some(true)),
+            ("(AND ${n} (NOT ${missing}))", Some(true), None),
+            ("(AND ${f} ${n})", Some(false), Some(false)),
+            ("(NOT ${n})", Some(false), None),
+            ("(XOR ${n} ${f})", Some(true), None),
+            ("(COND (${f} false) (${n} true))", Some(true), None),
+            ("(NOT \"x\")", None, None),
+        ];
+        for (rule, lenient, strict_bool) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            let expr = p.parse().unwrap();
+            assert_eq!(expr.eval(&kv).ok(), lenient.map(Value::BOOL), "{}", rule);
+            match (expr.eval_with_options(&kv, &strict), strict_bool) {
+                (Ok(val), Some(expected)) => assert_eq!(val, Value::BOOL(expected), "{}", rule),
+                (Err(AstError::TYPE_NOT_MATCH(_)), None) => {}
+                (other, _) => panic!("unexpected {:?} for {}", other, rule),
+            }
+        }
+    }
+
+    #[test]
+    fn test_arity() {
+        let cases = vec![
+            (
+                "(AND true\n  (MOD 7 2 3))",
+                "MOD operator should have exactly 2 arguments, but got 3 at line 2, column 4",
+            ),
+            (
+                "(EQUALS ${a})",
+                "EQUALS operator should have at least 2 arguments, but got 1 at line 1, column 2",
+            ),
+            (
+                "(IN)",
+                "IN operator should have at least 2 arguments, but got 0 at line 1, column 2",
+            ),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            match p.parse() {
+                Err(AstError::ARITY_NOT_MATCH(msg)) => assert_eq!(msg, expected),
+                other => panic!("unexpected {:?} for {}", other.is_ok(), rule),
+            }
+        }
+        for rule in ["(AND)", "(CONCAT)", "(MOD 7 2)", "(EQUALS 1 1 1)"] {
+            assert!(
+                Parser::create(rule.to_string()).unwrap().parse().is_ok(),
+                "{}",
+                rule
+            );
+        }
+    }
+
+    #[test]
+    fn test_variadic_equals() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("a".to_string(), Value::INT(2));
+        kv.insert("b".to_string(), Value::FLOAT(2.0));
+        kv.insert("s".to_string(), Value::from("Abc"));
+        let cases = vec![
+            ("(EQUALS ${a} ${b} 2)", true),
+            ("(EQUALS ${a} ${b} 3)", false),
+            ("(EQUALS ${a} 3 ${b})", false),
+            // Args after the first mismatch are not evaluated
+            ("(EQUALS 1 2 (LEN 5))", false),
+            ("(EQUALS-CI ${s} \"abc\" \"ABC\")", true),
+            ("(EQUALS-CI ${s} \"abc\" \"abd\")", false),
+            ("(EQUALS ${s} \"abc\" \"Abc\")", false),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(
+                p.parse().unwrap().eval(&kv).unwrap(),
+                Value::BOOL(expected),
+                "{}",
+                rule
+            );
+        }
+    }
+
+    #[test]
+    fn test_checked_mod() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("min".to_string(), Value::INT(i64::MIN));
+        kv.insert("zero".to_string(), Value::INT(0));
+        kv.insert("neg".to_string(), Value::INT(-1));
+        let cases = vec![
+            ("(MOD 7 ${zero})", "DIV_BY_ZERO: Mod operator can not divide 7 by zero at line 1, column 1"),
+            ("(MOD 7.5 0.0)", "DIV_BY_ZERO: Mod operator can not divide 7.5 by zero at line 1, column 1"),
+            ("(MOD ${min} ${neg})", "ARITH_OVERFLOW: Mod operator overflows for -9223372036854775808 and -1 at line 1, column 1"),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            let err = p.parse().unwrap().eval(&kv).unwrap_err();
+            assert_eq!(err.to_string(), expected);
+        }
+        let mut p = Parser::create("(MOD ${min} 7)".to_string()).unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&kv).unwrap(),
+            Value::INT(i64::MIN % 7)
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_overflow() {
+        use super::{OverflowPolicy, ParserOptions};
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("max".to_string(), Value::INT(i64::MAX));
+        kv.insert("price".to_string(), Value::FLOAT(2.5));
+        let cases = vec![
+            ("(ADD 1 2 3)", Value::INT(6)),
+            ("(SUB 10 3 2)", Value::INT(5)),
+            ("(SUB 7)", Value::INT(-7)),
+            ("(MUL ${price} 4)", Value::FLOAT(10.0)),
+            ("(GT (MUL 3 (ADD ${price} 1)) 10)", Value::BOOL(true)),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            assert_eq!(p.parse().unwrap().eval(&kv).unwrap(), expected, "{}", rule);
+        }
+        let policies = vec![
+            (OverflowPolicy::Saturate, i64::MAX, i64::MAX, i64::MAX),
+            (
+                OverflowPolicy::Wrap,
+                i64::MIN,
+                -2,
+                7_766_279_631_452_241_919,
+            ),
+        ];
+        for (overflow, add, mul, literal) in policies {
+            let options = ParserOptions {
+                overflow: overflow,
+                ..ParserOptions::default()
+            };
+            for (rule, expected) in [
+                ("(ADD ${max} 1)", add),
+                ("(MUL ${max} 2)", mul),
+                ("(ADD 99999999999999999999 0)", literal),
+            ] {
+                let mut p = Parser::create_with_options(rule.to_string(), options.clone()).unwrap();
+                assert_eq!(
+                    p.parse().unwrap().eval(&kv).unwrap(),
+                    Value::INT(expected),
+                    "{}",
+                    rule
+                );
+            }
+        }
+        let mut p = Parser::create("(ADD ${max} 1)".to_string()).unwrap();
+        assert_eq!(
+            p.parse().unwrap().eval(&kv).unwrap_err().to_string(),
+            "ARITH_OVERFLOW: ADD operator overflows for 9223372036854775807 and 1 at line 1, column 1"
+        );
+        let mut p = Parser::create("(ADD 1 \"2\")".to_string()).unwrap();
+        assert!(matches!(
+            p.parse().unwrap().eval(&kv),
+            Err(AstError::TYPE_NOT_MATCH(_))
+        ));
+    }
+
+    #[test]
+    fn test_interned_var_names() {
+        let mut p = Parser::create(
+            "(AND (GT ${user.age} 18) (LT ${user.age} 65) (EQUALS ${user} ${tier}))".to_string(),
+        )
+        .unwrap();
+        let expr = p.parse().unwrap();
+        let mut vars = Vec::new();
+        expr.walk(&mut |e| {
+            if let ExprKind::Var(var) = &e.kind {
+                vars.push(var.clone());
+            }
+        });
+        assert_eq!(vars.len(), 4);
+        assert!(Arc::ptr_eq(&vars[0].name, &vars[1].name));
+        match (&vars[0].path[0], &vars[2].path[0]) {
+            (PathSegment::Key(key), PathSegment::Key(user)) => {
+                assert!(Arc::ptr_eq(key, user));
+                assert!(Arc::ptr_eq(key, &vars[2].name));
+            }
+            _ => panic!("{:?}", vars),
+        }
+        assert!(!Arc::ptr_eq(&vars[2].name, &vars[3].name));
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert(
+            "user".to_string(),
+            Value::MAP(HashMap::from([("age".to_string(), Value::INT(30))])),
+        );
+        assert_eq!(expr.eval(&kv).unwrap(), Value::BOOL(false));
+    }
+
+    #[test]
+    fn test_define() {
+        let source = "(DEFINE is_adult (GTE ${age} 18))
+            (DEFINE vip (AND (is_adult) (IN ${tier} \"gold\" \"platinum\")))
+            (OR (vip) (EQUALS ${name} \"root\"))";
+        let mut p = Parser::create(source.to_string()).unwrap();
+        let exprs = p.parse_all().unwrap();
+        assert_eq!(exprs.len(), 1);
+        assert_eq!(
+            exprs[0].to_sexpr(),
+            "(OR (AND (GTE ${age} 18) (IN ${tier} \"gold\" \"platinum\")) (EQUALS ${name} \"root\"))"
+        );
+        let mut ctx: HashMap<String, Value> = HashMap::new();
+        ctx.insert("age".to_string(), Value::INT(30));
+        ctx.insert("tier".to_string(), Value::from("gold"));
+        assert_eq!(exprs[0].eval(&ctx).unwrap(), Value::BOOL(true));
+
+        let errors = vec![
+            (
+                "(AND (DEFINE x true) true)",
+                "FORMAT_NOT_MATCH: DEFINE is only allowed at the top level of the rule content at line 1, column 7",
+            ),
+            (
+                "(DEFINE x true) (DEFINE x false) (x)",
+                "FORMAT_NOT_MATCH: x is already defined at line 1, column 25",
+            ),
+            (
+                "(DEFINE x true) (x 1)",
+                "ARITY_NOT_MATCH: x is defined without arguments at line 1, column 18",
+            ),
+            (
+                "(x) (DEFINE x true)",
+                "NOT_SUPP_OPER: Not supported operator x at line 1, column 2",
+            ),
+            (
+                "(DEFINE x (x))",
+                "NOT_SUPP_OPER: Not supported operator x at line 1, column 12",
+            ),
+        ];
+        for (source, expected) in errors {
+            let mut p = Parser::create(source.to_string()).unwrap();
+            assert_eq!(
+                p.parse_all().unwrap_err().to_string(),
+                expected,
+                "{}",
+                source
+            );
+        }
+        let mut p = Parser::create("(DEFINE x (GT 1))\n(x)".to_string()).unwrap();
+        let (exprs, diagnostics) = p.parse_recover();
+        assert!(exprs.is_empty());
+        assert_eq!(diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn test_define_limits() {
+        use super::ParserOptions;
+        // Every definition uses the one before twice, inlined it doubles
+        let mut source = "(DEFINE d0 (EQUALS ${x} 1))".to_string();
+        for i in 1..=22 {
+            source.push_str(&format!("(DEFINE d{} (AND (d{}) (d{})))", i, i - 1, i - 1));
+        }
+        source.push_str("(d22)");
+        let mut p = Parser::create(source).unwrap();
+        assert!(matches!(p.parse_all(), Err(AstError::TOO_MANY_NODES(_))));
+
+        // Every definition nests the one before in 50 NOTs
+        let mut source = "(DEFINE d0 true)".to_string();
+        for i in 1..=2000 {
+            source.push_str(&format!(
+                "(DEFINE d{} {}(d{}){})",
+                i,
+                "(NOT ".repeat(50),
+                i - 1,
+                ")".repeat(50)
+            ));
+        }
+        source.push_str("(d2000)");
+        let mut p = Parser::create(source).unwrap();
+        assert_eq!(
+            p.parse_all().unwrap_err().to_string(),
+            "MAX_DEPTH_EXCEEDED: d1 nests brackets deeper than 64 at line 1, column 595"
+        );
+
+        let mut p = Parser::create("(DEFINE x (NOT (NOT true))) (NOT (x))".to_string()).unwrap();
+        assert_eq!(p.parse_all().unwrap()[0].height(), 3);
+        let options = ParserOptions {
+            max_depth: 2,
+            ..ParserOptions::default()
+        };
+        let source = "(DEFINE x (NOT (NOT true))) (NOT (x))".to_string();
+        let mut p = Parser::create_with_options(source, options).unwrap();
+        assert!(matches!(
+            p.parse_all(),
+            Err(AstError::MAX_DEPTH_EXCEEDED(_))
+        ));
+    }
+
+    #[test]
+    fn test_lambda() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), Value::INT(30));
+        let cases = vec![
+            ("(APPLY (LAMBDA (x y) (ADD ${x} ${y})) 1 2)", Value::INT(3)),
+            // The LAMBDA sees the LET around it, not the one it is applied in
+            (
+                "(LET ((n 10) (f (LAMBDA (x) (GT ${x} ${n})))) (LET ((n 100)) (APPLY ${f} 50)))",
+                Value::BOOL(true),
+            ),
+            (
+                "(LET ((f (LAMBDA (x) (GT ${x} ${age})))) (APPLY ${f} 31))",
+                Value::BOOL(true),
+            ),
+            ("(APPLY (LAMBDA (age) ${age}) 5)", Value::INT(5)),
+            ("(APPLY (LAMBDA () ${age}))", Value::INT(30)),
+        ];
+        for (rule, expected) in cases {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.eval(&kv).unwrap(), expected, "{}", rule);
+            assert_eq!(
+                expr.compile_to_bytecode().eval(&kv).unwrap(),
+                expected,
+                "{}",
+                rule
+            );
+            assert_eq!(expr.to_sexpr(), rule);
+            let json = super::Expr::from_json(&expr.to_json(), &Default::default()).unwrap();
+            assert_eq!(json, expr, "{}", rule);
+        }
+        let expr = Parser::create("(LAMBDA (x) (GT ${x} ${age}))".to_string())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            expr.variables().into_iter().collect::<Vec<_>>(),
+            vec!["age"]
+        );
+        let val = expr.eval(&kv).unwrap();
+        assert_eq!(val.type_name(), "LAMBDA");
+        assert_eq!(val.to_string(), "(LAMBDA (x) (GT ${x} ${age}))");
+        assert_eq!(val, val.clone());
+
+        let errors = vec![
+            (
+                "(APPLY 1 2)",
+                "TYPE_NOT_MATCH: Expected LAMBDA value, but got INT 1 at line 1, column 1",
+            ),
+            (
+                "(APPLY (LAMBDA (x y) ${x}) 1)",
+                "ARITY_NOT_MATCH: LAMBDA (x y) should be applied to 2 arguments, but got 1 at line 1, column 1",
+            ),
+        ];
+        for (rule, expected) in errors {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.eval(&kv).unwrap_err().to_string(), expected);
+        }
+        for rule in ["(LAMBDA (x x) 1)", "(LAMBDA x 1)", "(APPLY)"] {
+            assert!(
+                Parser::create(rule.to_string()).unwrap().parse().is_err(),
+                "{}",
+                rule
+            );
+        }
+    }
+
+    #[test]
+    fn test_lambda_max_calls() {
+        use super::ParserOptions;
+        use crate::rule::Rule;
+        let kv: HashMap<String, Value> = HashMap::new();
+        let rule =
+            Rule::compile("(APPLY (LAMBDA (x) (APPLY x x)) (LAMBDA (x) (APPLY x x)))").unwrap();
+        assert_eq!(
+            rule.eval(&kv).unwrap_err().to_string(),
+            "rule eval failed, MAX_DEPTH_EXCEEDED: LAMBDA (x) is applied inside more than 64 applications at line 1, column 45"
+        );
+
+        let source = "(APPLY (LAMBDA (f) (APPLY f 1)) (LAMBDA (x) (ADD x 1)))";
+        for (max_calls, expected) in [(2, Some(Value::INT(2))), (1, None)] {
+            let options = ParserOptions {
+                max_calls: max_calls,
+                ..ParserOptions::default()
+            };
+            let mut p = Parser::create_with_options(source.to_string(), options).unwrap();
+            match (p.parse().unwrap().eval(&kv), expected) {
+                (Ok(val), Some(expected)) => assert_eq!(val, expected),
+                (Err(AstError::MAX_DEPTH_EXCEEDED(_)), None) => {}
+                (result, _) => panic!("{:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_each() {
+        let order = |amount: i64, country: &str| {
+            Value::MAP(HashMap::from([
+                ("amount".to_string(), Value::INT(amount)),
+                ("country".to_string(), Value::from(country)),
+            ]))
+        };
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert(
+            "orders".to_string(),
+            Value::LIST(vec![order(20, "DE"), order(150, "FR"), order(300, "FR")]),
+        );
+        kv.insert("limit".to_string(), Value::INT(100));
+        kv.insert("empty".to_string(), Value::LIST(vec![]));
+        let cases = vec![
+            (
+                "(ANY ${orders} (GT ${item.amount} ${limit}))",
+                Value::BOOL(true),
+            ),
+            (
+                "(ALL ${orders} (GT ${item.amount} ${limit}))",
+                Value::BOOL(false),
+            ),
+            (
+                "(ALL ${orders} (LAMBDA (o) (GT ${o.amount} 10)))",
+                Value::BOOL(true),
+            ),
+            (
+                "(LEN (FILTER ${orders} (EQUALS ${item.country} \"FR\")))",
+                Value::INT(2),
+            ),
+            (
+                "(LET ((big (LAMBDA (o) (GT ${o.amount} 200)))) (FILTER ${orders} ${big}))",
+                Value::LIST(vec![order(300, "FR")]),
+            ),
+            ("(ANY ${empty} true)", Value::BOOL(false)),
+            ("(ALL ${empty} false)", Value::BOOL(true)),
+            // Nested predicates see the innermost element as ${item}
+            (
+                "(ANY (SPLIT \"a,b\" \",\") (ANY ${orders} (EQUALS ${item.amount} 150)))",
+                Value::BOOL(true),
+            ),
+        ];
+        for (rule, expected) in cases {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.eval(&kv).unwrap(), expected, "{}", rule);
+            assert_eq!(
+                expr.compile_to_bytecode().eval(&kv).unwrap(),
+                expected,
+                "{}",
+                rule
+            );
+            assert_eq!(expr.to_sexpr(), rule);
+        }
+        let expr = Parser::create("(ANY ${orders} (GT ${item.amount} ${limit}))".to_string())
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut vars: Vec<String> = expr.variables().into_iter().collect();
+        vars.sort();
+        assert_eq!(vars, vec!["limit", "orders"]);
+
+        // A bare item in the predicate is the element, like ${item}
+        let pair = |a: i64, b: i64| Value::LIST(vec![Value::INT(a), Value::INT(b)]);
+        kv.insert(
+            "pairs".to_string(),
+            Value::LIST(vec![pair(1, 2), pair(3, 4)]),
+        );
+        let cases = vec![
+            (
+                "(ANY ${orders} (GT item.amount 100))",
+                "(ANY ${orders} (GT ${item.amount} 100))",
+                Value::BOOL(true),
+            ),
+            (
+                "(FILTER (SPLIT \"a,b,a\" \",\") (EQUALS item \"a\"))",
+                "(FILTER (SPLIT \"a,b,a\" \",\") (EQUALS ${item} \"a\"))",
+                Value::LIST(vec![Value::from("a"), Value::from("a")]),
+            ),
+            (
+                "(ALL ${pairs} (GT item[1] item[0]))",
+                "(ALL ${pairs} (GT ${item[1]} ${item[0]}))",
+                Value::BOOL(true),
+            ),
+        ];
+        for (rule, sexpr, expected) in cases {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.eval(&kv).unwrap(), expected, "{}", rule);
+            assert_eq!(expr.to_sexpr(), sexpr);
+        }
+        for rule in ["(ANY item true)", "(GT item.amount 1)"] {
+            let err = Parser::create(rule.to_string())
+                .unwrap()
+                .parse()
+                .unwrap_err();
+            assert!(matches!(err, AstError::UNDEFINED_VAR(_)), "{}", rule);
+        }
+
+        let errors = vec![
+            (
+                "(ANY ${limit} true)",
+                "TYPE_NOT_MATCH: Expected LIST value, but got INT 100 at line 1, column 1",
+            ),
+            (
+                "(ALL ${orders} ${item.country})",
+                "FORMAT_NOT_MATCH: Not correct value format in all operator at line 1, column 1",
+            ),
+        ];
+        for (rule, expected) in errors {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.eval(&kv).unwrap_err().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert(
+            "prices".to_string(),
+            Value::LIST(vec![
+                Value::INT(100),
+                Value::FLOAT(250.5),
+                Value::NULL,
+                Value::INT(200),
+            ]),
+        );
+        kv.insert(
+            "counts".to_string(),
+            Value::LIST(vec![Value::INT(3), Value::INT(1), Value::INT(2)]),
+        );
+        kv.insert("empty".to_string(), Value::LIST(vec![Value::NULL]));
+        kv.insert(
+            "big".to_string(),
+            Value::LIST(vec![Value::INT(i64::MAX), Value::INT(1)]),
+        );
+        let cases = vec![
+            ("(SUM ${prices})", Value::FLOAT(550.5)),
+            ("(SUM ${counts})", Value::INT(6)),
+            ("(AVG ${counts})", Value::FLOAT(2.0)),
+            ("(COUNT ${prices})", Value::INT(3)),
+            ("(MIN ${prices})", Value::INT(100)),
+            ("(MAX ${prices})", Value::FLOAT(250.5)),
+            ("(MAX (SPLIT \"b,c,a\" \",\"))", Value::from("c")),
+            ("(GT (SUM ${prices}) 500)", Value::BOOL(true)),
+            // NULL elements are skipped, an empty LIST has no AVG, MIN or MAX
+            ("(SUM ${empty})", Value::INT(0)),
+            ("(COUNT ${empty})", Value::INT(0)),
+            ("(AVG ${empty})", Value::NULL),
+            ("(COALESCE (MAX ${empty}) 0)", Value::INT(0)),
+            ("(SUM (FILTER ${counts} (GT ${item} 1)))", Value::INT(5)),
+        ];
+        for (rule, expected) in cases {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.eval(&kv).unwrap(), expected, "{}", rule);
+            assert_eq!(
+                expr.compile_to_bytecode().eval(&kv).unwrap(),
+                expected,
+                "{}",
+                rule
+            );
+            assert_eq!(expr.to_sexpr(), rule);
+        }
+        let errors = vec![
+            (
+                "(SUM (SPLIT \"1,2\" \",\"))",
+                "TYPE_NOT_MATCH: SUM operator needs INT or FLOAT elements, but got STR \"1\" at line 1, column 1",
+            ),
+            (
+                "(MAX (SPLIT \"a\" \",\") ${counts})",
+                "ARITY_NOT_MATCH: MAX operator should have exactly 1 argument, but got 2 at line 1, column 2",
+            ),
+            (
+                "(SUM ${big})",
+                "ARITH_OVERFLOW: ADD operator overflows for 9223372036854775807 and 1 at line 1, column 1",
+            ),
+            (
+                "(COUNT 5)",
+                "TYPE_NOT_MATCH: Expected LIST value, but got INT 5 at line 1, column 1",
+            ),
+        ];
+        for (rule, expected) in errors {
+            let err = Parser::create(rule.to_string())
+                .unwrap()
+                .parse()
+                .and_then(|expr| expr.eval(&kv));
+            assert_eq!(err.unwrap_err().to_string(), expected, "{}", rule);
+        }
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("user_id".to_string(), Value::from("user-42"));
+        kv.insert("id".to_string(), Value::INT(42));
+        kv.insert("raw".to_string(), Value::BYTES(vec![1, 2, 3]));
+        // The hashes are pinned, rules which bucket on them must keep
+        // selecting the same users
+        let cases = vec![
+            ("(HASH \"\")", Value::INT(5472609002491880229)),
+            ("(HASH \"a\")", Value::INT(3414815163700866188)),
+            ("(HASH ${user_id})", Value::INT(3658848852250057419)),
+            ("(HASH ${id})", Value::INT(571532774284038691)),
+            ("(HASH (SUB 7))", Value::INT(562968678213606237)),
+            ("(HASH ${raw})", Value::INT(5812566126024783275)),
+            ("(EQUALS (HASH ${id}) (HASH \"42\"))", Value::BOOL(true)),
+            ("(MOD (HASH ${user_id}) 100)", Value::INT(19)),
+            ("(LT (MOD (HASH ${user_id}) 100) 10)", Value::BOOL(false)),
+        ];
+        for (rule, expected) in cases {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.eval(&kv).unwrap(), expected, "{}", rule);
+            assert_eq!(
+                expr.compile_to_bytecode().eval(&kv).unwrap(),
+                expected,
+                "{}",
+                rule
+            );
+            assert_eq!(expr.to_sexpr(), rule);
+        }
+        let expr = Parser::create("(HASH 1.5)".to_string())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            expr.eval(&kv).unwrap_err().to_string(),
+            "TYPE_NOT_MATCH: HASH operator needs a STR, INT or BYTES arg, but got FLOAT 1.5 at line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn test_rollout() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("user_id".to_string(), Value::from("user-42"));
+        // "checkout_v2:user-42" is in bucket 7256 of 10000
+        let cases = vec![
+            (
+                "(ROLLOUT ${user_id} \"checkout_v2\" 72.56)",
+                Value::BOOL(false),
+            ),
+            (
+                "(ROLLOUT ${user_id} \"checkout_v2\" 72.57)",
+                Value::BOOL(true),
+            ),
+            ("(ROLLOUT ${user_id} \"checkout_v2\" 0)", Value::BOOL(false)),
+            (
+                "(ROLLOUT ${user_id} \"checkout_v2\" 100)",
+                Value::BOOL(true),
+            ),
+            ("(ROLLOUT 42 \"checkout_v2\" 88.43)", Value::BOOL(true)),
+            ("(ROLLOUT \"42\" \"checkout_v2\" 88.42)", Value::BOOL(false)),
+        ];
+        for (rule, expected) in cases {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(expr.eval(&kv).unwrap(), expected, "{}", rule);
+            assert_eq!(
+                expr.compile_to_bytecode().eval(&kv).unwrap(),
+                expected,
+                "{}",
+                rule
+            );
+            assert_eq!(expr.to_sexpr(), rule);
+        }
+
+        // About a quarter of the ids are in a 25% rollout, and all of them
+        // are still in when it is raised to 50%
+        let quarter = Parser::create("(ROLLOUT ${id} \"checkout_v2\" 25)".to_string())
+            .unwrap()
+            .parse()
+            .unwrap();
+        let half = Parser::create("(ROLLOUT ${id} \"checkout_v2\" 50)".to_string())
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut count = 0;
+        for id in 0..10000 {
+            kv.insert("id".to_string(), Value::INT(id));
+            if quarter.eval(&kv).unwrap() == Value::BOOL(true) {
+                count += 1;
+                assert_eq!(half.eval(&kv).unwrap(), Value::BOOL(true));
+            }
+        }
+        assert_eq!(count, 2510);
+
+        let errors = vec![
+            (
+                "(ROLLOUT ${user_id} \"checkout_v2\" 120)",
+                "ARG_NOT_CORRECT: ROLLOUT operator needs a percentage from 0 to 100, but got 120 at line 1, column 1",
+            ),
+            (
+                "(ROLLOUT 1.5 \"checkout_v2\" 25)",
+                "TYPE_NOT_MATCH: ROLLOUT operator needs a STR, INT or BYTES arg, but got FLOAT 1.5 at line 1, column 1",
+            ),
+            (
+                "(ROLLOUT ${user_id} 2 25)",
+                "TYPE_NOT_MATCH: Expected STR value, but got INT 2 at line 1, column 1",
+            ),
+        ];
+        for (rule, expected) in errors {
+            let expr = Parser::create(rule.to_string()).unwrap().parse().unwrap();
+            assert_eq!(
+                expr.eval(&kv).unwrap_err().to_string(),
+                expected,
+                "{}",
+                rule
+            );
         }
     }
 }
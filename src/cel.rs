@@ -0,0 +1,788 @@
+use crate::ast::{
+    int_literal, ArithOp, AstError, CompareOp, Expr, ExprKind, ParserOptions, StrPredicateOp,
+    StrTransformOp, Var,
+};
+use crate::token::{Lexer, Span, Symbols, TokenTag};
+use std::fmt;
+
+// Token of CEL, names are identifiers and the words true, false, null, in
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Name(String),
+    Str(String),
+    Int(String),
+    Float(String),
+    Sym(&'static str),
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tok::Name(s) | Tok::Int(s) | Tok::Float(s) => write!(f, "{}", s),
+            Tok::Str(s) => write!(f, "{:?}", s),
+            Tok::Sym(sym) => write!(f, "{}", sym),
+        }
+    }
+}
+
+// Longer symbols first, so that <= is not read as < and =
+const SYMBOLS: [&str; 21] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "[", "]", ",", ".", "+", "-", "*",
+    "%", "?", ":",
+];
+
+// Length of the string token at the start of rest and its text, quoted
+// with ' or " and with the escapes \\ \' \" \n \r \t
+fn scan_str(rest: &str) -> Result<(String, usize), String> {
+    let mut chars = rest.char_indices();
+    let quote = chars.next().map(|(_, c)| c).unwrap_or('"');
+    let mut s = String::new();
+    while let Some((at, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((s, at + 1)),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('\\') => s.push('\\'),
+                Some('\'') => s.push('\''),
+                Some('"') => s.push('"'),
+                Some('n') => s.push('\n'),
+                Some('r') => s.push('\r'),
+                Some('t') => s.push('\t'),
+                Some(c) => return Err(format!("Unknown escape \\{} in string", c)),
+                None => break,
+            },
+            '\n' => break,
+            c => s.push(c),
+        }
+    }
+    return Err(format!("String is not closed with {}", quote));
+}
+
+fn scan(source: &str) -> Result<Vec<(Tok, Span)>, AstError> {
+    let mut tokens = Vec::new();
+    let (mut pos, mut line, mut column) = (0, 1, 1);
+    while let Some(c) = source[pos..].chars().next() {
+        let rest = &source[pos..];
+        let span = Span {
+            start: pos,
+            end: pos,
+            line: line,
+            column: column,
+        };
+        let (tok, len) = if c.is_whitespace() {
+            (None, c.len_utf8())
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (Some(Tok::Name(rest[..len].to_string())), len)
+        } else if c.is_ascii_digit() {
+            let mut len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            // 1.5 is a FLOAT, the dot of 1.size() is not part of it
+            let fraction = rest[len..].strip_prefix('.').map_or(0, |after| {
+                after
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(after.len())
+            });
+            if fraction == 0 {
+                (Some(Tok::Int(rest[..len].to_string())), len)
+            } else {
+                len += 1 + fraction;
+                (Some(Tok::Float(rest[..len].to_string())), len)
+            }
+        } else if c == '\'' || c == '"' {
+            match scan_str(rest) {
+                Ok((s, len)) => (Some(Tok::Str(s)), len),
+                Err(e) => return Err(AstError::LEXER_FAILED(e).at(span)),
+            }
+        } else {
+            match SYMBOLS.iter().find(|sym| rest.starts_with(*sym)) {
+                Some(sym) => (Some(Tok::Sym(sym)), sym.len()),
+                None => {
+                    return Err(
+                        AstError::LEXER_FAILED(format!("Not a valid char {:?} in CEL", c)).at(span),
+                    );
+                }
+            }
+        };
+        if let Some(tok) = tok {
+            tokens.push((
+                tok,
+                Span {
+                    end: pos + len,
+                    ..span
+                },
+            ));
+        }
+        for c in rest[..len].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        pos += len;
+    }
+    return Ok(tokens);
+}
+
+// Names which are not variables
+const RESERVED: [&str; 4] = ["true", "false", "null", "in"];
+
+// Variable path read so far, or the value of the operand it is not a path
+enum Member {
+    Path(String),
+    Expr(Expr),
+}
+
+// Parser of the CEL subset, see Rule::compile_cel
+struct Cel<'a> {
+    tokens: Vec<(Tok, Span)>,
+    at: usize,
+    options: &'a ParserOptions,
+    symbols: &'a Symbols,
+    depth: usize,
+}
+
+impl<'a> Cel<'a> {
+    fn peek(&self) -> Option<&Tok> {
+        return self.tokens.get(self.at).map(|(tok, _)| tok);
+    }
+
+    fn peek_sym(&self, sym: &str) -> bool {
+        return matches!(self.peek(), Some(Tok::Sym(s)) if *s == sym);
+    }
+
+    // Span of the next token, or of the last one at the end
+    fn span(&self) -> Span {
+        let at = self.at.min(self.tokens.len().saturating_sub(1));
+        return self
+            .tokens
+            .get(at)
+            .map(|(_, span)| *span)
+            .unwrap_or_default();
+    }
+
+    fn eat_sym(&mut self, sym: &str) -> bool {
+        let found = self.peek_sym(sym);
+        if found {
+            self.at += 1;
+        }
+        return found;
+    }
+
+    fn expected(&self, what: &str) -> AstError {
+        let got = match self.peek() {
+            Some(tok) => tok.to_string(),
+            None => "the end".to_string(),
+        };
+        return AstError::NOT_MATCH(format!("Expected {}, but got {}", what, got)).at(self.span());
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<(), AstError> {
+        if !self.eat_sym(sym) {
+            return Err(self.expected(sym));
+        }
+        return Ok(());
+    }
+
+    // One more level of nesting, limited the same as in rule content
+    fn enter(&mut self, span: Span) -> Result<(), AstError> {
+        self.depth += 1;
+        if self.depth > self.options.max_depth {
+            return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                "Operators are nested deeper than {}",
+                self.options.max_depth
+            ))
+            .at(span));
+        }
+        return Ok(());
+    }
+
+    fn parse(mut self) -> Result<Expr, AstError> {
+        if self.tokens.is_empty() {
+            return Err(AstError::FORMAT_NOT_MATCH(
+                "Rule should have one expression, but got 0".to_string(),
+            ));
+        }
+        let expr = self.ternary()?;
+        if self.peek().is_some() {
+            return Err(self.expected("an operator or the end"));
+        }
+        return Ok(expr);
+    }
+
+    // test ? a : b is (COND (test a) (ELSE b))
+    fn ternary(&mut self) -> Result<Expr, AstError> {
+        let test = self.or()?;
+        if !self.eat_sym("?") {
+            return Ok(test);
+        }
+        self.enter(test.span)?;
+        let result = self.ternary()?;
+        self.expect_sym(":")?;
+        let default = self.ternary()?;
+        self.depth -= 1;
+        let span = test.span.to(default.span);
+        let kind = ExprKind::Cond(vec![(test, result)], Some(Box::new(default)));
+        return Ok(Expr::new(kind, span));
+    }
+
+    fn or(&mut self) -> Result<Expr, AstError> {
+        return self.chain("||", Self::and, ExprKind::Or);
+    }
+
+    fn and(&mut self) -> Result<Expr, AstError> {
+        return self.chain("&&", Self::relation, ExprKind::And);
+    }
+
+    // && binds before ||, a run of either is a single node
+    fn chain(
+        &mut self,
+        sym: &str,
+        next: fn(&mut Self) -> Result<Expr, AstError>,
+        kind: fn(Vec<Expr>) -> ExprKind,
+    ) -> Result<Expr, AstError> {
+        let first = next(self)?;
+        if !self.peek_sym(sym) {
+            return Ok(first);
+        }
+        let mut args = vec![first];
+        while self.eat_sym(sym) {
+            args.push(next(self)?);
+        }
+        if args.len() > self.options.max_args {
+            return Err(AstError::TOO_MANY_ARGS(format!(
+                "{} operator has more than {} arguments",
+                sym, self.options.max_args
+            ))
+            .at(args[0].span));
+        }
+        let span = args[0].span.to(args[args.len() - 1].span);
+        return Ok(Expr::new(kind(args), span));
+    }
+
+    fn relation(&mut self) -> Result<Expr, AstError> {
+        let left = self.additive()?;
+        if self.peek() == Some(&Tok::Name("in".to_string())) {
+            self.at += 1;
+            return self.in_expr(left);
+        }
+        let op = match self.peek() {
+            Some(Tok::Sym(op)) => *op,
+            _ => return Ok(left),
+        };
+        let compare = match op {
+            ">" => CompareOp::GT,
+            ">=" => CompareOp::GTE,
+            "<" => CompareOp::LT,
+            "<=" => CompareOp::LTE,
+            "==" | "!=" => {
+                self.at += 1;
+                let right = self.additive()?;
+                let span = left.span.to(right.span);
+                let equals = Expr::new(ExprKind::Equals(vec![left, right]), span);
+                if op == "==" {
+                    return Ok(equals);
+                }
+                return Ok(Expr::new(ExprKind::Not(Box::new(equals)), span));
+            }
+            _ => return Ok(left),
+        };
+        self.at += 1;
+        let right = self.additive()?;
+        let span = left.span.to(right.span);
+        let kind = ExprKind::Compare(compare, Box::new(left), Box::new(right));
+        return Ok(Expr::new(kind, span));
+    }
+
+    // x in [a, b] tests the elements of the list, x in ${list} the elements
+    // of a LIST value
+    fn in_expr(&mut self, left: Expr) -> Result<Expr, AstError> {
+        let mut args = vec![left];
+        if self.eat_sym("[") {
+            if !self.peek_sym("]") {
+                loop {
+                    args.push(self.ternary()?);
+                    if !self.eat_sym(",") {
+                        break;
+                    }
+                }
+            }
+            if args.len() == 1 {
+                return Err(self.expected("an element of the list"));
+            }
+            let end = self.span();
+            self.expect_sym("]")?;
+            let span = args[0].span.to(end);
+            return Ok(Expr::new(ExprKind::In(args), span));
+        }
+        args.push(self.additive()?);
+        let span = args[0].span.to(args[1].span);
+        return Ok(Expr::new(ExprKind::In(args), span));
+    }
+
+    // * and % bind before + and -, every operator nests the operands before
+    // it one level deeper
+    fn additive(&mut self) -> Result<Expr, AstError> {
+        let depth = self.depth;
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Tok::Sym("+")) => ArithOp::ADD,
+                Some(Tok::Sym("-")) => ArithOp::SUB,
+                _ => break,
+            };
+            self.at += 1;
+            let right = self.multiplicative()?;
+            let span = left.span.to(right.span);
+            self.enter(span)?;
+            let kind = ExprKind::Arith(op, vec![left, right], self.options.overflow);
+            left = Expr::new(kind, span);
+        }
+        self.depth = depth;
+        return Ok(left);
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, AstError> {
+        let depth = self.depth;
+        let mut left = self.unary()?;
+        loop {
+            let mul = match self.peek() {
+                Some(Tok::Sym("*")) => true,
+                Some(Tok::Sym("%")) => false,
+                _ => break,
+            };
+            self.at += 1;
+            let right = self.unary()?;
+            let span = left.span.to(right.span);
+            self.enter(span)?;
+            let kind = match mul {
+                true => ExprKind::Arith(ArithOp::MUL, vec![left, right], self.options.overflow),
+                false => ExprKind::Mod(Box::new(left), Box::new(right)),
+            };
+            left = Expr::new(kind, span);
+        }
+        self.depth = depth;
+        return Ok(left);
+    }
+
+    // A minus before a number is part of the literal
+    fn unary(&mut self) -> Result<Expr, AstError> {
+        let span = self.span();
+        if self.eat_sym("!") {
+            self.enter(span)?;
+            let arg = self.unary()?;
+            self.depth -= 1;
+            let span = span.to(arg.span);
+            return Ok(Expr::new(ExprKind::Not(Box::new(arg)), span));
+        }
+        if !self.eat_sym("-") {
+            return self.member();
+        }
+        let number = match self.peek() {
+            Some(Tok::Int(digits)) => Some(self.int(&format!("-{}", digits), span)?),
+            Some(Tok::Float(digits)) => Some(self.float(&format!("-{}", digits), span)?),
+            _ => None,
+        };
+        if let Some(kind) = number {
+            let end = self.span();
+            self.at += 1;
+            return Ok(Expr::new(kind, span.to(end)));
+        }
+        self.enter(span)?;
+        let arg = self.unary()?;
+        self.depth -= 1;
+        let span = span.to(arg.span);
+        let kind = ExprKind::Arith(ArithOp::SUB, vec![arg], self.options.overflow);
+        return Ok(Expr::new(kind, span));
+    }
+
+    fn int(&self, digits: &str, span: Span) -> Result<ExprKind, AstError> {
+        // The INT range reaches one further below zero than above it
+        if let Ok(i) = digits.parse::<i64>() {
+            return Ok(ExprKind::Int(i));
+        }
+        let val = match digits.strip_prefix('-') {
+            Some(abs) => int_literal(abs, self.options.overflow)
+                .and_then(|i| self.options.overflow.apply(ArithOp::SUB, 0, i)),
+            None => int_literal(digits, self.options.overflow),
+        };
+        match val {
+            Some(i) => return Ok(ExprKind::Int(i)),
+            None => {
+                return Err(AstError::ARITH_OVERFLOW(format!(
+                    "Integer literal {} is out of the INT range",
+                    digits
+                ))
+                .at(span));
+            }
+        }
+    }
+
+    fn float(&self, digits: &str, span: Span) -> Result<ExprKind, AstError> {
+        match digits.parse::<f64>() {
+            Ok(f) => return Ok(ExprKind::Float(f)),
+            Err(_) => {
+                return Err(
+                    AstError::FORMAT_NOT_MATCH(format!("Not a valid number {}", digits)).at(span),
+                );
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<(String, Span), AstError> {
+        match self.tokens.get(self.at) {
+            Some((Tok::Name(name), span)) => {
+                let found = (name.clone(), *span);
+                self.at += 1;
+                return Ok(found);
+            }
+            _ => return Err(self.expected("a name")),
+        }
+    }
+
+    // Args of a call after its name, up to the closing bracket
+    fn call_args(&mut self) -> Result<(Vec<Expr>, Span), AstError> {
+        self.expect_sym("(")?;
+        let mut args = Vec::new();
+        if !self.peek_sym(")") {
+            loop {
+                args.push(self.ternary()?);
+                if !self.eat_sym(",") {
+                    break;
+                }
+            }
+        }
+        let end = self.span();
+        self.expect_sym(")")?;
+        return Ok((args, end));
+    }
+
+    fn finish(&self, member: Member, span: Span) -> Result<Expr, AstError> {
+        match member {
+            Member::Path(path) => {
+                let var = Var::create(&path, span, self.options, self.symbols)?;
+                return Ok(Expr::new(ExprKind::Var(var), span));
+            }
+            Member::Expr(expr) => return Ok(expr),
+        }
+    }
+
+    // A name with the fields and constant indexes after it is a single
+    // variable, user.items[0].price is ${user.items[0].price}. Fields of
+    // other values are read with GET.
+    fn member(&mut self) -> Result<Expr, AstError> {
+        let span = self.span();
+        let is_call = matches!(self.tokens.get(self.at + 1), Some((Tok::Sym("("), _)));
+        let mut member = match self.peek() {
+            Some(Tok::Name(name)) if !RESERVED.contains(&name.as_str()) && !is_call => {
+                let path = name.clone();
+                self.at += 1;
+                Member::Path(path)
+            }
+            _ => Member::Expr(self.primary()?),
+        };
+        let depth = self.depth;
+        let mut end = self.tokens[self.at - 1].1;
+        loop {
+            if self.eat_sym(".") {
+                let (field, at) = self.name()?;
+                member = match member {
+                    Member::Path(mut path) if !self.peek_sym("(") => {
+                        path.push('.');
+                        path.push_str(&field);
+                        Member::Path(path)
+                    }
+                    member => {
+                        let target = self.finish(member, span.to(end))?;
+                        self.enter(span)?;
+                        if self.peek_sym("(") {
+                            Member::Expr(self.method(target, &field, at)?)
+                        } else {
+                            let key = Expr::new(ExprKind::Str(field), at);
+                            let kind = ExprKind::Get(Box::new(target), Box::new(key));
+                            Member::Expr(Expr::new(kind, span.to(at)))
+                        }
+                    }
+                };
+            } else if self.eat_sym("[") {
+                let index = self.ternary()?;
+                let close = self.span();
+                self.expect_sym("]")?;
+                member = match (member, &index.kind) {
+                    (Member::Path(path), ExprKind::Int(i)) if *i >= 0 => {
+                        Member::Path(format!("{}[{}]", path, i))
+                    }
+                    (member, _) => {
+                        let target = self.finish(member, span.to(end))?;
+                        self.enter(span)?;
+                        let kind = ExprKind::Get(Box::new(target), Box::new(index));
+                        Member::Expr(Expr::new(kind, span.to(close)))
+                    }
+                };
+            } else {
+                break;
+            }
+            end = self.tokens[self.at - 1].1;
+        }
+        self.depth = depth;
+        return self.finish(member, span.to(end));
+    }
+
+    // String methods of CEL and of the strings extension of cel-go
+    fn method(&mut self, target: Expr, name: &str, at: Span) -> Result<Expr, AstError> {
+        let (mut args, end) = self.call_args()?;
+        let span = target.span.to(end);
+        let arity = |n: usize| {
+            if args.len() == n {
+                return Ok(());
+            }
+            return Err(AstError::ARITY_NOT_MATCH(format!(
+                "Method {} should have exactly {} argument{}, but got {}",
+                name,
+                n,
+                if n == 1 { "" } else { "s" },
+                args.len()
+            ))
+            .at(at));
+        };
+        let predicate = match name {
+            "contains" => Some(StrPredicateOp::CONTAINS),
+            "startsWith" => Some(StrPredicateOp::STARTS_WITH),
+            "endsWith" => Some(StrPredicateOp::ENDS_WITH),
+            _ => None,
+        };
+        if let Some(op) = predicate {
+            arity(1)?;
+            let arg = args.remove(0);
+            let kind = ExprKind::StrPredicate(op, Box::new(target), Box::new(arg));
+            return Ok(Expr::new(kind, span));
+        }
+        let transform = match name {
+            "lowerAscii" => Some(StrTransformOp::LOWER),
+            "upperAscii" => Some(StrTransformOp::UPPER),
+            "trim" => Some(StrTransformOp::TRIM),
+            _ => None,
+        };
+        if let Some(op) = transform {
+            arity(0)?;
+            return Ok(Expr::new(
+                ExprKind::StrTransform(op, Box::new(target)),
+                span,
+            ));
+        }
+        match name {
+            "size" => {
+                arity(0)?;
+                return Ok(Expr::new(ExprKind::Len(Box::new(target)), span));
+            }
+            "matches" => {
+                arity(1)?;
+                let pattern = match &args[0].kind {
+                    ExprKind::Str(pattern) => pattern.clone(),
+                    _ => {
+                        return Err(AstError::ARG_NOT_CORRECT(
+                            "Pattern of matches should be a string literal".to_string(),
+                        )
+                        .at(args[0].span));
+                    }
+                };
+                let kind = Expr::create_match(TokenTag::MATCH, target, &pattern)
+                    .map_err(|e| e.at(args[0].span))?;
+                return Ok(Expr::new(kind, span));
+            }
+            _ => {
+                return Err(
+                    AstError::NOT_SUPP_OPER(format!("Not supported CEL method {}", name)).at(at),
+                );
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, AstError> {
+        let span = self.span();
+        let tok = match self.peek() {
+            Some(tok) => tok.clone(),
+            None => return Err(self.expected("an operand")),
+        };
+        if tok == Tok::Sym("(") {
+            self.at += 1;
+            self.enter(span)?;
+            let expr = self.ternary()?;
+            self.depth -= 1;
+            let end = self.span();
+            self.expect_sym(")")?;
+            return Ok(Expr::new(expr.kind, span.to(end)));
+        }
+        let kind = match tok {
+            Tok::Int(digits) => self.int(&digits, span)?,
+            Tok::Float(digits) => self.float(&digits, span)?,
+            Tok::Str(s) => ExprKind::Str(s),
+            Tok::Name(name) => match name.as_str() {
+                "true" => ExprKind::Bool(true),
+                "false" => ExprKind::Bool(false),
+                "null" => ExprKind::Null,
+                "in" => return Err(self.expected("an operand")),
+                _ => {
+                    // Global size() and the functions of the host
+                    self.at += 1;
+                    let (mut args, end) = self.call_args()?;
+                    let span = span.to(end);
+                    if name == "size" && args.len() == 1 {
+                        let arg = args.remove(0);
+                        return Ok(Expr::new(ExprKind::Len(Box::new(arg)), span));
+                    }
+                    let function = match self.options.functions.get(&name) {
+                        Some(function) => function.clone(),
+                        None => {
+                            return Err(AstError::NOT_SUPP_OPER(format!(
+                                "Not supported CEL function {}",
+                                name
+                            ))
+                            .at(span));
+                        }
+                    };
+                    function.check_call(&args).map_err(|e| e.at(span))?;
+                    return Ok(Expr::new(ExprKind::Call(function, args), span));
+                }
+            },
+            Tok::Sym(_) => return Err(self.expected("an operand")),
+        };
+        self.at += 1;
+        return Ok(Expr::new(kind, span));
+    }
+}
+
+/**
+ * Expression of a rule written in the subset of CEL, see Rule::compile_cel
+ */
+pub(crate) fn parse_cel(source: &str, options: &ParserOptions) -> Result<Expr, AstError> {
+    let lexer = Lexer::create(String::new())
+        .map_err(|_| AstError::LEXER_FAILED("Lexer init failed!".to_string()))?;
+    let parser = Cel {
+        tokens: scan(source)?,
+        at: 0,
+        options: options,
+        symbols: lexer.symbols(),
+        depth: 1,
+    };
+    return parser.parse();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{AstError, ParserOptions, Value};
+    use crate::function::FunctionRegistry;
+    use crate::rule::{Context, Rule};
+
+    #[test]
+    fn test_compile_cel() {
+        let cases = vec![
+            (
+                "age >= 18 && country in ['DE', 'FR']",
+                "(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))",
+            ),
+            (
+                "a == 1 || b != \"x\" && !c",
+                "(OR (EQUALS ${a} 1) (AND (NOT (EQUALS ${b} \"x\")) (NOT ${c})))",
+            ),
+            (
+                "user.items[0].price > 10.5 && tags[1] in allowed",
+                "(AND (GT ${user.items[0].price} 10.5) (IN ${tags[1]} ${allowed}))",
+            ),
+            (
+                "name.startsWith('B') || email.endsWith('@x.io') && note.contains(\"ab\")",
+                "(OR (STARTS-WITH ${name} \"B\") (AND (ENDS-WITH ${email} \"@x.io\") (CONTAINS ${note} \"ab\")))",
+            ),
+            (
+                "s.size() == 3 && size(list) < 2 && code.trim().lowerAscii() == 'ok'",
+                "(AND (EQUALS (LEN ${s}) 3) (LT (LEN ${list}) 2) (EQUALS (LOWER (TRIM ${code})) \"ok\"))",
+            ),
+            (
+                "vip ? limit * 2 : limit % 3 + 1 - -n",
+                "(COND (${vip} (MUL ${limit} 2)) (ELSE (SUB (ADD (MOD ${limit} 3) 1) (SUB ${n}))))",
+            ),
+            (
+                "user[key].plan == null && (true || false)",
+                "(AND (EQUALS (GET (GET ${user} ${key}) \"plan\") NULL) (OR true false))",
+            ),
+            ("email.matches('^[a-z]+@')", "(MATCH ${email} \"^[a-z]+@\")"),
+        ];
+        for (source, sexpr) in cases {
+            let rule = Rule::compile_cel(source).unwrap();
+            assert_eq!(rule.expr().to_sexpr(), sexpr, "{}", source);
+            assert_eq!(rule, Rule::compile(sexpr).unwrap());
+            assert_eq!(rule.source(), source);
+        }
+
+        let rule = Rule::compile_cel("n > -4.5 || n < -9223372036854775808").unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("n", 0);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+
+        let mut functions = FunctionRegistry::new();
+        functions.register("double", |args| match args {
+            [Value::INT(i)] => Ok(Value::INT(i * 2)),
+            _ => Err("expected an INT".to_string()),
+        });
+        let options = ParserOptions {
+            functions: functions,
+            ..ParserOptions::default()
+        };
+        let rule = Rule::compile_cel_with_options("double(n + 1) == 2", options).unwrap();
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+
+        let errors = vec![
+            (
+                "age >= 18 &&",
+                "NOT_MATCH: Expected an operand, but got the end at line 1, column 11",
+            ),
+            (
+                "age >= 18 country == 'DE'",
+                "NOT_MATCH: Expected an operator or the end, but got country at line 1, column 11",
+            ),
+            (
+                "a == 1 &&\n  b == 'x",
+                "LEXER_FAILED: String is not closed with ' at line 2, column 8",
+            ),
+            (
+                "a in [1, 2",
+                "NOT_MATCH: Expected ], but got the end at line 1, column 10",
+            ),
+            (
+                "name.reverse()",
+                "NOT_SUPP_OPER: Not supported CEL method reverse at line 1, column 6",
+            ),
+            (
+                "name.startsWith()",
+                "ARITY_NOT_MATCH: Method startsWith should have exactly 1 argument, but got 0 at line 1, column 6",
+            ),
+            (
+                "exists(x)",
+                "NOT_SUPP_OPER: Not supported CEL function exists at line 1, column 1",
+            ),
+            (
+                "a @ b",
+                "LEXER_FAILED: Not a valid char '@' in CEL at line 1, column 3",
+            ),
+            ("", "FORMAT_NOT_MATCH: Rule should have one expression, but got 0"),
+        ];
+        for (source, expected) in errors {
+            let err = Rule::compile_cel(source).unwrap_err();
+            assert_eq!(err.0.to_string(), expected, "{}", source);
+        }
+        let options = ParserOptions {
+            max_depth: 3,
+            ..ParserOptions::default()
+        };
+        assert!(matches!(
+            Rule::compile_cel_with_options("((((a == 1))))", options)
+                .unwrap_err()
+                .0,
+            AstError::MAX_DEPTH_EXCEEDED(_)
+        ));
+    }
+}
@@ -0,0 +1,446 @@
+use crate::ast::{AggregateOp, AstError, Diagnostic, EachOp, Expr, ExprKind, ITEM};
+use std::collections::HashMap;
+use std::fmt;
+
+/**
+ * Type of a value, named like the variants of Value
+ */
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    INT,
+    FLOAT,
+    BOOL,
+    STR,
+    LIST,
+    MAP,
+    NULL,
+    BYTES,
+    TIMESTAMP,
+    LAMBDA,
+}
+
+impl Type {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::INT => "INT",
+            Type::FLOAT => "FLOAT",
+            Type::BOOL => "BOOL",
+            Type::STR => "STR",
+            Type::LIST => "LIST",
+            Type::MAP => "MAP",
+            Type::NULL => "NULL",
+            Type::BYTES => "BYTES",
+            Type::TIMESTAMP => "TIMESTAMP",
+            Type::LAMBDA => "LAMBDA",
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/**
+ * Declared types of the context variables, a variable is declared by the
+ * path written in ${...}, like user.age. Variables which are not declared
+ * can have any type. Every declared variable has a slot, the position it
+ * was first inserted at, see Rule::bind.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    types: HashMap<String, Type>,
+    names: Vec<String>,
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        return Schema::default();
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, ty: Type) {
+        let name = name.into();
+        if self.types.insert(name.clone(), ty).is_none() {
+            self.names.push(name);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Type> {
+        return self.types.get(name).copied();
+    }
+
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        return self.names.iter().position(|n| n == name);
+    }
+
+    // Declared variables in the order of their slots
+    pub fn names(&self) -> &[String] {
+        return &self.names;
+    }
+}
+
+// Values the boolean operators accept with the default truthiness
+const TRUTHY: &[Type] = &[Type::BOOL, Type::INT, Type::NULL];
+const NUMBER: &[Type] = &[Type::INT, Type::FLOAT];
+const STR: &[Type] = &[Type::STR];
+
+/**
+ * Find the operators whose args can never have the type the operator
+ * needs, every mismatch is a TYPE_NOT_MATCH diagnostic at the span of the
+ * arg. Types which are not known before eval are not checked.
+ */
+pub(crate) fn check(expr: &Expr, schema: &Schema) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        schema: schema,
+        scope: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    checker.infer(expr);
+    return checker.diagnostics;
+}
+
+// Type the expression evals to whatever the context is, None when it is
+// only known at eval
+pub(crate) fn static_type(expr: &Expr) -> Option<Type> {
+    let schema = Schema::new();
+    let mut checker = Checker {
+        schema: &schema,
+        scope: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    return checker.infer(expr);
+}
+
+struct Checker<'a> {
+    schema: &'a Schema,
+    // Types of the names bound by the enclosing LET expressions
+    scope: Vec<(String, Option<Type>)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// The type all the given types agree on
+fn same(types: &[Option<Type>]) -> Option<Type> {
+    let first = *types.first()?;
+    if types.iter().all(|t| *t == first) {
+        return first;
+    }
+    return None;
+}
+
+fn comparable(t0: Type, t1: Type) -> bool {
+    return t0 == t1 || (NUMBER.contains(&t0) && NUMBER.contains(&t1));
+}
+
+impl<'a> Checker<'a> {
+    fn mismatch(&mut self, expr: &Expr, msg: String) {
+        self.diagnostics.push(Diagnostic {
+            span: expr.span,
+            error: AstError::TYPE_NOT_MATCH(msg).at(expr.span),
+        });
+    }
+
+    // Type of arg, which is reported when it is not one of allowed
+    fn expect(&mut self, op: &Expr, arg: &Expr, allowed: &[Type]) -> Option<Type> {
+        let ty = self.infer(arg);
+        if let Some(t) = ty {
+            if !allowed.contains(&t) {
+                let names: Vec<&str> = allowed.iter().map(|t| t.name()).collect();
+                self.mismatch(
+                    arg,
+                    format!(
+                        "{} operator needs {} args, but got {} {}",
+                        op.name(),
+                        names.join(" or "),
+                        t,
+                        arg.to_sexpr()
+                    ),
+                );
+            }
+        }
+        return ty;
+    }
+
+    fn expect_comparable(&mut self, op: &Expr, arg0: &Expr, arg1: &Expr) {
+        if let (Some(t0), Some(t1)) = (self.infer(arg0), self.infer(arg1)) {
+            if !comparable(t0, t1) {
+                self.mismatch(
+                    op,
+                    format!(
+                        "{} operator needs comparable args, but got {} and {}",
+                        op.name(),
+                        t0,
+                        t1
+                    ),
+                );
+            }
+        }
+    }
+
+    fn var_type(&self, name: &str, key: &str) -> Option<Type> {
+        match self.scope.iter().rev().find(|(bound, _)| bound == key) {
+            // Only the whole bound value has a known type
+            Some((_, ty)) if name == key => *ty,
+            Some(_) => None,
+            None => self.schema.get(name),
+        }
+    }
+
+    // Type the expression evals to, None when it is only known at eval
+    fn infer(&mut self, expr: &Expr) -> Option<Type> {
+        match &expr.kind {
+            ExprKind::And(args)
+            | ExprKind::Or(args)
+            | ExprKind::Xor(args)
+            | ExprKind::Nand(args) => {
+                for arg in args.iter() {
+                    self.expect(expr, arg, TRUTHY);
+                }
+                return Some(Type::BOOL);
+            }
+            ExprKind::Not(arg) => {
+                self.expect(expr, arg, TRUTHY);
+                return Some(Type::BOOL);
+            }
+            ExprKind::Mod(arg0, arg1) => {
+                match (
+                    self.expect(expr, arg0, NUMBER),
+                    self.expect(expr, arg1, NUMBER),
+                ) {
+                    (Some(Type::INT), Some(Type::INT)) => return Some(Type::INT),
+                    (Some(t0), Some(t1)) if NUMBER.contains(&t0) && NUMBER.contains(&t1) => {
+                        return Some(Type::FLOAT)
+                    }
+                    _ => return None,
+                }
+            }
+            ExprKind::Arith(_, args, _) => {
+                let types: Vec<Option<Type>> = args
+                    .iter()
+                    .map(|arg| self.expect(expr, arg, NUMBER))
+                    .collect();
+                if types.iter().all(|t| *t == Some(Type::INT)) {
+                    return Some(Type::INT);
+                }
+                if types.iter().all(|t| t.is_some_and(|t| NUMBER.contains(&t))) {
+                    return Some(Type::FLOAT);
+                }
+                return None;
+            }
+            ExprKind::In(args) | ExprKind::Equals(args) | ExprKind::EqualsCi(args) => {
+                for arg in args.iter() {
+                    self.infer(arg);
+                }
+                return Some(Type::BOOL);
+            }
+            ExprKind::Compare(_, arg0, arg1) => {
+                self.expect_comparable(expr, arg0, arg1);
+                return Some(Type::BOOL);
+            }
+            ExprKind::Between(val, low, high) => {
+                self.expect_comparable(expr, val, low);
+                self.expect_comparable(expr, val, high);
+                return Some(Type::BOOL);
+            }
+            ExprKind::StrPredicate(_, arg0, arg1) => {
+                self.expect(expr, arg0, STR);
+                self.expect(expr, arg1, STR);
+                return Some(Type::BOOL);
+            }
+            ExprKind::StrTransform(_, arg) => {
+                self.expect(expr, arg, STR);
+                return Some(Type::STR);
+            }
+            ExprKind::Concat(args) => {
+                for arg in args.iter() {
+                    self.expect(expr, arg, &[Type::INT, Type::FLOAT, Type::BOOL, Type::STR]);
+                }
+                return Some(Type::STR);
+            }
+            ExprKind::Len(arg) => {
+                self.expect(expr, arg, &[Type::STR, Type::LIST, Type::BYTES]);
+                return Some(Type::INT);
+            }
+            ExprKind::Hash(arg) => {
+                self.expect(expr, arg, &[Type::STR, Type::INT, Type::BYTES]);
+                return Some(Type::INT);
+            }
+            ExprKind::Rollout(id, salt, percent) => {
+                self.expect(expr, id, &[Type::STR, Type::INT, Type::BYTES]);
+                self.expect(expr, salt, STR);
+                self.expect(expr, percent, &[Type::INT, Type::FLOAT]);
+                return Some(Type::BOOL);
+            }
+            ExprKind::Substr(s, start, len) => {
+                self.expect(expr, s, STR);
+                self.expect(expr, start, &[Type::INT]);
+                self.expect(expr, len, &[Type::INT]);
+                return Some(Type::STR);
+            }
+            ExprKind::IndexOf(s, needle) => {
+                self.expect(expr, s, STR);
+                self.expect(expr, needle, STR);
+                return Some(Type::INT);
+            }
+            ExprKind::Split(s, sep) => {
+                self.expect(expr, s, STR);
+                self.expect(expr, sep, STR);
+                return Some(Type::LIST);
+            }
+            ExprKind::Get(map, key) => {
+                self.expect(expr, map, &[Type::MAP]);
+                self.expect(expr, key, STR);
+                return None;
+            }
+            ExprKind::Match(arg, _) | ExprKind::Like(arg, _) => {
+                self.expect(expr, arg, STR);
+                return Some(Type::BOOL);
+            }
+            ExprKind::Coalesce(args) => {
+                let types: Vec<Option<Type>> = args.iter().map(|arg| self.infer(arg)).collect();
+                return same(&types);
+            }
+            ExprKind::Let(bindings, body) => {
+                let depth = self.scope.len();
+                for (name, arg) in bindings.iter() {
+                    let ty = self.infer(arg);
+                    self.scope.push((name.clone(), ty));
+                }
+                let ty = self.infer(body);
+                self.scope.truncate(depth);
+                return ty;
+            }
+            // Params can have any type
+            ExprKind::Lambda(params, body, _) => {
+                let depth = self.scope.len();
+                for name in params.iter() {
+                    self.scope.push((name.clone(), None));
+                }
+                self.infer(body);
+                self.scope.truncate(depth);
+                return Some(Type::LAMBDA);
+            }
+            ExprKind::Each(op, list, predicate) => {
+                self.expect(expr, list, &[Type::LIST]);
+                self.scope.push((ITEM.to_string(), None));
+                self.infer(predicate);
+                self.scope.pop();
+                match op {
+                    EachOp::FILTER => return Some(Type::LIST),
+                    _ => return Some(Type::BOOL),
+                }
+            }
+            ExprKind::Aggregate(op, list, _) => {
+                self.expect(expr, list, &[Type::LIST]);
+                // AVG, MIN and MAX of an empty LIST are NULL
+                match op {
+                    AggregateOp::COUNT => return Some(Type::INT),
+                    _ => return None,
+                }
+            }
+            ExprKind::Apply(f, args) => {
+                self.expect(expr, f, &[Type::LAMBDA]);
+                for arg in args.iter() {
+                    self.infer(arg);
+                }
+                return None;
+            }
+            ExprKind::Cond(branches, default) => {
+                let mut types = Vec::new();
+                for (test, result) in branches.iter() {
+                    self.expect(expr, test, TRUTHY);
+                    types.push(self.infer(result));
+                }
+                match default {
+                    Some(default) => types.push(self.infer(default)),
+                    None => types.push(Some(Type::BOOL)),
+                }
+                return same(&types);
+            }
+            // The referenced rule does not see the names bound around it
+            ExprKind::Rule(_, Some(body)) => {
+                let scope = std::mem::take(&mut self.scope);
+                let ty = self.infer(body);
+                self.scope = scope;
+                return ty;
+            }
+            ExprKind::Rule(_, None) => return None,
+            // Functions of the host give any type
+            ExprKind::Call(function, args) => {
+                for (at, arg) in args.iter().enumerate() {
+                    if let Some(ty) = self.infer(arg) {
+                        if let Err(e) = function.check_type(at, ty.name(), &arg.to_sexpr()) {
+                            self.diagnostics.push(Diagnostic {
+                                span: arg.span,
+                                error: e.at(arg.span),
+                            });
+                        }
+                    }
+                }
+                return None;
+            }
+            ExprKind::Now | ExprKind::Timestamp(_) => return Some(Type::TIMESTAMP),
+            ExprKind::Int(_) => return Some(Type::INT),
+            ExprKind::Float(_) => return Some(Type::FLOAT),
+            ExprKind::Str(_) => return Some(Type::STR),
+            ExprKind::Bool(_) => return Some(Type::BOOL),
+            ExprKind::Null => return Some(Type::NULL),
+            ExprKind::Bytes(_) => return Some(Type::BYTES),
+            ExprKind::Var(var) => return self.var_type(var.name(), var.key()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Schema, Type};
+    use crate::ast::{AstError, Parser};
+
+    #[test]
+    fn test_check() {
+        let mut schema = Schema::new();
+        schema.insert("name", Type::STR);
+        schema.insert("age", Type::INT);
+        schema.insert("user.score", Type::FLOAT);
+        let cases = vec![
+            ("(AND (GT ${age} 18) (STARTS-WITH ${name} \"a\"))", vec![]),
+            ("(EQUALS (MOD ${age} ${user.score}) 1.5)", vec![]),
+            ("(LET ((n (LEN ${name}))) (GT ${n} ${user.score}))", vec![]),
+            ("(GT ${unknown} \"x\")", vec![]),
+            (
+                "(AND (MOD ${name} 2)\n  (GT ${age} \"18\"))",
+                vec![
+                    "TYPE_NOT_MATCH: MOD operator needs INT or FLOAT args, but got STR ${name} at line 1, column 11",
+                    "TYPE_NOT_MATCH: GT operator needs comparable args, but got INT and STR at line 2, column 3",
+                ],
+            ),
+            (
+                "(LET ((age ${name})) (UPPER ${age}))",
+                vec![],
+            ),
+            (
+                "(NOT (COND ((LEN ${age}) \"a\")))",
+                vec![
+                    "TYPE_NOT_MATCH: LEN operator needs STR or LIST or BYTES args, but got INT ${age} at line 1, column 18",
+                ],
+            ),
+            (
+                "(NOT (UPPER ${name}))",
+                vec!["TYPE_NOT_MATCH: NOT operator needs BOOL or INT or NULL args, but got STR (UPPER ${name}) at line 1, column 6"],
+            ),
+        ];
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            let diagnostics = check(&p.parse().unwrap(), &schema);
+            let errors: Vec<String> = diagnostics
+                .iter()
+                .map(|d| {
+                    assert!(matches!(d.error, AstError::TYPE_NOT_MATCH(_)));
+                    d.error.to_string()
+                })
+                .collect();
+            assert_eq!(errors, expected, "{}", rule);
+        }
+    }
+}
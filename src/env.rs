@@ -0,0 +1,114 @@
+use crate::ast::{PathSegment, Value, VariableResolver};
+use std::collections::HashMap;
+
+// Context key of the environment variables
+const ENV: &str = "ENV";
+
+/**
+ * Variables of the process environment as ${ENV.NAME}, on top of another
+ * resolver which has the other variables. Only the names of the allowlist
+ * are read, when the resolver is created, so that a rule can not read a
+ * secret of the process and every eval sees the same values. A name which
+ * is not set or not UTF-8 is a missing variable, values are STR.
+ *
+ * ```
+ * use newlisp::{Context, EnvResolver, Rule, Value};
+ *
+ * std::env::set_var("REGION", "eu-west-1");
+ * let mut ctx = Context::new();
+ * ctx.insert("tier", 2);
+ * let env = EnvResolver::new(&ctx, ["REGION", "STAGE"]);
+ * let rule = Rule::compile("(AND (EQUALS ${ENV.REGION} \"eu-west-1\") (GT ${tier} 1))").unwrap();
+ * assert_eq!(rule.eval(&env).unwrap(), Value::BOOL(true));
+ * ```
+ */
+#[derive(Debug, Clone)]
+pub struct EnvResolver<R> {
+    inner: R,
+    vars: HashMap<String, Value>,
+}
+
+impl<R: VariableResolver> EnvResolver<R> {
+    pub fn new<S: AsRef<str>>(inner: R, allowed: impl IntoIterator<Item = S>) -> EnvResolver<R> {
+        let vars = allowed
+            .into_iter()
+            .filter_map(|name| {
+                let name = name.as_ref();
+                let val = std::env::var(name).ok()?;
+                return Some((name.to_string(), Value::STR(val)));
+            })
+            .collect();
+        return EnvResolver {
+            inner: inner,
+            vars: vars,
+        };
+    }
+
+    pub fn inner(&self) -> &R {
+        return &self.inner;
+    }
+}
+
+// ${ENV} is the MAP of the variables read, the resolver around hides an
+// ENV of the one inside
+impl<R: VariableResolver> VariableResolver for EnvResolver<R> {
+    fn resolve(&self, name: &str) -> Option<Value> {
+        if name == ENV {
+            return Some(Value::MAP(self.vars.clone()));
+        }
+        return self.inner.resolve(name);
+    }
+
+    fn resolve_path(&self, path: &[PathSegment]) -> Option<(Value, usize)> {
+        match path {
+            [PathSegment::Key(env), PathSegment::Key(name), ..] if &**env == ENV => {
+                return self.vars.get(&**name).map(|val| (val.clone(), 2));
+            }
+            [PathSegment::Key(env), ..] if &**env == ENV => {
+                return self.resolve(ENV).map(|val| (val, 1));
+            }
+            _ => return self.inner.resolve_path(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnvResolver;
+    use crate::ast::Value;
+    use crate::rule::{Context, Rule};
+
+    #[test]
+    fn test_env_resolver() {
+        std::env::set_var("NEWLISP_TEST_REGION", "eu-west-1");
+        std::env::set_var("NEWLISP_TEST_SECRET", "hunter2");
+        let mut ctx = Context::new();
+        ctx.insert("tier", 2);
+        ctx.insert("ENV", "hidden");
+        let env = EnvResolver::new(&ctx, vec!["NEWLISP_TEST_REGION", "NEWLISP_TEST_UNSET"]);
+        let cases = vec![
+            (
+                "(AND (EQUALS ${ENV.NEWLISP_TEST_REGION} \"eu-west-1\") (GT ${tier} 1))",
+                Value::BOOL(true),
+            ),
+            ("(NOT ${ENV.NEWLISP_TEST_SECRET})", Value::BOOL(true)),
+            ("(NOT ${ENV.NEWLISP_TEST_UNSET})", Value::BOOL(true)),
+            ("(GET ${ENV} \"NEWLISP_TEST_SECRET\")", Value::NULL),
+            (
+                "(GET ${ENV} \"NEWLISP_TEST_REGION\")",
+                Value::from("eu-west-1"),
+            ),
+        ];
+        for (source, expected) in cases {
+            let rule = Rule::compile(source).unwrap();
+            assert_eq!(rule.eval(&env).unwrap(), expected, "{}", source);
+        }
+
+        // Values are read once, when the resolver is created
+        std::env::set_var("NEWLISP_TEST_REGION", "us-east-1");
+        let rule = Rule::compile("(EQUALS ${ENV.NEWLISP_TEST_REGION} \"eu-west-1\")").unwrap();
+        assert_eq!(rule.eval(&env).unwrap(), Value::BOOL(true));
+        let env = EnvResolver::new(env.inner(), ["NEWLISP_TEST_REGION"]);
+        assert_eq!(rule.eval(&env).unwrap(), Value::BOOL(false));
+    }
+}
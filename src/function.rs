@@ -0,0 +1,734 @@
+use crate::ast::{AstError, Expr, Value};
+#[cfg(feature = "tokio")]
+use crate::ast::{Env, EvalOptions, VariableResolver};
+use crate::check::{static_type, Type};
+use crate::token::Span;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::sync::Arc;
+
+type Native = dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync;
+#[cfg(feature = "tokio")]
+type AsyncNative =
+    dyn Fn(Vec<Value>) -> Pin<Box<dyn Future<Output = Result<Value, String>> + Send>> + Send + Sync;
+
+#[derive(Clone)]
+enum Body {
+    Sync(Arc<Native>),
+    #[cfg(feature = "tokio")]
+    Async(Arc<AsyncNative>),
+}
+
+/**
+ * Count of the args a function takes and the types each arg can have.
+ * Calls which do not match are rejected when the rule is compiled, and by
+ * Rule::check for the variables of the schema.
+ *
+ * ```
+ * use newlisp::{Signature, Type};
+ *
+ * // (clamp value low [high])
+ * let signature = Signature::new(2, Some(3))
+ *     .arg(&[Type::INT, Type::FLOAT])
+ *     .arg(&[Type::INT])
+ *     .arg(&[Type::INT]);
+ * assert_eq!(signature.to_string(), "(INT|FLOAT, INT, [INT])");
+ * ```
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    min: usize,
+    // None when there is no upper bound
+    max: Option<usize>,
+    // Types of the args by position, an arg past the last position or
+    // without types can have any type
+    types: Vec<Vec<Type>>,
+}
+
+impl Signature {
+    pub fn new(min: usize, max: Option<usize>) -> Signature {
+        Signature {
+            min: min,
+            max: max.map(|max| max.max(min)),
+            types: Vec::new(),
+        }
+    }
+
+    // Types the next arg can have
+    pub fn arg(mut self, types: &[Type]) -> Signature {
+        self.types.push(types.to_vec());
+        return self;
+    }
+
+    pub fn types(&self, at: usize) -> &[Type] {
+        match self.types.get(at) {
+            Some(types) => return types,
+            None => return &[],
+        }
+    }
+
+    fn check_count(&self, len: usize) -> Result<(), String> {
+        let (bound, n) = match self.max {
+            Some(max) if max == self.min && len != max => ("exactly", max),
+            Some(max) if len > max => ("at most", max),
+            _ if len < self.min => ("at least", self.min),
+            _ => return Ok(()),
+        };
+        return Err(format!(
+            "should have {} {} argument{}, but got {}",
+            bound,
+            n,
+            if n == 1 { "" } else { "s" },
+            len
+        ));
+    }
+}
+
+// Positions past the minimum are in brackets, and a signature without an
+// upper bound ends in "..."
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let shown = match self.max {
+            Some(max) => max,
+            None => self.min.max(self.types.len()),
+        };
+        let mut args = Vec::new();
+        for at in 0..shown {
+            let names: Vec<&str> = self.types(at).iter().map(|t| t.name()).collect();
+            let arg = if names.is_empty() {
+                "ANY".to_string()
+            } else {
+                names.join("|")
+            };
+            if at < self.min {
+                args.push(arg);
+            } else {
+                args.push(format!("[{}]", arg));
+            }
+        }
+        if self.max.is_none() {
+            args.push("...".to_string());
+        }
+        write!(f, "({})", args.join(", "))
+    }
+}
+
+/**
+ * A function of the host which rules call by name, it gets the values of
+ * the args. Without a signature it checks their count and types itself.
+ */
+#[derive(Clone)]
+pub struct Function {
+    name: String,
+    body: Body,
+    signature: Option<Signature>,
+}
+
+impl Function {
+    pub fn name(&self) -> &str {
+        return &self.name;
+    }
+
+    pub fn signature(&self) -> Option<&Signature> {
+        return self.signature.as_ref();
+    }
+
+    // Whether the function is only called by Rule::eval_async
+    pub fn is_async(&self) -> bool {
+        return !matches!(self.body, Body::Sync(_));
+    }
+
+    pub fn call(&self, args: &[Value]) -> Result<Value, String> {
+        match &self.body {
+            Body::Sync(f) => return f(args),
+            #[cfg(feature = "tokio")]
+            Body::Async(_) => {
+                return Err("the function is async, the rule is evaluated by eval_async".to_string())
+            }
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn call_async(&self, args: Vec<Value>) -> Result<Value, String> {
+        match &self.body {
+            Body::Sync(f) => return f(&args),
+            Body::Async(f) => return f(args).await,
+        }
+    }
+
+    pub(crate) fn apply(&self, args: &[Value]) -> Result<Value, AstError> {
+        self.check_values(args)?;
+        return self.call(args).map_err(|e| self.failed(e));
+    }
+
+    /**
+     * Check the args of a call against the signature, the types of args
+     * which are only known at eval are not checked
+     */
+    pub(crate) fn check_call(&self, args: &[Expr]) -> Result<(), AstError> {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return Ok(()),
+        };
+        signature
+            .check_count(args.len())
+            .map_err(|e| AstError::ARITY_NOT_MATCH(format!("Function {} {}", self, e)))?;
+        for (at, arg) in args.iter().enumerate() {
+            if let Some(ty) = static_type(arg) {
+                self.check_type(at, ty.name(), &arg.to_sexpr())
+                    .map_err(|e| e.at(arg.span))?;
+            }
+        }
+        return Ok(());
+    }
+
+    // Values of the args at eval, for the ones whose type was not known
+    // before
+    fn check_values(&self, args: &[Value]) -> Result<(), AstError> {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return Ok(()),
+        };
+        signature
+            .check_count(args.len())
+            .map_err(|e| AstError::ARITY_NOT_MATCH(format!("Function {} {}", self, e)))?;
+        for (at, val) in args.iter().enumerate() {
+            self.check_type(at, val.type_name(), &val.to_string())?;
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn check_type(&self, at: usize, ty: &str, arg: &str) -> Result<(), AstError> {
+        let allowed = match &self.signature {
+            Some(signature) => signature.types(at),
+            None => return Ok(()),
+        };
+        if allowed.is_empty() || allowed.iter().any(|t| t.name() == ty) {
+            return Ok(());
+        }
+        let names: Vec<&str> = allowed.iter().map(|t| t.name()).collect();
+        return Err(AstError::TYPE_NOT_MATCH(format!(
+            "Function {} needs {} at arg {}, but got {} {}",
+            self,
+            names.join(" or "),
+            at + 1,
+            ty,
+            arg
+        )));
+    }
+
+    fn failed(&self, e: String) -> AstError {
+        return AstError::FUNCTION_FAILED(format!("Function {} failed: {}", self.name, e));
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Function({})", self)
+    }
+}
+
+// Name of the function with its signature, like geoip_country(STR)
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.signature {
+            Some(signature) => write!(f, "{}{}", self.name, signature),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// The same registration, a function registered again under its name is a
+// different one
+impl PartialEq for Function {
+    fn eq(&self, other: &Function) -> bool {
+        let same = match (&self.body, &other.body) {
+            (Body::Sync(f), Body::Sync(g)) => Arc::ptr_eq(f, g),
+            #[cfg(feature = "tokio")]
+            (Body::Async(f), Body::Async(g)) => Arc::ptr_eq(f, g),
+            #[cfg(feature = "tokio")]
+            _ => false,
+        };
+        return self.name == other.name && self.signature == other.signature && same;
+    }
+}
+
+/**
+ * Functions of the host by name, rules of a parser with the registry in
+ * its options call them as (CALL name args...) or (name args...). Calls
+ * are resolved when the rule is compiled, an unknown function is a
+ * NOT_SUPP_OPER error.
+ *
+ * ```
+ * use newlisp::{Context, FunctionRegistry, ParserOptions, Rule, Value};
+ *
+ * let mut functions = FunctionRegistry::new();
+ * functions.register("double", |args| match args {
+ *     [Value::INT(i)] => Ok(Value::INT(i * 2)),
+ *     _ => Err("expected an INT".to_string()),
+ * });
+ * let options = ParserOptions {
+ *     functions: functions,
+ *     ..ParserOptions::default()
+ * };
+ * let rule = Rule::compile_with_options("(EQUALS (double ${x}) 4)", options).unwrap();
+ * let mut ctx = Context::new();
+ * ctx.insert("x", 2);
+ * assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+ * ```
+ */
+#[derive(Debug, Clone, Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Function>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> FunctionRegistry {
+        return FunctionRegistry::default();
+    }
+
+    /**
+     * Register the function under the name, replacing the one registered
+     * before. Names are matched exactly. Rules are shared between threads,
+     * so the function is Send and Sync and so is anything it captures.
+     */
+    pub fn register<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.insert(name, Body::Sync(Arc::new(f)));
+    }
+
+    /**
+     * Register a function with a service of the host it uses, like a
+     * database pool or a cache. The host keeps a clone of the Arc to reach
+     * the same service, changes to it need interior mutability like a
+     * Mutex since calls run on any thread at the same time.
+     *
+     * ```
+     * use newlisp::{Context, FunctionRegistry, ParserOptions, Rule, Value};
+     * use std::sync::{Arc, Mutex};
+     *
+     * let blocked = Arc::new(Mutex::new(vec!["mallory".to_string()]));
+     * let mut functions = FunctionRegistry::new();
+     * functions.register_with_state("blocked", blocked.clone(), |blocked, args| {
+     *     let blocked = blocked.lock().unwrap();
+     *     Ok(Value::BOOL(blocked.iter().any(|user| Value::from(user.as_str()) == args[0])))
+     * });
+     * let options = ParserOptions {
+     *     functions: functions,
+     *     ..ParserOptions::default()
+     * };
+     * let rule = Rule::compile_with_options("(CALL blocked ${user})", options).unwrap();
+     * let mut ctx = Context::new();
+     * ctx.insert("user", "eve");
+     * assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+     * blocked.lock().unwrap().push("eve".to_string());
+     * assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+     * ```
+     */
+    pub fn register_with_state<S, F>(&mut self, name: &str, state: Arc<S>, f: F)
+    where
+        S: Send + Sync + ?Sized + 'static,
+        F: Fn(&S, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.register(name, move |args| f(&state, args));
+    }
+
+    /**
+     * Register a function which is awaited, like a lookup in a feature
+     * store. Rules calling it are evaluated by Rule::eval_async, their
+     * sync eval fails with FUNCTION_FAILED.
+     */
+    #[cfg(feature = "tokio")]
+    pub fn register_async<F, R>(&mut self, name: &str, f: F)
+    where
+        F: Fn(Vec<Value>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Value, String>> + Send + 'static,
+    {
+        let f: Arc<AsyncNative> = Arc::new(move |args| Box::pin(f(args)));
+        self.insert(name, Body::Async(f));
+    }
+
+    fn insert(&mut self, name: &str, body: Body) {
+        let function = Function {
+            name: name.to_string(),
+            body: body,
+            signature: None,
+        };
+        self.functions.insert(name.to_string(), function);
+    }
+
+    /**
+     * Declare the args the registered function takes, false when there is
+     * no function of the name. Rules compiled before keep the signature
+     * they were compiled with.
+     */
+    pub fn set_signature(&mut self, name: &str, signature: Signature) -> bool {
+        match self.functions.get_mut(name) {
+            Some(function) => {
+                function.signature = Some(signature);
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        return self.functions.get(name);
+    }
+
+    // Names of the registered functions, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.functions.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        return names;
+    }
+}
+
+/**
+ * Values of the calls of an async eval by the node of the call. The eval
+ * stops at the first async call without a value, and starts again once it
+ * is awaited, so the calls are made only when the rule reaches them. Every
+ * value is kept so that an eval which starts again takes the same
+ * branches.
+ */
+#[derive(Default)]
+pub(crate) struct Calls {
+    values: RefCell<HashMap<usize, Value>>,
+    pending: RefCell<Option<Pending>>,
+}
+
+// Async call the eval stopped at, by its node, with the values of its args
+type Pending = (usize, Function, Vec<Value>, Span);
+
+impl Calls {
+    pub(crate) fn call(
+        &self,
+        node: &Expr,
+        function: &Function,
+        args: Vec<Value>,
+    ) -> Result<Value, AstError> {
+        let key = node as *const Expr as usize;
+        if let Some(val) = self.values.borrow().get(&key) {
+            return Ok(val.clone());
+        }
+        if function.is_async() {
+            function.check_values(&args)?;
+            let name = function.name().to_string();
+            *self.pending.borrow_mut() = Some((key, function.clone(), args, node.span));
+            return Err(AstError::FUNCTION_FAILED(format!(
+                "Function {} is not awaited yet",
+                name
+            )));
+        }
+        let val = function.apply(&args)?;
+        self.values.borrow_mut().insert(key, val.clone());
+        return Ok(val);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Expr {
+    /**
+     * Eval with the async functions of the host awaited where the rule
+     * calls them, see Calls
+     */
+    pub async fn eval_async(&self, ctx: &(dyn VariableResolver + Sync)) -> Result<Value, AstError> {
+        let calls = Calls::default();
+        let options = EvalOptions::default();
+        loop {
+            let result = self.eval_env(Env {
+                vars: ctx,
+                root: ctx,
+                slots: None,
+                options: &options,
+                calls: Some(&calls),
+                depth: 0,
+            });
+            let pending = calls.pending.borrow_mut().take();
+            let (key, function, args, span) = match pending {
+                Some(pending) => pending,
+                None => return result,
+            };
+            let val = function
+                .call_async(args)
+                .await
+                .map_err(|e| function.failed(e).at(span))?;
+            calls.values.borrow_mut().insert(key, val);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FunctionRegistry, Signature};
+    use crate::ast::{AstError, ParserOptions, Value};
+    use crate::check::{Schema, Type};
+    use crate::rule::{Context, Rule};
+    use crate::ruleset::{RuleSet, Strategy};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_functions() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut functions = FunctionRegistry::new();
+        functions.register("geoip_country", move |args| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            match args {
+                [Value::STR(ip)] if ip.starts_with("85.") => Ok(Value::from("DE")),
+                [Value::STR(_)] => Ok(Value::from("US")),
+                _ => Err("expected an IP address".to_string()),
+            }
+        });
+        functions.register("zero", |_| Ok(Value::INT(0)));
+        assert_eq!(functions.names(), vec!["geoip_country", "zero"]);
+        let options = ParserOptions {
+            functions: functions,
+            ..ParserOptions::default()
+        };
+        let compile = |source: &str| Rule::compile_with_options(source, options.clone());
+
+        let mut ctx = Context::new();
+        ctx.insert("ip", "85.1.2.3");
+        let rule = compile("(EQUALS (CALL geoip_country ${ip}) \"DE\")").unwrap();
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+        assert_eq!(rule.expr().eval(&ctx).unwrap(), Value::BOOL(true));
+        let rule =
+            compile("(AND (EQUALS (geoip_country ${ip}) \"DE\") (EQUALS (CALL \"zero\") 0))");
+        assert_eq!(rule.unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+
+        // Calls are neither shared nor folded, every one calls the function
+        let mut rule = compile(
+            "(OR (EQUALS (geoip_country \"1.1.1.1\") \"DE\") (EQUALS (geoip_country \"1.1.1.1\") \"FR\"))",
+        )
+        .unwrap();
+        rule.optimize();
+        calls.store(0, Ordering::SeqCst);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let rule = compile("(EQUALS (geoip_country ${ip}) \"DE\")").unwrap();
+        assert_eq!(
+            rule.expr().to_sexpr(),
+            "(EQUALS (CALL \"geoip_country\" ${ip}) \"DE\")"
+        );
+        let json = rule.to_json();
+        assert!(json.contains("\"function\":\"geoip_country\""), "{}", json);
+        let copy = Rule::from_json_with_options(&json, options.clone()).unwrap();
+        assert_eq!(copy.expr(), rule.expr());
+        assert!(Rule::from_json(&json).is_err());
+
+        let err = compile("(geoip_country 1)")
+            .unwrap()
+            .eval(&ctx)
+            .unwrap_err();
+        assert!(matches!(err.0, AstError::FUNCTION_FAILED(_)));
+        assert_eq!(
+            err.0.to_string(),
+            "FUNCTION_FAILED: Function geoip_country failed: expected an IP address at line 1, column 1"
+        );
+        let err = compile("(CALL lookup ${ip})").unwrap_err();
+        assert!(matches!(err.0, AstError::NOT_SUPP_OPER(_)), "{}", err);
+        assert!(Rule::compile("(geoip_country ${ip})").is_err());
+
+        let set = RuleSet::compile_with_options(
+            vec![("de", "(EQUALS (geoip_country ${ip}) \"DE\")")],
+            options,
+        )
+        .unwrap();
+        assert_eq!(
+            set.find_matches(&ctx, Strategy::AllMatches).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_signatures() {
+        let mut functions = FunctionRegistry::new();
+        functions.register("geoip_country", |_| Ok(Value::from("DE")));
+        functions.register("clamp", |args| Ok(args[0].clone()));
+        functions.register("pick", |_| Ok(Value::NULL));
+        assert!(functions.set_signature(
+            "geoip_country",
+            Signature::new(1, Some(1)).arg(&[Type::STR])
+        ));
+        let numbers = [Type::INT, Type::FLOAT];
+        let clamp = Signature::new(2, Some(3))
+            .arg(&numbers)
+            .arg(&numbers)
+            .arg(&numbers);
+        assert!(functions.set_signature("clamp", clamp));
+        assert!(functions.set_signature("pick", Signature::new(0, None).arg(&[])));
+        assert!(!functions.set_signature("missing", Signature::new(0, None)));
+        assert_eq!(
+            functions.get("clamp").unwrap().to_string(),
+            "clamp(INT|FLOAT, INT|FLOAT, [INT|FLOAT])"
+        );
+        assert_eq!(
+            functions.get("pick").unwrap().to_string(),
+            "pick([ANY], ...)"
+        );
+        let options = ParserOptions {
+            functions: functions,
+            ..ParserOptions::default()
+        };
+        let compile = |source: &str| Rule::compile_with_options(source, options.clone());
+
+        assert!(compile("(clamp ${x} 0 (ADD 1 2))").is_ok());
+        assert!(compile("(pick 1 \"a\" true)").is_ok());
+        let errors = vec![
+            (
+                "(geoip_country)",
+                "ARITY_NOT_MATCH: Function geoip_country(STR) should have exactly 1 argument, but got 0 at line 1, column 2",
+            ),
+            (
+                "(AND true\n  (CALL clamp 1 2 3 4))",
+                "ARITY_NOT_MATCH: Function clamp(INT|FLOAT, INT|FLOAT, [INT|FLOAT]) should have at most 3 arguments, but got 4 at line 2, column 9",
+            ),
+            (
+                "(clamp ${x} \"0\")",
+                "TYPE_NOT_MATCH: Function clamp(INT|FLOAT, INT|FLOAT, [INT|FLOAT]) needs INT or FLOAT at arg 2, but got STR \"0\" at line 1, column 13",
+            ),
+            (
+                "(geoip_country (LEN ${ip}))",
+                "TYPE_NOT_MATCH: Function geoip_country(STR) needs STR at arg 1, but got INT (LEN ${ip}) at line 1, column 16",
+            ),
+        ];
+        for (source, expected) in errors {
+            assert_eq!(compile(source).unwrap_err().0.to_string(), expected);
+        }
+
+        // Variables are checked against the schema, and their values at eval
+        let rule = compile("(EQUALS (geoip_country ${ip}) \"DE\")").unwrap();
+        let mut schema = Schema::new();
+        schema.insert("ip", Type::INT);
+        let diagnostics = rule.check(&schema).unwrap_err();
+        assert_eq!(
+            diagnostics[0].error.to_string(),
+            "TYPE_NOT_MATCH: Function geoip_country(STR) needs STR at arg 1, but got INT ${ip} at line 1, column 24"
+        );
+        let mut ctx = Context::new();
+        ctx.insert("ip", 1);
+        assert_eq!(
+            rule.eval(&ctx).unwrap_err().0.to_string(),
+            "TYPE_NOT_MATCH: Function geoip_country(STR) needs STR at arg 1, but got INT 1 at line 1, column 9"
+        );
+        let json = rule.to_json().replace("{\"var\":\"ip\"}", "1");
+        let err = Rule::from_json_with_options(&json, options).unwrap_err();
+        assert!(matches!(err.0, AstError::TYPE_NOT_MATCH(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_state() {
+        fn shared<T: Send + Sync>() {}
+        shared::<FunctionRegistry>();
+        shared::<Rule>();
+        shared::<RuleSet>();
+
+        // Events of every user, the host adds to them while rules run
+        let events: Arc<Mutex<HashMap<String, usize>>> = Arc::new(Mutex::new(HashMap::new()));
+        let mut functions = FunctionRegistry::new();
+        functions.register_with_state("rate_of", events.clone(), |events, args| match args {
+            [Value::STR(user), Value::STR(window)] if window == "1h" => {
+                let events = events.lock().unwrap();
+                Ok(Value::INT(*events.get(user).unwrap_or(&0) as i64))
+            }
+            _ => Err("expected a user and a window".to_string()),
+        });
+        let options = ParserOptions {
+            functions: functions,
+            ..ParserOptions::default()
+        };
+        let rule =
+            Rule::compile_with_options("(GT (CALL rate_of ${user} \"1h\") 2)", options).unwrap();
+        assert_eq!(Arc::strong_count(&events), 2);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let rule = &rule;
+                let events = events.clone();
+                scope.spawn(move || {
+                    *events.lock().unwrap().entry("bob".to_string()).or_default() += 1;
+                    let mut ctx = Context::new();
+                    ctx.insert("user", "bob");
+                    assert!(rule.eval(&ctx).is_ok());
+                });
+            }
+        });
+        let mut ctx = Context::new();
+        ctx.insert("user", "bob");
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+        ctx.insert("user", "alice");
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+        drop(rule);
+        assert_eq!(Arc::strong_count(&events), 1);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_eval_async() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut functions = FunctionRegistry::new();
+        functions.register_async("score", move |args| {
+            let counted = counted.clone();
+            async move {
+                tokio::task::yield_now().await;
+                counted.fetch_add(1, Ordering::SeqCst);
+                match args.as_slice() {
+                    [Value::STR(user)] if user == "bob" => Ok(Value::INT(80)),
+                    [Value::STR(_)] => Ok(Value::INT(20)),
+                    _ => Err("expected a user".to_string()),
+                }
+            }
+        });
+        functions.register("twice", |args| match args {
+            [Value::INT(i)] => Ok(Value::INT(i * 2)),
+            _ => Err("expected an INT".to_string()),
+        });
+        let options = ParserOptions {
+            functions: functions,
+            ..ParserOptions::default()
+        };
+        let rule = Rule::compile_with_options(
+            "(OR (EQUALS ${country} \"DE\") (GT (twice (score ${user})) (score \"alice\")))",
+            options,
+        )
+        .unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("country", "DE");
+        ctx.insert("user", "bob");
+
+        fn send<T: Send>(future: T) -> T {
+            return future;
+        }
+        // The rule is true before it reaches the calls
+        let result = runtime.block_on(send(rule.eval_async(&ctx)));
+        assert_eq!(result.unwrap(), Value::BOOL(true));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        ctx.insert("country", "FR");
+        let result = runtime.block_on(rule.eval_async(&ctx));
+        assert_eq!(result.unwrap(), Value::BOOL(true));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let err = rule.eval(&ctx).unwrap_err();
+        assert!(matches!(err.0, AstError::FUNCTION_FAILED(_)), "{}", err);
+        ctx.insert("user", 1);
+        let err = runtime.block_on(rule.eval_async(&ctx)).unwrap_err();
+        assert_eq!(
+            err.0.to_string(),
+            "FUNCTION_FAILED: Function score failed: expected a user at line 1, column 41"
+        );
+    }
+}
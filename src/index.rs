@@ -0,0 +1,141 @@
+use crate::ast::{Expr, ExprKind, Value, VariableResolver};
+use crate::vm::{hash_value, literal};
+use std::collections::HashMap;
+
+// Variable a guard tests and the values it accepts: the expression, or the
+// first arg of an AND, when it is (EQUALS ${x} literal) or
+// (IN ${x} literal...). A rule whose guard is false evals to false without
+// evaluating anything else, so it can be skipped.
+fn guard(expr: &Expr) -> Option<(&Expr, Vec<Value>)> {
+    match &expr.kind {
+        ExprKind::And(args) if !args.is_empty() => return guard(&args[0]),
+        ExprKind::Rule(_, Some(body)) => return guard(body),
+        ExprKind::Equals(args) if args.len() == 2 => match (&args[0].kind, &args[1].kind) {
+            (ExprKind::Var(_), _) => return Some((&args[0], vec![literal(&args[1])?])),
+            (_, ExprKind::Var(_)) => return Some((&args[1], vec![literal(&args[0])?])),
+            _ => return None,
+        },
+        ExprKind::In(args) if args.len() >= 2 && matches!(args[0].kind, ExprKind::Var(_)) => {
+            let values: Option<Vec<Value>> = args[1..].iter().map(literal).collect();
+            return Some((&args[0], values?));
+        }
+        _ => return None,
+    }
+}
+
+/**
+ * Rules of a set by the variable their guard tests and the values it
+ * accepts, like a single layer of a Rete network. Every variable is
+ * evaluated once per context and the rules whose guard it passes are found
+ * by a lookup, instead of testing the guard of every rule.
+ */
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Index {
+    len: usize,
+    // Variable expressions the guards test
+    vars: Vec<Expr>,
+    // Rules guarded on every variable, by the hash of an accepted value
+    buckets: Vec<HashMap<u64, Vec<(Value, usize)>>>,
+    // Rules guarded on every variable, they are all evaluated when the
+    // variable fails so that the rules report the error
+    guarded: Vec<Vec<usize>>,
+    unguarded: Vec<usize>,
+}
+
+impl Index {
+    pub(crate) fn new<'a>(exprs: impl Iterator<Item = &'a Expr>) -> Index {
+        let mut index = Index::default();
+        for (at, expr) in exprs.enumerate() {
+            index.len += 1;
+            let (var, values) = match guard(expr) {
+                Some(guard) => guard,
+                None => {
+                    index.unguarded.push(at);
+                    continue;
+                }
+            };
+            let slot = match index.vars.iter().position(|v| v.kind == var.kind) {
+                Some(slot) => slot,
+                None => {
+                    index.vars.push(var.clone());
+                    index.buckets.push(HashMap::new());
+                    index.guarded.push(Vec::new());
+                    index.vars.len() - 1
+                }
+            };
+            for val in values {
+                let bucket = index.buckets[slot].entry(hash_value(&val)).or_default();
+                bucket.push((val, at));
+            }
+            index.guarded[slot].push(at);
+        }
+        return index;
+    }
+
+    /**
+     * Whether every rule has to be evaluated on the context, the ones which
+     * do not are known to eval to false
+     */
+    pub(crate) fn candidates(&self, ctx: &dyn VariableResolver) -> Vec<bool> {
+        let mut candidates = vec![false; self.len];
+        for at in self.unguarded.iter() {
+            candidates[*at] = true;
+        }
+        for (slot, var) in self.vars.iter().enumerate() {
+            let val = match var.eval(ctx) {
+                Ok(val) => val,
+                Err(_) => {
+                    for at in self.guarded[slot].iter() {
+                        candidates[*at] = true;
+                    }
+                    continue;
+                }
+            };
+            if let Some(bucket) = self.buckets[slot].get(&hash_value(&val)) {
+                for (accepted, at) in bucket.iter() {
+                    if *accepted == val {
+                        candidates[*at] = true;
+                    }
+                }
+            }
+        }
+        return candidates;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Index;
+    use crate::ast::Value;
+    use crate::rule::{Context, Rule};
+
+    #[test]
+    fn test_candidates() {
+        let rules: Vec<Rule> = [
+            "(AND (EQUALS ${country} \"DE\") (GT ${age} 18))",
+            "(IN ${country} \"FR\" \"DE\")",
+            "(AND (EQUALS 1 ${tier}) (LT ${age} 18))",
+            "(OR (EQUALS ${country} \"DE\") true)",
+            "(EQUALS ${country} ${home})",
+            "(AND (AND (EQUALS ${tier} 2.0)) true)",
+            "(EQUALS ${user.country} \"DE\")",
+        ]
+        .iter()
+        .map(|source| Rule::compile(source).unwrap())
+        .collect();
+        let index = Index::new(rules.iter().map(|rule| rule.expr()));
+        assert_eq!(index.vars.len(), 3);
+        let mut ctx = Context::new();
+        ctx.insert("country", "DE");
+        ctx.insert("tier", 2);
+        let expected = vec![true, true, false, true, true, true, false];
+        assert_eq!(index.candidates(&ctx), expected);
+        ctx.insert("country", Value::LIST(vec![Value::from("DE")]));
+        let expected = vec![false, false, false, true, true, true, false];
+        assert_eq!(index.candidates(&ctx), expected);
+        // A variable which fails leaves its rules to report the error
+        ctx.insert("user", 1);
+        let expected = vec![false, false, false, true, true, true, true];
+        assert_eq!(index.candidates(&ctx), expected);
+    }
+}
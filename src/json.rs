@@ -0,0 +1,393 @@
+use std::fmt;
+
+/**
+ * A JSON document. INT and FLOAT are kept apart the way they are written,
+ * a number with a fraction or an exponent is FLOAT. Object keys keep their
+ * order.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(entries: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            entries
+                .into_iter()
+                .map(|(key, val)| (key.to_string(), val))
+                .collect(),
+        )
+    }
+
+    // Value of the first entry with the key when this is an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+// Nesting of arrays and objects is limited so that a hostile document can
+// not overflow the stack of the recursive parser
+const MAX_DEPTH: usize = 512;
+
+struct JsonParser<'a> {
+    s: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+/**
+ * Parse a JSON document, the error has the byte offset of the problem
+ */
+pub fn parse(s: &str) -> Result<Json, String> {
+    let mut parser = JsonParser {
+        s: s,
+        pos: 0,
+        depth: 0,
+    };
+    let val = parser.value()?;
+    parser.blank();
+    if parser.pos < s.len() {
+        return Err(parser.error("unexpected trailing chars"));
+    }
+    Ok(val)
+}
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at {}", msg, self.pos)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn blank(&mut self) {
+        while let Some(c) = self.peek() {
+            if !" \t\r\n".contains(c) {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, word: &str) -> bool {
+        if self.s[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            return true;
+        }
+        false
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.blank();
+        match self.peek() {
+            Some('{') | Some('[') => {
+                if self.depth >= MAX_DEPTH {
+                    return Err(self.error("document is nested too deep"));
+                }
+                self.depth += 1;
+                let val = if self.peek() == Some('{') {
+                    self.object()
+                } else {
+                    self.array()
+                };
+                self.depth -= 1;
+                val
+            }
+            Some('"') => Ok(Json::Str(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => {
+                for (word, val) in [
+                    ("null", Json::Null),
+                    ("true", Json::Bool(true)),
+                    ("false", Json::Bool(false)),
+                ] {
+                    if self.eat(word) {
+                        return Ok(val);
+                    }
+                }
+                Err(self.error("expect a value"))
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        let mut entries = Vec::new();
+        self.pos += 1;
+        self.blank();
+        if self.eat("}") {
+            return Ok(Json::Object(entries));
+        }
+        loop {
+            self.blank();
+            if self.peek() != Some('"') {
+                return Err(self.error("expect a string key"));
+            }
+            let key = self.string()?;
+            self.blank();
+            if !self.eat(":") {
+                return Err(self.error("expect ':'"));
+            }
+            entries.push((key, self.value()?));
+            self.blank();
+            if self.eat("}") {
+                return Ok(Json::Object(entries));
+            }
+            if !self.eat(",") {
+                return Err(self.error("expect ',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        let mut items = Vec::new();
+        self.pos += 1;
+        self.blank();
+        if self.eat("]") {
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.blank();
+            if self.eat("]") {
+                return Ok(Json::Array(items));
+            }
+            if !self.eat(",") {
+                return Err(self.error("expect ',' or ']'"));
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let code = self
+            .s
+            .get(self.pos..self.pos + 4)
+            .filter(|h| h.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|h| u32::from_str_radix(h, 16).ok());
+        match code {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => Err(self.error("expect 4 hex digits")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut out = String::new();
+        self.pos += 1;
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("string is not closed")),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escaped = self.peek().unwrap_or(' ');
+                    self.pos += escaped.len_utf8();
+                    match escaped {
+                        '"' | '\\' | '/' => out.push(escaped),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'u' => {
+                            let mut code = self.hex4()?;
+                            // A surrogate pair is one char
+                            if (0xd800..0xdc00).contains(&code) && self.eat("\\u") {
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("invalid surrogate pair"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            match char::from_u32(code) {
+                                Some(c) => out.push(c),
+                                None => return Err(self.error("invalid unicode escape")),
+                            }
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c if (c as u32) < 0x20 => return Err(self.error("control char in string")),
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        self.eat("-");
+        let digits = |p: &mut Self| {
+            let from = p.pos;
+            while p.peek().is_some_and(|c| c.is_ascii_digit()) {
+                p.pos += 1;
+            }
+            p.pos > from
+        };
+        if !digits(self) {
+            return Err(self.error("expect digits"));
+        }
+        let mut float = false;
+        if self.eat(".") {
+            float = true;
+            if !digits(self) {
+                return Err(self.error("expect fraction digits"));
+            }
+        }
+        if self.eat("e") || self.eat("E") {
+            float = true;
+            if !self.eat("+") {
+                self.eat("-");
+            }
+            if !digits(self) {
+                return Err(self.error("expect exponent digits"));
+            }
+        }
+        let text = &self.s[start..self.pos];
+        if !float {
+            if let Ok(i) = text.parse::<i64>() {
+                return Ok(Json::Int(i));
+            }
+        }
+        // Integers out of the INT range are kept as FLOAT
+        text.parse::<f64>()
+            .map(Json::Float)
+            .map_err(|_| self.error("invalid number"))
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+// Compact form without blanks, floats always have a fraction or exponent
+// so that they read back as FLOAT
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            Json::Float(v) => {
+                if !v.is_finite() {
+                    write!(f, "null")
+                } else if v.fract() == 0.0 && v.abs() < 1e16 {
+                    write!(f, "{:.1}", v)
+                } else {
+                    write!(f, "{:?}", v)
+                }
+            }
+            Json::Str(s) => write_str(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, val)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", val)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Json};
+
+    #[test]
+    fn test_write_json() {
+        let doc = Json::object(vec![
+            ("op", Json::Str("IN".to_string())),
+            (
+                "args",
+                Json::Array(vec![
+                    Json::Int(-1),
+                    Json::Float(2.0),
+                    Json::Float(0.25),
+                    Json::Float(1e20),
+                    Json::Str("a\"b\\\n\u{1}".to_string()),
+                    Json::Null,
+                    Json::Bool(true),
+                ]),
+            ),
+        ]);
+        assert_eq!(
+            doc.to_string(),
+            "{\"op\":\"IN\",\"args\":[-1,2.0,0.25,1e20,\"a\\\"b\\\\\\n\\u0001\",null,true]}"
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let doc = parse(" {\"op\" : \"IN\", \"args\": [1, -2.5e1, 1e2, \"a\\u00e9\\ud83d\\ude00\\n\", [], {}, null, false, 99999999999999999999]} ").unwrap();
+        assert_eq!(doc.get("op"), Some(&Json::Str("IN".to_string())));
+        assert_eq!(
+            doc.get("args"),
+            Some(&Json::Array(vec![
+                Json::Int(1),
+                Json::Float(-25.0),
+                Json::Float(100.0),
+                Json::Str("a\u{e9}\u{1f600}\n".to_string()),
+                Json::Array(vec![]),
+                Json::Object(vec![]),
+                Json::Null,
+                Json::Bool(false),
+                Json::Float(1e20),
+            ]))
+        );
+        assert_eq!(parse(&doc.to_string()).unwrap(), doc);
+        for s in [
+            "",
+            "[1,]",
+            "{\"a\" 1}",
+            "\"abc",
+            "01x",
+            "[1] 2",
+            "\"\\x\"",
+            "-",
+            "tru",
+        ] {
+            assert!(parse(s).is_err(), "{}", s);
+        }
+        assert!(parse(&"[".repeat(100_000)).is_err());
+    }
+}
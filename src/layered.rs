@@ -0,0 +1,139 @@
+use crate::ast::{PathSegment, Value, VariableResolver};
+use std::fmt;
+
+/**
+ * Resolvers stacked in layers, like the variables of a request over those
+ * of its session over global defaults. A variable is read from the first
+ * layer which resolves it, no values are copied or merged, and layer_of
+ * tells which layer a variable comes from. A layer over MAP values
+ * resolves ${user.age} when it has ${user}, whether or not the MAP has age.
+ *
+ * ```
+ * use newlisp::{Context, LayeredContext, Rule, Value};
+ *
+ * let mut defaults = Context::new();
+ * defaults.insert("limit", 100);
+ * defaults.insert("country", "unknown");
+ * let mut request = Context::new();
+ * request.insert("country", "DE");
+ * let ctx = LayeredContext::new()
+ *     .layer("request", &request)
+ *     .layer("defaults", &defaults);
+ * let rule = Rule::compile("(AND (EQUALS ${country} \"DE\") (EQUALS ${limit} 100))").unwrap();
+ * assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+ * assert_eq!(ctx.layer_of("limit"), Some("defaults"));
+ * ```
+ */
+#[derive(Default)]
+pub struct LayeredContext<'a> {
+    layers: Vec<(String, Box<dyn VariableResolver + 'a>)>,
+}
+
+impl<'a> LayeredContext<'a> {
+    pub fn new() -> LayeredContext<'a> {
+        return LayeredContext::default();
+    }
+
+    // Add a layer below the ones added before
+    pub fn layer(
+        mut self,
+        name: impl Into<String>,
+        resolver: impl VariableResolver + 'a,
+    ) -> LayeredContext<'a> {
+        self.layers.push((name.into(), Box::new(resolver)));
+        return self;
+    }
+
+    // Name of the layer the context key is read from
+    pub fn layer_of(&self, name: &str) -> Option<&str> {
+        return self
+            .layers
+            .iter()
+            .find(|(_, resolver)| resolver.resolve(name).is_some())
+            .map(|(layer, _)| layer.as_str());
+    }
+
+    // Names of the layers, from the first one read
+    pub fn layers(&self) -> Vec<&str> {
+        return self.layers.iter().map(|(name, _)| name.as_str()).collect();
+    }
+}
+
+impl<'a> fmt::Debug for LayeredContext<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LayeredContext")
+            .field("layers", &self.layers())
+            .finish()
+    }
+}
+
+impl<'a> VariableResolver for LayeredContext<'a> {
+    fn resolve(&self, name: &str) -> Option<Value> {
+        return self
+            .layers
+            .iter()
+            .find_map(|(_, resolver)| resolver.resolve(name));
+    }
+
+    fn resolve_path(&self, path: &[PathSegment]) -> Option<(Value, usize)> {
+        return self
+            .layers
+            .iter()
+            .find_map(|(_, resolver)| resolver.resolve_path(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LayeredContext;
+    use crate::ast::Value;
+    use crate::rule::{Context, Rule};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_layered_context() {
+        let global: HashMap<String, Value> = HashMap::from([
+            ("limit".to_string(), Value::INT(100)),
+            ("country".to_string(), Value::from("unknown")),
+            ("beta".to_string(), Value::BOOL(false)),
+        ]);
+        let session = Context::builder()
+            .str("country", "FR")
+            .value(
+                "user",
+                Value::MAP(HashMap::from([("age".to_string(), Value::INT(20))])),
+            )
+            .build();
+        let request = Context::builder().str("country", "DE").build();
+        let ctx = LayeredContext::new()
+            .layer("request", &request)
+            .layer("session", session)
+            .layer("global", &global);
+        let cases = vec![
+            ("(EQUALS ${country} \"DE\")", Value::BOOL(true)),
+            ("(EQUALS ${limit} 100)", Value::BOOL(true)),
+            ("(GTE ${user.age} 18)", Value::BOOL(true)),
+            ("(NOT ${missing})", Value::BOOL(true)),
+            ("(LET ((limit 5)) (ADD ${limit} 1))", Value::INT(6)),
+        ];
+        for (source, expected) in cases {
+            let rule = Rule::compile(source).unwrap();
+            assert_eq!(rule.eval(&ctx).unwrap(), expected, "{}", source);
+        }
+        assert_eq!(ctx.layer_of("country"), Some("request"));
+        assert_eq!(ctx.layer_of("user"), Some("session"));
+        assert_eq!(ctx.layer_of("beta"), Some("global"));
+        assert_eq!(ctx.layer_of("missing"), None);
+        assert_eq!(ctx.layers(), vec!["request", "session", "global"]);
+
+        // A layered context is a layer as well
+        let mut flags = Context::new();
+        flags.insert("beta", true);
+        let outer = LayeredContext::new()
+            .layer("flags", &flags)
+            .layer("base", &ctx);
+        let rule = Rule::compile("(AND ${beta} (EQUALS ${country} \"DE\"))").unwrap();
+        assert_eq!(rule.eval(&outer).unwrap(), Value::BOOL(true));
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+    }
+}
@@ -1,2 +1,59 @@
+// The crate writes explicit returns and field: field initializers, and
+// names enum variants in upper case like the operators of the rules
+#![allow(
+    clippy::needless_return,
+    clippy::redundant_field_names,
+    clippy::upper_case_acronyms
+)]
+
+// The code #[derive(RuleContext)] generates names the crate as ::newlisp,
+// which is this crate in its own tests
+#[cfg(feature = "derive")]
+extern crate self as newlisp;
+
+pub mod analysis;
+pub mod ast;
+mod cel;
+mod check;
+pub mod env;
+pub mod function;
+mod index;
+mod json;
+pub mod layered;
+pub mod loader;
+mod mongo;
+mod optimize;
+mod regex;
+pub mod registry;
+pub mod report;
+pub mod rule;
+pub mod ruleset;
+pub mod sql;
+mod time;
 mod token;
-pub mod ast;
\ No newline at end of file
+mod vm;
+mod yaml;
+
+pub use analysis::Finding;
+pub use ast::{
+    AstError, Diagnostic, EvalOptions, Expr, ExprKind, OverflowPolicy, ParserOptions, PathSegment,
+    ToValue, Truthiness, Value, VariableResolver,
+};
+pub use check::{Schema, Type};
+pub use env::EnvResolver;
+pub use function::{Function, FunctionRegistry, Signature};
+pub use layered::LayeredContext;
+pub use loader::{LoadError, RuleLoader, SharedRuleSet};
+pub use registry::{Activation, Decision, Registry, RegistryError, RuleVersion};
+pub use report::{Report, RuleReport};
+pub use rule::{
+    validate, validate_with_options, BoundRule, CompileError, Context, ContextBuilder, EvalError,
+    Rule,
+};
+pub use ruleset::{Match, Metadata, RuleSet, RuleSetError, Strategy};
+pub use sql::{Dialect, Sql};
+pub use token::{ErrCode, Lexeme, Lexer, Span, Token, TokenTag};
+pub use vm::Bytecode;
+
+#[cfg(feature = "derive")]
+pub use newlisp_derive::RuleContext;
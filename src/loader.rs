@@ -0,0 +1,206 @@
+use crate::ruleset::{RuleSet, RuleSetError};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/**
+ * A rule set shared between threads and replaced as a whole, evaluations
+ * which loaded the old set go on with it while new ones get the new set.
+ * Clones share the same set.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SharedRuleSet {
+    current: Arc<RwLock<Arc<RuleSet>>>,
+}
+
+impl SharedRuleSet {
+    pub fn new(rules: RuleSet) -> SharedRuleSet {
+        SharedRuleSet {
+            current: Arc::new(RwLock::new(Arc::new(rules))),
+        }
+    }
+
+    pub fn load(&self) -> Arc<RuleSet> {
+        // The lock only guards the swap of the Arc, it is never poisoned
+        // by a failed evaluation
+        return self.current.read().unwrap().clone();
+    }
+
+    pub fn store(&self, rules: RuleSet) {
+        *self.current.write().unwrap() = Arc::new(rules);
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, io::Error),
+    Rules(RuleSetError),
+    #[cfg(feature = "notify")]
+    Watch(notify::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(path, e) => write!(f, "rule load failed, {}: {}", path.display(), e),
+            LoadError::Rules(e) => write!(f, "rule load failed, {}", e),
+            #[cfg(feature = "notify")]
+            LoadError::Watch(e) => write!(f, "rule watch failed, {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// Extension of the files holding one rule each
+const RULE_EXTENSION: &str = "rule";
+
+/**
+ * Compile the rules at the path, which is a `.rule` file or a directory
+ * of them. Every file holds one rule named by the file stem, the rules of
+ * a directory are in the order of their file names.
+ */
+pub fn load_rules(path: &Path) -> Result<RuleSet, LoadError> {
+    let io_error = |e| LoadError::Io(path.to_path_buf(), e);
+    let mut files = Vec::new();
+    if fs::metadata(path).map_err(io_error)?.is_dir() {
+        for entry in fs::read_dir(path).map_err(io_error)? {
+            let file = entry.map_err(io_error)?.path();
+            if file.extension().is_some_and(|ext| ext == RULE_EXTENSION) {
+                files.push(file);
+            }
+        }
+        files.sort();
+    } else {
+        files.push(path.to_path_buf());
+    }
+    let mut sources = Vec::with_capacity(files.len());
+    for file in files.iter() {
+        let name = file.file_stem().unwrap_or_default().to_string_lossy();
+        let source = fs::read_to_string(file).map_err(|e| LoadError::Io(file.clone(), e))?;
+        sources.push((name.to_string(), source));
+    }
+    return RuleSet::compile(
+        sources
+            .iter()
+            .map(|(name, source)| (name.as_str(), source.as_str())),
+    )
+    .map_err(LoadError::Rules);
+}
+
+/**
+ * Rules loaded from a path, see load_rules, and reloaded into the same
+ * shared set. A reload either replaces all the rules or, when any of them
+ * fails, keeps the current ones.
+ *
+ * With the notify feature, `watch` reloads the rules whenever the path
+ * changes.
+ */
+#[derive(Debug)]
+pub struct RuleLoader {
+    path: PathBuf,
+    rules: SharedRuleSet,
+}
+
+impl RuleLoader {
+    pub fn new(path: impl Into<PathBuf>) -> Result<RuleLoader, LoadError> {
+        let path = path.into();
+        let rules = load_rules(&path)?;
+        Ok(RuleLoader {
+            path: path,
+            rules: SharedRuleSet::new(rules),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    // Handle of the loaded rules, it sees every later reload
+    pub fn rules(&self) -> SharedRuleSet {
+        return self.rules.clone();
+    }
+
+    pub fn reload(&self) -> Result<(), LoadError> {
+        self.rules.store(load_rules(&self.path)?);
+        return Ok(());
+    }
+
+    /**
+     * Reload the rules on every change of the path until the returned
+     * watcher is dropped, reloads which fail are passed to on_error
+     */
+    #[cfg(feature = "notify")]
+    pub fn watch(
+        &self,
+        on_error: impl Fn(LoadError) + Send + 'static,
+    ) -> Result<notify::RecommendedWatcher, LoadError> {
+        use notify::Watcher;
+        let path = self.path.clone();
+        let rules = self.rules.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if event.kind.is_access() => {}
+                Ok(_) => match load_rules(&path) {
+                    Ok(loaded) => rules.store(loaded),
+                    Err(e) => on_error(e),
+                },
+                Err(e) => on_error(LoadError::Watch(e)),
+            })
+            .map_err(LoadError::Watch)?;
+        watcher
+            .watch(&self.path, notify::RecursiveMode::NonRecursive)
+            .map_err(LoadError::Watch)?;
+        return Ok(watcher);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoadError, RuleLoader};
+    use crate::rule::Context;
+    use crate::ruleset::RuleSetError;
+    use std::fs;
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("newlisp-loader-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b_adult.rule"), "(GTE ${age} 18)").unwrap();
+        fs::write(dir.join("a_minor.rule"), "(LT ${age} 18)").unwrap();
+        fs::write(dir.join("notes.txt"), "(NOT").unwrap();
+        let loader = RuleLoader::new(&dir).unwrap();
+        let rules = loader.rules();
+        let mut ctx = Context::new();
+        ctx.insert("age", 30);
+        let before = rules.load();
+        assert_eq!(
+            before.names().collect::<Vec<&str>>(),
+            vec!["a_minor", "b_adult"]
+        );
+        assert_eq!(before.first_match(&ctx).unwrap(), Some("b_adult"));
+
+        fs::write(dir.join("a_minor.rule"), "(LT ${age} 40)").unwrap();
+        loader.reload().unwrap();
+        assert_eq!(rules.load().first_match(&ctx).unwrap(), Some("a_minor"));
+        // A set loaded before the reload is not changed by it
+        assert_eq!(before.first_match(&ctx).unwrap(), Some("b_adult"));
+
+        fs::write(dir.join("c_broken.rule"), "(GT ${age}").unwrap();
+        match loader.reload().unwrap_err() {
+            LoadError::Rules(RuleSetError::Compile(errors)) => assert_eq!(errors[0].0, "c_broken"),
+            e => panic!("{}", e),
+        }
+        assert_eq!(rules.load().len(), 2);
+
+        let single = RuleLoader::new(dir.join("b_adult.rule")).unwrap();
+        assert_eq!(
+            single.rules().load().names().collect::<Vec<&str>>(),
+            vec!["b_adult"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(loader.reload(), Err(LoadError::Io(..))));
+    }
+}
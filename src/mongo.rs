@@ -0,0 +1,470 @@
+use crate::ast::{AstError, CompareOp, EachOp, Expr, ExprKind, ParserOptions, Var};
+use crate::json::{self, Json};
+use crate::time;
+use crate::token::{Lexer, Span, Symbols, TokenTag};
+
+// Converter of MongoDB filter documents, see Rule::compile_mongo
+struct Mongo<'a> {
+    options: ParserOptions,
+    symbols: &'a Symbols,
+}
+
+fn expr(kind: ExprKind) -> Expr {
+    return Expr::new(kind, Span::default());
+}
+
+// A single arg as it is, several args are AND-ed and none is true
+fn all(mut args: Vec<Expr>) -> Expr {
+    match args.len() {
+        0 => return expr(ExprKind::Bool(true)),
+        1 => return args.remove(0),
+        _ => return expr(ExprKind::And(args)),
+    }
+}
+
+fn not(arg: Expr) -> Expr {
+    return expr(ExprKind::Not(Box::new(arg)));
+}
+
+fn not_supported(what: String) -> AstError {
+    return AstError::NOT_SUPP_OPER(what);
+}
+
+// A document of operators like {"$gt": 18} rather than an embedded
+// document to compare with
+fn is_operators(doc: &Json) -> bool {
+    match doc {
+        Json::Object(entries) => entries.iter().any(|(key, _)| key.starts_with('$')),
+        _ => false,
+    }
+}
+
+impl<'a> Mongo<'a> {
+    fn enter(&self, depth: usize) -> Result<usize, AstError> {
+        if depth >= self.options.max_depth {
+            return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                "Query documents are nested deeper than {}",
+                self.options.max_depth
+            )));
+        }
+        return Ok(depth + 1);
+    }
+
+    // The entries of a query document all hold, $and, $or and $nor take
+    // arrays of query documents and the other keys are fields
+    fn query(&self, doc: &Json, prefix: &str, depth: usize) -> Result<Expr, AstError> {
+        let entries = match doc {
+            Json::Object(entries) => entries,
+            _ => {
+                return Err(AstError::FORMAT_NOT_MATCH(format!(
+                    "Expected a query document, but got {}",
+                    doc
+                )));
+            }
+        };
+        let depth = self.enter(depth)?;
+        let mut args = Vec::new();
+        for (key, val) in entries.iter() {
+            if !key.starts_with('$') {
+                args.push(self.field(&format!("{}{}", prefix, key), val, depth)?);
+                continue;
+            }
+            let docs = match (key.as_str(), val) {
+                ("$and", Json::Array(docs))
+                | ("$or", Json::Array(docs))
+                | ("$nor", Json::Array(docs))
+                    if !docs.is_empty() =>
+                {
+                    docs
+                }
+                ("$and", _) | ("$or", _) | ("$nor", _) => {
+                    return Err(AstError::FORMAT_NOT_MATCH(format!(
+                        "{} should have a non-empty array of query documents, but got {}",
+                        key, val
+                    )));
+                }
+                _ => {
+                    return Err(not_supported(format!(
+                        "Not supported query operator {}",
+                        key
+                    )))
+                }
+            };
+            self.check_args(key, docs.len())?;
+            let docs = docs
+                .iter()
+                .map(|doc| self.query(doc, prefix, depth))
+                .collect::<Result<Vec<Expr>, AstError>>()?;
+            args.push(match key.as_str() {
+                "$and" => all(docs),
+                "$or" if docs.len() == 1 => all(docs),
+                "$or" => expr(ExprKind::Or(docs)),
+                _ => not(expr(ExprKind::Or(docs))),
+            });
+        }
+        self.check_args("$and", args.len())?;
+        return Ok(all(args));
+    }
+
+    fn check_args(&self, op: &str, len: usize) -> Result<(), AstError> {
+        if len > self.options.max_args {
+            return Err(AstError::TOO_MANY_ARGS(format!(
+                "{} has more than {} arguments",
+                op, self.options.max_args
+            )));
+        }
+        return Ok(());
+    }
+
+    // items.0.price is ${items[0].price}
+    fn var(&self, path: &str) -> Result<Expr, AstError> {
+        let mut name = String::new();
+        for part in path.split('.') {
+            if !name.is_empty() && !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()) {
+                name.push_str(&format!("[{}]", part));
+                continue;
+            }
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(part);
+        }
+        let var = Var::create(&name, Span::default(), &self.options, self.symbols)
+            .map_err(|_| AstError::FORMAT_NOT_MATCH(format!("Field path {} is not valid", path)))?;
+        return Ok(expr(ExprKind::Var(var)));
+    }
+
+    // Scalars and {"$date": ..} of extended JSON, which is a TIMESTAMP
+    fn literal(&self, path: &str, val: &Json) -> Result<Expr, AstError> {
+        let kind = match val {
+            Json::Null => ExprKind::Null,
+            Json::Bool(b) => ExprKind::Bool(*b),
+            Json::Int(i) => ExprKind::Int(*i),
+            Json::Float(f) => ExprKind::Float(*f),
+            Json::Str(s) => ExprKind::Str(s.clone()),
+            Json::Object(entries) if entries.len() == 1 && entries[0].0 == "$date" => {
+                match &entries[0].1 {
+                    Json::Int(millis) => ExprKind::Timestamp(*millis),
+                    Json::Str(s) => ExprKind::Timestamp(time::parse_iso8601(s).map_err(|e| {
+                        AstError::FORMAT_NOT_MATCH(format!("Not a valid $date {}: {}", s, e))
+                    })?),
+                    other => {
+                        return Err(AstError::FORMAT_NOT_MATCH(format!(
+                            "Not a valid $date {}",
+                            other
+                        )));
+                    }
+                }
+            }
+            Json::Array(_) | Json::Object(_) => {
+                return Err(not_supported(format!(
+                    "Not supported value {} of {}, only scalars are compared",
+                    val, path
+                )));
+            }
+        };
+        return Ok(expr(kind));
+    }
+
+    fn literals(&self, path: &str, op: &str, val: &Json) -> Result<Vec<Expr>, AstError> {
+        match val {
+            Json::Array(items) => {
+                self.check_args(op, items.len())?;
+                return items.iter().map(|item| self.literal(path, item)).collect();
+            }
+            _ => {
+                return Err(AstError::FORMAT_NOT_MATCH(format!(
+                    "{} of {} should have an array, but got {}",
+                    op, path, val
+                )));
+            }
+        }
+    }
+
+    fn field(&self, path: &str, val: &Json, depth: usize) -> Result<Expr, AstError> {
+        let entries = match val {
+            Json::Object(entries) if is_operators(val) && val.get("$date").is_none() => entries,
+            _ => {
+                let args = vec![self.var(path)?, self.literal(path, val)?];
+                return Ok(expr(ExprKind::Equals(args)));
+            }
+        };
+        let mut args = Vec::new();
+        for (op, arg) in entries.iter() {
+            match op.as_str() {
+                "$regex" => {
+                    let pattern = match arg {
+                        Json::Str(pattern) => pattern,
+                        _ => {
+                            return Err(AstError::FORMAT_NOT_MATCH(format!(
+                                "$regex of {} should be a string, but got {}",
+                                path, arg
+                            )));
+                        }
+                    };
+                    match val.get("$options") {
+                        None => {}
+                        Some(Json::Str(options)) if options.is_empty() => {}
+                        Some(options) => {
+                            return Err(not_supported(format!(
+                                "Not supported $options {} of {}",
+                                options, path
+                            )));
+                        }
+                    }
+                    let kind = Expr::create_match(TokenTag::MATCH, self.var(path)?, pattern)?;
+                    args.push(expr(kind));
+                }
+                "$options" if val.get("$regex").is_some() => {}
+                _ => args.push(self.operator(path, op, arg, depth)?),
+            }
+        }
+        return Ok(all(args));
+    }
+
+    fn operator(&self, path: &str, op: &str, arg: &Json, depth: usize) -> Result<Expr, AstError> {
+        let var = self.var(path)?;
+        let compare = |compare: CompareOp| -> Result<Expr, AstError> {
+            let literal = self.literal(path, arg)?;
+            let kind = ExprKind::Compare(compare, Box::new(var.clone()), Box::new(literal));
+            return Ok(expr(kind));
+        };
+        match op {
+            "$eq" | "$ne" => {
+                let equals = expr(ExprKind::Equals(vec![var, self.literal(path, arg)?]));
+                return Ok(if op == "$eq" { equals } else { not(equals) });
+            }
+            "$gt" => return compare(CompareOp::GT),
+            "$gte" => return compare(CompareOp::GTE),
+            "$lt" => return compare(CompareOp::LT),
+            "$lte" => return compare(CompareOp::LTE),
+            "$in" | "$nin" => {
+                let values = self.literals(path, op, arg)?;
+                // Nothing is in an empty array
+                let found = match values.is_empty() {
+                    true => expr(ExprKind::Bool(false)),
+                    false => expr(ExprKind::In(vec![var].into_iter().chain(values).collect())),
+                };
+                return Ok(if op == "$in" { found } else { not(found) });
+            }
+            "$all" => {
+                let values = self.literals(path, op, arg)?;
+                if values.is_empty() {
+                    return Ok(expr(ExprKind::Bool(false)));
+                }
+                let args = values
+                    .into_iter()
+                    .map(|val| expr(ExprKind::In(vec![val, var.clone()])))
+                    .collect();
+                return Ok(all(args));
+            }
+            "$exists" => {
+                let exists = match arg {
+                    Json::Bool(exists) => *exists,
+                    _ => {
+                        return Err(AstError::FORMAT_NOT_MATCH(format!(
+                            "$exists of {} should be true or false, but got {}",
+                            path, arg
+                        )));
+                    }
+                };
+                let null = expr(ExprKind::Equals(vec![var, expr(ExprKind::Null)]));
+                return Ok(if exists { not(null) } else { null });
+            }
+            "$size" => {
+                let len = expr(ExprKind::Len(Box::new(var)));
+                return Ok(expr(ExprKind::Equals(vec![len, self.literal(path, arg)?])));
+            }
+            "$mod" => match arg {
+                Json::Array(items) if items.len() == 2 => {
+                    let (divisor, remainder) = (self.literal(path, &items[0])?, &items[1]);
+                    let kind = ExprKind::Mod(Box::new(var), Box::new(divisor));
+                    let args = vec![expr(kind), self.literal(path, remainder)?];
+                    return Ok(expr(ExprKind::Equals(args)));
+                }
+                _ => {
+                    return Err(AstError::FORMAT_NOT_MATCH(format!(
+                        "$mod of {} should have [divisor, remainder], but got {}",
+                        path, arg
+                    )));
+                }
+            },
+            "$not" if is_operators(arg) => {
+                let depth = self.enter(depth)?;
+                return Ok(not(self.field(path, arg, depth)?));
+            }
+            // Fields of the element are fields of ${item}, operators apply
+            // to the element itself
+            "$elemMatch" => {
+                let depth = self.enter(depth)?;
+                let predicate = match is_operators(arg) {
+                    true => self.field("item", arg, depth)?,
+                    false => self.query(arg, "item.", depth)?,
+                };
+                let kind = ExprKind::Each(EachOp::ANY, Box::new(var), Box::new(predicate));
+                return Ok(expr(kind));
+            }
+            "$not" => {
+                return Err(AstError::FORMAT_NOT_MATCH(format!(
+                    "$not of {} should have a document of operators, but got {}",
+                    path, arg
+                )));
+            }
+            _ => {
+                return Err(not_supported(format!(
+                    "Not supported operator {} of {}",
+                    op, path
+                )));
+            }
+        }
+    }
+}
+
+/**
+ * Expression of a MongoDB filter document, see Rule::compile_mongo
+ */
+pub(crate) fn parse_mongo(source: &str, options: &ParserOptions) -> Result<Expr, AstError> {
+    let doc = json::parse(source)
+        .map_err(|e| AstError::FORMAT_NOT_MATCH(format!("Not a valid query document: {}", e)))?;
+    let lexer = Lexer::create(String::new())
+        .map_err(|_| AstError::LEXER_FAILED("Lexer init failed!".to_string()))?;
+    // A missing field is null in MongoDB, {"email": null} matches it
+    let mongo = Mongo {
+        options: ParserOptions {
+            missing_as_null: true,
+            ..options.clone()
+        },
+        symbols: lexer.symbols(),
+    };
+    return mongo.query(&doc, "", 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{AstError, ParserOptions, Value};
+    use crate::rule::{Context, Rule};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_compile_mongo() {
+        let cases = vec![
+            (
+                r#"{"age": {"$gt": 18}, "tags": {"$in": ["a", "b"]}}"#,
+                "(AND (GT ${age} 18) (IN ${tags} \"a\" \"b\"))",
+            ),
+            (r#"{"country": "DE"}"#, "(EQUALS ${country} \"DE\")"),
+            (
+                r#"{"$or": [{"tier": {"$gte": 2, "$lt": 5}}, {"vip": true}], "name": {"$ne": null}}"#,
+                "(AND (OR (AND (GTE ${tier} 2) (LT ${tier} 5)) (EQUALS ${vip} true)) (NOT (EQUALS ${name} NULL)))",
+            ),
+            (
+                r#"{"$nor": [{"a": 1}], "$and": [{"b": {"$nin": [1, 2.5]}}]}"#,
+                "(AND (NOT (OR (EQUALS ${a} 1))) (NOT (IN ${b} 1 2.5)))",
+            ),
+            (
+                r#"{"user.items.0.price": {"$lte": 10}, "email": {"$exists": false}}"#,
+                "(AND (LTE ${user.items[0].price} 10) (EQUALS ${email} NULL))",
+            ),
+            (
+                r#"{"name": {"$regex": "^B", "$options": ""}, "n": {"$not": {"$mod": [3, 1]}}}"#,
+                "(AND (MATCH ${name} \"^B\") (NOT (EQUALS (MOD ${n} 3) 1)))",
+            ),
+            (
+                r#"{"tags": {"$all": ["x", "y"], "$size": 2}}"#,
+                "(AND (AND (IN \"x\" ${tags}) (IN \"y\" ${tags})) (EQUALS (LEN ${tags}) 2))",
+            ),
+            (
+                r#"{"orders": {"$elemMatch": {"amount": {"$gt": 100}, "country": "FR"}}}"#,
+                "(ANY ${orders} (AND (GT ${item.amount} 100) (EQUALS ${item.country} \"FR\")))",
+            ),
+            (
+                r#"{"scores": {"$elemMatch": {"$gte": 90}}, "ids": {"$in": []}}"#,
+                "(AND (ANY ${scores} (GTE ${item} 90)) false)",
+            ),
+            (
+                r#"{"created": {"$gte": {"$date": "2024-01-31T00:00:00Z"}}}"#,
+                "(GTE ${created} 2024-01-31T00:00:00Z)",
+            ),
+            ("{}", "true"),
+        ];
+        for (doc, sexpr) in cases {
+            let rule = Rule::compile_mongo(doc).unwrap();
+            assert_eq!(rule.expr().to_sexpr(), sexpr, "{}", doc);
+            assert_eq!(rule.source(), doc);
+        }
+
+        let rule = Rule::compile_mongo(
+            r#"{"age": {"$gt": 18}, "tags": {"$all": ["a"]}, "email": null, "vip": {"$ne": true}}"#,
+        )
+        .unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("age", 20);
+        ctx.insert("tags", vec![Value::from("a"), Value::from("b")]);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+        ctx.insert("email", "a@x.io");
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+
+        let rule =
+            Rule::compile_mongo(r#"{"orders": {"$elemMatch": {"amount": {"$gt": 100}}}}"#).unwrap();
+        let order =
+            |amount: i64| Value::MAP(HashMap::from([("amount".to_string(), Value::INT(amount))]));
+        ctx.insert("orders", vec![order(20), order(150)]);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+
+        let errors = vec![
+            (
+                r#"{"age": {"$gt": 18}"#,
+                "FORMAT_NOT_MATCH: Not a valid query document: ",
+            ),
+            ("[1]", "FORMAT_NOT_MATCH: Expected a query document, but got [1]"),
+            (
+                r#"{"$where": "this.a > 1"}"#,
+                "NOT_SUPP_OPER: Not supported query operator $where",
+            ),
+            (
+                r#"{"a": {"$near": [1, 2]}}"#,
+                "NOT_SUPP_OPER: Not supported operator $near of a",
+            ),
+            (
+                r#"{"a": {"$in": 1}}"#,
+                "FORMAT_NOT_MATCH: $in of a should have an array, but got 1",
+            ),
+            (
+                r#"{"a": [1, 2]}"#,
+                "NOT_SUPP_OPER: Not supported value [1,2] of a, only scalars are compared",
+            ),
+            (
+                r#"{"$or": []}"#,
+                "FORMAT_NOT_MATCH: $or should have a non-empty array of query documents, but got []",
+            ),
+            (
+                r#"{"name": {"$regex": "^b", "$options": "i"}}"#,
+                "NOT_SUPP_OPER: Not supported $options \"i\" of name",
+            ),
+            (
+                r#"{"a..b": 1}"#,
+                "FORMAT_NOT_MATCH: Field path a..b is not valid",
+            ),
+        ];
+        for (doc, expected) in errors {
+            let err = Rule::compile_mongo(doc).unwrap_err();
+            assert!(
+                err.0.to_string().starts_with(expected),
+                "{}: {}",
+                doc,
+                err.0
+            );
+        }
+        let options = ParserOptions {
+            max_depth: 3,
+            ..ParserOptions::default()
+        };
+        let nested = r#"{"$and": [{"$and": [{"$and": [{"a": 1}]}]}]}"#;
+        assert!(matches!(
+            Rule::compile_mongo_with_options(nested, options)
+                .unwrap_err()
+                .0,
+            AstError::MAX_DEPTH_EXCEEDED(_)
+        ));
+    }
+}
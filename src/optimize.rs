@@ -0,0 +1,137 @@
+use crate::ast::{EvalOptions, Expr, ExprKind, Truthiness, Value};
+use crate::check::{static_type, Type};
+use std::collections::HashMap;
+
+// Node of a literal with the value, LIST, MAP and LAMBDA have no literal
+fn literal(val: Value) -> Option<ExprKind> {
+    match val {
+        Value::INT(i) => Some(ExprKind::Int(i)),
+        Value::FLOAT(f) => Some(ExprKind::Float(f)),
+        Value::BOOL(b) => Some(ExprKind::Bool(b)),
+        Value::STR(s) => Some(ExprKind::Str(s)),
+        Value::NULL => Some(ExprKind::Null),
+        Value::BYTES(bytes) => Some(ExprKind::Bytes(bytes)),
+        Value::TIMESTAMP(millis) => Some(ExprKind::Timestamp(millis)),
+        Value::LIST(_) | Value::MAP(_) | Value::LAMBDA(_) => None,
+    }
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Str(_)
+            | ExprKind::Null
+            | ExprKind::Bytes(_)
+            | ExprKind::Timestamp(_)
+    )
+}
+
+// Value of a node whose args are all literals, when it is the same with
+// every truthiness policy. Nodes which fail are kept so that they still
+// fail at eval.
+fn constant(expr: &Expr) -> Option<Value> {
+    if is_literal(expr)
+        || matches!(
+            expr.kind,
+            ExprKind::Now | ExprKind::Var(_) | ExprKind::Call(..)
+        )
+    {
+        return None;
+    }
+    if !expr.children().into_iter().all(is_literal) {
+        return None;
+    }
+    let ctx: HashMap<String, Value> = HashMap::new();
+    let lenient = expr.eval(&ctx).ok()?;
+    let strict = EvalOptions {
+        truthiness: Truthiness::StrictBool,
+    };
+    match expr.eval_with_options(&ctx, &strict) {
+        Ok(val) if val == lenient => Some(lenient),
+        _ => None,
+    }
+}
+
+// AND without its true args and OR without its false args, a leading
+// false or true decides the result. `stop` is the arg value which decides.
+fn fold_logic(args: &mut Vec<Expr>, stop: bool) -> Option<ExprKind> {
+    args.retain(|arg| arg.kind != ExprKind::Bool(!stop));
+    match args.first() {
+        None => return Some(ExprKind::Bool(!stop)),
+        Some(first) if first.kind == ExprKind::Bool(stop) => return Some(ExprKind::Bool(stop)),
+        // A single arg is only the result when it is already a BOOL
+        Some(first) if args.len() == 1 && static_type(first) == Some(Type::BOOL) => {
+            return Some(args.remove(0).kind);
+        }
+        _ => return None,
+    }
+}
+
+impl Expr {
+    /**
+     * Pre-evaluate the constant parts of the expression, like (MOD 10 3)
+     * which becomes 1 and (AND true X) which becomes X when X is a BOOL.
+     * The optimized expression evals to the same value as before.
+     */
+    pub fn optimize(&mut self) {
+        self.walk_mut(&mut |expr| {
+            let folded = match &mut expr.kind {
+                ExprKind::And(args) => fold_logic(args, false),
+                ExprKind::Or(args) => fold_logic(args, true),
+                _ => None,
+            };
+            if let Some(kind) = folded {
+                expr.kind = kind;
+            }
+            if let Some(kind) = constant(expr).and_then(literal) {
+                expr.kind = kind;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{Parser, Value};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_optimize() {
+        let cases = vec![
+            ("(MOD 10 3)", "1"),
+            ("(AND true (GT ${age} 18))", "(GT ${age} 18)"),
+            ("(AND true ${flag})", "(AND ${flag})"),
+            ("(AND (EQUALS (MOD 10 3) 1) true)", "true"),
+            ("(OR false (LT ${age} (MUL 6 3)) false)", "(LT ${age} 18)"),
+            ("(OR (GT ${age} 1) (NOT false))", "(OR (GT ${age} 1) true)"),
+            ("(AND false (GT ${age} 18))", "false"),
+            (
+                "(CONCAT \"a\" (UPPER \"b\") ${x})",
+                "(CONCAT \"a\" \"B\" ${x})",
+            ),
+            ("(AND 1 ${flag})", "(AND 1 ${flag})"),
+            ("(MOD 1 0)", "(MOD 1 0)"),
+            ("(SPLIT \"a,b\" \",\")", "(SPLIT \"a,b\" \",\")"),
+            ("(GT (NOW) 2024-01-01)", "(GT (NOW) 2024-01-01T00:00:00Z)"),
+        ];
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), Value::INT(30));
+        kv.insert("flag".to_string(), Value::INT(2));
+        kv.insert("x".to_string(), Value::from("c"));
+        for (rule, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            let expr = p.parse().unwrap();
+            let mut optimized = expr.clone();
+            optimized.optimize();
+            assert_eq!(optimized.to_sexpr(), expected, "{}", rule);
+            match (expr.eval(&kv), optimized.eval(&kv)) {
+                (Ok(val), Ok(folded)) => assert_eq!(val, folded, "{}", rule),
+                (Err(_), Err(_)) => {}
+                (val, folded) => panic!("{}: {:?} and {:?}", rule, val, folded),
+            }
+        }
+    }
+}
@@ -0,0 +1,429 @@
+/**
+ * A small backtracking regex engine used by the MATCH operator.
+ *
+ * Supported syntax: literals, `.`, `^`, `$`, groups `(...)`, alternation `|`,
+ * classes `[a-z]` / `[^0-9]`, escapes `\d \w \s \D \W \S` and escaped
+ * meta chars, and greedy quantifiers `* + ? {n} {n,} {n,m}`.
+ * Patterns are matched anywhere in the input unless anchored.
+ */
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Char(ch) => c == ch,
+            ClassItem::Range(low, high) => low <= c && c <= high,
+            ClassItem::Digit(positive) => c.is_ascii_digit() == positive,
+            ClassItem::Word(positive) => (c.is_alphanumeric() || c == '_') == positive,
+            ClassItem::Space(positive) => c.is_whitespace() == positive,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    pattern: String,
+    alts: Vec<Vec<Node>>,
+}
+
+struct Compiler {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Compiler {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn alternation(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alts = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut seq = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            seq.push(self.quantifier(atom)?);
+        }
+        Ok(seq)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let index = self.pos;
+        match self.next() {
+            Some('.') => Ok(Node::Any),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('(') => {
+                let alts = self.alternation()?;
+                if self.next() != Some(')') {
+                    return Err(format!("unclosed group opened at {}", index));
+                }
+                Ok(Node::Group(alts))
+            }
+            Some('[') => self.class(index),
+            Some('\\') => match self.escape()? {
+                ClassItem::Char(c) => Ok(Node::Char(c)),
+                item => Ok(Node::Class(vec![item], false)),
+            },
+            Some(c) if c == '*' || c == '+' || c == '?' || c == '{' => {
+                Err(format!("nothing to repeat at {}", index))
+            }
+            Some(c) => Ok(Node::Char(c)),
+            None => Err("unexpected end of pattern".to_string()),
+        }
+    }
+
+    fn escape(&mut self) -> Result<ClassItem, String> {
+        match self.next() {
+            Some('d') => Ok(ClassItem::Digit(true)),
+            Some('D') => Ok(ClassItem::Digit(false)),
+            Some('w') => Ok(ClassItem::Word(true)),
+            Some('W') => Ok(ClassItem::Word(false)),
+            Some('s') => Ok(ClassItem::Space(true)),
+            Some('S') => Ok(ClassItem::Space(false)),
+            Some('n') => Ok(ClassItem::Char('\n')),
+            Some('t') => Ok(ClassItem::Char('\t')),
+            Some(c) if !c.is_alphanumeric() => Ok(ClassItem::Char(c)),
+            Some(c) => Err(format!("unknown escape \\{} at {}", c, self.pos - 2)),
+            None => Err("pattern ends with a dangling \\".to_string()),
+        }
+    }
+
+    fn class(&mut self, index: usize) -> Result<Node, String> {
+        let mut items = Vec::new();
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        loop {
+            let item = match self.next() {
+                Some(']') if !items.is_empty() => return Ok(Node::Class(items, negated)),
+                Some('\\') => self.escape()?,
+                Some(c) => ClassItem::Char(c),
+                None => return Err(format!("unclosed class opened at {}", index)),
+            };
+            if let ClassItem::Char(low) = item {
+                if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                    self.pos += 1;
+                    let high = match self.next() {
+                        Some('\\') => match self.escape()? {
+                            ClassItem::Char(c) => c,
+                            _ => return Err(format!("bad class range at {}", self.pos)),
+                        },
+                        Some(c) => c,
+                        None => return Err(format!("unclosed class opened at {}", index)),
+                    };
+                    if high < low {
+                        return Err(format!("bad class range {}-{}", low, high));
+                    }
+                    items.push(ClassItem::Range(low, high));
+                    continue;
+                }
+            }
+            items.push(item);
+        }
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => return self.counted(atom),
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    fn counted(&mut self, atom: Node) -> Result<Node, String> {
+        let index = self.pos;
+        self.pos += 1;
+        let mut body = String::new();
+        loop {
+            match self.next() {
+                Some('}') => break,
+                Some(c) => body.push(c),
+                None => return Err(format!("unclosed counted repetition at {}", index)),
+            }
+        }
+        let bad = || format!("bad counted repetition {{{}}} at {}", body, index);
+        let (min, max) = match body.find(',') {
+            None => {
+                let n = body.parse::<usize>().map_err(|_| bad())?;
+                (n, Some(n))
+            }
+            Some(i) => {
+                let min = body[..i].parse::<usize>().map_err(|_| bad())?;
+                let max = if body[i + 1..].is_empty() {
+                    None
+                } else {
+                    Some(body[i + 1..].parse::<usize>().map_err(|_| bad())?)
+                };
+                (min, max)
+            }
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(bad());
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+}
+
+fn match_seq(seq: &[Node], input: &[char], pos: usize, k: &dyn Fn(usize) -> bool) -> bool {
+    match seq.split_first() {
+        None => k(pos),
+        Some((node, rest)) => match_node(node, input, pos, &|p| match_seq(rest, input, p, k)),
+    }
+}
+
+fn match_node(node: &Node, input: &[char], pos: usize, k: &dyn Fn(usize) -> bool) -> bool {
+    match node {
+        Node::Char(c) => input.get(pos) == Some(c) && k(pos + 1),
+        Node::Any => pos < input.len() && input[pos] != '\n' && k(pos + 1),
+        Node::Class(items, negated) => match input.get(pos) {
+            Some(c) => items.iter().any(|item| item.matches(*c)) != *negated && k(pos + 1),
+            None => false,
+        },
+        Node::Start => pos == 0 && k(pos),
+        Node::End => pos == input.len() && k(pos),
+        Node::Group(alts) => alts.iter().any(|seq| match_seq(seq, input, pos, k)),
+        Node::Repeat(node, min, max) => match_repeat(node, *min, *max, 0, input, pos, k),
+    }
+}
+
+fn match_repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    input: &[char],
+    pos: usize,
+    k: &dyn Fn(usize) -> bool,
+) -> bool {
+    if max.is_none_or(|max| count < max) {
+        let more = match_node(node, input, pos, &|p| {
+            // an empty iteration can not make progress, stop repeating
+            if p == pos && count >= min {
+                return false;
+            }
+            match_repeat(node, min, max, count + 1, input, p, k)
+        });
+        if more {
+            return true;
+        }
+    }
+    count >= min && k(pos)
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut compiler = Compiler {
+            chars: pattern.chars().collect(),
+            pos: 0,
+        };
+        let alts = compiler.alternation()?;
+        if compiler.pos < compiler.chars.len() {
+            return Err(format!("unmatched ) at {}", compiler.pos));
+        }
+        Ok(Regex {
+            pattern: pattern.to_string(),
+            alts: alts,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        let input: Vec<char> = s.chars().collect();
+        (0..=input.len()).any(|start| {
+            self.alts
+                .iter()
+                .any(|seq| match_seq(seq, &input, start, &|_| true))
+        })
+    }
+}
+
+/**
+ * SQL LIKE pattern: `%` matches any sequence of chars, `_` matches exactly
+ * one char, and `\%` / `\_` / `\\` match the char itself.
+ */
+#[derive(Debug, Clone)]
+pub struct LikePattern {
+    pattern: String,
+    parts: Vec<LikePart>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum LikePart {
+    Char(char),
+    One,
+    Any,
+}
+
+impl LikePattern {
+    pub fn new(pattern: &str) -> Result<LikePattern, String> {
+        let mut parts = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '%' => {
+                    // a run of % is the same as a single one
+                    if parts.last() != Some(&LikePart::Any) {
+                        parts.push(LikePart::Any);
+                    }
+                }
+                '_' => parts.push(LikePart::One),
+                '\\' => match chars.next() {
+                    Some(c) if c == '%' || c == '_' || c == '\\' => parts.push(LikePart::Char(c)),
+                    Some(c) => return Err(format!("unknown escape \\{} in like pattern", c)),
+                    None => return Err("like pattern ends with a dangling \\".to_string()),
+                },
+                c => parts.push(LikePart::Char(c)),
+            }
+        }
+        Ok(LikePattern {
+            pattern: pattern.to_string(),
+            parts: parts,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    // Greedy wildcard matching, only the last % is backtracked so the
+    // cost stays linear in practice
+    pub fn is_match(&self, s: &str) -> bool {
+        let input: Vec<char> = s.chars().collect();
+        let (mut p, mut i) = (0, 0);
+        let mut star: Option<(usize, usize)> = None;
+        while i < input.len() {
+            match self.parts.get(p) {
+                Some(LikePart::Any) => {
+                    star = Some((p, i));
+                    p += 1;
+                }
+                Some(LikePart::One) => {
+                    p += 1;
+                    i += 1;
+                }
+                Some(LikePart::Char(c)) if *c == input[i] => {
+                    p += 1;
+                    i += 1;
+                }
+                _ => match star {
+                    Some((star_p, star_i)) => {
+                        p = star_p + 1;
+                        i = star_i + 1;
+                        star = Some((star_p, star_i + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.parts[p..].iter().all(|part| *part == LikePart::Any)
+    }
+}
+
+// Patterns compiled from the same text match the same strings
+impl PartialEq for Regex {
+    fn eq(&self, other: &Regex) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl PartialEq for LikePattern {
+    fn eq(&self, other: &LikePattern) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LikePattern, Regex};
+
+    #[test]
+    fn test_regex_match() {
+        let cases = vec![
+            ("^[a-z]+\\d{3}$", "abc123", true),
+            ("^[a-z]+\\d{3}$", "abc1234", false),
+            ("^[a-z]+\\d{3}$", "ABC123", false),
+            ("admin", "/api/admin/users", true),
+            ("^(foo|bar)+baz$", "foobarfoobaz", true),
+            ("^a.c$", "abc", true),
+            ("^a.c$", "ac", false),
+            ("^[^0-9]*$", "abc", true),
+            ("^[^0-9]*$", "ab1c", false),
+            ("colou?r", "color", true),
+            ("^\\w+@\\w+\\.com$", "me@example.com", true),
+            ("^(a*)*b$", "aaaaab", true),
+            ("^x{2,}$", "x", false),
+            ("^x{2,}$", "xxxx", true),
+        ];
+        for (pattern, input, expected) in cases {
+            let regex = Regex::new(pattern).unwrap();
+            assert_eq!(regex.is_match(input), expected, "{} on {}", pattern, input);
+        }
+        for pattern in ["(abc", "abc)", "[a-", "*a", "a{3,1}", "\\q"] {
+            assert!(Regex::new(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_like_match() {
+        let cases = vec![
+            ("/api/%/health", "/api/v1/health", true),
+            ("/api/%/health", "/api/v1/users/health", true),
+            ("/api/%/health", "/api/health", false),
+            ("a_c", "abc", true),
+            ("a_c", "abbc", false),
+            ("%", "", true),
+            ("%abc%", "xxabcxx", true),
+            ("100\\%", "100%", true),
+            ("100\\%", "1000", false),
+            ("%a%b%c", "aXbXcXc", true),
+        ];
+        for (pattern, input, expected) in cases {
+            let like = LikePattern::new(pattern).unwrap();
+            assert_eq!(like.is_match(input), expected, "{} on {}", pattern, input);
+        }
+        assert!(LikePattern::new("abc\\").is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use crate::ast::{Value, VariableResolver};
+use crate::rule::{CompileError, EvalError, Rule};
+use crate::time;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/**
+ * A compiled version of a named rule, versions of a rule are numbered from
+ * 1 in the order they are published
+ */
+#[derive(Debug, Clone)]
+pub struct RuleVersion {
+    pub version: u64,
+    pub rule: Arc<Rule>,
+    // Milliseconds since the Unix epoch
+    pub published_at: i64,
+}
+
+/**
+ * A version became the active one of its rule at the time, in milliseconds
+ * since the Unix epoch. The source is kept after the version itself is
+ * dropped from the registry.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Activation {
+    pub version: u64,
+    pub source: String,
+    pub at: i64,
+}
+
+/**
+ * Value of a rule and the version of the rule which produced it
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub value: Value,
+    pub version: u64,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    UnknownRule(String),
+    UnknownVersion(String, u64),
+    NothingToRollBack(String),
+    Eval(EvalError),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::UnknownRule(name) => write!(f, "rule {} has no active version", name),
+            RegistryError::UnknownVersion(name, version) => {
+                write!(f, "rule {} has no version {}", name, version)
+            }
+            RegistryError::NothingToRollBack(name) => {
+                write!(f, "rule {} has no earlier version to roll back to", name)
+            }
+            RegistryError::Eval(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+#[derive(Debug, Default)]
+struct History {
+    // Versions kept, oldest first
+    versions: Vec<RuleVersion>,
+    active: Option<u64>,
+    // Versions which were active before, the latest last
+    previous: Vec<u64>,
+    activations: Vec<Activation>,
+    published: u64,
+}
+
+impl History {
+    fn get(&self, version: u64) -> Option<&RuleVersion> {
+        return self.versions.iter().find(|v| v.version == version);
+    }
+
+    fn activate(&mut self, version: u64) {
+        let source = self.get(version).map(|v| v.rule.source().to_string());
+        self.active = Some(version);
+        self.activations.push(Activation {
+            version: version,
+            source: source.unwrap_or_default(),
+            at: time::now_millis(),
+        });
+    }
+}
+
+/**
+ * Named rules with the last versions of each, evaluations use the active
+ * version of a rule. A version is published first and becomes active when
+ * it is promoted, a rollback makes the version active before it active
+ * again. Every activation is recorded so that the rule text active at any
+ * time can be told.
+ *
+ * ```
+ * use newlisp::{Context, Registry, Value};
+ *
+ * let registry = Registry::new(5);
+ * let v1 = registry.publish("adult", "(GTE ${age} 18)").unwrap();
+ * registry.promote("adult", v1).unwrap();
+ * let v2 = registry.publish("adult", "(GTE ${age} 21)").unwrap();
+ * registry.promote("adult", v2).unwrap();
+ * let mut ctx = Context::new();
+ * ctx.insert("age", 20);
+ * assert_eq!(registry.eval("adult", &ctx).unwrap().value, Value::BOOL(false));
+ * registry.rollback("adult").unwrap();
+ * let decision = registry.eval("adult", &ctx).unwrap();
+ * assert_eq!((decision.value, decision.version), (Value::BOOL(true), v1));
+ * ```
+ */
+#[derive(Debug)]
+pub struct Registry {
+    // Versions kept of each rule, the active one is never dropped
+    keep: usize,
+    rules: RwLock<HashMap<String, History>>,
+}
+
+impl Registry {
+    pub fn new(keep: usize) -> Registry {
+        Registry {
+            keep: keep.max(1),
+            rules: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Compile the source as the next version of the rule, the oldest
+     * versions which are not active are dropped past the count to keep
+     */
+    pub fn publish(&self, name: &str, source: &str) -> Result<u64, CompileError> {
+        let rule = Rule::compile(source)?;
+        let mut rules = self.rules.write().unwrap();
+        let history = rules.entry(name.to_string()).or_default();
+        history.published += 1;
+        let version = history.published;
+        history.versions.push(RuleVersion {
+            version: version,
+            rule: Arc::new(rule),
+            published_at: time::now_millis(),
+        });
+        while history.versions.len() > self.keep {
+            let active = history.active;
+            match history
+                .versions
+                .iter()
+                .position(|v| Some(v.version) != active)
+            {
+                Some(at) => history.versions.remove(at),
+                None => break,
+            };
+        }
+        return Ok(version);
+    }
+
+    pub fn promote(&self, name: &str, version: u64) -> Result<(), RegistryError> {
+        let mut rules = self.rules.write().unwrap();
+        let history = match rules.get_mut(name) {
+            Some(history) if history.get(version).is_some() => history,
+            _ => return Err(RegistryError::UnknownVersion(name.to_string(), version)),
+        };
+        if history.active == Some(version) {
+            return Ok(());
+        }
+        if let Some(active) = history.active {
+            history.previous.push(active);
+        }
+        history.activate(version);
+        return Ok(());
+    }
+
+    // Make the version active before the active one active again, and
+    // tell which version it is
+    pub fn rollback(&self, name: &str) -> Result<u64, RegistryError> {
+        let mut rules = self.rules.write().unwrap();
+        let history = match rules.get_mut(name) {
+            Some(history) => history,
+            None => return Err(RegistryError::UnknownRule(name.to_string())),
+        };
+        while let Some(version) = history.previous.pop() {
+            // Versions dropped since they were active are skipped
+            if history.get(version).is_some() {
+                history.activate(version);
+                return Ok(version);
+            }
+        }
+        return Err(RegistryError::NothingToRollBack(name.to_string()));
+    }
+
+    pub fn active(&self, name: &str) -> Option<RuleVersion> {
+        let rules = self.rules.read().unwrap();
+        let history = rules.get(name)?;
+        return history.get(history.active?).cloned();
+    }
+
+    // Versions of the rule kept, oldest first
+    pub fn versions(&self, name: &str) -> Vec<u64> {
+        let rules = self.rules.read().unwrap();
+        match rules.get(name) {
+            Some(history) => return history.versions.iter().map(|v| v.version).collect(),
+            None => return Vec::new(),
+        }
+    }
+
+    pub fn activations(&self, name: &str) -> Vec<Activation> {
+        let rules = self.rules.read().unwrap();
+        match rules.get(name) {
+            Some(history) => return history.activations.clone(),
+            None => return Vec::new(),
+        }
+    }
+
+    // The activation of the rule which was in effect at the time, None
+    // before the first one
+    pub fn active_at(&self, name: &str, at: i64) -> Option<Activation> {
+        let rules = self.rules.read().unwrap();
+        let activations = &rules.get(name)?.activations;
+        return activations.iter().rev().find(|a| a.at <= at).cloned();
+    }
+
+    /**
+     * Eval the active version of the rule, a promote or rollback while it
+     * is evaluated does not change the version which is used
+     */
+    pub fn eval(&self, name: &str, ctx: &dyn VariableResolver) -> Result<Decision, RegistryError> {
+        let active = match self.active(name) {
+            Some(active) => active,
+            None => return Err(RegistryError::UnknownRule(name.to_string())),
+        };
+        let value = active.rule.eval(ctx).map_err(RegistryError::Eval)?;
+        Ok(Decision {
+            value: value,
+            version: active.version,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Registry, RegistryError};
+    use crate::ast::Value;
+    use crate::rule::Context;
+
+    #[test]
+    fn test_registry() {
+        let registry = Registry::new(2);
+        let mut ctx = Context::new();
+        ctx.insert("age", 20);
+        assert!(matches!(
+            registry.eval("adult", &ctx),
+            Err(RegistryError::UnknownRule(_))
+        ));
+        let v1 = registry.publish("adult", "(GTE ${age} 18)").unwrap();
+        assert!(registry.eval("adult", &ctx).is_err());
+        registry.promote("adult", v1).unwrap();
+        let v2 = registry.publish("adult", "(GTE ${age} 21)").unwrap();
+        assert_eq!((v1, v2), (1, 2));
+        assert_eq!(registry.eval("adult", &ctx).unwrap().version, v1);
+        registry.promote("adult", v2).unwrap();
+        let decision = registry.eval("adult", &ctx).unwrap();
+        assert_eq!((decision.value, decision.version), (Value::BOOL(false), v2));
+
+        // v1 is dropped for v3, the active v2 is kept
+        let v3 = registry.publish("adult", "(GTE ${age} 16)").unwrap();
+        assert_eq!(registry.versions("adult"), vec![2, 3]);
+        registry.promote("adult", v3).unwrap();
+        assert_eq!(registry.rollback("adult").unwrap(), v2);
+        assert!(matches!(
+            registry.rollback("adult"),
+            Err(RegistryError::NothingToRollBack(_))
+        ));
+        assert!(matches!(
+            registry.promote("adult", v1),
+            Err(RegistryError::UnknownVersion(_, 1))
+        ));
+        assert!(registry.publish("adult", "(GTE ${age}").is_err());
+        assert_eq!(registry.versions("adult"), vec![2, 3]);
+
+        let activations = registry.activations("adult");
+        let versions: Vec<u64> = activations.iter().map(|a| a.version).collect();
+        assert_eq!(versions, vec![1, 2, 3, 2]);
+        assert_eq!(activations[0].source, "(GTE ${age} 18)");
+        let last = activations.last().unwrap();
+        assert_eq!(registry.active_at("adult", last.at), Some(last.clone()));
+        assert_eq!(registry.active_at("adult", activations[0].at - 1), None);
+        assert_eq!(registry.active_at("missing", last.at), None);
+        assert_eq!(
+            registry.active("adult").unwrap().rule.source(),
+            "(GTE ${age} 21)"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use crate::ast::Value;
+use crate::json::Json;
+use crate::rule::EvalError;
+use crate::time;
+use std::time::Duration;
+
+/**
+ * Result of one rule of a set in a Report. A rule the index of the set
+ * skipped was not evaluated, its value is false and its duration zero.
+ */
+#[derive(Debug)]
+pub struct RuleReport {
+    pub name: String,
+    pub priority: i32,
+    pub evaluated: bool,
+    pub result: Result<Value, EvalError>,
+    pub duration: Duration,
+}
+
+impl RuleReport {
+    pub fn matched(&self) -> bool {
+        return matches!(self.result, Ok(Value::BOOL(true)));
+    }
+}
+
+/**
+ * What every rule of a set produced on one context, in the order of the
+ * set, see RuleSet::evaluate_with_report
+ */
+#[derive(Debug)]
+pub struct Report {
+    pub rules: Vec<RuleReport>,
+    // Time of the whole evaluation, the index lookup included
+    pub duration: Duration,
+}
+
+impl Report {
+    pub fn matched(&self) -> Vec<&str> {
+        return self
+            .rules
+            .iter()
+            .filter(|rule| rule.matched())
+            .map(|rule| rule.name.as_str())
+            .collect();
+    }
+
+    pub fn errors(&self) -> Vec<(&str, &EvalError)> {
+        return self
+            .rules
+            .iter()
+            .filter_map(|rule| match &rule.result {
+                Err(e) => Some((rule.name.as_str(), e)),
+                Ok(_) => None,
+            })
+            .collect();
+    }
+
+    /**
+     * JSON document of the report, durations are in microseconds
+     *
+     * ```json
+     * {"duration_us":12,"matched":["adult"],"rules":[{"name":"adult","priority":0,
+     *   "evaluated":true,"matched":true,"value":true,"duration_us":3},
+     *   {"name":"named",...,"matched":false,"error":"rule eval failed, ...",...}]}
+     * ```
+     */
+    pub fn to_json(&self) -> String {
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let result = match &rule.result {
+                    Ok(val) => ("value", value_json(val)),
+                    Err(e) => ("error", Json::Str(e.to_string())),
+                };
+                Json::object(vec![
+                    ("name", Json::Str(rule.name.clone())),
+                    ("priority", Json::Int(rule.priority as i64)),
+                    ("evaluated", Json::Bool(rule.evaluated)),
+                    ("matched", Json::Bool(rule.matched())),
+                    result,
+                    ("duration_us", micros(rule.duration)),
+                ])
+            })
+            .collect();
+        let matched = self
+            .matched()
+            .into_iter()
+            .map(|name| Json::Str(name.to_string()))
+            .collect();
+        return Json::object(vec![
+            ("duration_us", micros(self.duration)),
+            ("matched", Json::Array(matched)),
+            ("rules", Json::Array(rules)),
+        ])
+        .to_string();
+    }
+}
+
+fn micros(duration: Duration) -> Json {
+    return Json::Int(duration.as_micros().min(i64::MAX as u128) as i64);
+}
+
+// MAP keys are sorted so that the same value always gives the same
+// document, BYTES, TIMESTAMP and LAMBDA are written as in rule content
+fn value_json(val: &Value) -> Json {
+    match val {
+        Value::INT(i) => Json::Int(*i),
+        Value::FLOAT(f) => Json::Float(*f),
+        Value::BOOL(b) => Json::Bool(*b),
+        Value::STR(s) => Json::Str(s.clone()),
+        Value::NULL => Json::Null,
+        Value::LIST(list) => Json::Array(list.iter().map(value_json).collect()),
+        Value::MAP(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            Json::Object(
+                keys.into_iter()
+                    .map(|key| (key.clone(), value_json(&map[key])))
+                    .collect(),
+            )
+        }
+        Value::BYTES(_) | Value::LAMBDA(_) => Json::Str(val.to_string()),
+        Value::TIMESTAMP(millis) => Json::Str(time::format_iso8601(*millis)),
+    }
+}
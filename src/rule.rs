@@ -0,0 +1,1080 @@
+use crate::ast::{
+    json_value, AstError, Diagnostic, EvalOptions, Expr, ExprKind, Parser, ParserOptions, Value,
+    VariableResolver,
+};
+use crate::cel;
+use crate::check::{self, Schema};
+use crate::json::{self, Json};
+use crate::mongo;
+use crate::sql::{self, Dialect, Sql};
+use crate::token::Span;
+use crate::vm::Bytecode;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::iter::FromIterator;
+use std::sync::Arc;
+
+/**
+ * Variables a rule is evaluated against, cloning a context is cheap as
+ * the values are shared
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    values: Arc<HashMap<String, Value>>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        return Context::default();
+    }
+
+    /**
+     * Builder of a context with a method per type
+     *
+     * ```
+     * use newlisp::{Context, Value};
+     *
+     * let ctx = Context::builder()
+     *     .int("age", 42)
+     *     .str("env", "prod")
+     *     .bool("beta", true)
+     *     .build();
+     * assert_eq!(ctx.get("env"), Some(&Value::from("prod")));
+     * ```
+     */
+    pub fn builder() -> ContextBuilder {
+        return ContextBuilder::default();
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<Value>) {
+        Arc::make_mut(&mut self.values).insert(key.into(), value.into());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        return self.values.get(key);
+    }
+
+    /**
+     * Context of a JSON object, its keys are the variables and the values
+     * are mapped as described on Value. The error has the byte offset of
+     * the problem.
+     *
+     * ```
+     * use newlisp::{Context, Rule, Value};
+     *
+     * let ctx = Context::from_json_str(r#"{"age": 20, "user": {"tags": ["vip"]}}"#).unwrap();
+     * let rule = Rule::compile("(AND (GTE ${age} 18) (IN \"vip\" ${user.tags}))").unwrap();
+     * assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+     * ```
+     */
+    pub fn from_json_str(json: &str) -> Result<Context, String> {
+        match json::parse(json)? {
+            Json::Object(entries) => {
+                let values = entries
+                    .into_iter()
+                    .map(|(key, val)| (key, json_value(val)))
+                    .collect::<HashMap<String, Value>>();
+                return Ok(Context::from(values));
+            }
+            other => return Err(format!("Expected a JSON object, but got {}", other)),
+        }
+    }
+}
+
+// A context is written as the object of its variables
+#[cfg(feature = "serde")]
+impl serde::Serialize for Context {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return crate::ast::serialize_map(&self.values, serializer);
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Context {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Context, D::Error> {
+        return HashMap::<String, Value>::deserialize(d).map(Context::from);
+    }
+}
+
+impl VariableResolver for Context {
+    fn resolve(&self, name: &str) -> Option<Value> {
+        return self.values.get(name).cloned();
+    }
+}
+
+impl From<HashMap<String, Value>> for Context {
+    fn from(values: HashMap<String, Value>) -> Self {
+        Context {
+            values: Arc::new(values),
+        }
+    }
+}
+
+// A later value of a key replaces the one before, as with insert
+impl<K: Into<String>, V: Into<Value>> FromIterator<(K, V)> for Context {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(values: I) -> Self {
+        let mut ctx = Context::new();
+        ctx.extend(values);
+        return ctx;
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>> Extend<(K, V)> for Context {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, values: I) {
+        let map = Arc::make_mut(&mut self.values);
+        for (key, val) in values {
+            map.insert(key.into(), val.into());
+        }
+    }
+}
+
+/**
+ * Values of a context before it is built, see Context::builder. A key
+ * set again replaces the value before.
+ */
+#[derive(Debug, Default)]
+pub struct ContextBuilder {
+    values: HashMap<String, Value>,
+}
+
+impl ContextBuilder {
+    pub fn int(self, key: impl Into<String>, i: i64) -> ContextBuilder {
+        return self.value(key, Value::INT(i));
+    }
+
+    pub fn float(self, key: impl Into<String>, f: f64) -> ContextBuilder {
+        return self.value(key, Value::FLOAT(f));
+    }
+
+    pub fn bool(self, key: impl Into<String>, b: bool) -> ContextBuilder {
+        return self.value(key, Value::BOOL(b));
+    }
+
+    pub fn str(self, key: impl Into<String>, s: impl Into<String>) -> ContextBuilder {
+        return self.value(key, Value::STR(s.into()));
+    }
+
+    pub fn null(self, key: impl Into<String>) -> ContextBuilder {
+        return self.value(key, Value::NULL);
+    }
+
+    pub fn value(mut self, key: impl Into<String>, val: impl Into<Value>) -> ContextBuilder {
+        self.values.insert(key.into(), val.into());
+        return self;
+    }
+
+    pub fn values<K, V>(mut self, values: impl IntoIterator<Item = (K, V)>) -> ContextBuilder
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        for (key, val) in values {
+            self.values.insert(key.into(), val.into());
+        }
+        return self;
+    }
+
+    pub fn build(self) -> Context {
+        return Context::from(self.values);
+    }
+}
+
+/**
+ * The rule content is not valid, the message of the error has the
+ * position of the problem
+ */
+#[derive(Debug)]
+pub struct CompileError(pub AstError);
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rule compile failed, {}", self.0)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/**
+ * The rule failed on the given context, like a type mismatch
+ */
+#[derive(Debug)]
+pub struct EvalError(pub AstError);
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rule eval failed, {}", self.0)
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/**
+ * A compiled rule, compile once and eval it against many contexts
+ *
+ * ```
+ * use newlisp::{Context, Rule, Value};
+ *
+ * let rule = Rule::compile("(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))").unwrap();
+ * let mut ctx = Context::new();
+ * ctx.insert("age", 20);
+ * ctx.insert("country", "DE");
+ * assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+ * ```
+ */
+#[derive(Clone)]
+pub struct Rule {
+    source: String,
+    expr: Expr,
+    // Rules are evaluated on the bytecode of the expression
+    bytecode: Bytecode,
+}
+
+// RULE references are only resolved in a rule set, see RuleSet::compile
+fn standalone(mut expr: Expr) -> Result<Expr, CompileError> {
+    expr.resolve_rules(&mut |name, span| {
+        Err(AstError::UNDEFINED_RULE(format!(
+            "Rule {} can only be referenced in a rule set",
+            name
+        ))
+        .at(span))
+    })
+    .map_err(CompileError)?;
+    return Ok(expr);
+}
+
+/**
+ * Everything Rule::compile would reject in the rule content, found without
+ * compiling or evaluating it: all the syntax errors rather than the first
+ * one, RULE references and the type errors every context hits, see
+ * Rule::check. No diagnostics means the rule compiles. The code of a
+ * diagnostic is error.code() and its position the span.
+ *
+ * ```
+ * use newlisp::validate;
+ *
+ * let diagnostics = validate("(AND (GT ${age} 18)\n  (FOO 1) (LT 2))");
+ * let found: Vec<(&str, usize, usize)> = diagnostics
+ *     .iter()
+ *     .map(|d| (d.error.code(), d.span.line, d.span.column))
+ *     .collect();
+ * assert_eq!(found, vec![("NOT_SUPP_OPER", 2, 4), ("ARITY_NOT_MATCH", 2, 16)]);
+ * ```
+ */
+pub fn validate(source: &str) -> Vec<Diagnostic> {
+    return validate_with_options(source, ParserOptions::default());
+}
+
+pub fn validate_with_options(source: &str, options: ParserOptions) -> Vec<Diagnostic> {
+    let diagnostic = |span: Span, error: AstError| Diagnostic {
+        span: span,
+        error: error.at(span),
+    };
+    let mut parser = match Parser::create_with_options(source.to_string(), options) {
+        Ok(parser) => parser,
+        Err(e) => return vec![diagnostic(Span::default(), e)],
+    };
+    let (exprs, diagnostics) = parser.parse_recover();
+    if !diagnostics.is_empty() {
+        return diagnostics;
+    }
+    if exprs.len() != 1 {
+        let span = exprs.get(1).map_or(Span::default(), |expr| expr.span);
+        let error = AstError::FORMAT_NOT_MATCH(format!(
+            "Rule should have one expression, but got {}",
+            exprs.len()
+        ));
+        return vec![diagnostic(span, error)];
+    }
+    let mut diagnostics = Vec::new();
+    let mut pending = vec![&exprs[0]];
+    while let Some(expr) = pending.pop() {
+        if let ExprKind::Rule(name, _) = &expr.kind {
+            let error = AstError::UNDEFINED_RULE(format!(
+                "Rule {} can only be referenced in a rule set",
+                name
+            ));
+            diagnostics.push(diagnostic(expr.span, error));
+        }
+        pending.extend(expr.children().into_iter().rev());
+    }
+    diagnostics.extend(check::check(&exprs[0], &Schema::new()));
+    return diagnostics;
+}
+
+impl Rule {
+    pub(crate) fn create(source: String, expr: Expr) -> Rule {
+        Rule {
+            source: source,
+            bytecode: expr.compile_to_bytecode(),
+            expr: expr,
+        }
+    }
+
+    pub fn compile(source: &str) -> Result<Rule, CompileError> {
+        return Self::compile_with_options(source, ParserOptions::default());
+    }
+
+    /**
+     * The source should have exactly one top-level expression
+     */
+    pub fn compile_with_options(
+        source: &str,
+        options: ParserOptions,
+    ) -> Result<Rule, CompileError> {
+        let expr = standalone(Self::parse(source, options)?)?;
+        Ok(Rule::create(source.to_string(), expr))
+    }
+
+    // Expression of the source with its RULE references not resolved
+    pub(crate) fn parse(source: &str, options: ParserOptions) -> Result<Expr, CompileError> {
+        let mut parser =
+            Parser::create_with_options(source.to_string(), options).map_err(CompileError)?;
+        let mut exprs = parser.parse_all().map_err(CompileError)?;
+        if exprs.len() != 1 {
+            return Err(CompileError(AstError::FORMAT_NOT_MATCH(format!(
+                "Rule should have one expression, but got {}",
+                exprs.len()
+            ))));
+        }
+        return Ok(exprs.remove(0));
+    }
+
+    /**
+     * Compile a rule written as a SQL WHERE clause, like
+     * `age >= 18 AND country IN ('DE', 'FR')`, into the same expression as
+     * its rule content. Names are variables and a.b is ${a.b}, strings are
+     * in single quotes. It has AND, OR, NOT, =, != or <>, <, <=, >, >=,
+     * [NOT] IN, [NOT] BETWEEN, [NOT] LIKE, IS [NOT] NULL and +, -, *, %.
+     * The source is kept as written and errors have positions in it.
+     *
+     * ```
+     * use newlisp::{Context, Rule, Value};
+     *
+     * let rule = Rule::compile_sql("age >= 18 AND country IN ('DE', 'FR')").unwrap();
+     * assert_eq!(rule, Rule::compile("(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))").unwrap());
+     * let mut ctx = Context::new();
+     * ctx.insert("age", 20);
+     * ctx.insert("country", "DE");
+     * assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+     * ```
+     */
+    pub fn compile_sql(source: &str) -> Result<Rule, CompileError> {
+        return Self::compile_sql_with_options(source, ParserOptions::default());
+    }
+
+    pub fn compile_sql_with_options(
+        source: &str,
+        options: ParserOptions,
+    ) -> Result<Rule, CompileError> {
+        let expr = sql::parse_where(source, &options).map_err(CompileError)?;
+        Ok(Rule::create(source.to_string(), expr))
+    }
+
+    /**
+     * Compile a rule written in a subset of CEL, like
+     * `age >= 18 && country in ['DE', 'FR']`, into the same expression as
+     * its rule content. Names are variables and a.b[0] is ${a.b[0]}. It has
+     * ==, !=, <, <=, >, >=, in, &&, ||, !, +, -, *, %, ?: and the string
+     * methods contains, startsWith, endsWith, matches, size, lowerAscii,
+     * upperAscii and trim, size() and the functions of the options can be
+     * called. The source is kept as written and errors have positions in
+     * it.
+     *
+     * ```
+     * use newlisp::Rule;
+     *
+     * let rule = Rule::compile_cel("user.age >= 18 && user.email.endsWith('@example.com')").unwrap();
+     * assert_eq!(
+     *     rule.expr().to_sexpr(),
+     *     "(AND (GTE ${user.age} 18) (ENDS-WITH ${user.email} \"@example.com\"))"
+     * );
+     * ```
+     */
+    pub fn compile_cel(source: &str) -> Result<Rule, CompileError> {
+        return Self::compile_cel_with_options(source, ParserOptions::default());
+    }
+
+    pub fn compile_cel_with_options(
+        source: &str,
+        options: ParserOptions,
+    ) -> Result<Rule, CompileError> {
+        let expr = cel::parse_cel(source, &options).map_err(CompileError)?;
+        Ok(Rule::create(source.to_string(), expr))
+    }
+
+    /**
+     * Compile a rule from a MongoDB filter document, like
+     * `{"age": {"$gt": 18}, "tags": {"$in": ["a", "b"]}}`. Fields are
+     * variables, a.0.b is ${a[0].b}, and a missing field is NULL as in
+     * MongoDB. It has $and, $or, $nor, $eq, $ne, $gt, $gte, $lt, $lte, $in,
+     * $nin, $exists, $regex, $not, $mod, $size, $all and $elemMatch, and
+     * {"$date": ..} values are timestamps. A field is compared as a whole,
+     * an array field is only matched by its elements with $all, $size and
+     * $elemMatch. The document is kept as the source.
+     *
+     * ```
+     * use newlisp::Rule;
+     *
+     * let rule = Rule::compile_mongo(r#"{"age": {"$gte": 18}, "country": {"$in": ["DE", "FR"]}}"#).unwrap();
+     * assert_eq!(
+     *     rule.expr().to_sexpr(),
+     *     "(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))"
+     * );
+     * ```
+     */
+    pub fn compile_mongo(doc: &str) -> Result<Rule, CompileError> {
+        return Self::compile_mongo_with_options(doc, ParserOptions::default());
+    }
+
+    pub fn compile_mongo_with_options(
+        doc: &str,
+        options: ParserOptions,
+    ) -> Result<Rule, CompileError> {
+        let expr = mongo::parse_mongo(doc, &options).map_err(CompileError)?;
+        Ok(Rule::create(doc.to_string(), expr))
+    }
+
+    /**
+     * Compile a rule from the JSON document written by to_json, the source
+     * of the rule is the canonical rule content of the expression
+     */
+    pub fn from_json(json: &str) -> Result<Rule, CompileError> {
+        return Self::from_json_with_options(json, ParserOptions::default());
+    }
+
+    pub fn from_json_with_options(
+        json: &str,
+        options: ParserOptions,
+    ) -> Result<Rule, CompileError> {
+        let expr = standalone(Expr::from_json(json, &options).map_err(CompileError)?)?;
+        Ok(Rule::create(expr.to_sexpr(), expr))
+    }
+
+    /**
+     * The context is usually a Context, any other VariableResolver can
+     * supply the variables as well
+     */
+    pub fn eval(&self, ctx: &dyn VariableResolver) -> Result<Value, EvalError> {
+        return self.bytecode.eval(ctx).map_err(EvalError);
+    }
+
+    /**
+     * Eval the rule with its async functions awaited, only the calls the
+     * rule reaches are made
+     */
+    #[cfg(feature = "tokio")]
+    pub async fn eval_async(
+        &self,
+        ctx: &(dyn VariableResolver + Sync),
+    ) -> Result<Value, EvalError> {
+        return self.expr.eval_async(ctx).await.map_err(EvalError);
+    }
+
+    /**
+     * Eval the rule on a JSON object, ${a.b} is read in the document and
+     * only the values the rule reads are converted. It is the same as
+     * eval, which takes the document as well.
+     *
+     * ```
+     * use newlisp::{Rule, Value};
+     *
+     * let event = serde_json::json!({"user": {"age": 20, "tags": ["vip"]}});
+     * let rule = Rule::compile("(AND (GTE ${user.age} 18) (EQUALS ${user.tags[0]} \"vip\"))").unwrap();
+     * assert_eq!(rule.eval_json(&event).unwrap(), Value::BOOL(true));
+     * ```
+     */
+    #[cfg(feature = "serde_json")]
+    pub fn eval_json(&self, doc: &serde_json::Value) -> Result<Value, EvalError> {
+        return self.eval(doc);
+    }
+
+    pub fn eval_with_options(
+        &self,
+        ctx: &dyn VariableResolver,
+        options: &EvalOptions,
+    ) -> Result<Value, EvalError> {
+        return self
+            .bytecode
+            .eval_with_options(ctx, options)
+            .map_err(EvalError);
+    }
+
+    /**
+     * Eval the rule on every context, the results are in the order of the
+     * contexts. With the rayon feature the contexts are evaluated in
+     * parallel.
+     */
+    pub fn eval_batch(&self, contexts: &[Context]) -> Vec<Result<Value, EvalError>> {
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            return contexts.par_iter().map(|ctx| self.eval(ctx)).collect();
+        }
+        #[cfg(not(feature = "rayon"))]
+        return contexts.iter().map(|ctx| self.eval(ctx)).collect();
+    }
+
+    pub fn source(&self) -> &str {
+        return &self.source;
+    }
+
+    pub fn expr(&self) -> &Expr {
+        return &self.expr;
+    }
+
+    // Context keys the rule reads, see Expr::variables
+    pub fn variables(&self) -> HashSet<String> {
+        return self.expr.variables();
+    }
+
+    /**
+     * Values the variables of the rule eval to when the context does not
+     * have them, by path, from the defaults of the options it was
+     * compiled with
+     */
+    pub fn defaults(&self) -> BTreeMap<String, Value> {
+        let mut defaults = BTreeMap::new();
+        self.expr.walk(&mut |expr| {
+            if let ExprKind::Var(var) = &expr.kind {
+                if let Some(default) = var.default_value() {
+                    defaults.insert(var.name().to_string(), default.clone());
+                }
+            }
+        });
+        return defaults;
+    }
+
+    /**
+     * Check the rule against the declared types of the context variables,
+     * so that a rule like (MOD ${name} 2) with a STR name is rejected when
+     * it is saved instead of failing at eval
+     */
+    pub fn check(&self, schema: &Schema) -> Result<(), Vec<Diagnostic>> {
+        let diagnostics = check::check(&self.expr, schema);
+        if diagnostics.is_empty() {
+            return Ok(());
+        }
+        return Err(diagnostics);
+    }
+
+    /**
+     * Pre-evaluate the constant sub-expressions of the rule, see
+     * Expr::optimize. The source is kept as written.
+     */
+    pub fn optimize(&mut self) {
+        self.expr.optimize();
+        self.bytecode = self.expr.compile_to_bytecode();
+    }
+
+    /**
+     * Bind every variable of the rule to its slot in the schema, a
+     * variable the schema does not declare is an UNDEFINED_VAR error
+     */
+    pub fn bind(&self, schema: &Schema) -> Result<BoundRule, CompileError> {
+        let mut expr = self.expr.clone();
+        expr.bind_slots(schema).map_err(CompileError)?;
+        Ok(BoundRule {
+            source: self.source.clone(),
+            bytecode: expr.compile_to_bytecode(),
+        })
+    }
+
+    // See Expr::to_json for the layout of the document
+    pub fn to_json(&self) -> String {
+        return self.expr.to_json();
+    }
+
+    /**
+     * WHERE clause of the rule for the database, so that the rows which
+     * can not match are filtered out by the database and only the residual
+     * of the rule is evaluated on the others, see Sql
+     */
+    pub fn to_sql(&self, dialect: Dialect) -> Sql {
+        return sql::to_sql(&self.expr, dialect);
+    }
+}
+
+/**
+ * A rule bound to a schema, it is evaluated on the values of the declared
+ * variables in the order of Schema::names, so that no variable is looked
+ * up by name. A slot past the end of the values is a missing variable.
+ *
+ * ```
+ * use newlisp::{Rule, Schema, Type, Value};
+ *
+ * let mut schema = Schema::new();
+ * schema.insert("age", Type::INT);
+ * schema.insert("country", Type::STR);
+ * let rule = Rule::compile("(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))").unwrap();
+ * let bound = rule.bind(&schema).unwrap();
+ * let values = [Value::INT(20), Value::from("DE")];
+ * assert_eq!(bound.eval(&values).unwrap(), Value::BOOL(true));
+ * ```
+ */
+#[derive(Debug, Clone)]
+pub struct BoundRule {
+    source: String,
+    bytecode: Bytecode,
+}
+
+impl BoundRule {
+    pub fn eval(&self, values: &[Value]) -> Result<Value, EvalError> {
+        return self.eval_with_options(values, &EvalOptions::default());
+    }
+
+    pub fn eval_with_options(
+        &self,
+        values: &[Value],
+        options: &EvalOptions,
+    ) -> Result<Value, EvalError> {
+        return self.bytecode.eval_slots(values, options).map_err(EvalError);
+    }
+
+    pub fn source(&self) -> &str {
+        return &self.source;
+    }
+}
+
+// Rules are equal when their expressions are, even if the source text is
+// written differently
+impl PartialEq for Rule {
+    fn eq(&self, other: &Rule) -> bool {
+        self.expr == other.expr
+    }
+}
+
+impl fmt::Debug for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rule")
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, validate_with_options, Context, Rule};
+    use crate::ast::{AstError, ParserOptions, Value};
+    use crate::check::{Schema, Type};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_compile_and_eval() {
+        let rule = Rule::compile("(AND (GT ${age} 18) (EQUALS ${name} \"Bob\"))").unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("age", 30);
+        ctx.insert("name", "Bob");
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+        let shared = ctx.clone();
+        ctx.insert("age", 10);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+        assert_eq!(rule.eval(&shared).unwrap(), Value::BOOL(true));
+        let rule = Rule::compile("(LEN ${age})").unwrap();
+        assert!(matches!(rule.eval(&ctx), Err(e) if e.to_string().starts_with("rule eval failed")));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        for source in ["(AND 1", "", "(NOT 1) (NOT 0)", "(FOO 1)"] {
+            assert!(Rule::compile(source).is_err(), "{}", source);
+        }
+        let options = ParserOptions {
+            max_depth: 1,
+            ..ParserOptions::default()
+        };
+        let err = Rule::compile_with_options("(NOT (NOT 1))", options).unwrap_err();
+        assert!(matches!(err.0, AstError::MAX_DEPTH_EXCEEDED(_)));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let rule = Rule::compile("(and (gt ${age} 18) (in ${tier} \"gold\" \"silver\"))").unwrap();
+        let copy = Rule::from_json(&rule.to_json()).unwrap();
+        assert_eq!(
+            copy.source(),
+            "(AND (GT ${age} 18) (IN ${tier} \"gold\" \"silver\"))"
+        );
+        let mut ctx = Context::new();
+        ctx.insert("age", 30);
+        ctx.insert("tier", "gold");
+        assert_eq!(copy.eval(&ctx).unwrap(), Value::BOOL(true));
+        assert!(Rule::from_json("{\"op\": \"AND\"").is_err());
+        assert_eq!(copy, rule);
+        assert_ne!(copy, Rule::compile("(GT ${age} 18)").unwrap());
+    }
+
+    #[test]
+    fn test_in_list_variable() {
+        let rule = Rule::compile("(IN ${user_id} ${vip_list} 99)").unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("user_id", 7);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+        ctx.insert("vip_list", vec![3, 7]);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+        ctx.insert("vip_list", vec![3]);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+        ctx.insert("user_id", 99);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+        let rule = Rule::compile("(IN ${country} ${countries})").unwrap();
+        ctx.insert("country", "DE");
+        ctx.insert("countries", vec!["FR", "DE"]);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+    }
+
+    #[test]
+    fn test_bind() {
+        let mut schema = Schema::new();
+        schema.insert("age", Type::INT);
+        schema.insert("user", Type::MAP);
+        schema.insert("user.score", Type::FLOAT);
+        let mut user: HashMap<String, Value> = HashMap::new();
+        user.insert("tags".to_string(), Value::LIST(vec![Value::from("vip")]));
+        user.insert("score".to_string(), Value::FLOAT(0.5));
+        let values = [Value::INT(30), Value::MAP(user.clone()), Value::FLOAT(2.5)];
+        let mut ctx = Context::new();
+        ctx.insert("age", 30);
+        ctx.insert("user", Value::MAP(user));
+        let cases = vec![
+            (
+                "(AND (GT ${age} 18) (IN \"vip\" ${user.tags}))",
+                Value::BOOL(true),
+            ),
+            ("(EQUALS ${user.tags[0]} \"vip\")", Value::BOOL(true)),
+            (
+                "(LET ((age (MUL ${age} 2))) (ADD ${age} 1))",
+                Value::INT(61),
+            ),
+            (
+                "(LET ((u ${user})) (CONCAT ${u.tags[0]} (UPPER \"!\")))",
+                Value::from("vip!"),
+            ),
+        ];
+        for (source, expected) in cases {
+            let rule = Rule::compile(source).unwrap();
+            let bound = rule.bind(&schema).unwrap();
+            assert_eq!(bound.eval(&values).unwrap(), expected, "{}", source);
+            assert_eq!(rule.eval(&ctx).unwrap(), expected, "{}", source);
+        }
+        // The declared path has its own slot, the context has it nested
+        let rule = Rule::compile("(GT ${user.score} 1)").unwrap();
+        assert_eq!(
+            rule.bind(&schema).unwrap().eval(&values).unwrap(),
+            Value::BOOL(true)
+        );
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+        // Slots past the end of the values are missing
+        let bound = Rule::compile("(NOT ${age})")
+            .unwrap()
+            .bind(&schema)
+            .unwrap();
+        assert_eq!(bound.eval(&[]).unwrap(), Value::BOOL(true));
+        let err = Rule::compile("(AND true\n  ${name})")
+            .unwrap()
+            .bind(&schema)
+            .unwrap_err();
+        assert!(matches!(err.0, AstError::UNDEFINED_VAR(_)));
+        assert_eq!(
+            err.0.to_string(),
+            "UNDEFINED_VAR: Variable ${name} is not declared in the schema at line 2, column 3"
+        );
+    }
+
+    #[test]
+    fn test_eval_batch() {
+        let rule = Rule::compile("(AND (GT ${age} 18) (LT (MOD ${age} 10) 5))").unwrap();
+        let contexts: Vec<Context> = (0..1000)
+            .map(|age| {
+                let mut ctx = Context::new();
+                if age != 500 {
+                    ctx.insert("age", age);
+                } else {
+                    ctx.insert("age", "old");
+                }
+                ctx
+            })
+            .collect();
+        let results = rule.eval_batch(&contexts);
+        assert_eq!(results.len(), contexts.len());
+        for (age, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(val) => assert_eq!(val, Value::BOOL(age > 18 && age % 10 < 5), "{}", age),
+                Err(e) => assert_eq!(age, 500, "{}", e),
+            }
+        }
+        assert!(rule.eval_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_context_from_json_str() {
+        let ctx = Context::from_json_str(
+            r#"{"age": 20, "score": 1.5, "name": "Bob", "vip": true, "note": null,
+                "user": {"tags": ["a", "b"]}, "big": 9223372036854775808}"#,
+        )
+        .unwrap();
+        assert_eq!(ctx.get("age"), Some(&Value::INT(20)));
+        assert_eq!(ctx.get("score"), Some(&Value::FLOAT(1.5)));
+        assert_eq!(ctx.get("note"), Some(&Value::NULL));
+        assert_eq!(ctx.get("big"), Some(&Value::FLOAT(9223372036854775808.0)));
+        let rule = Rule::compile(
+            "(AND (GTE ${age} 18) (EQUALS ${name} \"Bob\") ${vip} (IN \"b\" ${user.tags}))",
+        )
+        .unwrap();
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+
+        assert_eq!(
+            Context::from_json_str("[1, 2]").unwrap_err(),
+            "Expected a JSON object, but got [1,2]"
+        );
+        assert!(Context::from_json_str("{\"age\": }").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let json = r##"{"age":20,"at":"2024-01-31T00:00:00Z","id":"#x\"00ff\"","tags":["a",1.5,null,{"a":false,"b":true}]}"##;
+        let mut ctx = Context::new();
+        ctx.insert("age", 20);
+        ctx.insert("at", Value::TIMESTAMP(1706659200000));
+        ctx.insert("id", Value::BYTES(vec![0, 255]));
+        let map = HashMap::from([
+            ("b".to_string(), Value::BOOL(true)),
+            ("a".to_string(), Value::BOOL(false)),
+        ]);
+        ctx.insert(
+            "tags",
+            vec![
+                Value::from("a"),
+                Value::FLOAT(1.5),
+                Value::NULL,
+                Value::MAP(map.clone()),
+            ],
+        );
+        assert_eq!(serde_json::to_string(&ctx).unwrap(), json);
+
+        let copy: Context = serde_json::from_str(json).unwrap();
+        assert_eq!(copy.get("at"), Some(&Value::from("2024-01-31T00:00:00Z")));
+        assert_eq!(copy.get("tags"), ctx.get("tags"));
+        let val: Value =
+            serde_json::from_value(serde_json::json!({"n": 18446744073709551615u64})).unwrap();
+        let expected = HashMap::from([("n".to_string(), Value::FLOAT(18446744073709551615.0))]);
+        assert_eq!(val, Value::MAP(expected));
+        assert!(serde_json::from_str::<Context>("[1]").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("(AND (GT ${age} 18) (IN ${country} \"DE\" \"FR\"))").is_empty());
+        let found = |source: &str| {
+            validate(source)
+                .iter()
+                .map(|d| (d.error.code(), d.span.line, d.span.column))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            found("(AND (GT ${a} 1)\n  (FOO 1) (LT 2))"),
+            vec![("NOT_SUPP_OPER", 2, 4), ("ARITY_NOT_MATCH", 2, 16)]
+        );
+        assert_eq!(
+            found("(OR (RULE \"adult\") (ADD \"a\" 1) (RULE \"vip\"))"),
+            vec![
+                ("UNDEFINED_RULE", 1, 5),
+                ("UNDEFINED_RULE", 1, 32),
+                ("TYPE_NOT_MATCH", 1, 25),
+            ]
+        );
+        assert_eq!(
+            found("(GT ${a} 1) (LT ${a} 5)"),
+            vec![("FORMAT_NOT_MATCH", 1, 13)]
+        );
+        assert_eq!(found(""), vec![("FORMAT_NOT_MATCH", 0, 0)]);
+
+        // The message is the error without its code, compile reports the
+        // same errors
+        let source = "(AND (GT ${a} 1) (MOD ${b} \"x\"))";
+        let diagnostics = validate(source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].error.message(),
+            "MOD operator needs INT or FLOAT args, but got STR \"x\" at line 1, column 28"
+        );
+        let options = ParserOptions {
+            max_depth: 2,
+            ..ParserOptions::default()
+        };
+        let diagnostics = validate_with_options("(NOT (NOT (NOT true)))", options.clone());
+        assert_eq!(diagnostics[0].error.code(), "MAX_DEPTH_EXCEEDED");
+        assert_eq!(
+            diagnostics[0].error.to_string(),
+            Rule::compile_with_options("(NOT (NOT (NOT true)))", options)
+                .unwrap_err()
+                .0
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_context_builder() {
+        let ctx = Context::builder()
+            .int("age", 42)
+            .float("score", 1.5)
+            .str("env", "prod")
+            .bool("beta", true)
+            .null("note")
+            .value("tags", vec!["a", "b"])
+            .values(vec![("country", "DE"), ("env", "dev")])
+            .build();
+        assert_eq!(ctx.get("age"), Some(&Value::INT(42)));
+        assert_eq!(ctx.get("score"), Some(&Value::FLOAT(1.5)));
+        assert_eq!(ctx.get("env"), Some(&Value::from("dev")));
+        assert_eq!(ctx.get("note"), Some(&Value::NULL));
+        let rule = Rule::compile(
+            "(AND (GT ${age} 18) ${beta} (IN \"b\" ${tags}) (EQUALS ${country} \"DE\"))",
+        )
+        .unwrap();
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+
+        let mut ctx: Context = vec![("age", 20), ("limit", 100)].into_iter().collect();
+        assert_eq!(ctx.get("limit"), Some(&Value::INT(100)));
+        let shared = ctx.clone();
+        ctx.extend(HashMap::from([("age".to_string(), Value::INT(10))]));
+        assert_eq!(ctx.get("age"), Some(&Value::INT(10)));
+        assert_eq!(shared.get("age"), Some(&Value::INT(20)));
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn test_eval_json() {
+        let event = serde_json::json!({
+            "user": {"age": 20, "score": 1.5, "items": [{"price": 30}], "note": null},
+            "country": "DE",
+            "big": 18446744073709551615u64
+        });
+        let cases = vec![
+            ("(GT ${user.items[0].price} 25)", Value::BOOL(true)),
+            ("(EQUALS ${user.score} 1.5)", Value::BOOL(true)),
+            ("(LEN ${user.items})", Value::INT(1)),
+            ("(GT ${big} 1.0)", Value::BOOL(true)),
+            ("(EQUALS ${user.note} NULL)", Value::BOOL(true)),
+            ("(COALESCE ${user.missing} \"none\")", Value::BOOL(false)),
+            ("(LET ((user 5)) (ADD ${user} 1))", Value::INT(6)),
+            ("(GET ${user} \"age\")", Value::INT(20)),
+        ];
+        for (source, expected) in cases {
+            let rule = Rule::compile(source).unwrap();
+            assert_eq!(rule.eval_json(&event).unwrap(), expected, "{}", source);
+        }
+
+        // Paths fail the same as on a Context with the same values
+        let ctx = Context::from_json_str(&event.to_string()).unwrap();
+        for source in [
+            "(GT ${user.items[3].price} 1)",
+            "(GT ${country.code} 1)",
+            "(GT ${user.note.x} 1)",
+        ] {
+            let rule = Rule::compile(source).unwrap();
+            assert_eq!(
+                rule.eval_json(&event).unwrap_err().to_string(),
+                rule.eval(&ctx).unwrap_err().to_string()
+            );
+        }
+        let rule = Rule::compile("(EQUALS ${country} \"DE\")").unwrap();
+        assert_eq!(
+            rule.eval_json(&serde_json::json!([1])).unwrap(),
+            Value::BOOL(false)
+        );
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive_rule_context() {
+        use crate::{RuleContext, ToValue};
+
+        #[derive(RuleContext)]
+        struct Address {
+            city: String,
+            zip: Option<u32>,
+        }
+
+        #[derive(RuleContext)]
+        struct Order<'a, T: ToValue = i64> {
+            #[rule(rename = "user_age")]
+            pub age: u8,
+            tags: Vec<&'a str>,
+            amount: T,
+            address: Address,
+            r#type: String,
+            limits: HashMap<String, f64>,
+            #[rule(skip)]
+            #[allow(dead_code)]
+            secret: String,
+        }
+
+        let order = Order {
+            age: 20,
+            tags: vec!["vip"],
+            amount: 150,
+            address: Address {
+                city: "Berlin".to_string(),
+                zip: None,
+            },
+            r#type: "online".to_string(),
+            limits: HashMap::from([("daily".to_string(), 500.0)]),
+            secret: "x".to_string(),
+        };
+        let cases = vec![
+            "(GTE ${user_age} 18)",
+            "(IN \"vip\" ${tags})",
+            "(GT ${amount} 100)",
+            "(EQUALS ${address.city} \"Berlin\")",
+            "(EQUALS ${address.zip} NULL)",
+            "(EQUALS ${type} \"online\")",
+            "(LT ${amount} ${limits.daily})",
+            "(NOT ${secret})",
+            "(NOT ${age})",
+        ];
+        for source in cases {
+            let rule = Rule::compile(source).unwrap();
+            assert_eq!(rule.eval(&order).unwrap(), Value::BOOL(true), "{}", source);
+        }
+        assert_eq!(
+            order.address.to_value().to_string(),
+            "{\"city\": \"Berlin\", \"zip\": NULL}"
+        );
+    }
+
+    #[test]
+    fn test_rule_defaults() {
+        let options = ParserOptions {
+            strict_vars: true,
+            defaults: HashMap::from([
+                ("country".to_string(), Value::from("unknown")),
+                ("user.tier".to_string(), Value::INT(1)),
+                ("unused".to_string(), Value::NULL),
+            ]),
+            ..ParserOptions::default()
+        };
+        let source = "(AND (EQUALS ${country} \"unknown\") (EQUALS ${user.tier} 1))";
+        let rule = Rule::compile_with_options(source, options.clone()).unwrap();
+        let ctx = Context::builder()
+            .value("user", Value::MAP(HashMap::new()))
+            .build();
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+        let ctx = Context::builder().str("country", "DE").build();
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(false));
+        assert_eq!(
+            rule.defaults().into_iter().collect::<Vec<_>>(),
+            vec![
+                ("country".to_string(), Value::from("unknown")),
+                ("user.tier".to_string(), Value::INT(1)),
+            ]
+        );
+
+        // Variables without a default are still strict
+        let rule = Rule::compile_with_options("(EQUALS ${age} 1)", options).unwrap();
+        let err = rule.eval(&Context::new()).unwrap_err();
+        assert!(matches!(err.0, AstError::UNDEFINED_VAR(_)), "{}", err);
+        assert!(rule.defaults().is_empty());
+        let rule = Rule::compile("(NOT ${country})").unwrap();
+        assert_eq!(rule.eval(&Context::new()).unwrap(), Value::BOOL(true));
+    }
+}
@@ -0,0 +1,1279 @@
+use crate::analysis::{Conjunction, Finding};
+use crate::ast::{json_value, AstError, Expr, ParserOptions, Value, VariableResolver};
+use crate::index::Index;
+use crate::json::{self, Json};
+use crate::report::{Report, RuleReport};
+use crate::rule::{CompileError, EvalError, Rule};
+use crate::yaml;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Instant;
+
+/**
+ * Rules of a set which failed, by name in the order of the set, or the
+ * document of the set which could not be read
+ */
+#[derive(Debug)]
+pub enum RuleSetError {
+    Compile(Vec<(String, CompileError)>),
+    Eval(Vec<(String, EvalError)>),
+    Document(String),
+}
+
+impl fmt::Display for RuleSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let errors: Vec<String> = match self {
+            RuleSetError::Document(msg) => {
+                return write!(f, "rule set document is not valid, {}", msg)
+            }
+            RuleSetError::Compile(errors) => errors
+                .iter()
+                .map(|(name, e)| format!("{}: {}", name, e))
+                .collect(),
+            RuleSetError::Eval(errors) => errors
+                .iter()
+                .map(|(name, e)| format!("{}: {}", name, e))
+                .collect(),
+        };
+        write!(
+            f,
+            "{} rules of the set failed; {}",
+            errors.len(),
+            errors.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for RuleSetError {}
+
+/**
+ * Which matching rules of a set are selected, ties of priority are broken
+ * by the order the rules were inserted in
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Strategy {
+    // The first rule which matches in the order of the set
+    #[default]
+    FirstMatch,
+    // All the rules which match, highest priority first
+    AllMatches,
+    // The rule with the highest priority of the ones which match
+    HighestPriority,
+}
+
+/**
+ * What a rule is for and who owns it, fields which are not known are left
+ * empty
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    // Id of the rule outside of the set, like a ticket or policy number
+    pub id: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub owner: String,
+}
+
+/**
+ * A rule of a set which matched
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Match<'a> {
+    pub name: &'a str,
+    pub priority: i32,
+    pub metadata: &'a Metadata,
+}
+
+/**
+ * Named rules evaluated together against one context, in the order they
+ * were inserted. A rule matches when it evals to true, every rule has a
+ * priority which is 0 and empty metadata unless it is inserted with them.
+ *
+ * Rules which start with a test of a variable against literals, like
+ * (EQUALS ${country} "DE") or (AND (IN ${tier} 1 2) ...), are indexed by
+ * the variable. Each such variable is read once per evaluation and only
+ * the rules whose test passes are evaluated, the others are false.
+ *
+ * ```
+ * use newlisp::{Context, RuleSet};
+ *
+ * let rules = RuleSet::compile(vec![
+ *     ("adult", "(GTE ${age} 18)"),
+ *     ("senior", "(GTE ${age} 65)"),
+ * ])
+ * .unwrap();
+ * let mut ctx = Context::new();
+ * ctx.insert("age", 30);
+ * assert_eq!(rules.matching_names(&ctx).unwrap(), vec!["adult"]);
+ * ```
+ */
+#[derive(Debug, Clone, Default)]
+pub struct RuleSet {
+    entries: Vec<Entry>,
+    // Indexes of the entries, highest priority first
+    ranked: Vec<usize>,
+    index: Index,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    rule: Rule,
+    priority: i32,
+    metadata: Metadata,
+}
+
+// A rule of a set being compiled, before its RULE references are resolved
+struct Parsed {
+    source: String,
+    expr: Expr,
+    priority: i32,
+    metadata: Metadata,
+}
+
+// Resolves the RULE references of the rules compiled together, a rule is
+// resolved once and its expression then copied into every rule which
+// references it. The copies count against the max_depth and max_inlined
+// of the options the rules are parsed with.
+struct Linker {
+    // Expression of every rule by name, None when it did not compile
+    exprs: HashMap<String, Option<Expr>>,
+    resolved: HashMap<String, Expr>,
+    // Rules being resolved, the outermost first
+    path: Vec<String>,
+    max_depth: usize,
+    max_inlined: usize,
+}
+
+impl Linker {
+    fn resolve(&mut self, name: &str) -> Result<Expr, AstError> {
+        if let Some(expr) = self.resolved.get(name) {
+            return Ok(expr.clone());
+        }
+        if let Some(at) = self.path.iter().position(|n| n == name) {
+            let mut cycle = self.path[at..].to_vec();
+            cycle.push(name.to_string());
+            return Err(AstError::RULE_CYCLE(format!(
+                "Rule {} references itself through {}",
+                name,
+                cycle.join(" -> ")
+            )));
+        }
+        if self.path.len() >= self.max_depth {
+            return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                "Rule {} references rules more than {} deep",
+                self.path[0], self.max_depth
+            )));
+        }
+        let mut expr = match self.exprs.get(name) {
+            Some(Some(expr)) => expr.clone(),
+            Some(None) => {
+                return Err(AstError::UNDEFINED_RULE(format!(
+                    "Rule {} does not compile",
+                    name
+                )));
+            }
+            None => {
+                return Err(AstError::UNDEFINED_RULE(format!(
+                    "Rule {} is not in the rule set",
+                    name
+                )));
+            }
+        };
+        // Errors are at the reference in the outermost rule, the only one
+        // whose source they are reported with
+        let outermost = self.path.is_empty();
+        let max_inlined = self.max_inlined;
+        let mut inlined = 0;
+        self.path.push(name.to_string());
+        let result = expr.resolve_rules(&mut |referenced, span| {
+            let result = self.resolve(referenced).and_then(|body| {
+                inlined += body.size();
+                if inlined > max_inlined {
+                    return Err(AstError::TOO_MANY_NODES(format!(
+                        "Rule {} adds more than {} nodes with the rules it references",
+                        name, max_inlined
+                    )));
+                }
+                return Ok(body);
+            });
+            match result {
+                Err(e) if outermost => Err(e.at(span)),
+                result => result,
+            }
+        });
+        self.path.pop();
+        result?;
+        if inlined > 0 && expr.height() > self.max_depth {
+            return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                "Rule {} is nested deeper than {} with the rules it references",
+                name, self.max_depth
+            )));
+        }
+        self.resolved.insert(name.to_string(), expr.clone());
+        return Ok(expr);
+    }
+}
+
+impl RuleSet {
+    pub fn new() -> RuleSet {
+        return RuleSet::default();
+    }
+
+    /**
+     * Compile every (name, source) pair, the errors of all the rules which
+     * do not compile are returned together. A name used twice is an error
+     * of its second rule.
+     *
+     * Rules compiled together can use `(RULE "name")` for the value of
+     * another rule of the set. The referenced rule becomes part of the
+     * rule referencing it, so replacing it in the set later does not change
+     * that rule. A reference to an unknown rule is an UNDEFINED_RULE error
+     * and rules which reference themselves are a RULE_CYCLE error.
+     */
+    pub fn compile<'a>(
+        rules: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<RuleSet, RuleSetError> {
+        return Self::compile_with_options(rules, ParserOptions::default());
+    }
+
+    // Same as compile, with the options every rule is parsed with
+    pub fn compile_with_options<'a>(
+        rules: impl IntoIterator<Item = (&'a str, &'a str)>,
+        options: ParserOptions,
+    ) -> Result<RuleSet, RuleSetError> {
+        let parsed = rules.into_iter().map(|(name, source)| {
+            let parsed = Rule::parse(source, options.clone()).map(|expr| Parsed {
+                source: source.to_string(),
+                expr: expr,
+                priority: 0,
+                metadata: Metadata::default(),
+            });
+            (name.to_string(), parsed)
+        });
+        return Self::link(parsed.collect(), &options);
+    }
+
+    // Resolve the RULE references of the parsed rules and build the set,
+    // errors are in the order of the rules
+    fn link(
+        rules: Vec<(String, Result<Parsed, CompileError>)>,
+        options: &ParserOptions,
+    ) -> Result<RuleSet, RuleSetError> {
+        let mut linker = Linker {
+            exprs: HashMap::new(),
+            resolved: HashMap::new(),
+            path: Vec::new(),
+            max_depth: options.max_depth,
+            max_inlined: options.max_inlined,
+        };
+        let mut checked = Vec::with_capacity(rules.len());
+        for (name, parsed) in rules {
+            if linker.exprs.contains_key(&name) {
+                let e = AstError::FORMAT_NOT_MATCH(format!("Rule {} is defined twice", name));
+                checked.push((name, Err(CompileError(e))));
+                continue;
+            }
+            let expr = parsed.as_ref().ok().map(|parsed| parsed.expr.clone());
+            linker.exprs.insert(name.clone(), expr);
+            checked.push((name, parsed));
+        }
+        let mut set = RuleSet::new();
+        let mut errors = Vec::new();
+        for (name, parsed) in checked {
+            let parsed = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    errors.push((name, e));
+                    continue;
+                }
+            };
+            // Names are distinct, the set is ranked once at the end
+            match linker.resolve(&name) {
+                Ok(expr) => set.entries.push(Entry {
+                    name: name,
+                    rule: Rule::create(parsed.source, expr),
+                    priority: parsed.priority,
+                    metadata: parsed.metadata,
+                }),
+                Err(e) => errors.push((name, CompileError(e))),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(RuleSetError::Compile(errors));
+        }
+        set.rank();
+        return Ok(set);
+    }
+
+    /**
+     * Read a rule set from a JSON document like
+     *
+     * ```json
+     * {"defaults": {"country": "unknown"},
+     *  "rules": [{"name": "adult", "source": "(GTE ${age} 18)", "priority": 10,
+     *   "defaults": {"age": 0},
+     *   "metadata": {"id": "POL-1", "description": "...", "tags": ["age"], "owner": "..."}}]}
+     * ```
+     *
+     * where only rules, name and source are required. The defaults are the
+     * values of the variables the context does not have, see
+     * ParserOptions::defaults, those of a rule win over those of the set.
+     * Every rule which can not be read or compiled is reported by its
+     * name, or by its position in the rules when it has none.
+     */
+    pub fn from_json(json: &str) -> Result<RuleSet, RuleSetError> {
+        let doc = json::parse(json).map_err(RuleSetError::Document)?;
+        return Self::from_document(&doc);
+    }
+
+    /**
+     * Read a rule set from a YAML document with the same layout as the
+     * JSON one of from_json
+     *
+     * ```yaml
+     * defaults:
+     *   country: unknown
+     * rules:
+     *   - name: adult
+     *     source: (GTE ${age} 18)
+     *     priority: 10
+     *     metadata:
+     *       tags: [age]
+     * ```
+     */
+    pub fn from_yaml(yaml: &str) -> Result<RuleSet, RuleSetError> {
+        let doc = yaml::parse(yaml).map_err(RuleSetError::Document)?;
+        return Self::from_document(&doc);
+    }
+
+    fn from_document(doc: &Json) -> Result<RuleSet, RuleSetError> {
+        let rules = match doc.get("rules") {
+            Some(Json::Array(rules)) => rules,
+            _ => {
+                return Err(RuleSetError::Document(
+                    "Expected a \"rules\" array".to_string(),
+                ))
+            }
+        };
+        let defaults = match doc.get("defaults") {
+            Some(Json::Object(entries)) => Self::defaults_of(entries),
+            Some(other) => {
+                return Err(RuleSetError::Document(format!(
+                    "Expected \"defaults\" to be an object, but got {}",
+                    other
+                )))
+            }
+            None => HashMap::new(),
+        };
+        let mut parsed = Vec::with_capacity(rules.len());
+        for (i, doc) in rules.iter().enumerate() {
+            let name = match doc.get("name") {
+                Some(Json::Str(name)) => name.clone(),
+                _ => format!("rules[{}]", i),
+            };
+            parsed.push((name, Self::entry_of(doc, &defaults)));
+        }
+        return Self::link(parsed, &ParserOptions::default());
+    }
+
+    // Values of the variables by path, JSON values map to Value like the
+    // ones of a context
+    fn defaults_of(entries: &[(String, Json)]) -> HashMap<String, Value> {
+        return entries
+            .iter()
+            .map(|(key, val)| (key.clone(), json_value(val.clone())))
+            .collect();
+    }
+
+    fn entry_of(doc: &Json, defaults: &HashMap<String, Value>) -> Result<Parsed, CompileError> {
+        let invalid = |msg: String| CompileError(AstError::FORMAT_NOT_MATCH(msg));
+        let entries = match doc {
+            Json::Object(entries) => entries,
+            other => {
+                return Err(invalid(format!(
+                    "Expected a rule object, but got {}",
+                    other
+                )))
+            }
+        };
+        let str_of = |key: &str, val: &Json| match val {
+            Json::Str(s) => Ok(s.clone()),
+            other => Err(invalid(format!(
+                "Expected \"{}\" to be a string, but got {}",
+                key, other
+            ))),
+        };
+        let mut source = None;
+        let mut priority = 0;
+        let mut metadata = Metadata::default();
+        let mut options = ParserOptions {
+            defaults: defaults.clone(),
+            ..ParserOptions::default()
+        };
+        for (key, val) in entries.iter() {
+            match (key.as_str(), val) {
+                ("name", val) => {
+                    str_of(key, val)?;
+                }
+                ("source", val) => source = Some(str_of(key, val)?),
+                ("priority", Json::Int(i)) if i32::try_from(*i).is_ok() => priority = *i as i32,
+                ("priority", other) => {
+                    return Err(invalid(format!(
+                        "Expected \"priority\" to be a 32-bit INT, but got {}",
+                        other
+                    )))
+                }
+                ("metadata", Json::Object(fields)) => {
+                    for (key, val) in fields.iter() {
+                        match (key.as_str(), val) {
+                            ("id", val) => metadata.id = str_of(key, val)?,
+                            ("description", val) => metadata.description = str_of(key, val)?,
+                            ("owner", val) => metadata.owner = str_of(key, val)?,
+                            ("tags", Json::Array(tags)) => {
+                                for tag in tags.iter() {
+                                    metadata.tags.push(str_of(key, tag)?);
+                                }
+                            }
+                            (key, _) => {
+                                return Err(invalid(format!("Unknown metadata field {}", key)))
+                            }
+                        }
+                    }
+                }
+                ("defaults", Json::Object(entries)) => {
+                    options.defaults.extend(Self::defaults_of(entries));
+                }
+                ("defaults", other) => {
+                    return Err(invalid(format!(
+                        "Expected \"defaults\" to be an object, but got {}",
+                        other
+                    )))
+                }
+                (key, _) => return Err(invalid(format!("Unknown rule field {}", key))),
+            }
+        }
+        let source =
+            source.ok_or_else(|| invalid("Expected \"source\" in the rule".to_string()))?;
+        return Ok(Parsed {
+            expr: Rule::parse(&source, options)?,
+            source: source,
+            priority: priority,
+            metadata: metadata,
+        });
+    }
+
+    /**
+     * Add the rule, a rule with the same name is replaced in its place and
+     * returned
+     */
+    pub fn insert(&mut self, name: impl Into<String>, rule: Rule) -> Option<Rule> {
+        return self.insert_with_metadata(name, rule, 0, Metadata::default());
+    }
+
+    pub fn insert_with_priority(
+        &mut self,
+        name: impl Into<String>,
+        rule: Rule,
+        priority: i32,
+    ) -> Option<Rule> {
+        return self.insert_with_metadata(name, rule, priority, Metadata::default());
+    }
+
+    pub fn insert_with_metadata(
+        &mut self,
+        name: impl Into<String>,
+        rule: Rule,
+        priority: i32,
+        metadata: Metadata,
+    ) -> Option<Rule> {
+        let name = name.into();
+        let old = match self.entries.iter_mut().find(|entry| entry.name == name) {
+            Some(entry) => {
+                entry.priority = priority;
+                entry.metadata = metadata;
+                Some(std::mem::replace(&mut entry.rule, rule))
+            }
+            None => {
+                self.entries.push(Entry {
+                    name: name,
+                    rule: rule,
+                    priority: priority,
+                    metadata: metadata,
+                });
+                None
+            }
+        };
+        self.rank();
+        return old;
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Rule> {
+        let at = self.entries.iter().position(|entry| entry.name == name)?;
+        let entry = self.entries.remove(at);
+        self.rank();
+        return Some(entry.rule);
+    }
+
+    // Sort by priority is stable, so equal priorities keep the order of
+    // the set. The index is built again as well.
+    fn rank(&mut self) {
+        let mut ranked: Vec<usize> = (0..self.entries.len()).collect();
+        ranked.sort_by_key(|at| Reverse(self.entries[*at].priority));
+        self.ranked = ranked;
+        self.index = Index::new(self.entries.iter().map(|entry| entry.rule.expr()));
+    }
+
+    pub fn priority(&self, name: &str) -> Option<i32> {
+        return self
+            .entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.priority);
+    }
+
+    pub fn metadata(&self, name: &str) -> Option<&Metadata> {
+        return self
+            .entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| &entry.metadata);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Rule> {
+        return self
+            .entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| &entry.rule);
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        return self.entries.iter().map(|entry| entry.name.as_str());
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /**
+     * Pairs of rules which are never true together, and rules which are
+     * true whenever another one is, from the tests of variables against
+     * literals in the top AND of the rules. Every finding holds, but pairs
+     * whose relation depends on other operators are not found. Pairs are
+     * in the order of the set.
+     */
+    pub fn analyze(&self) -> Vec<Finding> {
+        let conjunctions = self.conjunctions();
+        let mut findings = Vec::new();
+        for first in 0..self.entries.len() {
+            for second in first + 1..self.entries.len() {
+                self.compare(&conjunctions, first, second, &mut findings);
+            }
+        }
+        return findings;
+    }
+
+    // Findings of analyze about the rule and any other, like for a rule
+    // which was just inserted
+    pub fn analyze_rule(&self, name: &str) -> Vec<Finding> {
+        let at = match self.entries.iter().position(|entry| entry.name == name) {
+            Some(at) => at,
+            None => return Vec::new(),
+        };
+        let conjunctions = self.conjunctions();
+        let mut findings = Vec::new();
+        for other in 0..self.entries.len() {
+            if other != at {
+                self.compare(&conjunctions, at.min(other), at.max(other), &mut findings);
+            }
+        }
+        return findings;
+    }
+
+    fn conjunctions(&self) -> Vec<Option<Conjunction>> {
+        return self
+            .entries
+            .iter()
+            .map(|entry| Conjunction::of(entry.rule.expr()))
+            .collect();
+    }
+
+    fn compare(
+        &self,
+        conjunctions: &[Option<Conjunction>],
+        first: usize,
+        second: usize,
+        findings: &mut Vec<Finding>,
+    ) {
+        let (a, b) = match (&conjunctions[first], &conjunctions[second]) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return,
+        };
+        if let Some(variable) = a.conflict(b) {
+            findings.push(Finding::Conflict {
+                first: self.entries[first].name.clone(),
+                second: self.entries[second].name.clone(),
+                variable: variable.to_string(),
+            });
+            return;
+        }
+        let rank = |at: usize| self.ranked.iter().position(|r| *r == at);
+        for (wider, narrower, c, d) in [(first, second, a, b), (second, first, b, a)] {
+            if c.subsumes(d) {
+                findings.push(Finding::Subsumes {
+                    wider: self.entries[wider].name.clone(),
+                    narrower: self.entries[narrower].name.clone(),
+                    shadowed: rank(wider) < rank(narrower),
+                });
+            }
+        }
+    }
+
+    // Result of every rule by name, a rule which fails does not stop the
+    // others
+    pub fn eval_all(&self, ctx: &dyn VariableResolver) -> Vec<(&str, Result<Value, EvalError>)> {
+        let candidates = self.index.candidates(ctx);
+        return self
+            .entries
+            .iter()
+            .zip(candidates)
+            .map(|(entry, candidate)| match candidate {
+                true => (entry.name.as_str(), entry.rule.eval(ctx)),
+                false => (entry.name.as_str(), Ok(Value::BOOL(false))),
+            })
+            .collect();
+    }
+
+    /**
+     * Eval every rule like eval_all and report the value, the error and the
+     * duration of each, see Report::to_json for a serialized form
+     */
+    pub fn evaluate_with_report(&self, ctx: &dyn VariableResolver) -> Report {
+        let start = Instant::now();
+        let candidates = self.index.candidates(ctx);
+        let mut rules = Vec::with_capacity(self.entries.len());
+        for (entry, candidate) in self.entries.iter().zip(candidates) {
+            let rule_start = Instant::now();
+            let result = match candidate {
+                true => entry.rule.eval(ctx),
+                false => Ok(Value::BOOL(false)),
+            };
+            rules.push(RuleReport {
+                name: entry.name.clone(),
+                priority: entry.priority,
+                evaluated: candidate,
+                result: result,
+                duration: rule_start.elapsed(),
+            });
+        }
+        return Report {
+            rules: rules,
+            duration: start.elapsed(),
+        };
+    }
+
+    /**
+     * Rules the strategy selects, in the order it selects them. Rules are evaluated in the
+     * order the strategy looks at them, and FirstMatch and HighestPriority
+     * stop at the first match. The errors of all the rules evaluated which
+     * failed are returned together, even when a later rule matches.
+     */
+    pub fn find_matches(
+        &self,
+        ctx: &dyn VariableResolver,
+        strategy: Strategy,
+    ) -> Result<Vec<Match<'_>>, RuleSetError> {
+        let order: Vec<usize> = match strategy {
+            Strategy::FirstMatch => (0..self.entries.len()).collect(),
+            Strategy::AllMatches | Strategy::HighestPriority => self.ranked.clone(),
+        };
+        let candidates = self.index.candidates(ctx);
+        let mut matches = Vec::new();
+        let mut errors = Vec::new();
+        for at in order {
+            if !candidates[at] {
+                continue;
+            }
+            let entry = &self.entries[at];
+            match entry.rule.eval(ctx) {
+                Ok(Value::BOOL(true)) => {
+                    matches.push(Match {
+                        name: &entry.name,
+                        priority: entry.priority,
+                        metadata: &entry.metadata,
+                    });
+                    if strategy != Strategy::AllMatches {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => errors.push((entry.name.clone(), e)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(RuleSetError::Eval(errors));
+        }
+        return Ok(matches);
+    }
+
+    // See Strategy::FirstMatch
+    pub fn first_match(&self, ctx: &dyn VariableResolver) -> Result<Option<&str>, RuleSetError> {
+        let matches = self.find_matches(ctx, Strategy::FirstMatch)?;
+        return Ok(matches.first().map(|m| m.name));
+    }
+
+    // See Strategy::AllMatches
+    pub fn matching_names(&self, ctx: &dyn VariableResolver) -> Result<Vec<&str>, RuleSetError> {
+        let matches = self.find_matches(ctx, Strategy::AllMatches)?;
+        return Ok(matches.iter().map(|m| m.name).collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metadata, RuleSet, RuleSetError, Strategy};
+    use crate::analysis::Finding;
+    use crate::ast::Value;
+    use crate::check::{Schema, Type};
+    use crate::json::{self, Json};
+    use crate::rule::{Context, Rule};
+
+    #[test]
+    fn test_rule_set() {
+        let mut rules = RuleSet::compile(vec![
+            ("minor", "(LT ${age} 18)"),
+            ("adult", "(GTE ${age} 18)"),
+            ("named", "(EQUALS ${name} \"Bob\")"),
+            ("age", "(ADD ${age} 1)"),
+        ])
+        .unwrap();
+        assert_eq!(rules.len(), 4);
+        let mut ctx = Context::new();
+        ctx.insert("age", 30);
+        ctx.insert("name", "Bob");
+        assert_eq!(rules.first_match(&ctx).unwrap(), Some("adult"));
+        assert_eq!(rules.matching_names(&ctx).unwrap(), vec!["adult", "named"]);
+        let results = rules.eval_all(&ctx);
+        assert_eq!(results[3].0, "age");
+        assert_eq!(results[3].1.as_ref().unwrap(), &Value::INT(31));
+
+        let old = rules.insert("minor", Rule::compile("(LT ${age} 40)").unwrap());
+        assert_eq!(old.unwrap().source(), "(LT ${age} 18)");
+        assert_eq!(rules.first_match(&ctx).unwrap(), Some("minor"));
+        assert!(rules.remove("minor").is_some());
+        assert_eq!(
+            rules.names().collect::<Vec<&str>>(),
+            vec!["adult", "named", "age"]
+        );
+
+        ctx.insert("age", "thirty");
+        match rules.matching_names(&ctx).unwrap_err() {
+            RuleSetError::Eval(errors) => {
+                let names: Vec<&str> = errors.iter().map(|(name, _)| name.as_str()).collect();
+                assert_eq!(names, vec!["adult", "age"]);
+            }
+            e => panic!("{}", e),
+        }
+        assert!(rules.first_match(&ctx).is_err());
+        rules.insert("first", Rule::compile("(NOT false)").unwrap());
+        assert!(rules.first_match(&ctx).is_err());
+    }
+
+    #[test]
+    fn test_rule_set_compile_errors() {
+        let err = RuleSet::compile(vec![
+            ("a", "(AND true"),
+            ("b", "(NOT false)"),
+            ("b", "(NOT true)"),
+            ("c", "(FOO 1)"),
+        ])
+        .unwrap_err();
+        match &err {
+            RuleSetError::Compile(errors) => {
+                let names: Vec<&str> = errors.iter().map(|(name, _)| name.as_str()).collect();
+                assert_eq!(names, vec!["a", "b", "c"]);
+            }
+            e => panic!("{}", e),
+        }
+        assert!(err
+            .to_string()
+            .starts_with("3 rules of the set failed; a: rule compile failed"));
+        assert!(RuleSet::compile(vec![]).unwrap().is_empty());
+    }
+
+    fn names<'a>(rules: &'a RuleSet, ctx: &Context, strategy: Strategy) -> Vec<&'a str> {
+        let matches = rules.find_matches(ctx, strategy).unwrap();
+        return matches.iter().map(|m| m.name).collect();
+    }
+
+    #[test]
+    fn test_strategies() {
+        let mut rules = RuleSet::new();
+        let rule = |source: &str| Rule::compile(source).unwrap();
+        rules.insert("default", rule("(NOT false)"));
+        rules.insert_with_priority("eu", rule("(IN ${country} \"DE\" \"FR\")"), 10);
+        rules.insert_with_priority("de", rule("(EQUALS ${country} \"DE\")"), 20);
+        rules.insert_with_priority("vip", rule("(GT ${score} 90)"), 20);
+        rules.insert_with_priority("blocked", rule("(EQUALS ${country} \"XX\")"), -5);
+        let mut ctx = Context::new();
+        ctx.insert("country", "DE");
+        ctx.insert("score", 95);
+        let cases = [
+            (Strategy::FirstMatch, vec!["default"]),
+            (Strategy::AllMatches, vec!["de", "vip", "eu", "default"]),
+            (Strategy::HighestPriority, vec!["de"]),
+        ];
+        for (strategy, expected) in cases.iter() {
+            assert_eq!(&names(&rules, &ctx, *strategy), expected, "{:?}", strategy);
+        }
+        assert_eq!(rules.priority("vip"), Some(20));
+        // Raising vip above de makes it win, replacing keeps its place
+        rules.insert_with_priority("vip", rule("(GT ${score} 90)"), 30);
+        assert_eq!(names(&rules, &ctx, Strategy::HighestPriority), vec!["vip"]);
+        ctx.insert("score", 10);
+        assert_eq!(names(&rules, &ctx, Strategy::HighestPriority), vec!["de"]);
+        ctx.insert("country", "US");
+        assert_eq!(
+            names(&rules, &ctx, Strategy::HighestPriority),
+            vec!["default"]
+        );
+        rules.remove("default");
+        assert!(names(&rules, &ctx, Strategy::HighestPriority).is_empty());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut rules = RuleSet::new();
+        let fraud = Metadata {
+            id: "POL-7".to_string(),
+            description: "Large transfers to new payees".to_string(),
+            tags: vec!["fraud".to_string(), "payments".to_string()],
+            owner: "risk-team".to_string(),
+        };
+        let rule = Rule::compile("(GT ${amount} 10000)").unwrap();
+        rules.insert_with_metadata("large_transfer", rule, 5, fraud.clone());
+        rules.insert("any", Rule::compile("(GT ${amount} 0)").unwrap());
+        let mut ctx = Context::new();
+        ctx.insert("amount", 20000);
+        let matches = rules.find_matches(&ctx, Strategy::AllMatches).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].name, "large_transfer");
+        assert_eq!(matches[0].priority, 5);
+        assert_eq!(matches[0].metadata, &fraud);
+        assert_eq!(matches[1].metadata, &Metadata::default());
+        assert_eq!(rules.metadata("large_transfer").unwrap().owner, "risk-team");
+        // Replacing a rule replaces its metadata as well
+        rules.insert(
+            "large_transfer",
+            Rule::compile("(GT ${amount} 5000)").unwrap(),
+        );
+        assert_eq!(rules.metadata("large_transfer"), Some(&Metadata::default()));
+        assert_eq!(rules.metadata("missing"), None);
+    }
+
+    #[test]
+    fn test_from_documents() {
+        let yaml = "
+rules:
+  - name: de
+    source: (EQUALS ${country} \"DE\")
+    priority: 20
+    metadata:
+      id: ROUTE-1
+      tags: [eu, germany]
+      owner: routing
+  - name: eu
+    # several lines
+    source: |
+      (IN ${country}
+          \"DE\" \"FR\")
+    priority: 10
+  - name: fallback
+    source: (NOT false)
+";
+        let json = r#"{"rules": [
+            {"name": "de", "source": "(EQUALS ${country} \"DE\")", "priority": 20,
+             "metadata": {"id": "ROUTE-1", "tags": ["eu", "germany"], "owner": "routing"}},
+            {"name": "eu", "source": "(IN ${country}\n    \"DE\" \"FR\")\n", "priority": 10},
+            {"name": "fallback", "source": "(NOT false)"}
+        ]}"#;
+        let mut ctx = Context::new();
+        ctx.insert("country", "DE");
+        for rules in [
+            RuleSet::from_yaml(yaml).unwrap(),
+            RuleSet::from_json(json).unwrap(),
+        ] {
+            assert_eq!(
+                names(&rules, &ctx, Strategy::AllMatches),
+                vec!["de", "eu", "fallback"]
+            );
+            let metadata = rules.metadata("de").unwrap();
+            assert_eq!(metadata.id, "ROUTE-1");
+            assert_eq!(metadata.tags, vec!["eu", "germany"]);
+            assert_eq!(metadata.description, "");
+            assert_eq!(rules.priority("fallback"), Some(0));
+        }
+
+        let yaml = "
+rules:
+  - name: ok
+    source: (NOT false)
+  - name: broken
+    source: (GT ${age}
+  - source: (NOT true)
+    priority: high
+  - name: ok
+    source: (NOT true)
+  - name: typo
+    sourse: (NOT true)
+";
+        match RuleSet::from_yaml(yaml).unwrap_err() {
+            RuleSetError::Compile(errors) => {
+                let names: Vec<&str> = errors.iter().map(|(name, _)| name.as_str()).collect();
+                assert_eq!(names, vec!["broken", "rules[2]", "ok", "typo"]);
+                assert!(
+                    errors[1].1.to_string().contains("32-bit INT"),
+                    "{}",
+                    errors[1].1
+                );
+                assert!(errors[3]
+                    .1
+                    .to_string()
+                    .contains("Unknown rule field sourse"));
+            }
+            e => panic!("{}", e),
+        }
+        for doc in ["rules: [", "{\"rules\": 1}", "- name: a"] {
+            let err = RuleSet::from_yaml(doc).unwrap_err();
+            assert!(matches!(err, RuleSetError::Document(_)), "{}", doc);
+        }
+        assert!(matches!(
+            RuleSet::from_json("{"),
+            Err(RuleSetError::Document(_))
+        ));
+        assert!(RuleSet::from_yaml("rules: []").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rule_references() {
+        let offer = "(AND (RULE \"is_vip\") (LET ((spend 0)) (RULE \"big_spender\")))";
+        let rules = RuleSet::compile(vec![
+            ("offer", offer),
+            (
+                "is_vip",
+                "(OR (RULE \"big_spender\") (EQUALS ${tier} \"gold\"))",
+            ),
+            ("big_spender", "(GT ${spend} 1000)"),
+        ])
+        .unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("spend", 2000);
+        ctx.insert("tier", "silver");
+        // The LET does not shadow the variables of the referenced rule
+        assert_eq!(
+            rules.matching_names(&ctx).unwrap(),
+            vec!["offer", "is_vip", "big_spender"]
+        );
+        ctx.insert("spend", 10);
+        ctx.insert("tier", "gold");
+        assert_eq!(rules.matching_names(&ctx).unwrap(), vec!["is_vip"]);
+
+        let rule = rules.get("offer").unwrap();
+        assert_eq!(rule.source(), offer);
+        assert_eq!(rule.expr().to_sexpr(), offer);
+        let mut vars: Vec<String> = rule.variables().into_iter().collect();
+        vars.sort();
+        assert_eq!(vars, vec!["spend", "tier"]);
+        let mut schema = Schema::new();
+        schema.insert("spend", Type::INT);
+        schema.insert("tier", Type::STR);
+        assert!(rule.check(&schema).is_ok());
+        let bound = rule.bind(&schema).unwrap();
+        let values = [Value::INT(2000), Value::from("silver")];
+        assert_eq!(bound.eval(&values).unwrap(), Value::BOOL(true));
+
+        let err = RuleSet::compile(vec![
+            ("a", "(RULE \"b\")"),
+            ("b", "(NOT (RULE \"a\"))"),
+            ("c", "(AND true (RULE \"missing\"))"),
+            ("d", "(RULE \"broken\")"),
+            ("broken", "(GT 1"),
+            ("e", "(RULE \"e\")"),
+        ])
+        .unwrap_err();
+        let messages: Vec<String> = match err {
+            RuleSetError::Compile(errors) => errors.iter().map(|(_, e)| e.to_string()).collect(),
+            e => panic!("{}", e),
+        };
+        let expected = vec![
+            "RULE_CYCLE: Rule a references itself through a -> b -> a at line 1, column 1",
+            "RULE_CYCLE: Rule b references itself through b -> a -> b at line 1, column 6",
+            "UNDEFINED_RULE: Rule missing is not in the rule set at line 1, column 11",
+            "UNDEFINED_RULE: Rule broken does not compile at line 1, column 1",
+            "",
+            "RULE_CYCLE: Rule e references itself through e -> e at line 1, column 1",
+        ];
+        for (message, expected) in messages.iter().zip(expected) {
+            assert!(message.ends_with(expected), "{}", message);
+        }
+
+        let err = Rule::compile("(RULE \"a\")").unwrap_err();
+        assert!(err.to_string().contains("UNDEFINED_RULE"), "{}", err);
+        assert!(Rule::from_json(r#"{"op":"RULE","rule":"a"}"#).is_err());
+        let yaml = "
+rules:
+  - name: adult
+    source: (GTE ${age} 18)
+  - name: senior
+    source: (AND (RULE \"adult\") (GTE ${age} 65))
+";
+        let rules = RuleSet::from_yaml(yaml).unwrap();
+        let senior = rules.get("senior").unwrap();
+        assert!(senior.to_json().contains(r#"{"op":"RULE","rule":"adult"}"#));
+    }
+
+    #[test]
+    fn test_rule_reference_limits() {
+        let errors_of = |err: RuleSetError| match err {
+            RuleSetError::Compile(errors) => errors
+                .into_iter()
+                .map(|(name, e)| (name, e.0.to_string()))
+                .collect::<Vec<_>>(),
+            e => panic!("{}", e),
+        };
+
+        // Every rule references the one before twice, linked it doubles
+        let names: Vec<String> = (0..20).map(|i| format!("r{}", i)).collect();
+        let mut sources = vec!["(EQUALS ${x} 1)".to_string()];
+        for i in 1..20 {
+            sources.push(format!("(AND (RULE \"r{}\") (RULE \"r{}\"))", i - 1, i - 1));
+        }
+        let rules = names
+            .iter()
+            .map(|n| n.as_str())
+            .zip(sources.iter().map(|s| s.as_str()));
+        let errors = errors_of(RuleSet::compile(rules).unwrap_err());
+        assert_eq!(errors.len(), 5);
+        assert_eq!(errors[0].0, "r15");
+        assert_eq!(
+            errors[0].1,
+            "TOO_MANY_NODES: Rule r15 adds more than 100000 nodes with the rules it references at line 1, column 19"
+        );
+        assert!(errors.iter().all(|(_, e)| e.starts_with("TOO_MANY_NODES")));
+
+        // Every rule nests the one before in a NOT, read from a document
+        let mut entries = vec![r#"{"name": "n0", "source": "(EQUALS ${x} 1)"}"#.to_string()];
+        for i in 1..3000 {
+            entries.push(format!(
+                r#"{{"name": "n{}", "source": "(NOT (RULE \"n{}\"))"}}"#,
+                i,
+                i - 1
+            ));
+        }
+        let json = format!(r#"{{"rules": [{}]}}"#, entries.join(", "));
+        let errors = errors_of(RuleSet::from_json(&json).unwrap_err());
+        assert_eq!(errors.len(), 3000 - 32);
+        assert_eq!(
+            errors[0],
+            (
+                "n32".to_string(),
+                "MAX_DEPTH_EXCEEDED: Rule n32 is nested deeper than 64 with the rules it references"
+                    .to_string()
+            )
+        );
+        assert!(errors
+            .iter()
+            .all(|(_, e)| e.starts_with("MAX_DEPTH_EXCEEDED")));
+    }
+
+    #[test]
+    fn test_indexed_rules() {
+        let mut sources = Vec::new();
+        for i in 0..50 {
+            sources.push((
+                format!("country_{}", i),
+                format!(
+                    "(AND (EQUALS ${{country}} \"C{}\") (GT ${{age}} {}))",
+                    i % 10,
+                    i
+                ),
+            ));
+            sources.push((
+                format!("tier_{}", i),
+                format!("(IN ${{tier}} {} {})", i, i + 1),
+            ));
+        }
+        sources.push((
+            "broken".to_string(),
+            "(AND (EQUALS ${user.id} 1) true)".to_string(),
+        ));
+        sources.push(("plain".to_string(), "(LT ${age} 30)".to_string()));
+        let rules = RuleSet::compile(
+            sources
+                .iter()
+                .map(|(name, source)| (name.as_str(), source.as_str())),
+        )
+        .unwrap();
+        for (country, tier, user) in [("C3", 7, Value::NULL), ("C9", 70, Value::INT(1))] {
+            let mut ctx = Context::new();
+            ctx.insert("country", country);
+            ctx.insert("age", 25);
+            ctx.insert("tier", tier);
+            ctx.insert("user", user);
+            // The same results as evaluating every rule
+            for (name, result) in rules.eval_all(&ctx) {
+                let expected = rules.get(name).unwrap().eval(&ctx);
+                assert_eq!(
+                    format!("{:?}", result),
+                    format!("{:?}", expected),
+                    "{}",
+                    name
+                );
+            }
+        }
+        let mut ctx = Context::new();
+        ctx.insert("country", "C3");
+        ctx.insert("age", 25);
+        ctx.insert("tier", 7);
+        let expected = vec![
+            "country_3",
+            "tier_6",
+            "tier_7",
+            "country_13",
+            "country_23",
+            "plain",
+        ];
+        assert_eq!(rules.matching_names(&ctx).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_report() {
+        let rules = RuleSet::compile(vec![
+            ("adult", "(GTE ${age} 18)"),
+            ("german", "(EQUALS ${country} \"DE\")"),
+            ("bonus", "(ADD ${age} 1)"),
+            ("broken", "(MOD ${age} 0)"),
+        ])
+        .unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("age", 30);
+        ctx.insert("country", "FR");
+        let report = rules.evaluate_with_report(&ctx);
+        assert_eq!(report.matched(), vec!["adult"]);
+        let errors: Vec<&str> = report.errors().iter().map(|(name, _)| *name).collect();
+        assert_eq!(errors, vec!["broken"]);
+        let evaluated: Vec<bool> = report.rules.iter().map(|rule| rule.evaluated).collect();
+        assert_eq!(evaluated, vec![true, false, true, true]);
+        assert_eq!(report.rules[2].result.as_ref().unwrap(), &Value::INT(31));
+        assert!(report
+            .rules
+            .iter()
+            .all(|rule| rule.duration <= report.duration));
+
+        let doc = json::parse(&report.to_json()).unwrap();
+        assert_eq!(
+            doc.get("matched"),
+            Some(&Json::Array(vec![Json::Str("adult".to_string())]))
+        );
+        let reported = match doc.get("rules") {
+            Some(Json::Array(rules)) => rules,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(reported[1].get("evaluated"), Some(&Json::Bool(false)));
+        assert_eq!(reported[2].get("value"), Some(&Json::Int(31)));
+        match reported[3].get("error") {
+            Some(Json::Str(e)) => assert!(e.contains("DIV_BY_ZERO"), "{}", e),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(reported[0].get("duration_us"), Some(Json::Int(_))));
+    }
+
+    #[test]
+    fn test_analyze() {
+        let mut rules = RuleSet::compile(vec![
+            ("adult", "(GTE ${age} 18)"),
+            ("minor", "(AND (LT ${age} 18) (EQUALS ${country} \"DE\"))"),
+            ("senior", "(AND (GTE ${age} 65) (RULE \"adult\"))"),
+            ("retired", "(AND (RULE \"senior\") ${retired})"),
+            ("bonus", "(ADD ${age} 1)"),
+        ])
+        .unwrap();
+        let findings: Vec<String> = rules.analyze().iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            findings,
+            vec![
+                "rules adult and minor are never true together, by their tests of ${age}",
+                "rule senior is shadowed by adult, which ranks before it and is true whenever it is",
+                "rule retired is shadowed by adult, which ranks before it and is true whenever it is",
+                "rules minor and senior are never true together, by their tests of ${age}",
+                "rules minor and retired are never true together, by their tests of ${age}",
+                "rule retired is shadowed by senior, which ranks before it and is true whenever it is",
+            ]
+        );
+        let senior = rules.get("senior").unwrap().clone();
+        rules.insert_with_priority("senior", senior, 10);
+        assert_eq!(
+            rules.analyze_rule("senior"),
+            vec![
+                Finding::Subsumes {
+                    wider: "adult".to_string(),
+                    narrower: "senior".to_string(),
+                    shadowed: false,
+                },
+                Finding::Conflict {
+                    first: "minor".to_string(),
+                    second: "senior".to_string(),
+                    variable: "age".to_string(),
+                },
+                Finding::Subsumes {
+                    wider: "senior".to_string(),
+                    narrower: "retired".to_string(),
+                    shadowed: true,
+                },
+            ]
+        );
+        assert!(rules.analyze_rule("missing").is_empty());
+    }
+
+    #[test]
+    fn test_rule_set_defaults() {
+        let yaml = "
+defaults:
+  country: unknown
+  tier: 1
+rules:
+  - name: unknown_country
+    source: (EQUALS ${country} \"unknown\")
+  - name: basic
+    source: (EQUALS ${tier} 1)
+  - name: premium
+    source: (EQUALS ${tier} 3)
+    defaults:
+      tier: 3
+";
+        let rules = RuleSet::from_yaml(yaml).unwrap();
+        assert_eq!(
+            names(&rules, &Context::new(), Strategy::AllMatches),
+            vec!["unknown_country", "basic", "premium"]
+        );
+        let mut ctx = Context::new();
+        ctx.insert("country", "DE");
+        ctx.insert("tier", 3);
+        assert_eq!(names(&rules, &ctx, Strategy::AllMatches), vec!["premium"]);
+        let defaults = rules.get("premium").unwrap().defaults();
+        assert_eq!(defaults.get("tier"), Some(&Value::INT(3)));
+
+        let json = r#"{"defaults": [], "rules": []}"#;
+        match RuleSet::from_json(json).unwrap_err() {
+            RuleSetError::Document(msg) => assert!(msg.contains("\"defaults\""), "{}", msg),
+            other => panic!("{}", other),
+        }
+    }
+}
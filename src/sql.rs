@@ -0,0 +1,881 @@
+use crate::ast::{
+    int_literal, ArithOp, AstError, CompareOp, Expr, ExprKind, ParserOptions, Value, Var,
+};
+use crate::token::{Lexer, Span, Symbols, TokenTag};
+use crate::vm::literal;
+use std::fmt;
+
+/**
+ * Database a rule is rendered for, see Rule::to_sql
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    // "column" and $1, $2 ... placeholders
+    Postgres,
+    // `column` and ? placeholders
+    MySql,
+}
+
+impl Dialect {
+    fn quote(&self, name: &str) -> String {
+        match self {
+            Dialect::Postgres => format!("\"{}\"", name.replace('"', "\"\"")),
+            Dialect::MySql => format!("`{}`", name.replace('`', "``")),
+        }
+    }
+
+    // Placeholder of the param at the index, from 1
+    fn placeholder(&self, at: usize) -> String {
+        match self {
+            Dialect::Postgres => format!("${}", at),
+            Dialect::MySql => "?".to_string(),
+        }
+    }
+}
+
+/**
+ * WHERE clause of a rule, the literals of the rule are params in the order
+ * of their placeholders. ${x} is the column x and ${t.x} the column x of
+ * the table t. The args of the top AND which have no SQL form are left in
+ * the residual: a row the clause selects matches the rule when the
+ * residual evals to true on it, the residual is None when the whole rule
+ * is in the clause.
+ *
+ * The clause follows SQL for NULL columns and the collation of the column
+ * for STR comparisons, so a NOT over the test of a column which can be
+ * NULL does not select the rows the rule matches.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sql {
+    pub clause: String,
+    pub params: Vec<Value>,
+    pub residual: Option<Expr>,
+}
+
+// Args of nested ANDs and referenced rules, the rule itself otherwise
+fn conjuncts<'a>(expr: &'a Expr, out: &mut Vec<&'a Expr>) {
+    match &expr.kind {
+        ExprKind::And(args) => {
+            for arg in args.iter() {
+                conjuncts(arg, out);
+            }
+        }
+        ExprKind::Rule(_, Some(body)) => conjuncts(body, out),
+        _ => out.push(expr),
+    }
+}
+
+struct Render {
+    dialect: Dialect,
+    params: Vec<Value>,
+}
+
+impl Render {
+    // Column of a variable or placeholder of a literal. NULL literals are
+    // left to the residual, x = NULL is never true in SQL.
+    fn operand(&mut self, expr: &Expr) -> Option<String> {
+        if let ExprKind::Var(var) = &expr.kind {
+            let keys = var.keys()?;
+            let quoted: Vec<String> = keys.iter().map(|key| self.dialect.quote(key)).collect();
+            return Some(quoted.join("."));
+        }
+        match literal(expr)? {
+            Value::NULL => return None,
+            val => {
+                self.params.push(val);
+                return Some(self.dialect.placeholder(self.params.len()));
+            }
+        }
+    }
+
+    fn join(&mut self, args: &[Expr], sep: &str) -> Option<String> {
+        let parts: Option<Vec<String>> = args.iter().map(|arg| self.condition(arg)).collect();
+        return Some(format!("({})", parts?.join(sep)));
+    }
+
+    // None when the expression has no SQL form, the params pushed for it
+    // are then left for the caller to drop
+    fn condition(&mut self, expr: &Expr) -> Option<String> {
+        match &expr.kind {
+            ExprKind::And(args) if !args.is_empty() => return self.join(args, " AND "),
+            ExprKind::Or(args) if !args.is_empty() => return self.join(args, " OR "),
+            // MySQL with HIGH_NOT_PRECEDENCE binds NOT before comparisons
+            ExprKind::Not(arg) => {
+                let arg = self.condition(arg)?;
+                if arg.starts_with('(') {
+                    return Some(format!("NOT {}", arg));
+                }
+                return Some(format!("NOT ({})", arg));
+            }
+            ExprKind::Rule(_, Some(body)) => return self.condition(body),
+            ExprKind::Bool(true) => return Some("TRUE".to_string()),
+            ExprKind::Bool(false) => return Some("FALSE".to_string()),
+            ExprKind::Var(_) => return self.operand(expr),
+            ExprKind::Equals(args) if args.len() >= 2 => {
+                let first = self.operand(&args[0])?;
+                let mut tests = Vec::new();
+                for arg in args[1..].iter() {
+                    tests.push(format!("{} = {}", first, self.operand(arg)?));
+                }
+                if tests.len() == 1 {
+                    return tests.pop();
+                }
+                return Some(format!("({})", tests.join(" AND ")));
+            }
+            ExprKind::In(args) if args.len() >= 2 => {
+                let val = self.operand(&args[0])?;
+                let mut values = Vec::new();
+                for arg in args[1..].iter() {
+                    // A variable can be a LIST of the values
+                    literal(arg)?;
+                    values.push(self.operand(arg)?);
+                }
+                return Some(format!("{} IN ({})", val, values.join(", ")));
+            }
+            ExprKind::Compare(op, arg0, arg1) => {
+                let sign = match op {
+                    CompareOp::GT => ">",
+                    CompareOp::GTE => ">=",
+                    CompareOp::LT => "<",
+                    CompareOp::LTE => "<=",
+                };
+                let arg0 = self.operand(arg0)?;
+                return Some(format!("{} {} {}", arg0, sign, self.operand(arg1)?));
+            }
+            ExprKind::Between(val, low, high) => {
+                let val = self.operand(val)?;
+                let low = self.operand(low)?;
+                return Some(format!(
+                    "{} BETWEEN {} AND {}",
+                    val,
+                    low,
+                    self.operand(high)?
+                ));
+            }
+            _ => return None,
+        }
+    }
+}
+
+pub(crate) fn to_sql(expr: &Expr, dialect: Dialect) -> Sql {
+    let mut args = Vec::new();
+    conjuncts(expr, &mut args);
+    let mut render = Render {
+        dialect: dialect,
+        params: Vec::new(),
+    };
+    let mut clauses = Vec::new();
+    let mut rest = Vec::new();
+    for arg in args {
+        let pushed = render.params.len();
+        match render.condition(arg) {
+            Some(clause) => clauses.push(clause),
+            None => {
+                render.params.truncate(pushed);
+                rest.push(arg.clone());
+            }
+        }
+    }
+    let residual = match rest.len() {
+        0 => None,
+        1 => rest.pop(),
+        _ => Some(Expr::new(ExprKind::And(rest), expr.span)),
+    };
+    let clause = match clauses.is_empty() {
+        true => "TRUE".to_string(),
+        false => clauses.join(" AND "),
+    };
+    return Sql {
+        clause: clause,
+        params: render.params,
+        residual: residual,
+    };
+}
+
+// Token of the WHERE syntax, words are names and keywords
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Word(String),
+    Str(String),
+    Num(String),
+    Sym(&'static str),
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tok::Word(s) | Tok::Num(s) => write!(f, "{}", s),
+            Tok::Str(s) => write!(f, "'{}'", s.replace('\'', "''")),
+            Tok::Sym(sym) => write!(f, "{}", sym),
+        }
+    }
+}
+
+// Longer symbols first, so that <= is not read as < and =
+const SYMBOLS: [&str; 14] = [
+    "<=", ">=", "<>", "!=", "=", "<", ">", "(", ")", ",", "+", "-", "*", "%",
+];
+
+// Length of the string token at the start of rest and its text, a quote in
+// the string is written twice
+fn scan_str(rest: &str) -> Option<(String, usize)> {
+    let mut s = String::new();
+    let mut len = 1;
+    loop {
+        let at = rest[len..].find('\'')?;
+        s.push_str(&rest[len..len + at]);
+        len += at + 1;
+        if !rest[len..].starts_with('\'') {
+            return Some((s, len));
+        }
+        s.push('\'');
+        len += 1;
+    }
+}
+
+fn scan(source: &str) -> Result<Vec<(Tok, Span)>, AstError> {
+    let mut tokens = Vec::new();
+    let (mut pos, mut line, mut column) = (0, 1, 1);
+    while let Some(c) = source[pos..].chars().next() {
+        let rest = &source[pos..];
+        let span = Span {
+            start: pos,
+            end: pos,
+            line: line,
+            column: column,
+        };
+        let word = |c: char| c.is_ascii_alphanumeric() || "_.[]".contains(c);
+        let (tok, len) = if c.is_whitespace() {
+            (None, c.len_utf8())
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest.find(|c| !word(c)).unwrap_or(rest.len());
+            (Some(Tok::Word(rest[..len].to_string())), len)
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+            (Some(Tok::Num(rest[..len].to_string())), len)
+        } else if c == '\'' {
+            match scan_str(rest) {
+                Some((s, len)) => (Some(Tok::Str(s)), len),
+                None => {
+                    return Err(
+                        AstError::LEXER_FAILED("String is not closed with '".to_string()).at(span),
+                    );
+                }
+            }
+        } else {
+            match SYMBOLS.iter().find(|sym| rest.starts_with(*sym)) {
+                Some(sym) => (Some(Tok::Sym(sym)), sym.len()),
+                None => {
+                    let hint = match c {
+                        '"' => ", strings are written in single quotes",
+                        _ => "",
+                    };
+                    return Err(AstError::LEXER_FAILED(format!(
+                        "Not a valid char {:?} in the WHERE syntax{}",
+                        c, hint
+                    ))
+                    .at(span));
+                }
+            }
+        };
+        if let Some(tok) = tok {
+            tokens.push((
+                tok,
+                Span {
+                    end: pos + len,
+                    ..span
+                },
+            ));
+        }
+        for c in rest[..len].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        pos += len;
+    }
+    return Ok(tokens);
+}
+
+fn negate(expr: Expr, negated: bool) -> Expr {
+    if !negated {
+        return expr;
+    }
+    let span = expr.span;
+    return Expr::new(ExprKind::Not(Box::new(expr)), span);
+}
+
+// Parser of the WHERE syntax, see Rule::compile_sql
+struct Where<'a> {
+    tokens: Vec<(Tok, Span)>,
+    at: usize,
+    options: &'a ParserOptions,
+    symbols: &'a Symbols,
+    depth: usize,
+}
+
+impl<'a> Where<'a> {
+    fn peek(&self) -> Option<&Tok> {
+        return self.tokens.get(self.at).map(|(tok, _)| tok);
+    }
+
+    // Span of the next token, or of the last one at the end
+    fn span(&self) -> Span {
+        let at = self.at.min(self.tokens.len().saturating_sub(1));
+        return self
+            .tokens
+            .get(at)
+            .map(|(_, span)| *span)
+            .unwrap_or_default();
+    }
+
+    // Keywords are matched ignoring case
+    fn keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Tok::Word(word)) => return word.eq_ignore_ascii_case(keyword),
+            _ => return false,
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.keyword(keyword);
+        if found {
+            self.at += 1;
+        }
+        return found;
+    }
+
+    fn eat_sym(&mut self, sym: &str) -> bool {
+        let found = matches!(self.peek(), Some(Tok::Sym(s)) if *s == sym);
+        if found {
+            self.at += 1;
+        }
+        return found;
+    }
+
+    fn expected(&self, what: &str) -> AstError {
+        let got = match self.peek() {
+            Some(tok) => tok.to_string(),
+            None => "the end".to_string(),
+        };
+        return AstError::NOT_MATCH(format!("Expected {}, but got {}", what, got)).at(self.span());
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), AstError> {
+        if !self.eat_keyword(keyword) {
+            return Err(self.expected(keyword));
+        }
+        return Ok(());
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<(), AstError> {
+        if !self.eat_sym(sym) {
+            return Err(self.expected(sym));
+        }
+        return Ok(());
+    }
+
+    // One more level of nesting, limited the same as in rule content
+    fn enter(&mut self, span: Span) -> Result<(), AstError> {
+        self.depth += 1;
+        if self.depth > self.options.max_depth {
+            return Err(AstError::MAX_DEPTH_EXCEEDED(format!(
+                "Operators are nested deeper than {}",
+                self.options.max_depth
+            ))
+            .at(span));
+        }
+        return Ok(());
+    }
+
+    fn too_many(&self, op: &str, args: &[Expr]) -> Result<(), AstError> {
+        if args.len() > self.options.max_args {
+            return Err(AstError::TOO_MANY_ARGS(format!(
+                "{} operator has more than {} arguments",
+                op, self.options.max_args
+            ))
+            .at(args[0].span));
+        }
+        return Ok(());
+    }
+
+    fn parse(mut self) -> Result<Expr, AstError> {
+        if self.tokens.is_empty() {
+            return Err(AstError::FORMAT_NOT_MATCH(
+                "Rule should have one expression, but got 0".to_string(),
+            ));
+        }
+        let expr = self.or()?;
+        if self.peek().is_some() {
+            return Err(self.expected("AND, OR or the end"));
+        }
+        return Ok(expr);
+    }
+
+    fn or(&mut self) -> Result<Expr, AstError> {
+        return self.chain("OR", Self::and, ExprKind::Or);
+    }
+
+    fn and(&mut self) -> Result<Expr, AstError> {
+        return self.chain("AND", Self::not, ExprKind::And);
+    }
+
+    // AND binds before OR, a run of either is a single node
+    fn chain(
+        &mut self,
+        keyword: &str,
+        next: fn(&mut Self) -> Result<Expr, AstError>,
+        kind: fn(Vec<Expr>) -> ExprKind,
+    ) -> Result<Expr, AstError> {
+        let first = next(self)?;
+        if !self.keyword(keyword) {
+            return Ok(first);
+        }
+        let mut args = vec![first];
+        while self.eat_keyword(keyword) {
+            args.push(next(self)?);
+        }
+        self.too_many(keyword, &args)?;
+        let span = args[0].span.to(args[args.len() - 1].span);
+        return Ok(Expr::new(kind(args), span));
+    }
+
+    fn not(&mut self) -> Result<Expr, AstError> {
+        let span = self.span();
+        if !self.eat_keyword("NOT") {
+            return self.predicate();
+        }
+        self.enter(span)?;
+        let arg = self.not()?;
+        self.depth -= 1;
+        let span = span.to(arg.span);
+        return Ok(Expr::new(ExprKind::Not(Box::new(arg)), span));
+    }
+
+    fn predicate(&mut self) -> Result<Expr, AstError> {
+        let left = self.additive()?;
+        let op = match self.peek() {
+            Some(Tok::Sym(op)) => *op,
+            _ => "",
+        };
+        let compare = match op {
+            ">" => Some(CompareOp::GT),
+            ">=" => Some(CompareOp::GTE),
+            "<" => Some(CompareOp::LT),
+            "<=" => Some(CompareOp::LTE),
+            _ => None,
+        };
+        if let Some(compare) = compare {
+            self.at += 1;
+            let right = self.additive()?;
+            let span = left.span.to(right.span);
+            let kind = ExprKind::Compare(compare, Box::new(left), Box::new(right));
+            return Ok(Expr::new(kind, span));
+        }
+        if op == "=" || op == "!=" || op == "<>" {
+            self.at += 1;
+            let right = self.additive()?;
+            let span = left.span.to(right.span);
+            let equals = Expr::new(ExprKind::Equals(vec![left, right]), span);
+            return Ok(negate(equals, op != "="));
+        }
+        if self.eat_keyword("IS") {
+            let negated = self.eat_keyword("NOT");
+            let null = self.span();
+            self.expect_keyword("NULL")?;
+            let span = left.span.to(null);
+            let args = vec![left, Expr::new(ExprKind::Null, null)];
+            return Ok(negate(Expr::new(ExprKind::Equals(args), span), negated));
+        }
+        let negated = self.eat_keyword("NOT");
+        if self.eat_keyword("IN") {
+            self.expect_sym("(")?;
+            let mut args = vec![left];
+            loop {
+                args.push(self.additive()?);
+                if !self.eat_sym(",") {
+                    break;
+                }
+            }
+            let span = args[0].span.to(self.span());
+            self.expect_sym(")")?;
+            self.too_many("IN", &args)?;
+            return Ok(negate(Expr::new(ExprKind::In(args), span), negated));
+        }
+        if self.eat_keyword("BETWEEN") {
+            let low = self.additive()?;
+            self.expect_keyword("AND")?;
+            let high = self.additive()?;
+            let span = left.span.to(high.span);
+            let kind = ExprKind::Between(Box::new(left), Box::new(low), Box::new(high));
+            return Ok(negate(Expr::new(kind, span), negated));
+        }
+        if self.eat_keyword("LIKE") {
+            let (pattern, at) = match self.tokens.get(self.at) {
+                Some((Tok::Str(pattern), at)) => (pattern.clone(), *at),
+                _ => return Err(self.expected("a LIKE pattern in single quotes")),
+            };
+            self.at += 1;
+            let span = left.span.to(at);
+            let kind = Expr::create_match(TokenTag::LIKE, left, &pattern).map_err(|e| e.at(at))?;
+            return Ok(negate(Expr::new(kind, span), negated));
+        }
+        if negated {
+            return Err(self.expected("IN, BETWEEN or LIKE after NOT"));
+        }
+        return Ok(left);
+    }
+
+    // * and % bind before + and -, every operator nests the operands before
+    // it one level deeper
+    fn additive(&mut self) -> Result<Expr, AstError> {
+        let depth = self.depth;
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Tok::Sym("+")) => ArithOp::ADD,
+                Some(Tok::Sym("-")) => ArithOp::SUB,
+                _ => break,
+            };
+            self.at += 1;
+            let right = self.multiplicative()?;
+            let span = left.span.to(right.span);
+            self.enter(span)?;
+            let kind = ExprKind::Arith(op, vec![left, right], self.options.overflow);
+            left = Expr::new(kind, span);
+        }
+        self.depth = depth;
+        return Ok(left);
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, AstError> {
+        let depth = self.depth;
+        let mut left = self.unary()?;
+        loop {
+            let mul = match self.peek() {
+                Some(Tok::Sym("*")) => true,
+                Some(Tok::Sym("%")) => false,
+                _ => break,
+            };
+            self.at += 1;
+            let right = self.unary()?;
+            let span = left.span.to(right.span);
+            self.enter(span)?;
+            let kind = match mul {
+                true => ExprKind::Arith(ArithOp::MUL, vec![left, right], self.options.overflow),
+                false => ExprKind::Mod(Box::new(left), Box::new(right)),
+            };
+            left = Expr::new(kind, span);
+        }
+        self.depth = depth;
+        return Ok(left);
+    }
+
+    // A minus before a number is part of the literal
+    fn unary(&mut self) -> Result<Expr, AstError> {
+        let span = self.span();
+        if !self.eat_sym("-") {
+            return self.primary();
+        }
+        if let Some(Tok::Num(digits)) = self.peek() {
+            let digits = format!("-{}", digits);
+            let end = self.span();
+            self.at += 1;
+            return Ok(Expr::new(self.number(&digits, span)?, span.to(end)));
+        }
+        self.enter(span)?;
+        let arg = self.unary()?;
+        self.depth -= 1;
+        let span = span.to(arg.span);
+        let kind = ExprKind::Arith(ArithOp::SUB, vec![arg], self.options.overflow);
+        return Ok(Expr::new(kind, span));
+    }
+
+    fn number(&self, digits: &str, span: Span) -> Result<ExprKind, AstError> {
+        if digits.contains('.') {
+            match digits.parse::<f64>() {
+                Ok(f) => return Ok(ExprKind::Float(f)),
+                Err(_) => {
+                    return Err(AstError::FORMAT_NOT_MATCH(format!(
+                        "Not a valid number {}",
+                        digits
+                    ))
+                    .at(span));
+                }
+            }
+        }
+        // The INT range reaches one further below zero than above it
+        if let Ok(i) = digits.parse::<i64>() {
+            return Ok(ExprKind::Int(i));
+        }
+        let (negative, digits) = match digits.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, digits),
+        };
+        let val = int_literal(digits, self.options.overflow).and_then(|i| match negative {
+            true => self.options.overflow.apply(ArithOp::SUB, 0, i),
+            false => Some(i),
+        });
+        match val {
+            Some(i) => return Ok(ExprKind::Int(i)),
+            None => {
+                return Err(AstError::ARITH_OVERFLOW(format!(
+                    "Integer literal {}{} is out of the INT range",
+                    if negative { "-" } else { "" },
+                    digits
+                ))
+                .at(span));
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, AstError> {
+        let span = self.span();
+        let tok = match self.peek() {
+            Some(tok) => tok.clone(),
+            None => return Err(self.expected("an operand")),
+        };
+        if tok == Tok::Sym("(") {
+            self.at += 1;
+            self.enter(span)?;
+            let expr = self.or()?;
+            self.depth -= 1;
+            let end = self.span();
+            self.expect_sym(")")?;
+            return Ok(Expr::new(expr.kind, span.to(end)));
+        }
+        let kind = match tok {
+            Tok::Num(digits) => self.number(&digits, span)?,
+            Tok::Str(s) => ExprKind::Str(s),
+            Tok::Word(word) => match word.to_ascii_uppercase().as_str() {
+                "TRUE" => ExprKind::Bool(true),
+                "FALSE" => ExprKind::Bool(false),
+                "NULL" => ExprKind::Null,
+                "AND" | "OR" | "NOT" | "IN" | "BETWEEN" | "LIKE" | "IS" => {
+                    return Err(self.expected("an operand"));
+                }
+                _ => ExprKind::Var(Var::create(&word, span, self.options, self.symbols)?),
+            },
+            Tok::Sym(_) => return Err(self.expected("an operand")),
+        };
+        self.at += 1;
+        return Ok(Expr::new(kind, span));
+    }
+}
+
+/**
+ * Expression of a WHERE clause like age >= 18 AND country IN ('DE', 'FR'),
+ * see Rule::compile_sql
+ */
+pub(crate) fn parse_where(source: &str, options: &ParserOptions) -> Result<Expr, AstError> {
+    let lexer = Lexer::create(String::new())
+        .map_err(|_| AstError::LEXER_FAILED("Lexer init failed!".to_string()))?;
+    let parser = Where {
+        tokens: scan(source)?,
+        at: 0,
+        options: options,
+        symbols: lexer.symbols(),
+        depth: 1,
+    };
+    return parser.parse();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dialect, Sql};
+    use crate::ast::{AstError, ParserOptions, Value};
+    use crate::rule::{Context, Rule};
+    use crate::ruleset::RuleSet;
+
+    fn to_sql(source: &str, dialect: Dialect) -> Sql {
+        return Rule::compile(source).unwrap().to_sql(dialect);
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let cases = vec![
+            (
+                "(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))",
+                "\"age\" >= $1 AND \"country\" IN ($2, $3)",
+                "`age` >= ? AND `country` IN (?, ?)",
+            ),
+            (
+                "(OR (EQUALS ${user.tier} 2) (NOT (BETWEEN 10 ${a} ${b})))",
+                "(\"user\".\"tier\" = $1 OR NOT ($2 BETWEEN \"a\" AND \"b\"))",
+                "(`user`.`tier` = ? OR NOT (? BETWEEN `a` AND `b`))",
+            ),
+            (
+                "(AND ${vip} (AND true (EQUALS ${x} ${y} 1)))",
+                "\"vip\" AND TRUE AND (\"x\" = \"y\" AND \"x\" = $1)",
+                "`vip` AND TRUE AND (`x` = `y` AND `x` = ?)",
+            ),
+        ];
+        for (source, postgres, mysql) in cases {
+            let sql = to_sql(source, Dialect::Postgres);
+            assert_eq!(sql.clause, postgres);
+            assert_eq!(sql.residual, None);
+            assert_eq!(to_sql(source, Dialect::MySql).clause, mysql);
+        }
+        let sql = to_sql(
+            "(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))",
+            Dialect::MySql,
+        );
+        assert_eq!(
+            sql.params,
+            vec![Value::INT(18), Value::from("DE"), Value::from("FR")]
+        );
+
+        // Args without a SQL form are left to eval, with no params
+        let sql = to_sql(
+            "(AND (GT ${amount} 100) (STARTS-WITH ${name} \"a\") (IN ${c} ${allowed}) (LT ${n} 5))",
+            Dialect::Postgres,
+        );
+        assert_eq!(sql.clause, "\"amount\" > $1 AND \"n\" < $2");
+        assert_eq!(sql.params, vec![Value::INT(100), Value::INT(5)]);
+        assert_eq!(
+            sql.residual.unwrap().to_sexpr(),
+            "(AND (STARTS-WITH ${name} \"a\") (IN ${c} ${allowed}))"
+        );
+        let sql = to_sql("(OR (GT ${a} 1) (LEN ${s}))", Dialect::Postgres);
+        assert_eq!((sql.clause.as_str(), sql.params.len()), ("TRUE", 0));
+        assert_eq!(
+            sql.residual.unwrap().to_sexpr(),
+            "(OR (GT ${a} 1) (LEN ${s}))"
+        );
+        let sql = to_sql(
+            "(AND (EQUALS ${items[0]} 1) (EQUALS ${x} NULL))",
+            Dialect::MySql,
+        );
+        assert_eq!(sql.clause, "TRUE");
+
+        // The residual matches the rows the clause selects as the rule does
+        let sql = to_sql(
+            "(AND (GT ${amount} 100) (CONTAINS ${name} \"x\"))",
+            Dialect::MySql,
+        );
+        let mut ctx = Context::new();
+        ctx.insert("name", "box");
+        assert_eq!(sql.residual.unwrap().eval(&ctx).unwrap(), Value::BOOL(true));
+
+        // Referenced rules are rendered in place
+        let set = RuleSet::compile(vec![
+            ("adult", "(GTE ${age} 18)"),
+            (
+                "target",
+                "(AND (RULE \"adult\") (EQUALS ${country} \"DE\"))",
+            ),
+        ])
+        .unwrap();
+        let sql = set.get("target").unwrap().to_sql(Dialect::Postgres);
+        assert_eq!(sql.clause, "\"age\" >= $1 AND \"country\" = $2");
+    }
+
+    #[test]
+    fn test_compile_sql() {
+        let cases = vec![
+            (
+                "age >= 18 AND country IN ('DE', 'FR')",
+                "(AND (GTE ${age} 18) (IN ${country} \"DE\" \"FR\"))",
+            ),
+            (
+                "a = 1 or b <> 'x' and not c",
+                "(OR (EQUALS ${a} 1) (AND (NOT (EQUALS ${b} \"x\")) (NOT ${c})))",
+            ),
+            (
+                "(a = 1 OR b != 2) AND c = 3 AND d < 4.5",
+                "(AND (OR (EQUALS ${a} 1) (NOT (EQUALS ${b} 2))) (EQUALS ${c} 3) (LT ${d} 4.5))",
+            ),
+            (
+                "user.tier NOT IN (1, 2) AND items[0].price BETWEEN 10 AND 20",
+                "(AND (NOT (IN ${user.tier} 1 2)) (BETWEEN ${items[0].price} 10 20))",
+            ),
+            (
+                "name NOT LIKE 'B%' AND email IS NOT NULL AND note IS NULL",
+                "(AND (NOT (LIKE ${name} \"B%\")) (NOT (EQUALS ${email} NULL)) (EQUALS ${note} NULL))",
+            ),
+            (
+                "price * qty + 5 - -discount % 3 > 100",
+                "(GT (SUB (ADD (MUL ${price} ${qty}) 5) (MOD (SUB ${discount}) 3)) 100)",
+            ),
+            ("vip = TRUE OR name = 'O''Brien'", "(OR (EQUALS ${vip} true) (EQUALS ${name} \"O'Brien\"))"),
+        ];
+        for (source, sexpr) in cases {
+            let rule = Rule::compile_sql(source).unwrap();
+            assert_eq!(rule.expr().to_sexpr(), sexpr, "{}", source);
+            assert_eq!(rule, Rule::compile(sexpr).unwrap());
+            assert_eq!(rule.source(), source);
+        }
+
+        // Negative literals have no rule content
+        let rule = Rule::compile_sql("n > -4.5 OR n < -9223372036854775808").unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("n", 0);
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+
+        let rule = Rule::compile_sql("age >= 18 AND\n  country IN ('DE', 'FR')").unwrap();
+        ctx.insert("age", 20);
+        ctx.insert("country", "FR");
+        assert_eq!(rule.eval(&ctx).unwrap(), Value::BOOL(true));
+        assert_eq!(
+            rule.to_sql(Dialect::Postgres).clause,
+            "\"age\" >= $1 AND \"country\" IN ($2, $3)"
+        );
+        ctx.insert("age", "20");
+        assert_eq!(
+            rule.eval(&ctx).unwrap_err().to_string(),
+            "rule eval failed, TYPE_NOT_MATCH: GTE operator needs two comparable args, but got STR(\"20\") and INT(18) at line 1, column 1"
+        );
+
+        let errors = vec![
+            (
+                "age >= 18 AND",
+                "NOT_MATCH: Expected an operand, but got the end at line 1, column 11",
+            ),
+            (
+                "age >= 18 country = 'DE'",
+                "NOT_MATCH: Expected AND, OR or the end, but got country at line 1, column 11",
+            ),
+            (
+                "country = \"DE\"",
+                "LEXER_FAILED: Not a valid char '\"' in the WHERE syntax, strings are written in single quotes at line 1, column 11",
+            ),
+            (
+                "a = 1 AND\n  (b = 'x)",
+                "LEXER_FAILED: String is not closed with ' at line 2, column 8",
+            ),
+            (
+                "a NOT = 1",
+                "NOT_MATCH: Expected IN, BETWEEN or LIKE after NOT, but got = at line 1, column 7",
+            ),
+            (
+                "a IN (1, 2",
+                "NOT_MATCH: Expected ), but got the end at line 1, column 10",
+            ),
+            (
+                "n > 9223372036854775808",
+                "ARITH_OVERFLOW: Integer literal 9223372036854775808 is out of the INT range at line 1, column 5",
+            ),
+            ("", "FORMAT_NOT_MATCH: Rule should have one expression, but got 0"),
+        ];
+        for (source, expected) in errors {
+            let err = Rule::compile_sql(source).unwrap_err();
+            assert_eq!(err.0.to_string(), expected, "{}", source);
+        }
+        let options = ParserOptions {
+            max_depth: 3,
+            ..ParserOptions::default()
+        };
+        let nested = "((((a = 1))))";
+        assert!(matches!(
+            Rule::compile_sql_with_options(nested, options)
+                .unwrap_err()
+                .0,
+            AstError::MAX_DEPTH_EXCEEDED(_)
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/**
+ * Timestamps are milliseconds since the Unix epoch in UTC.
+ */
+pub fn now_millis() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+// Days from 1970-01-01 to the given civil date, proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Civil date of the given days from 1970-01-01, inverse of days_from_civil
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+fn number(s: &str, from: usize, len: usize) -> Result<i64, String> {
+    match s.get(from..from + len) {
+        Some(part) if part.chars().all(|c| c.is_ascii_digit()) => {
+            Ok(part.parse::<i64>().unwrap_or(0))
+        }
+        _ => Err(format!("expect {} digits at {}", len, from)),
+    }
+}
+
+fn expect(s: &str, at: usize, c: char) -> Result<(), String> {
+    if s[at..].starts_with(c) {
+        Ok(())
+    } else {
+        Err(format!("expect '{}' at {}", c, at))
+    }
+}
+
+/**
+ * Parse `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM:SS` with optional `.fff` fraction
+ * and optional `Z` or `+HH:MM` / `-HH:MM` offset. Dates without an offset
+ * are taken as UTC.
+ */
+pub fn parse_iso8601(s: &str) -> Result<i64, String> {
+    if !s.is_ascii() {
+        return Err("timestamp should only contain ascii chars".to_string());
+    }
+    let year = number(s, 0, 4)?;
+    expect(s, 4, '-')?;
+    let month = number(s, 5, 2)?;
+    expect(s, 7, '-')?;
+    let day = number(s, 8, 2)?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return Err(format!(
+            "{}-{:02}-{:02} is not a valid date",
+            year, month, day
+        ));
+    }
+    let mut millis = days_from_civil(year, month, day) * 86_400_000;
+    let mut at = 10;
+    if at < s.len() {
+        expect(s, at, 'T')?;
+        let hour = number(s, at + 1, 2)?;
+        expect(s, at + 3, ':')?;
+        let minute = number(s, at + 4, 2)?;
+        expect(s, at + 6, ':')?;
+        let second = number(s, at + 7, 2)?;
+        if hour > 23 || minute > 59 || second > 59 {
+            return Err(format!(
+                "{:02}:{:02}:{:02} is not a valid time",
+                hour, minute, second
+            ));
+        }
+        millis += ((hour * 60 + minute) * 60 + second) * 1000;
+        at += 9;
+        if s[at..].starts_with('.') {
+            let digits = s[at + 1..]
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .count();
+            if digits == 0 {
+                return Err(format!("expect fraction digits at {}", at + 1));
+            }
+            // only milliseconds are kept
+            let fraction = &s[at + 1..at + 1 + digits.min(3)];
+            millis += fraction.parse::<i64>().unwrap_or(0) * 10_i64.pow(3 - fraction.len() as u32);
+            at += 1 + digits;
+        }
+        match s[at..].chars().next() {
+            Some('Z') => at += 1,
+            Some(sign) if sign == '+' || sign == '-' => {
+                let offset_hour = number(s, at + 1, 2)?;
+                expect(s, at + 3, ':')?;
+                let offset_minute = number(s, at + 4, 2)?;
+                let offset = (offset_hour * 60 + offset_minute) * 60_000;
+                millis -= if sign == '+' { offset } else { -offset };
+                at += 6;
+            }
+            _ => {}
+        }
+    }
+    if at != s.len() {
+        return Err(format!("unexpected trailing chars at {}", at));
+    }
+    Ok(millis)
+}
+
+/**
+ * Format as `YYYY-MM-DDTHH:MM:SSZ`, milliseconds are only written when
+ * they are not zero, so the output always parses back to the same value.
+ */
+pub fn format_iso8601(millis: i64) -> String {
+    let days = millis.div_euclid(86_400_000);
+    let rest = millis.rem_euclid(86_400_000);
+    let (year, month, day) = civil_from_days(days);
+    let (hour, minute, second) = (rest / 3_600_000, rest / 60_000 % 60, rest / 1000 % 60);
+    let mut s = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year, month, day, hour, minute, second
+    );
+    if rest % 1000 != 0 {
+        s.push_str(&format!(".{:03}", rest % 1000));
+    }
+    s.push('Z');
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_iso8601, parse_iso8601};
+
+    #[test]
+    fn test_parse_iso8601() {
+        let cases = vec![
+            ("1970-01-01", 0),
+            ("1970-01-02T00:00:00Z", 86_400_000),
+            ("2024-02-29T12:30:15Z", 1_709_209_815_000),
+            ("2024-02-29T12:30:15.5Z", 1_709_209_815_500),
+            ("2024-02-29T14:30:15+02:00", 1_709_209_815_000),
+            ("1969-12-31T23:59:59Z", -1000),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(parse_iso8601(s), Ok(expected), "{}", s);
+        }
+        for s in [
+            "2023-02-29",
+            "2024-13-01",
+            "2024-01-01T25:00:00",
+            "2024-01-01Tx",
+            "2024",
+        ] {
+            assert!(parse_iso8601(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_format_iso8601() {
+        let cases = vec![
+            (0, "1970-01-01T00:00:00Z"),
+            (1_709_209_815_500, "2024-02-29T12:30:15.500Z"),
+            (-1000, "1969-12-31T23:59:59Z"),
+        ];
+        for (millis, expected) in cases {
+            assert_eq!(format_iso8601(millis), expected);
+            assert_eq!(parse_iso8601(expected), Ok(millis));
+        }
+    }
+}
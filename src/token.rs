@@ -1,13 +1,68 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::{Deref, Range};
 use std::sync::Arc;
 #[allow(dead_code, non_camel_case_types)]
-#[derive(Debug, PartialEq,Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum TokenTag {
     AND,
     OR,
     MOD,
     IN,
     EQUALS,
+    NOT,
+    GT,
+    GTE,
+    LT,
+    LTE,
+    LET,
+    XOR,
+    NAND,
+    BETWEEN,
+    COND,
+    ELSE,
+    CONTAINS,
+    STARTS_WITH,
+    ENDS_WITH,
+    MATCH,
+    UPPER,
+    LOWER,
+    TRIM,
+    CONCAT,
+    LEN,
+    SUBSTR,
+    INDEX_OF,
+    SPLIT,
+    LIKE,
+    EQUALS_CI,
+    GET,
+    NULL,
+    COALESCE,
+    BYTES,
+    TIMESTAMP,
+    NOW,
+    ADD,
+    SUB,
+    MUL,
+    RULE,
+    CALL,
+    DEFINE,
+    LAMBDA,
+    APPLY,
+    ANY,
+    ALL,
+    FILTER,
+    SUM,
+    AVG,
+    COUNT,
+    MIN,
+    MAX,
+    HASH,
+    ROLLOUT,
+    FLOAT,
+    BOOL,
+    IDENT,
     VAR,
     OTHER,
     NUM,
@@ -17,7 +72,7 @@ pub enum TokenTag {
 }
 
 #[derive(Debug)]
-#[allow(non_camel_case_types)]
+#[allow(dead_code, non_camel_case_types)]
 pub enum ErrCode {
     READ_TO_END(String),
     OTHER(String),
@@ -38,27 +93,197 @@ impl TokenTag {
             TokenTag::LEFT_BRACKET => 264,
             TokenTag::RIGHT_BRACKET => 265,
             TokenTag::STR => 266,
+            TokenTag::NOT => 267,
+            TokenTag::GT => 268,
+            TokenTag::GTE => 269,
+            TokenTag::LT => 270,
+            TokenTag::LTE => 271,
+            TokenTag::LET => 272,
+            TokenTag::IDENT => 273,
+            TokenTag::XOR => 274,
+            TokenTag::NAND => 275,
+            TokenTag::BETWEEN => 276,
+            TokenTag::COND => 277,
+            TokenTag::ELSE => 278,
+            TokenTag::CONTAINS => 279,
+            TokenTag::STARTS_WITH => 280,
+            TokenTag::ENDS_WITH => 281,
+            TokenTag::MATCH => 282,
+            TokenTag::UPPER => 283,
+            TokenTag::LOWER => 284,
+            TokenTag::TRIM => 285,
+            TokenTag::CONCAT => 286,
+            TokenTag::LEN => 287,
+            TokenTag::SUBSTR => 288,
+            TokenTag::INDEX_OF => 289,
+            TokenTag::SPLIT => 290,
+            TokenTag::LIKE => 291,
+            TokenTag::EQUALS_CI => 292,
+            TokenTag::GET => 293,
+            TokenTag::NULL => 294,
+            TokenTag::COALESCE => 295,
+            TokenTag::BYTES => 296,
+            TokenTag::TIMESTAMP => 297,
+            TokenTag::NOW => 298,
+            TokenTag::FLOAT => 299,
+            TokenTag::BOOL => 300,
+            TokenTag::ADD => 301,
+            TokenTag::SUB => 302,
+            TokenTag::MUL => 303,
+            TokenTag::RULE => 304,
+            TokenTag::CALL => 305,
+            TokenTag::DEFINE => 306,
+            TokenTag::LAMBDA => 307,
+            TokenTag::APPLY => 308,
+            TokenTag::ANY => 309,
+            TokenTag::ALL => 310,
+            TokenTag::FILTER => 311,
+            TokenTag::SUM => 312,
+            TokenTag::AVG => 313,
+            TokenTag::COUNT => 314,
+            TokenTag::MIN => 315,
+            TokenTag::MAX => 316,
+            TokenTag::HASH => 317,
+            TokenTag::ROLLOUT => 318,
+        }
+    }
+}
+
+/**
+ * Position of a token in the rule content, start and end are byte offsets
+ * (end exclusive), line and column start from 1 and count chars
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    // From the start of this span to the end of the other one
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end.max(self.end),
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/**
+ * Text of a token, scanned tokens share the rule content and only keep
+ * the byte range of their text, so that reading a lexeme never copies
+ */
+#[derive(Debug, Clone)]
+pub enum Lexeme {
+    Slice(Arc<str>, usize, usize),
+    Static(&'static str),
+    Owned(String),
+}
+
+impl Lexeme {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Lexeme::Slice(content, start, end) => &content[*start..*end],
+            Lexeme::Static(s) => s,
+            Lexeme::Owned(s) => s,
         }
     }
 }
 
+impl Deref for Lexeme {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        return self.as_str();
+    }
+}
+
+impl PartialEq for Lexeme {
+    fn eq(&self, other: &Lexeme) -> bool {
+        return self.as_str() == other.as_str();
+    }
+}
+
+impl PartialEq<&str> for Lexeme {
+    fn eq(&self, other: &&str) -> bool {
+        return self.as_str() == *other;
+    }
+}
+
+impl fmt::Display for Lexeme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<&'static str> for Lexeme {
+    fn from(s: &'static str) -> Self {
+        Lexeme::Static(s)
+    }
+}
+
+impl From<String> for Lexeme {
+    fn from(s: String) -> Self {
+        Lexeme::Owned(s)
+    }
+}
+
+/**
+ * Names interned while parsing, every occurrence of a name shares one
+ * allocation so that cloning it never copies
+ */
+#[derive(Debug, Default)]
+pub struct Symbols {
+    names: RefCell<HashSet<Arc<str>>>,
+}
+
+impl Symbols {
+    pub fn intern(&self, name: &str) -> Arc<str> {
+        let mut names = self.names.borrow_mut();
+        if let Some(interned) = names.get(name) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = Arc::from(name);
+        names.insert(interned.clone());
+        return interned;
+    }
+}
+
 pub trait Token {
     fn token_tag(&self) -> &TokenTag;
-    fn lexeme(&self) -> String;
+    fn lexeme(&self) -> &Lexeme;
+    fn span(&self) -> Span;
+    fn set_span(&mut self, span: Span);
 }
 
 #[derive(Debug)]
 pub struct OpType {
     pub tag: TokenTag,
-    pub lexeme: String,
+    pub lexeme: Lexeme,
+    pub span: Span,
 }
 
 impl OpType {
     #[allow(dead_code)]
-    fn create_with_token(token_tag: TokenTag, lexeme: String) -> Result<Box<dyn Token>, ErrCode> {
+    fn create_with_token(
+        token_tag: TokenTag,
+        lexeme: impl Into<Lexeme>,
+    ) -> Result<Box<dyn Token>, ErrCode> {
+        let lexeme = lexeme.into();
         Ok(Box::new(OpType {
             tag: token_tag,
             lexeme: lexeme,
+            span: Span::default(),
         }))
     }
 }
@@ -67,26 +292,35 @@ impl Token for OpType {
     fn token_tag(&self) -> &TokenTag {
         return &self.tag;
     }
-    fn lexeme(&self) -> String {
-        return self.lexeme.clone();
+    fn lexeme(&self) -> &Lexeme {
+        return &self.lexeme;
+    }
+    fn span(&self) -> Span {
+        return self.span;
+    }
+    fn set_span(&mut self, span: Span) {
+        self.span = span;
     }
 }
 
 #[derive(Debug)]
 pub struct Var {
-    s: String,
+    s: Lexeme,
     token_tag: TokenTag,
+    span: Span,
 }
 
 impl Var {
     #[allow(dead_code)]
     pub fn create_with_token_and_val(
         token_tag: TokenTag,
-        s: String,
+        s: impl Into<Lexeme>,
     ) -> Result<Box<dyn Token>, ErrCode> {
+        let s = s.into();
         Ok(Box::new(Var {
             s: s,
             token_tag: token_tag,
+            span: Span::default(),
         }))
     }
 }
@@ -95,8 +329,14 @@ impl Token for Var {
     fn token_tag(&self) -> &TokenTag {
         return &self.token_tag;
     }
-    fn lexeme(&self) -> String {
-        return self.s.clone();
+    fn lexeme(&self) -> &Lexeme {
+        return &self.s;
+    }
+    fn span(&self) -> Span {
+        return self.span;
+    }
+    fn set_span(&mut self, span: Span) {
+        self.span = span;
     }
 }
 
@@ -104,23 +344,32 @@ impl Token for Var {
 #[derive(Debug)]
 pub struct Num {
     token_tag: TokenTag,
-    val: i64,
-    lexeme: String,
+    lexeme: Lexeme,
+    span: Span,
 }
 impl Num {
+    // Decimal digits or 0x / 0b followed by digits, values out of the INT
+    // range are left to the overflow policy of the parser
     #[allow(dead_code)]
     pub fn create_with_token_and_val(
         token_tag: TokenTag,
-        lexeme: String,
+        lexeme: impl Into<Lexeme>,
     ) -> Result<Box<dyn Token>, ErrCode> {
-        if !lexeme.parse::<i64>().is_ok() {
-            println!("lexeme is {}", lexeme);
-            return Err(ErrCode::OTHER("Not a number lexeme".to_string()));
+        let lexeme = lexeme.into();
+        let (digits, radix) = if let Some(digits) = lexeme.strip_prefix("0x") {
+            (digits, 16)
+        } else if let Some(digits) = lexeme.strip_prefix("0b") {
+            (digits, 2)
+        } else {
+            (&lexeme[..], 10)
+        };
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err(ErrCode::OTHER(format!("Not a number lexeme: {}", lexeme)));
         }
         Ok(Box::new(Num {
             token_tag: token_tag,
-            val: lexeme.parse::<i64>().unwrap(),
             lexeme: lexeme,
+            span: Span::default(),
         }))
     }
 }
@@ -130,26 +379,77 @@ impl Token for Num {
         return &self.token_tag;
     }
     #[allow(dead_code)]
-    fn lexeme(&self) -> String {
-        return self.lexeme.clone();
+    fn lexeme(&self) -> &Lexeme {
+        return &self.lexeme;
+    }
+    fn span(&self) -> Span {
+        return self.span;
+    }
+    fn set_span(&mut self, span: Span) {
+        self.span = span;
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Float {
+    token_tag: TokenTag,
+    val: f64,
+    lexeme: Lexeme,
+    span: Span,
+}
+impl Float {
+    #[allow(dead_code)]
+    pub fn create_with_token_and_val(
+        token_tag: TokenTag,
+        lexeme: impl Into<Lexeme>,
+    ) -> Result<Box<dyn Token>, ErrCode> {
+        let lexeme = lexeme.into();
+        match lexeme.parse::<f64>() {
+            Ok(val) => Ok(Box::new(Float {
+                token_tag: token_tag,
+                val: val,
+                lexeme: lexeme,
+                span: Span::default(),
+            })),
+            Err(_) => Err(ErrCode::OTHER(format!("Not a float lexeme: {}", lexeme))),
+        }
+    }
+}
+
+impl Token for Float {
+    fn token_tag(&self) -> &TokenTag {
+        return &self.token_tag;
+    }
+    fn lexeme(&self) -> &Lexeme {
+        return &self.lexeme;
+    }
+    fn span(&self) -> Span {
+        return self.span;
+    }
+    fn set_span(&mut self, span: Span) {
+        self.span = span;
     }
 }
 
 #[derive(Debug)]
 pub struct Str {
     token_tag: TokenTag,
-    s: String,
+    s: Lexeme,
+    span: Span,
 }
 
 impl Str {
     #[allow(dead_code)]
     pub fn create_with_token_and_val(
         token_tag: TokenTag,
-        s: String,
+        s: impl Into<Lexeme>,
     ) -> Result<Box<dyn Token>, ErrCode> {
+        let s = s.into();
         Ok(Box::new(Str {
             token_tag: token_tag,
             s: s,
+            span: Span::default(),
         }))
     }
 }
@@ -158,23 +458,34 @@ impl Token for Str {
     fn token_tag(&self) -> &TokenTag {
         return &self.token_tag;
     }
-    fn lexeme(&self) -> String {
-        return self.s.clone();
+    fn lexeme(&self) -> &Lexeme {
+        return &self.s;
+    }
+    fn span(&self) -> Span {
+        return self.span;
+    }
+    fn set_span(&mut self, span: Span) {
+        self.span = span;
     }
 }
 
 #[derive(Debug)]
 struct Other {
     token_tag: TokenTag,
-    lexeme: String,
+    lexeme: Lexeme,
+    span: Span,
 }
 
 impl Other {
     #[allow(dead_code)]
-    fn create_with_token_and_val(token_tag: TokenTag, s: char) -> Result<Box<dyn Token>, ErrCode> {
+    fn create_with_token_and_val(
+        token_tag: TokenTag,
+        lexeme: Lexeme,
+    ) -> Result<Box<dyn Token>, ErrCode> {
         Ok(Box::new(Other {
             token_tag: token_tag,
-            lexeme: s.to_string(),
+            lexeme: lexeme,
+            span: Span::default(),
         }))
     }
 }
@@ -182,50 +493,257 @@ impl Token for Other {
     fn token_tag(&self) -> &TokenTag {
         return &self.token_tag;
     }
-    fn lexeme(&self) -> String {
-        return self.lexeme.clone();
+    fn lexeme(&self) -> &Lexeme {
+        return &self.lexeme;
+    }
+    fn span(&self) -> Span {
+        return self.span;
+    }
+    fn set_span(&mut self, span: Span) {
+        self.span = span;
     }
 }
 
+/**
+ * Scanner of rule content, an iterator over its tokens. An editor can keep
+ * the tokens and update them after every edit with relex.
+ */
 #[allow(dead_code)]
 pub struct Lexer {
     reserved: HashMap<String, Arc<Box<dyn Token>>>,
-    rule_content: String,
+    rule_content: Arc<str>,
+    // Variable names of all the content loaded so far
+    symbols: Symbols,
     chars: Vec<char>,
+    // byte offset of every char, with the content length at the end
+    offsets: Vec<usize>,
+    // char index where every line starts
+    line_starts: Vec<usize>,
     cur_step: i32,
     peek: Option<char>,
+    // Set when the iterator has met the end or an error
+    finished: bool,
+    // After an illegal variable, the next scan skips the rest of it up to
+    // the closing `}` so that it is not taken as a bracket
+    skip_var: bool,
 }
 
 impl Lexer {
-    #[allow(dead_code)]
+    // The reserved tokens are only read on the thread of the lexer
+    #[allow(dead_code, clippy::arc_with_non_send_sync)]
     pub fn create(content: String) -> Result<Lexer, ErrCode> {
         let mut reserved: HashMap<String, Arc<Box<dyn Token>>> = HashMap::new();
-        let and_ops = OpType::create_with_token(TokenTag::AND, "AND".to_string())?;
-        let or_ops = OpType::create_with_token(TokenTag::OR, "OR".to_string())?;
-        let mod_ops = OpType::create_with_token(TokenTag::MOD, "MOD".to_string())?;
-        let in_ops = OpType::create_with_token(TokenTag::IN, "IN".to_string())?;
-        let eq_ops = OpType::create_with_token(TokenTag::EQUALS, "EQUAL".to_string())?;
-        reserved.insert(and_ops.lexeme(), Arc::new(and_ops));
-        reserved.insert(or_ops.lexeme(), Arc::new(or_ops));
-        reserved.insert(mod_ops.lexeme(), Arc::new(mod_ops));
-        reserved.insert(in_ops.lexeme(), Arc::new(in_ops));
-        reserved.insert(eq_ops.lexeme(), Arc::new(eq_ops));
-        let chars: Vec<char> = content.chars().collect();
-        Ok(Lexer {
+        // Every keyword and operator is one entry here
+        for (tag, lexeme) in [
+            (TokenTag::AND, "AND"),
+            (TokenTag::OR, "OR"),
+            (TokenTag::MOD, "MOD"),
+            (TokenTag::IN, "IN"),
+            (TokenTag::EQUALS, "EQUALS"),
+            (TokenTag::NOT, "NOT"),
+            (TokenTag::GT, "GT"),
+            (TokenTag::GTE, "GTE"),
+            (TokenTag::LT, "LT"),
+            (TokenTag::LTE, "LTE"),
+            (TokenTag::LET, "LET"),
+            (TokenTag::XOR, "XOR"),
+            (TokenTag::NAND, "NAND"),
+            (TokenTag::BETWEEN, "BETWEEN"),
+            (TokenTag::COND, "COND"),
+            (TokenTag::ELSE, "ELSE"),
+            (TokenTag::CONTAINS, "CONTAINS"),
+            (TokenTag::STARTS_WITH, "STARTS-WITH"),
+            (TokenTag::ENDS_WITH, "ENDS-WITH"),
+            (TokenTag::MATCH, "MATCH"),
+            (TokenTag::UPPER, "UPPER"),
+            (TokenTag::LOWER, "LOWER"),
+            (TokenTag::TRIM, "TRIM"),
+            (TokenTag::CONCAT, "CONCAT"),
+            (TokenTag::LEN, "LEN"),
+            (TokenTag::SUBSTR, "SUBSTR"),
+            (TokenTag::INDEX_OF, "INDEX-OF"),
+            (TokenTag::SPLIT, "SPLIT"),
+            (TokenTag::LIKE, "LIKE"),
+            (TokenTag::EQUALS_CI, "EQUALS-CI"),
+            (TokenTag::GET, "GET"),
+            (TokenTag::NULL, "NULL"),
+            (TokenTag::COALESCE, "COALESCE"),
+            (TokenTag::NOW, "NOW"),
+            (TokenTag::ADD, "ADD"),
+            (TokenTag::SUB, "SUB"),
+            (TokenTag::MUL, "MUL"),
+            (TokenTag::RULE, "RULE"),
+            (TokenTag::CALL, "CALL"),
+            (TokenTag::DEFINE, "DEFINE"),
+            (TokenTag::LAMBDA, "LAMBDA"),
+            (TokenTag::APPLY, "APPLY"),
+            (TokenTag::ANY, "ANY"),
+            (TokenTag::ALL, "ALL"),
+            (TokenTag::FILTER, "FILTER"),
+            (TokenTag::SUM, "SUM"),
+            (TokenTag::AVG, "AVG"),
+            (TokenTag::COUNT, "COUNT"),
+            (TokenTag::MIN, "MIN"),
+            (TokenTag::MAX, "MAX"),
+            (TokenTag::HASH, "HASH"),
+            (TokenTag::ROLLOUT, "ROLLOUT"),
+            (TokenTag::BOOL, "TRUE"),
+            (TokenTag::BOOL, "FALSE"),
+        ] {
+            let ops = OpType::create_with_token(tag, lexeme)?;
+            reserved.insert(ops.lexeme().to_string(), Arc::new(ops));
+        }
+        let mut lexer = Lexer {
+            offsets: Vec::new(),
+            line_starts: Vec::new(),
             reserved: reserved,
-            rule_content: content,
+            rule_content: Arc::from(""),
+            symbols: Symbols::default(),
             cur_step: -1,
             peek: None,
-            chars: chars,
-        })
+            finished: false,
+            skip_var: false,
+            chars: Vec::new(),
+        };
+        lexer.load(content);
+        Ok(lexer)
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        return &self.symbols;
+    }
+
+    // Take the content and scan it from the start
+    fn load(&mut self, content: String) {
+        self.chars = content.chars().collect();
+        self.offsets = content.char_indices().map(|(i, _)| i).collect();
+        self.offsets.push(content.len());
+        self.line_starts = vec![0];
+        for (i, c) in self.chars.iter().enumerate() {
+            if *c == '\n' {
+                self.line_starts.push(i + 1);
+            }
+        }
+        self.rule_content = Arc::from(content);
+        self.cur_step = -1;
+        self.peek = None;
+        self.finished = false;
+        self.skip_var = false;
+    }
+
+    // Char index of the byte offset
+    fn char_index(&self, offset: usize) -> usize {
+        return self.offsets.partition_point(|o| *o < offset);
+    }
+
+    /**
+     * Replace the bytes in range with new_text, tokens are the tokens of
+     * the content before the edit and are updated in place. Only the
+     * tokens from the one before the edit are scanned again, until a new
+     * token lines up with an old one after the edit, the old tokens from
+     * there on are kept with their spans moved. The lexer is at the end
+     * of the new content afterwards. When an error is returned, tokens
+     * are left as they were and should be scanned again from the start.
+     *
+     * ```
+     * use newlisp::{Lexer, Token};
+     *
+     * let content = "(GT ${age} 18)";
+     * let mut tokens: Vec<Box<dyn Token>> = Lexer::create(content.to_string())
+     *     .unwrap()
+     *     .map(|t| t.unwrap())
+     *     .collect();
+     * let mut lexer = Lexer::create(content.to_string()).unwrap();
+     * lexer.relex(&mut tokens, 11..13, "21").unwrap();
+     * assert_eq!(tokens[3].lexeme().as_str(), "21");
+     * ```
+     */
+    pub fn relex(
+        &mut self,
+        tokens: &mut Vec<Box<dyn Token>>,
+        range: Range<usize>,
+        new_text: &str,
+    ) -> Result<(), ErrCode> {
+        let old = self.rule_content.clone();
+        if range.start > range.end
+            || range.end > old.len()
+            || !old.is_char_boundary(range.start)
+            || !old.is_char_boundary(range.end)
+        {
+            return Err(ErrCode::OTHER(format!(
+                "Illegal relex range {:?} for content of {} bytes",
+                range,
+                old.len()
+            )));
+        }
+        self.load(format!(
+            "{}{}{}",
+            &old[..range.start],
+            new_text,
+            &old[range.end..]
+        ));
+        let edit_end = range.start + new_text.len();
+        let moved = |offset: usize| offset + edit_end - range.end;
+        // tokens touching the edit may change, like words growing longer
+        let first = tokens
+            .iter()
+            .position(|t| t.span().end >= range.start)
+            .unwrap_or(tokens.len());
+        let from = if first == 0 {
+            0
+        } else {
+            tokens[first - 1].span().end
+        };
+        self.cur_step = self.char_index(from) as i32 - 1;
+        let mut fresh: Vec<Box<dyn Token>> = Vec::new();
+        let mut kept = tokens.len();
+        let mut old_index = first;
+        loop {
+            let token = match self.scan() {
+                Ok(token) => token,
+                Err(ErrCode::READ_TO_END(_)) => break,
+                Err(e) => return Err(e),
+            };
+            let span = token.span();
+            if span.start >= edit_end {
+                while old_index < tokens.len()
+                    && (tokens[old_index].span().start < range.end
+                        || moved(tokens[old_index].span().start) < span.start)
+                {
+                    old_index += 1;
+                }
+                if let Some(old_token) = tokens.get(old_index) {
+                    let old_span = old_token.span();
+                    if moved(old_span.start) == span.start
+                        && moved(old_span.end) == span.end
+                        && old_token.token_tag() == token.token_tag()
+                    {
+                        kept = old_index;
+                        break;
+                    }
+                }
+            }
+            fresh.push(token);
+        }
+        let fresh_len = fresh.len();
+        tokens.splice(first..kept, fresh);
+        for token in tokens[first + fresh_len..].iter_mut() {
+            let span = token.span();
+            let from = self.char_index(moved(span.start)) as i32;
+            let to = self.char_index(moved(span.end)) as i32 - 1;
+            token.set_span(self.span_of(from, to));
+        }
+        self.cur_step = self.chars.len() as i32 - 1;
+        self.peek = self.chars.last().copied();
+        Ok(())
     }
 
     #[allow(dead_code)]
-    fn read(step: &mut i32, peek: &mut Option<char>, c: &Vec<char>) -> Result<(), ErrCode> {
+    fn read(step: &mut i32, peek: &mut Option<char>, c: &[char]) -> Result<(), ErrCode> {
         *step += 1;
         match c.get(*step as usize) {
             Some(i) => {
-                peek.replace(i.clone());
+                peek.replace(*i);
             }
             None => {
                 return Err(ErrCode::READ_TO_END("Has read to the end".to_string()));
@@ -237,7 +755,7 @@ impl Lexer {
     fn back_read(
         step: &mut i32,
         peek: &mut Option<char>,
-        c: &Vec<char>,
+        c: &[char],
         ori_step: i32,
     ) -> Result<(), ErrCode> {
         loop {
@@ -247,7 +765,7 @@ impl Lexer {
             *step -= 1;
             match c.get(*step as usize) {
                 Some(i) => {
-                    peek.replace(i.clone());
+                    peek.replace(*i);
                 }
                 None => {
                     return Err(ErrCode::OTHER("Back failed!".to_string()));
@@ -271,19 +789,116 @@ impl Lexer {
     }
 
     /**
-     * Skip all the blank chars
+     * Try to read the rest chars of a keyword, step back to the
+     * original position when they don't match
+     */
+    fn read_word(&mut self, rest: &str) -> Result<bool, ErrCode> {
+        let ori_step = self.cur_step;
+        let ori_peek = self.peek;
+        for c in rest.chars() {
+            match Self::read(&mut self.cur_step, &mut self.peek, &self.chars) {
+                Ok(()) if self.peek == Some(c) => continue,
+                Ok(()) | Err(ErrCode::READ_TO_END(_)) => {
+                    self.cur_step = ori_step;
+                    self.peek = ori_peek;
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /**
+     * Read the following chars as long as they satisfy pred, the last
+     * matched char stays as peek
+     */
+    fn read_while(&mut self, pred: impl Fn(char) -> bool) -> Lexeme {
+        let from = self.cur_step + 1;
+        loop {
+            let step = self.cur_step;
+            let read_result = Self::read(&mut self.cur_step, &mut self.peek, &self.chars);
+            let peek = self.peek.unwrap_or(' ');
+            if read_result.is_err() || !pred(peek) {
+                self.cur_step = step;
+                self.peek = self.chars.get(step as usize).copied();
+                return self.lexeme_of(from, step);
+            }
+        }
+    }
+
+    // Read the .key and [index] segments right after a word, so a bare
+    // word like item.amount is the path of a bound name
+    fn read_path(&mut self) {
+        let mut end = self.cur_step as usize;
+        loop {
+            let after = self.chars.get(end + 2).copied().unwrap_or(' ');
+            match self.chars.get(end + 1) {
+                Some('.') if after.is_ascii_alphabetic() || after == '_' => {
+                    end += 2;
+                    while self
+                        .chars
+                        .get(end + 1)
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+                    {
+                        end += 1;
+                    }
+                }
+                Some('[') if after.is_ascii_digit() => {
+                    let mut at = end + 2;
+                    while self.chars.get(at).is_some_and(|c| c.is_ascii_digit()) {
+                        at += 1;
+                    }
+                    if self.chars.get(at) != Some(&']') {
+                        break;
+                    }
+                    end = at;
+                }
+                _ => break,
+            }
+        }
+        self.cur_step = end as i32;
+        self.peek = self.chars.get(end).copied();
+    }
+
+    /**
+     * Lexeme of the chars from index `from` to `to`, both inclusive, it is
+     * empty when `to` is before `from`
+     */
+    fn lexeme_of(&self, from: i32, to: i32) -> Lexeme {
+        let start = self.offsets[from as usize];
+        let end = self.offsets[(to + 1).max(from) as usize];
+        return Lexeme::Slice(self.rule_content.clone(), start, end);
+    }
+
+    /**
+     * Skip all the blank chars and comments, `;` comments run to the end
+     * of the line and `#| ... |#` comments may span several lines
      */
     #[allow(dead_code)]
     fn skip_blank_and_read(
         step: &mut i32,
         peek: &mut Option<char>,
-        chars: &Vec<char>,
+        chars: &[char],
     ) -> Result<(), ErrCode> {
         loop {
             Self::read(step, peek, chars)?;
-            let peek = peek.as_ref().unwrap_or(&' ').clone();
-            if peek == ' ' || peek == '\t' {
+            let cur = *peek.as_ref().unwrap_or(&' ');
+            if cur == ' ' || cur == '\t' || cur == '\n' || cur == '\r' {
                 continue;
+            } else if cur == ';' {
+                while *peek != Some('\n') {
+                    Self::read(step, peek, chars)?;
+                }
+            } else if cur == '#' && chars.get(*step as usize + 1) == Some(&'|') {
+                Self::read(step, peek, chars)?;
+                loop {
+                    Self::read(step, peek, chars)?;
+                    if *peek == Some('|') && chars.get(*step as usize + 1) == Some(&'#') {
+                        Self::read(step, peek, chars)?;
+                        break;
+                    }
+                }
             } else {
                 break;
             }
@@ -291,149 +906,286 @@ impl Lexer {
         Ok(())
     }
 
+    /**
+     * Span of the chars from index `from` to `to`, both inclusive
+     */
+    fn span_of(&self, from: i32, to: i32) -> Span {
+        let from = from.max(0) as usize;
+        let to = (to.max(0) as usize + 1).clamp(from, self.chars.len());
+        let line = self.line_starts.partition_point(|s| *s <= from);
+        Span {
+            start: self.offsets[from.min(self.chars.len())],
+            end: self.offsets[to],
+            line: line,
+            column: from - self.line_starts[line - 1] + 1,
+        }
+    }
+
+    // Tag of the keyword or operator, words are matched case-insensitively
+    pub fn keyword(&self, word: &str) -> Option<TokenTag> {
+        self.reserved
+            .get(&word.to_ascii_uppercase())
+            .map(|token| token.token_tag().clone())
+    }
+
+    /**
+     * Position of the char which the lexer is standing on
+     */
+    pub fn position(&self) -> Span {
+        return self.span_of(self.cur_step, self.cur_step);
+    }
+
     #[allow(dead_code)]
     pub fn scan(&mut self) -> Result<Box<dyn Token>, ErrCode> {
+        if self.skip_var {
+            self.skip_var = false;
+            while self.peek != Some('}') {
+                Self::read(&mut self.cur_step, &mut self.peek, &self.chars)?;
+            }
+        }
         Self::skip_blank_and_read(&mut self.cur_step, &mut self.peek, &self.chars)?;
+        let start_step = self.cur_step;
+        let mut token = self.scan_token()?;
+        token.set_span(self.span_of(start_step, self.cur_step));
+        Ok(token)
+    }
+
+    #[allow(dead_code)]
+    fn scan_token(&mut self) -> Result<Box<dyn Token>, ErrCode> {
         // 操作符Token匹配
         match self.peek {
-            Some('(') => {
-                return Ok(Other::create_with_token_and_val(
+            // Square and curly brackets group like parentheses, the parser
+            // checks that they are closed by the same kind
+            Some('(') | Some('[') | Some('{') => {
+                return Other::create_with_token_and_val(
                     TokenTag::LEFT_BRACKET,
-                    self.peek.as_ref().unwrap_or(&' ').clone(),
-                )?);
+                    self.lexeme_of(self.cur_step, self.cur_step),
+                );
             }
-            Some(')') => {
-                return Ok(Other::create_with_token_and_val(
+            Some(')') | Some(']') | Some('}') => {
+                return Other::create_with_token_and_val(
                     TokenTag::RIGHT_BRACKET,
-                    self.peek.as_ref().unwrap_or(&' ').clone(),
-                )?);
-            }
-            Some('I') => {
-                let ori_step = self.cur_step.clone();
-                if self.read_next('N')? {
-                    return Ok(OpType::create_with_token(TokenTag::IN, "IN".to_string())?);
-                } else {
-                    Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, ori_step)?;
-                    return Ok(Other::create_with_token_and_val(
-                        TokenTag::OTHER,
-                        self.peek.as_ref().unwrap_or(&' ').clone(),
-                    )?);
-                }
-            }
-            Some('M') => {
-                let ori_step = self.cur_step.clone();
-                if self.read_next('O')? && self.read_next('D')? {
-                    return Ok(OpType::create_with_token(TokenTag::MOD, "MOD".to_string())?);
-                } else {
-                    Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, ori_step)?;
-                    return Ok(Other::create_with_token_and_val(
-                        TokenTag::OTHER,
-                        self.peek.as_ref().unwrap_or(&' ').clone(),
-                    )?);
-                }
-            }
-            Some('A') => {
-                let ori_step = self.cur_step.clone();
-                if self.read_next('N')? && self.read_next('D')? {
-                    return Ok(OpType::create_with_token(TokenTag::AND, "AND".to_string())?);
-                } else {
-                    Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, ori_step)?;
-                    return Ok(Other::create_with_token_and_val(
-                        TokenTag::OTHER,
-                        self.peek.as_ref().unwrap_or(&' ').clone(),
-                    )?);
-                }
-            }
-            Some('O') => {
-                let ori_step = self.cur_step.clone();
-                if self.read_next('R')? {
-                    return Ok(OpType::create_with_token(TokenTag::OR, "OR".to_string())?);
-                } else {
-                    Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, ori_step)?;
-                    return Ok(Other::create_with_token_and_val(
-                        TokenTag::OTHER,
-                        self.peek.as_ref().unwrap_or(&' ').clone(),
-                    )?);
-                }
-            }
-            Some('E') => {
-                let ori_step = self.cur_step.clone();
-                if self.read_next('Q')?
-                    && self.read_next('U')?
-                    && self.read_next('A')?
-                    && self.read_next('L')?
-                    && self.read_next('S')?
-                {
-                    return Ok(OpType::create_with_token(
-                        TokenTag::EQUALS,
-                        "EQUALS".to_string(),
-                    )?);
-                } else {
-                    Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, ori_step)?;
-                    return Ok(Other::create_with_token_and_val(
-                        TokenTag::OTHER,
-                        self.peek.as_ref().unwrap_or(&' ').clone(),
-                    )?);
-                }
+                    self.lexeme_of(self.cur_step, self.cur_step),
+                );
             }
             Some('"') => {
-                let mut s = String::new();
+                let start_step = self.cur_step;
                 loop {
                     Self::read(&mut self.cur_step, &mut self.peek, &self.chars)?;
-                    let cur_peek = self.peek.as_ref().unwrap_or(&' ').clone();
-                    if cur_peek == '"' {
-                        return Ok(OpType::create_with_token(
-                            TokenTag::STR,
-                            s,
-                        )?);
+                    if self.peek == Some('"') {
+                        let s = self.lexeme_of(start_step + 1, self.cur_step - 1);
+                        return OpType::create_with_token(TokenTag::STR, s);
                     }
-                    s.push(cur_peek);
                 }
             }
             _ => {}
         }
+        // Bytes literal like #x"00ff", two hex digits for every byte
+        if self.peek == Some('#') && self.read_word("x\"")? {
+            let hex = self.read_while(|c| c.is_ascii_hexdigit());
+            if !self.read_word("\"")? || hex.is_empty() || !hex.len().is_multiple_of(2) {
+                return Err(ErrCode::OTHER(format!(
+                    "Illegal bytes literal #x\"{}, it should have even hex digits, char index:{}",
+                    hex, self.cur_step
+                )));
+            }
+            return Str::create_with_token_and_val(TokenTag::BYTES, hex);
+        }
+        // Hex and binary integer literals like 0xff and 0b1010, the value
+        // and its range are checked by the parser
+        if self.peek == Some('0') {
+            let start_step = self.cur_step;
+            for (prefix, radix) in [("x", 16), ("b", 2)] {
+                if self.read_word(prefix)? {
+                    let digits = self.read_while(|c| c.is_digit(radix));
+                    if digits.is_empty() {
+                        return Err(ErrCode::OTHER(format!(
+                            "Illegal integer literal 0{}, char index:{}",
+                            prefix, self.cur_step
+                        )));
+                    }
+                    return Num::create_with_token_and_val(
+                        TokenTag::NUM,
+                        self.lexeme_of(start_step, self.cur_step),
+                    );
+                }
+            }
+        }
         // Numberic Token analyze
-        if self.peek.as_ref().unwrap_or(&' ').clone().is_numeric() {
-            let mut v = 0;
+        if (*self.peek.as_ref().unwrap_or(&' ')).is_numeric() {
+            let start_step = self.cur_step;
             loop {
-                v = 10 * v + self.peek.unwrap().to_digit(10 as u32).unwrap();
-                let ori_step = self.cur_step.clone();
+                let ori_step = self.cur_step;
                 Self::read(&mut self.cur_step, &mut self.peek, &self.chars)?;
-                if !self.peek.as_ref().unwrap_or(&' ').clone().is_numeric() {
+                if !(*self.peek.as_ref().unwrap_or(&' ')).is_numeric() {
                     Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, ori_step)?;
                     break;
                 }
             }
-            return Ok(Num::create_with_token_and_val(
+            // ISO-8601 timestamp literal like 2024-01-31T12:00:00Z, it is
+            // validated by the parser
+            if self.cur_step - start_step == 3
+                && self.chars.get(self.cur_step as usize + 1) == Some(&'-')
+            {
+                loop {
+                    let step = self.cur_step;
+                    let read_result = Self::read(&mut self.cur_step, &mut self.peek, &self.chars);
+                    let peek = self.peek.unwrap_or(' ');
+                    if read_result.is_err() || !(peek.is_ascii_digit() || "-:TZ+.".contains(peek)) {
+                        Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, step)?;
+                        break;
+                    }
+                }
+                let iso = self.lexeme_of(start_step, self.cur_step);
+                return Str::create_with_token_and_val(TokenTag::TIMESTAMP, iso);
+            }
+            // Fraction part of a float literal like 3.14, a dot without
+            // digits after it is not part of the number
+            let ori_step = self.cur_step;
+            if self.read_next('.')? {
+                let dot_step = self.cur_step;
+                loop {
+                    let step = self.cur_step;
+                    let read_result = Self::read(&mut self.cur_step, &mut self.peek, &self.chars);
+                    let peek = self.peek.unwrap_or(' ');
+                    if read_result.is_err() || !peek.is_ascii_digit() {
+                        Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, step)?;
+                        break;
+                    }
+                }
+                if self.cur_step > dot_step {
+                    return Float::create_with_token_and_val(
+                        TokenTag::FLOAT,
+                        self.lexeme_of(start_step, self.cur_step),
+                    );
+                }
+            }
+            Self::back_read(&mut self.cur_step, &mut self.peek, &self.chars, ori_step)?;
+            return Num::create_with_token_and_val(
                 TokenTag::NUM,
-                v.to_string(),
-            )?);
+                self.lexeme_of(start_step, self.cur_step),
+            );
         }
         // Var Token analyze
-        if self.peek.as_ref().unwrap_or(&' ').clone() == '$' && self.read_next('{')? {
-            let mut id = String::new();
+        if *self.peek.as_ref().unwrap_or(&' ') == '$' && self.read_next('{')? {
+            let start_step = self.cur_step + 1;
             loop {
                 Self::read(&mut self.cur_step, &mut self.peek, &self.chars)?;
-                let peek_num = self.peek.as_ref().unwrap_or(&' ').clone();
+                let peek_num = *self.peek.as_ref().unwrap_or(&' ');
                 if peek_num.is_numeric()
-                    || (peek_num >= 'a' && peek_num <= 'z')
-                    || (peek_num >= 'A' && peek_num <= 'Z')
+                    || peek_num.is_ascii_lowercase()
+                    || peek_num.is_ascii_uppercase()
+                    || peek_num == '_'
+                    || peek_num == '.'
+                    || peek_num == '['
+                    || peek_num == ']'
                 {
-                    id.push(peek_num);
+                    continue;
                 } else if peek_num == '}' {
-                    return Ok(Var::create_with_token_and_val(TokenTag::VAR, id)?);
+                    let id = self.lexeme_of(start_step, self.cur_step - 1);
+                    return Var::create_with_token_and_val(TokenTag::VAR, id);
                 } else {
+                    self.skip_var = true;
                     return Err(ErrCode::OTHER(format!(
-                            "Illegal arg format for id, id should only contains a-zA-Z0-9, char index:{}",
+                            "Illegal arg format for id, id should only contains a-zA-Z0-9_.[], char index:{}",
                             self.cur_step
                         )));
                 }
             }
         }
-        Ok(Other::create_with_token_and_val(
+        // Word Token analyze, reserved words are keywords and operators
+        // matched case-insensitively, the others are identifiers used by
+        // names bound in LET
+        if self.peek.unwrap_or(' ').is_ascii_alphabetic() || self.peek == Some('_') {
+            let start_step = self.cur_step;
+            self.read_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            self.read_path();
+            let word = self.lexeme_of(start_step, self.cur_step);
+            if let Some(reserved) = self.reserved.get(&word.to_ascii_uppercase()) {
+                return OpType::create_with_token(
+                    reserved.token_tag().clone(),
+                    reserved.lexeme().clone(),
+                );
+            }
+            return Var::create_with_token_and_val(TokenTag::IDENT, word);
+        }
+        Other::create_with_token_and_val(
             TokenTag::OTHER,
-            self.peek.unwrap(),
-        )?)
+            self.lexeme_of(self.cur_step, self.cur_step),
+        )
+    }
+}
+
+/**
+ * Iterate over the tokens, the iteration ends at the end of the rule
+ * content, and stops after yielding the first scan error
+ */
+impl Iterator for Lexer {
+    type Item = Result<Box<dyn Token>, ErrCode>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match self.scan() {
+            Ok(token) => Some(Ok(token)),
+            Err(ErrCode::READ_TO_END(_)) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lexer, Span, Token, TokenTag};
+
+    #[test]
+    fn test_relex() {
+        let lex = |content: &str| -> Vec<(TokenTag, String, Span)> {
+            Lexer::create(content.to_string())
+                .unwrap()
+                .map(|t| t.unwrap())
+                .map(|t| (t.token_tag().clone(), t.lexeme().to_string(), t.span()))
+                .collect()
+        };
+        let content = "(AND (GT ${a} 1)\n  (LT ${b} \"x y\") ; note\n  (IN ${c} 10 20))";
+        for (range, new_text) in [
+            (15..16, "100"),
+            (5..8, "(GTE"),
+            (1..4, "OR"),
+            (21..21, "\n\n"),
+            (0..0, "  "),
+            (35..35, " ; "),
+            (42..49, "more"),
+            (11..12, "abc"),
+            (53..55, "5"),
+            (0..content.len(), "(NOT 1)"),
+        ] {
+            let mut lexer = Lexer::create(content.to_string()).unwrap();
+            let mut tokens: Vec<Box<dyn Token>> = Lexer::create(content.to_string())
+                .unwrap()
+                .map(|t| t.unwrap())
+                .collect();
+            lexer.relex(&mut tokens, range.clone(), new_text).unwrap();
+            let expected = format!(
+                "{}{}{}",
+                &content[..range.start],
+                new_text,
+                &content[range.end..]
+            );
+            let relexed: Vec<(TokenTag, String, Span)> = tokens
+                .iter()
+                .map(|t| (t.token_tag().clone(), t.lexeme().to_string(), t.span()))
+                .collect();
+            assert_eq!(relexed, lex(&expected), "{:?} {}", range, new_text);
+            assert!(lexer.next().is_none());
+        }
     }
 }
 
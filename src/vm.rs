@@ -0,0 +1,692 @@
+use crate::ast::{
+    arith, between, equal_values, is_member, modulo, truthy, ArithOp, AstError, CompareOp, Env,
+    EvalOptions, Expr, ExprKind, OverflowPolicy, Value, Var, VariableResolver,
+};
+use crate::function::Function;
+use crate::token::Span;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+// One step of the stack machine, spans are of the node the step belongs to
+// so that errors have the same position as with the tree-walking eval
+#[derive(Debug, Clone)]
+enum Instr {
+    Const(Value),
+    Load(Var, Span),
+    // Node which has no instructions of its own, evaluated by walking it
+    Tree(Expr),
+    Jump(usize),
+    // Pop a value and jump when its truthiness is `when`, pushing `push`
+    // first when it is set
+    JumpIf {
+        op: &'static str,
+        when: bool,
+        push: Option<bool>,
+        target: usize,
+        span: Span,
+    },
+    Not(Span),
+    Xor(usize, Span),
+    Compare(CompareOp, Span),
+    Between(Span),
+    Mod(Span),
+    Arith {
+        op: ArithOp,
+        overflow: OverflowPolicy,
+        n: usize,
+        span: Span,
+    },
+    // Pop an arg of IN, when the value below it is a member replace that
+    // value with true and jump
+    InStep(usize),
+    // Replace the top value with whether it is one of the literal args of IN
+    InSet(ValueSet),
+    // Pop an arg of EQUALS, when it differs from the value below it
+    // replace that value with false and jump
+    EqualsStep {
+        ignore_case: bool,
+        target: usize,
+    },
+    // Replace the top value
+    Replace(Value),
+    // Push the value kept in the memo slot and jump, when there is one
+    Memo {
+        slot: usize,
+        end: usize,
+    },
+    // Keep the top value in the memo slot
+    Store(usize),
+    // Pop the n args of the function and push its value
+    Call(Function, usize, Span),
+}
+
+/**
+ * Flat instructions of an expression for a stack machine, so evaluation is
+ * a loop over the instructions instead of a recursive call per node.
+ * Operators without instructions of their own are evaluated by walking
+ * their tree, the result is always the same as Expr::eval.
+ */
+#[derive(Debug, Clone)]
+pub struct Bytecode {
+    code: Vec<Instr>,
+    // Count of the memo slots an eval keeps results of shared nodes in
+    memo_slots: usize,
+}
+
+impl Expr {
+    pub fn compile_to_bytecode(&self) -> Bytecode {
+        let mut shared = HashMap::new();
+        count_shared(self, &mut shared);
+        let mut compiler = Compiler {
+            code: Vec::new(),
+            shared: shared,
+            slots: HashMap::new(),
+        };
+        compiler.emit(self);
+        return Bytecode {
+            code: compiler.code,
+            memo_slots: compiler.slots.len(),
+        };
+    }
+}
+
+// Literal args of IN by the hash of their value, so that membership is a
+// lookup instead of a comparison with every arg
+#[derive(Debug, Clone)]
+struct ValueSet {
+    buckets: HashMap<u64, Vec<Value>>,
+}
+
+impl ValueSet {
+    fn contains(&self, val: &Value) -> bool {
+        match self.buckets.get(&hash_value(val)) {
+            Some(values) => values.iter().any(|v| v == val),
+            None => false,
+        }
+    }
+}
+
+// Values which are equal have the same hash, INT is hashed as the FLOAT it
+// is compared as so that (IN 1 1.0) holds
+pub(crate) fn hash_value(val: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match val {
+        Value::INT(i) => hash_number(*i as f64, &mut hasher),
+        Value::FLOAT(f) => hash_number(*f, &mut hasher),
+        Value::BOOL(b) => (1, b).hash(&mut hasher),
+        Value::STR(s) => (2, s).hash(&mut hasher),
+        Value::NULL => 3.hash(&mut hasher),
+        Value::BYTES(bytes) => (4, bytes).hash(&mut hasher),
+        Value::TIMESTAMP(millis) => (5, millis).hash(&mut hasher),
+        // Never equal to a literal
+        Value::LIST(_) | Value::MAP(_) | Value::LAMBDA(_) => 6.hash(&mut hasher),
+    }
+    return hasher.finish();
+}
+
+fn hash_number(f: f64, hasher: &mut DefaultHasher) {
+    // -0.0 equals 0.0
+    let f = if f == 0.0 { 0.0 } else { f };
+    (0, f.to_bits()).hash(hasher);
+}
+
+pub(crate) fn literal(expr: &Expr) -> Option<Value> {
+    match &expr.kind {
+        ExprKind::Int(i) => Some(Value::INT(*i)),
+        ExprKind::Float(f) => Some(Value::FLOAT(*f)),
+        ExprKind::Str(s) => Some(Value::STR(s.clone())),
+        ExprKind::Bool(b) => Some(Value::BOOL(*b)),
+        ExprKind::Null => Some(Value::NULL),
+        ExprKind::Bytes(bytes) => Some(Value::BYTES(bytes.clone())),
+        ExprKind::Timestamp(millis) => Some(Value::TIMESTAMP(*millis)),
+        _ => None,
+    }
+}
+
+struct Compiler {
+    code: Vec<Instr>,
+    // Occurrences of every pure node with args, by its rule content
+    shared: HashMap<String, usize>,
+    // Memo slot of every shared node emitted so far
+    slots: HashMap<String, usize>,
+}
+
+// Count the pure nodes with args, those without NOW or CALL, and tell whether the
+// expression is pure. Nodes under LET, LAMBDA and the operators over LIST
+// elements are evaluated by walking that node so they are not counted.
+fn count_shared(expr: &Expr, shared: &mut HashMap<String, usize>) -> bool {
+    let pure = match &expr.kind {
+        ExprKind::Now => false,
+        // Functions of the host may give another value on every call
+        ExprKind::Call(_, args) => {
+            for arg in args.iter() {
+                count_shared(arg, shared);
+            }
+            false
+        }
+        ExprKind::Let(..) | ExprKind::Lambda(..) | ExprKind::Each(..) => {
+            let mut pure = true;
+            expr.walk(&mut |e| pure &= !matches!(e.kind, ExprKind::Now | ExprKind::Call(..)));
+            pure
+        }
+        _ => {
+            let mut pure = true;
+            for child in expr.children() {
+                pure &= count_shared(child, shared);
+            }
+            pure
+        }
+    };
+    if pure && !expr.children().is_empty() {
+        *shared.entry(expr.to_sexpr()).or_insert(0) += 1;
+    }
+    return pure;
+}
+
+// Point the jumps at the given instructions to the current end
+fn patch(code: &mut [Instr], jumps: &[usize]) {
+    let end = code.len();
+    for at in jumps.iter() {
+        match &mut code[*at] {
+            Instr::Jump(target)
+            | Instr::JumpIf { target, .. }
+            | Instr::InStep(target)
+            | Instr::EqualsStep { target, .. }
+            | Instr::Memo { end: target, .. } => *target = end,
+            _ => {}
+        }
+    }
+}
+
+impl Compiler {
+    // Pure nodes which occur more than once are evaluated once per eval,
+    // the first result is kept in a memo slot
+    fn emit(&mut self, expr: &Expr) {
+        if expr.children().is_empty() {
+            return self.emit_kind(expr);
+        }
+        let key = expr.to_sexpr();
+        if self.shared.get(&key).is_none_or(|count| *count < 2) {
+            return self.emit_kind(expr);
+        }
+        let slots = self.slots.len();
+        let slot = *self.slots.entry(key).or_insert(slots);
+        let memo = self.code.len();
+        self.code.push(Instr::Memo { slot: slot, end: 0 });
+        self.emit_kind(expr);
+        self.code.push(Instr::Store(slot));
+        patch(&mut self.code, &[memo]);
+    }
+
+    // AND, OR and NAND stop at the first arg whose truthiness is `when`
+    fn emit_short_circuit(
+        &mut self,
+        args: &[Expr],
+        op: &'static str,
+        when: bool,
+        result: bool,
+        span: Span,
+    ) {
+        let mut jumps = Vec::new();
+        for arg in args.iter() {
+            self.emit(arg);
+            jumps.push(self.code.len());
+            self.code.push(Instr::JumpIf {
+                op: op,
+                when: when,
+                push: Some(result),
+                target: 0,
+                span: span,
+            });
+        }
+        self.code.push(Instr::Const(Value::BOOL(!result)));
+        patch(&mut self.code, &jumps);
+    }
+
+    fn emit_kind(&mut self, expr: &Expr) {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::And(args) => self.emit_short_circuit(args, "and", false, false, span),
+            ExprKind::Or(args) => self.emit_short_circuit(args, "or", true, true, span),
+            ExprKind::Nand(args) => self.emit_short_circuit(args, "nand", false, true, span),
+            ExprKind::Not(arg) => {
+                self.emit(arg);
+                self.code.push(Instr::Not(span));
+            }
+            ExprKind::Xor(args) => {
+                for arg in args.iter() {
+                    self.emit(arg);
+                }
+                self.code.push(Instr::Xor(args.len(), span));
+            }
+            // A value is never a member of a literal, only equal to it
+            ExprKind::In(args)
+                if args.len() >= 2 && args[1..].iter().all(|a| literal(a).is_some()) =>
+            {
+                let mut buckets: HashMap<u64, Vec<Value>> = HashMap::new();
+                for val in args[1..].iter().filter_map(literal) {
+                    buckets.entry(hash_value(&val)).or_default().push(val);
+                }
+                self.emit(&args[0]);
+                self.code.push(Instr::InSet(ValueSet { buckets: buckets }));
+            }
+            ExprKind::In(args) | ExprKind::Equals(args) | ExprKind::EqualsCi(args)
+                if args.len() >= 2 =>
+            {
+                let mut jumps = Vec::new();
+                self.emit(&args[0]);
+                for arg in args[1..].iter() {
+                    self.emit(arg);
+                    jumps.push(self.code.len());
+                    self.code.push(match &expr.kind {
+                        ExprKind::In(_) => Instr::InStep(0),
+                        kind => Instr::EqualsStep {
+                            ignore_case: matches!(kind, ExprKind::EqualsCi(_)),
+                            target: 0,
+                        },
+                    });
+                }
+                let in_expr = matches!(expr.kind, ExprKind::In(_));
+                self.code.push(Instr::Replace(Value::BOOL(!in_expr)));
+                patch(&mut self.code, &jumps);
+            }
+            ExprKind::Compare(op, arg0, arg1) => {
+                self.emit(arg0);
+                self.emit(arg1);
+                self.code.push(Instr::Compare(*op, span));
+            }
+            ExprKind::Between(val, low, high) => {
+                self.emit(val);
+                self.emit(low);
+                self.emit(high);
+                self.code.push(Instr::Between(span));
+            }
+            ExprKind::Mod(arg0, arg1) => {
+                self.emit(arg0);
+                self.emit(arg1);
+                self.code.push(Instr::Mod(span));
+            }
+            ExprKind::Arith(op, args, overflow) => {
+                let mut n = args.len();
+                if *op == ArithOp::SUB && n == 1 {
+                    self.code.push(Instr::Const(Value::INT(0)));
+                    n += 1;
+                }
+                for arg in args.iter() {
+                    self.emit(arg);
+                }
+                self.code.push(Instr::Arith {
+                    op: *op,
+                    overflow: *overflow,
+                    n: n,
+                    span: span,
+                });
+            }
+            // Test of every branch jumps to the next branch when it is false,
+            // the result of a branch jumps to the end
+            ExprKind::Cond(branches, default) => {
+                let mut ends = Vec::new();
+                for (test, result) in branches.iter() {
+                    self.emit(test);
+                    let next = self.code.len();
+                    self.code.push(Instr::JumpIf {
+                        op: "cond",
+                        when: false,
+                        push: None,
+                        target: 0,
+                        span: span,
+                    });
+                    self.emit(result);
+                    ends.push(self.code.len());
+                    self.code.push(Instr::Jump(0));
+                    patch(&mut self.code, &[next]);
+                }
+                match default {
+                    Some(default) => self.emit(default),
+                    None => self.code.push(Instr::Const(Value::BOOL(false))),
+                }
+                patch(&mut self.code, &ends);
+            }
+            ExprKind::Int(i) => self.code.push(Instr::Const(Value::INT(*i))),
+            ExprKind::Float(f) => self.code.push(Instr::Const(Value::FLOAT(*f))),
+            ExprKind::Str(s) => self.code.push(Instr::Const(Value::STR(s.clone()))),
+            ExprKind::Bool(b) => self.code.push(Instr::Const(Value::BOOL(*b))),
+            ExprKind::Null => self.code.push(Instr::Const(Value::NULL)),
+            ExprKind::Bytes(bytes) => self.code.push(Instr::Const(Value::BYTES(bytes.clone()))),
+            ExprKind::Timestamp(millis) => self.code.push(Instr::Const(Value::TIMESTAMP(*millis))),
+            ExprKind::Var(var) => self.code.push(Instr::Load(var.clone(), span)),
+            // Outside of LET the variables are the ones of the context, so
+            // the referenced rule is evaluated in place
+            ExprKind::Rule(_, Some(body)) => self.emit(body),
+            ExprKind::Call(function, args) => {
+                for arg in args.iter() {
+                    self.emit(arg);
+                }
+                self.code
+                    .push(Instr::Call(function.clone(), args.len(), span));
+            }
+            _ => self.code.push(Instr::Tree(expr.clone())),
+        }
+    }
+}
+
+impl Bytecode {
+    pub fn eval(&self, ctx: &dyn VariableResolver) -> Result<Value, AstError> {
+        return self.eval_with_options(ctx, &EvalOptions::default());
+    }
+
+    pub fn eval_with_options(
+        &self,
+        ctx: &dyn VariableResolver,
+        options: &EvalOptions,
+    ) -> Result<Value, AstError> {
+        return self.run(Env {
+            vars: ctx,
+            root: ctx,
+            slots: None,
+            options: options,
+            calls: None,
+            depth: 0,
+        });
+    }
+
+    // Eval with the values of the slots the variables are bound to, see
+    // Expr::bind_slots
+    pub(crate) fn eval_slots(
+        &self,
+        slots: &[Value],
+        options: &EvalOptions,
+    ) -> Result<Value, AstError> {
+        let vars: HashMap<String, Value> = HashMap::new();
+        return self.run(Env {
+            vars: &vars,
+            root: &vars,
+            slots: Some(slots),
+            options: options,
+            calls: None,
+            depth: 0,
+        });
+    }
+
+    fn run(&self, ctx: Env) -> Result<Value, AstError> {
+        let mut stack: Vec<Value> = Vec::with_capacity(8);
+        let mut memo: Vec<Option<Value>> = vec![None; self.memo_slots];
+        let mut pc = 0;
+        while pc < self.code.len() {
+            match &self.code[pc] {
+                Instr::Const(val) => stack.push(val.clone()),
+                Instr::Load(var, span) => stack.push(var.eval(ctx).map_err(|e| e.at(*span))?),
+                Instr::Tree(expr) => stack.push(expr.eval_env(ctx)?),
+                Instr::Jump(target) => {
+                    pc = *target;
+                    continue;
+                }
+                Instr::JumpIf {
+                    op,
+                    when,
+                    push,
+                    target,
+                    span,
+                } => {
+                    let val = stack.pop().unwrap();
+                    if truthy(val, op, ctx.options).map_err(|e| e.at(*span))? == *when {
+                        if let Some(b) = push {
+                            stack.push(Value::BOOL(*b));
+                        }
+                        pc = *target;
+                        continue;
+                    }
+                }
+                Instr::Not(span) => {
+                    let val = stack.pop().unwrap();
+                    let b = truthy(val, "not", ctx.options).map_err(|e| e.at(*span))?;
+                    stack.push(Value::BOOL(!b));
+                }
+                Instr::Xor(n, span) => {
+                    let mut result = false;
+                    for val in stack.split_off(stack.len() - n) {
+                        if truthy(val, "xor", ctx.options).map_err(|e| e.at(*span))? {
+                            result = !result;
+                        }
+                    }
+                    stack.push(Value::BOOL(result));
+                }
+                Instr::Compare(op, span) => {
+                    let arg1 = stack.pop().unwrap();
+                    let arg0 = stack.pop().unwrap();
+                    stack.push(op.apply(&arg0, &arg1).map_err(|e| e.at(*span))?);
+                }
+                Instr::Between(span) => {
+                    let high = stack.pop().unwrap();
+                    let low = stack.pop().unwrap();
+                    let val = stack.pop().unwrap();
+                    stack.push(between(&val, &low, &high).map_err(|e| e.at(*span))?);
+                }
+                Instr::Mod(span) => {
+                    let arg1 = stack.pop().unwrap();
+                    let arg0 = stack.pop().unwrap();
+                    stack.push(modulo(arg0, arg1).map_err(|e| e.at(*span))?);
+                }
+                Instr::Arith {
+                    op,
+                    overflow,
+                    n,
+                    span,
+                } => {
+                    let values = stack.split_off(stack.len() - n);
+                    stack.push(arith(*op, *overflow, values).map_err(|e| e.at(*span))?);
+                }
+                Instr::Call(function, n, span) => {
+                    let values = stack.split_off(stack.len() - n);
+                    stack.push(function.apply(&values).map_err(|e| e.at(*span))?);
+                }
+                Instr::InStep(target) => {
+                    let arg = stack.pop().unwrap();
+                    let top = stack.last_mut().unwrap();
+                    if is_member(top, &arg) {
+                        *top = Value::BOOL(true);
+                        pc = *target;
+                        continue;
+                    }
+                }
+                Instr::EqualsStep {
+                    ignore_case,
+                    target,
+                } => {
+                    let arg = stack.pop().unwrap();
+                    let top = stack.last_mut().unwrap();
+                    if !equal_values(top, &arg, *ignore_case) {
+                        *top = Value::BOOL(false);
+                        pc = *target;
+                        continue;
+                    }
+                }
+                Instr::Replace(val) => *stack.last_mut().unwrap() = val.clone(),
+                Instr::InSet(set) => {
+                    let top = stack.last_mut().unwrap();
+                    *top = Value::BOOL(set.contains(top));
+                }
+                Instr::Memo { slot, end } => {
+                    if let Some(val) = &memo[*slot] {
+                        stack.push(val.clone());
+                        pc = *end;
+                        continue;
+                    }
+                }
+                Instr::Store(slot) => memo[*slot] = stack.last().cloned(),
+            }
+            pc += 1;
+        }
+        return Ok(stack.pop().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{EvalOptions, Parser, Truthiness, Value, VariableResolver};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_bytecode_matches_tree_eval() {
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        kv.insert("age".to_string(), Value::INT(30));
+        kv.insert("name".to_string(), Value::from("Bob"));
+        kv.insert("price".to_string(), Value::FLOAT(2.5));
+        kv.insert("tags".to_string(), Value::from(vec!["a", "b"]));
+        kv.insert("max".to_string(), Value::INT(i64::MAX));
+        let rules = [
+            "(AND (GT ${age} 18) (EQUALS ${name} \"Bob\"))",
+            "(AND (GT ${age} 40) (LEN 5))",
+            "(OR (LT ${age} 18) (IN ${name} \"Al\" \"Bob\") (LEN 5))",
+            "(OR ${missing} 0 NULL)",
+            "(NAND true ${age} (NOT 1))",
+            "(XOR true false true)",
+            "(NOT (IN \"c\" ${tags} \"d\"))",
+            "(IN \"b\" \"x\" ${tags})",
+            "(EQUALS ${age} 30 30.0 (LEN 5))",
+            "(EQUALS ${age} 31 (LEN 5))",
+            "(EQUALS-CI ${name} \"BOB\" \"bob\")",
+            "(BETWEEN ${age} 18 (MUL 10 3))",
+            "(COND ((GT ${age} 40) \"old\") ((GT ${age} 20) (UPPER ${name})) (ELSE \"young\"))",
+            "(COND ((LT ${age} 20) 1))",
+            "(ADD (MOD ${age} 7) (SUB ${price}) (MUL 2 3))",
+            "(LET ((x (ADD ${age} 1))) (AND (GT ${x} 30) (IN ${x} 31)))",
+            "(AND true\n  (GT ${name} 1))",
+            "(OR false (MOD ${age} 0))",
+            "(ADD ${max} 1)",
+            "(NOT \"x\")",
+            "(COND (\"x\" 1))",
+            "(AND (LEN ${age}))",
+            "(AND (OR true (MOD ${age} 0)) (GT (MOD ${age} 0) 1))",
+            "(IN ${age} 1 \"30\" 30.0)",
+            "(IN ${price} 2 2.5 NULL)",
+            "(IN ${tags} \"a\" \"b\")",
+            "(IN ${missing} true NULL)",
+            "(ADD (MOD ${age} 7) (MOD ${age} 7) (LET ((age 1)) (MOD ${age} 7)))",
+        ];
+        let strict = EvalOptions {
+            truthiness: Truthiness::StrictBool,
+        };
+        for rule in rules {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            let expr = p.parse().unwrap();
+            let bytecode = expr.compile_to_bytecode();
+            for options in [EvalOptions::default(), strict.clone()] {
+                let tree = expr.eval_with_options(&kv, &options);
+                let vm = bytecode.eval_with_options(&kv, &options);
+                match (tree, vm) {
+                    (Ok(tree), Ok(vm)) => assert_eq!(tree, vm, "{}", rule),
+                    (Err(tree), Err(vm)) => {
+                        assert_eq!(tree.to_string(), vm.to_string(), "{}", rule)
+                    }
+                    (tree, vm) => panic!("{}: tree {:?}, bytecode {:?}", rule, tree, vm),
+                }
+            }
+        }
+    }
+
+    // Resolves variables from a map and counts the lookups
+    struct Counting {
+        values: HashMap<String, Value>,
+        lookups: Cell<usize>,
+    }
+
+    impl VariableResolver for Counting {
+        fn resolve(&self, name: &str) -> Option<Value> {
+            self.lookups.set(self.lookups.get() + 1);
+            return self.values.get(name).cloned();
+        }
+    }
+
+    #[test]
+    fn test_shared_nodes_eval_once() {
+        let mut values: HashMap<String, Value> = HashMap::new();
+        values.insert("id".to_string(), Value::INT(142));
+        let ctx = Counting {
+            values: values,
+            lookups: Cell::new(0),
+        };
+        let cases = [
+            (
+                "(AND (GT (MOD ${id} 100) 10) (LT (MOD ${id} 100) 50))",
+                1,
+                true,
+            ),
+            (
+                "(OR (GT ${id} 200) (EQUALS (MOD ${id} 100) 42) (MOD ${id} 100))",
+                2,
+                true,
+            ),
+            (
+                "(IN (ADD ${id} 1) (SUB (ADD ${id} 1) 1) (ADD ${id} 1))",
+                1,
+                true,
+            ),
+            (
+                "(AND (GT (ADD ${id} 1) 0) (LET ((id 5)) (EQUALS (ADD ${id} 1) 6)))",
+                1,
+                true,
+            ),
+            ("(EQUALS (MOD ${id} 100) (MOD ${id} 10))", 2, false),
+        ];
+        for (rule, lookups, expected) in cases {
+            let mut p = Parser::create(rule.to_string()).unwrap();
+            let bytecode = p.parse().unwrap().compile_to_bytecode();
+            for _ in 0..2 {
+                ctx.lookups.set(0);
+                assert_eq!(
+                    bytecode.eval(&ctx).unwrap(),
+                    Value::BOOL(expected),
+                    "{}",
+                    rule
+                );
+                assert_eq!(ctx.lookups.get(), lookups, "{}", rule);
+            }
+        }
+    }
+
+    #[test]
+    fn test_in_literal_set() {
+        let allowed: Vec<String> = (0..5000).map(|i| format!("\"user-{}\"", i * 2)).collect();
+        let rule = format!("(IN ${{user}} {})", allowed.join(" "));
+        let mut p = Parser::create(rule).unwrap();
+        let bytecode = p.parse().unwrap().compile_to_bytecode();
+        let mut kv: HashMap<String, Value> = HashMap::new();
+        for (user, expected) in [
+            ("user-4242", true),
+            ("user-4243", false),
+            ("user-9998", true),
+        ] {
+            kv.insert("user".to_string(), Value::from(user));
+            assert_eq!(
+                bytecode.eval(&kv).unwrap(),
+                Value::BOOL(expected),
+                "{}",
+                user
+            );
+        }
+        let mut p = Parser::create("(IN ${n} 9007199254740992 0.0 1.5)".to_string()).unwrap();
+        let bytecode = p.parse().unwrap().compile_to_bytecode();
+        let cases = [
+            (Value::INT(9_007_199_254_740_992), true),
+            (Value::INT(9_007_199_254_740_993), false),
+            (Value::FLOAT(9_007_199_254_740_992.0), true),
+            (Value::FLOAT(-0.0), true),
+            (Value::INT(0), true),
+            (Value::FLOAT(1.5), true),
+            (Value::FLOAT(f64::NAN), false),
+            (Value::from("1.5"), false),
+        ];
+        for (n, expected) in cases {
+            kv.insert("n".to_string(), n.clone());
+            assert_eq!(
+                bytecode.eval(&kv).unwrap(),
+                Value::BOOL(expected),
+                "{:?}",
+                n
+            );
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use crate::json::{self, Json};
+
+/**
+ * Parse the block style of YAML which configuration files are written in
+ * into the same tree as JSON: mappings and sequences nested by indent,
+ * `|` and `>` block scalars, flow collections like [a, b], plain and
+ * quoted scalars and comments. Anchors, tags and documents after the first
+ * one are not supported. The error has the line of the problem.
+ */
+pub fn parse(s: &str) -> Result<Json, String> {
+    let mut parser = YamlParser {
+        lines: s.lines().map(|line| line.to_string()).collect(),
+        pos: 0,
+        depth: 0,
+    };
+    let doc = match parser.current()? {
+        Some((indent, _)) => parser.node(indent)?,
+        None => Json::Null,
+    };
+    if parser.current()?.is_some() {
+        return Err(parser.error("unexpected content after the document"));
+    }
+    return Ok(doc);
+}
+
+// Nesting is limited the same way as with JSON
+const MAX_DEPTH: usize = 512;
+
+struct YamlParser {
+    lines: Vec<String>,
+    // Index of the line being parsed
+    pos: usize,
+    depth: usize,
+}
+
+impl YamlParser {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at line {}", msg, self.pos + 1)
+    }
+
+    // Indent and text of the next line with content, blank lines, comment
+    // lines and the document start are skipped
+    fn current(&mut self) -> Result<Option<(usize, String)>, String> {
+        while self.pos < self.lines.len() {
+            let line = &self.lines[self.pos];
+            let text = line.trim_start_matches(' ');
+            if text.starts_with('\t') {
+                return Err(self.error("tabs can not indent"));
+            }
+            let trimmed = text.trim_end();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed == "---" {
+                self.pos += 1;
+                continue;
+            }
+            return Ok(Some((line.len() - text.len(), trimmed.to_string())));
+        }
+        return Ok(None);
+    }
+
+    fn node(&mut self, indent: usize) -> Result<Json, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+        let (_, text) = self.current()?.unwrap_or_default();
+        let val = if is_item(&text) {
+            self.sequence(indent)?
+        } else if split_key(&text)?.is_some() {
+            self.mapping(indent)?
+        } else {
+            self.pos += 1;
+            flow(strip_comment(&text)).map_err(|e| self.error(&e))?
+        };
+        self.depth -= 1;
+        return Ok(val);
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Json, String> {
+        let mut items = Vec::new();
+        while let Some((n, text)) = self.current()? {
+            if n < indent || (n == indent && !is_item(&text)) {
+                break;
+            }
+            if n > indent {
+                return Err(self.error("expected a sequence item"));
+            }
+            let rest = text[1..].trim_start();
+            if strip_comment(rest).is_empty() {
+                self.pos += 1;
+                match self.current()? {
+                    Some((m, _)) if m > indent => items.push(self.node(m)?),
+                    _ => items.push(Json::Null),
+                }
+                continue;
+            }
+            // The item is parsed as if it started a line of its own, so a
+            // mapping goes on with the lines of the same indent
+            let offset = n + text.len() - rest.len();
+            self.lines[self.pos] = format!("{}{}", " ".repeat(offset), rest);
+            items.push(self.node(offset)?);
+        }
+        return Ok(Json::Array(items));
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Json, String> {
+        let mut entries: Vec<(String, Json)> = Vec::new();
+        while let Some((n, text)) = self.current()? {
+            if n < indent {
+                break;
+            }
+            let (key, rest) = match split_key(&text)? {
+                Some(entry) if n == indent => entry,
+                _ => return Err(self.error("expected a key")),
+            };
+            if entries.iter().any(|(k, _)| *k == key) {
+                return Err(self.error(&format!("duplicate key {}", key)));
+            }
+            let rest = strip_comment(rest);
+            if !rest.is_empty() && !rest.starts_with('|') && !rest.starts_with('>') {
+                let val = flow(rest).map_err(|e| self.error(&e))?;
+                self.pos += 1;
+                entries.push((key, val));
+                continue;
+            }
+            self.pos += 1;
+            let val = if rest.is_empty() {
+                match self.current()? {
+                    Some((m, _)) if m > indent => self.node(m)?,
+                    // A sequence may have the indent of its key
+                    Some((m, t)) if m == indent && is_item(&t) => self.sequence(m)?,
+                    _ => Json::Null,
+                }
+            } else {
+                self.block_scalar(indent, rest)?
+            };
+            entries.push((key, val));
+        }
+        return Ok(Json::Object(entries));
+    }
+
+    // Lines indented deeper than the key, `|` keeps the line breaks and
+    // `>` folds the lines into one. The last line break is kept, all of
+    // them with `+` and none with `-`.
+    fn block_scalar(&mut self, indent: usize, header: &str) -> Result<Json, String> {
+        let chomp = &header[1..];
+        if !["", "-", "+"].contains(&chomp) {
+            return Err(self.error("unsupported block scalar header"));
+        }
+        let mut lines = Vec::new();
+        let mut block_indent = None;
+        while self.pos < self.lines.len() {
+            let line = &self.lines[self.pos];
+            let text = line.trim_start_matches(' ');
+            let n = line.len() - text.len();
+            if text.is_empty() {
+                lines.push("");
+            } else if n > indent {
+                let at = *block_indent.get_or_insert(n);
+                lines.push(&line[at.min(n)..]);
+            } else {
+                break;
+            }
+            self.pos += 1;
+        }
+        let mut s = if header.starts_with('|') {
+            lines.join("\n")
+        } else {
+            let mut folded = String::new();
+            for line in lines.iter() {
+                match (line.is_empty(), folded.is_empty() || folded.ends_with('\n')) {
+                    (true, _) => folded.push('\n'),
+                    (false, true) => folded.push_str(line),
+                    (false, false) => {
+                        folded.push(' ');
+                        folded.push_str(line);
+                    }
+                }
+            }
+            folded
+        };
+        if chomp != "+" {
+            s.truncate(s.trim_end_matches('\n').len());
+        }
+        if chomp == "+" || (chomp.is_empty() && !s.is_empty()) {
+            s.push('\n');
+        }
+        return Ok(Json::Str(s));
+    }
+}
+
+fn is_item(text: &str) -> bool {
+    return text == "-" || text.starts_with("- ");
+}
+
+// Key of a `key: value` line and the rest of the line after the colon
+fn split_key(text: &str) -> Result<Option<(String, &str)>, String> {
+    if text.starts_with('"') || text.starts_with('\'') {
+        let end = quoted_end(text)?;
+        let rest = &text[end..];
+        if rest.starts_with(':') && (rest.len() == 1 || rest[1..].starts_with(' ')) {
+            let key = match flow(&text[..end])? {
+                Json::Str(key) => key,
+                _ => return Ok(None),
+            };
+            return Ok(Some((key, &rest[1..])));
+        }
+        return Ok(None);
+    }
+    if text.starts_with('[') || text.starts_with('{') || text.starts_with('#') {
+        return Ok(None);
+    }
+    let text = strip_comment(text);
+    for (i, c) in text.char_indices() {
+        if c == ':' && (i + 1 == text.len() || text[i + 1..].starts_with(' ')) {
+            return Ok(Some((text[..i].trim_end().to_string(), &text[i + 1..])));
+        }
+    }
+    return Ok(None);
+}
+
+// Byte offset after the closing quote of the quoted scalar s starts with
+fn quoted_end(s: &str) -> Result<usize, String> {
+    let quote = s.chars().next().unwrap_or('"');
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote == '"' => escaped = true,
+            '\'' if quote == '\'' && s[i + 1..].starts_with('\'') => escaped = true,
+            _ if c == quote => return Ok(i + 1),
+            _ => {}
+        }
+    }
+    return Err("unterminated quoted scalar".to_string());
+}
+
+// The text without its comment, a comment starts with a # at the start of
+// the text or after a blank, outside of quotes
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut prev = ' ';
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if (c == '"' || c == '\'') && (prev == ' ' || prev == '[' || prev == ',') => {
+                quote = Some(c)
+            }
+            None if c == '#' && prev == ' ' => return text[..i].trim(),
+            None => {}
+        }
+        prev = c;
+    }
+    return text.trim();
+}
+
+// Value written on one line, a flow collection or a scalar
+fn flow(text: &str) -> Result<Json, String> {
+    let mut parser = FlowParser { s: text, pos: 0 };
+    let val = parser.value(false)?;
+    parser.blank();
+    if parser.pos < text.len() {
+        return Err(format!("unexpected chars after {}", &text[..parser.pos]));
+    }
+    return Ok(val);
+}
+
+struct FlowParser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> FlowParser<'a> {
+    fn blank(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    // In a collection plain scalars end at `,` and the closing bracket
+    fn value(&mut self, nested: bool) -> Result<Json, String> {
+        self.blank();
+        let rest = &self.s[self.pos..];
+        if rest.starts_with('[') || rest.starts_with('{') {
+            return self.collection();
+        }
+        if rest.starts_with('"') || rest.starts_with('\'') {
+            let end = quoted_end(rest)?;
+            self.pos += end;
+            if rest.starts_with('\'') {
+                return Ok(Json::Str(rest[1..end - 1].replace("''", "'")));
+            }
+            return json::parse(&rest[..end])
+                .map_err(|e| format!("not a valid quoted scalar, {}", e));
+        }
+        let end = match nested {
+            true => rest.find([',', ']', '}']).unwrap_or(rest.len()),
+            false => rest.len(),
+        };
+        self.pos += end;
+        return Ok(plain(rest[..end].trim()));
+    }
+
+    // A plain key ends at the colon
+    fn key(&mut self) -> Result<String, String> {
+        let rest = &self.s[self.pos..];
+        if rest.starts_with('"') || rest.starts_with('\'') {
+            match self.value(true)? {
+                Json::Str(key) => return Ok(key),
+                other => return Ok(other.to_string()),
+            }
+        }
+        match rest.find([':', ',', '}']) {
+            Some(end) if rest[end..].starts_with(':') => {
+                self.pos += end;
+                return Ok(rest[..end].trim().to_string());
+            }
+            _ => return Err(format!("expected : after {}", rest)),
+        }
+    }
+
+    fn collection(&mut self) -> Result<Json, String> {
+        let is_map = self.s[self.pos..].starts_with('{');
+        let close = if is_map { '}' } else { ']' };
+        self.pos += 1;
+        let mut items = Vec::new();
+        let mut entries = Vec::new();
+        loop {
+            self.blank();
+            if self.s[self.pos..].starts_with(close) {
+                self.pos += 1;
+                break;
+            }
+            if is_map {
+                let key = self.key()?;
+                self.blank();
+                if !self.s[self.pos..].starts_with(':') {
+                    return Err(format!("expected : after {}", key));
+                }
+                self.pos += 1;
+                entries.push((key, self.value(true)?));
+            } else {
+                items.push(self.value(true)?);
+            }
+            self.blank();
+            if self.s[self.pos..].starts_with(',') {
+                self.pos += 1;
+            } else if !self.s[self.pos..].starts_with(close) {
+                return Err(format!("expected , or {}", close));
+            }
+        }
+        if is_map {
+            return Ok(Json::Object(entries));
+        }
+        return Ok(Json::Array(items));
+    }
+}
+
+// Plain scalars are null, bools and numbers when they read as one
+fn plain(s: &str) -> Json {
+    match s {
+        "" | "~" | "null" | "Null" | "NULL" => return Json::Null,
+        "true" | "True" | "TRUE" => return Json::Bool(true),
+        "false" | "False" | "FALSE" => return Json::Bool(false),
+        _ => {}
+    }
+    let digits = s.strip_prefix(['-', '+']).unwrap_or(s);
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        if let Ok(i) = s.parse::<i64>() {
+            return Json::Int(i);
+        }
+    }
+    let numeric = digits.starts_with(|c: char| c.is_ascii_digit() || c == '.');
+    if numeric && digits.chars().any(|c| c.is_ascii_digit()) {
+        if let Ok(f) = s.parse::<f64>() {
+            return Json::Float(f);
+        }
+    }
+    return Json::Str(s.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use crate::json::{self, Json};
+
+    #[test]
+    fn test_parse_yaml() {
+        let doc = parse(
+            "---
+# rules of the checkout
+rules:
+- name: adult   # the first one
+  source: (GTE ${age} 18)
+  priority: -2
+  tags: [a, \"b, c\", 'd''e', 1.5]
+  extra: {x: 1, y: [true, ~]}
+- name: \"long\"
+  source: |
+    (AND
+      (GT ${n} 1)   # kept
+
+      (LT ${n} 9))
+  folded: >-
+    one
+    two
+
+    three
+-
+  - 1
+  -   nested: yes
+      empty:
+owner: 'risk team'
+list:
+  - 0x10
+  - '#not a comment'
+",
+        )
+        .unwrap();
+        let expected = json::parse(
+            r##"{"rules": [
+                {"name": "adult", "source": "(GTE ${age} 18)", "priority": -2,
+                 "tags": ["a", "b, c", "d'e", 1.5], "extra": {"x": 1, "y": [true, null]}},
+                {"name": "long",
+                 "source": "(AND\n  (GT ${n} 1)   # kept\n\n  (LT ${n} 9))\n",
+                 "folded": "one two\nthree"},
+                [1, {"nested": "yes", "empty": null}]
+            ],
+            "owner": "risk team",
+            "list": ["0x10", "#not a comment"]}"##,
+        )
+        .unwrap();
+        assert_eq!(doc, expected);
+        assert_eq!(parse("").unwrap(), Json::Null);
+        assert_eq!(
+            parse("- a\n- b: 1").unwrap().to_string(),
+            "[\"a\",{\"b\":1}]"
+        );
+        for s in [
+            "a: 1\na: 2",
+            "a: 1\n  b: 2",
+            "a:\n\t- 1",
+            "a: [1, 2",
+            "a: \"x",
+            "- a\nb: 1",
+            "a: {b 1}",
+        ] {
+            assert!(parse(s).is_err(), "{}", s);
+        }
+        let err = parse("a: 1\nb: [1,\n").unwrap_err();
+        assert!(err.ends_with("at line 2"), "{}", err);
+    }
+}